@@ -1,12 +1,32 @@
 // src/file_system/directory_manager.rs
 
-use std::path::PathBuf;
-use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::collections::{HashMap, HashSet, VecDeque};
 use tokio::sync::RwLock;
 use anyhow::Result;
 use serde::{Serialize, Deserialize};
+use globset::{GlobBuilder, GlobMatcher};
+use nucleo::pattern::{CaseMatching, Normalization, Pattern};
+use nucleo::{Config, Matcher, Utf32Str};
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+use crate::utils::path_utils::{is_ignored_path, to_relative_path, SymlinkPolicy};
+
+// Reading a directory with more entries than this logs its progress every
+// this many entries
+const PROGRESS_LOG_ENTRIES: usize = 10_000;
+
+// Whether the initial load of the workspace root has finished. It runs in the
+// background so the server accepts connections while a huge root is read.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "content")]
+pub enum IndexState {
+    Indexing,
+    Ready,
+    Failed { message: String },
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct FileNode {
     pub name: String,
     pub path: PathBuf,
@@ -22,10 +42,12 @@ pub struct DirectoryManager {
     workspace_path: PathBuf,
     cache: RwLock<HashMap<PathBuf, Vec<FileNode>>>,
     root: RwLock<Option<FileNode>>,
+    symlinks: SymlinkPolicy,
+    index_state: RwLock<IndexState>,
 }
 
 impl DirectoryManager {
-    pub fn new(workspace_path: PathBuf) -> Result<Self> {
+    pub fn new(workspace_path: PathBuf, symlinks: SymlinkPolicy) -> Result<Self> {
         let workspace_path = workspace_path.canonicalize()?;
         println!("Initialized directory manager at: {:?}", workspace_path);
 
@@ -33,6 +55,8 @@ impl DirectoryManager {
             workspace_path,
             cache: RwLock::new(HashMap::new()),
             root: RwLock::new(None),
+            symlinks,
+            index_state: RwLock::new(IndexState::Indexing),
         })
     }
 
@@ -40,6 +64,10 @@ impl DirectoryManager {
         &self.workspace_path
     }
 
+    pub fn symlink_policy(&self) -> SymlinkPolicy {
+        self.symlinks
+    }
+
     // pub fn get_full_path(&self, relative_path: &str) -> Result<PathBuf> {
     //     let path = if relative_path.is_empty() {
     //         self.workspace_path.clone()
@@ -55,7 +83,8 @@ impl DirectoryManager {
     //     Ok(canonical)
     // }
 
-    async fn read_directory(&self, path: &PathBuf) -> Result<Vec<FileNode>> {
+    // Calls `on_entry` with the number of entries read so far after each one
+    async fn read_directory(&self, path: &PathBuf, mut on_entry: impl FnMut(usize)) -> Result<Vec<FileNode>> {
         println!("Reading directory contents: {:?}", path);
         
         let mut entries = tokio::fs::read_dir(path).await?;
@@ -63,19 +92,32 @@ impl DirectoryManager {
         
         while let Some(entry) = entries.next_entry().await? {
             let path = entry.path();
-            let metadata = entry.metadata().await?;
-            
+            let mut metadata = entry.metadata().await?;
+
+            // Links the policy doesn't follow, and dangling ones, are listed as
+            // plain entries
+            let mut path = path;
+            if metadata.file_type().is_symlink() && self.symlinks.follows(&path, &self.workspace_path) {
+                if let (Ok(target_metadata), Ok(target)) =
+                    (tokio::fs::metadata(&path).await, path.canonicalize())
+                {
+                    metadata = target_metadata;
+                    path = target;
+                }
+            }
+
             nodes.push(FileNode {
-                name: path.file_name()
-                    .unwrap_or_default()
+                name: entry
+                    .file_name()
                     .to_string_lossy()
                     .into_owned(),
-                path: path.canonicalize()?,
+                path,
                 is_directory: metadata.is_dir(),
                 size: metadata.len(),
                 children: None,
                 is_loaded: false,
             });
+            on_entry(nodes.len());
         }
         
         Ok(nodes)
@@ -86,14 +128,35 @@ impl DirectoryManager {
             return Ok(cached.clone());
         }
 
-        let nodes = self.read_directory(path).await?;
+        let nodes = self.read_directory(path, |_| {}).await?;
         self.cache.write().await.insert(path.clone(), nodes.clone());
         
         Ok(nodes)
     }
 
     pub async fn init(&self) -> Result<()> {
-        let root_contents = self.load_directory(&self.workspace_path).await?;
+        println!("Loading workspace root: {:?}", self.workspace_path);
+        let started = std::time::Instant::now();
+        let log_progress = |count: usize| {
+            if count.is_multiple_of(PROGRESS_LOG_ENTRIES) {
+                println!("Read {} entries of the workspace root so far", count);
+            }
+        };
+        let root_contents = match self.read_directory(&self.workspace_path, log_progress).await {
+            Ok(contents) => {
+                self.cache.write().await.insert(self.workspace_path.clone(), contents.clone());
+                contents
+            }
+            Err(e) => {
+                *self.index_state.write().await = IndexState::Failed { message: e.to_string() };
+                return Err(e);
+            }
+        };
+        println!(
+            "Loaded workspace root: {} entries in {:?}",
+            root_contents.len(),
+            started.elapsed()
+        );
         *self.root.write().await = Some(FileNode {
             name: self.workspace_path
                 .file_name()
@@ -106,11 +169,16 @@ impl DirectoryManager {
             children: Some(root_contents),
             is_loaded: true,
         });
+        *self.index_state.write().await = IndexState::Ready;
         Ok(())
     }
 
+    pub async fn index_state(&self) -> IndexState {
+        self.index_state.read().await.clone()
+    }
+
     pub async fn refresh_directory(&self, path: &PathBuf) -> Result<Vec<FileNode>> {
-        let nodes = self.read_directory(path).await?;
+        let nodes = self.read_directory(path, |_| {}).await?;
         self.cache.write().await.insert(path.clone(), nodes.clone());
         Ok(nodes)
     }
@@ -118,4 +186,376 @@ impl DirectoryManager {
     pub async fn invalidate_cache(&self, path: &PathBuf) {
         self.cache.write().await.remove(path);
     }
-}
\ No newline at end of file
+
+    // Entries of the directory whose names fuzzy-match `query`, best first.
+    // Uses the cached listing when there is one.
+    pub async fn filter_directory(&self, path: &PathBuf, query: &str) -> Result<Vec<FileNode>> {
+        let nodes = self.load_directory(path).await?;
+        Ok(filter_nodes(nodes, query))
+    }
+
+    // The whole workspace tree, skipping the directories search ignores and,
+    // without `show_hidden`, hidden entries. At most `max_entries` entries are
+    // listed; directories are expanded breadth first, so a cut tree has its upper
+    // levels complete. Returns whether it was cut.
+    pub async fn full_tree(&self, max_entries: usize, show_hidden: bool) -> Result<(FileNode, bool)> {
+        let root = self.workspace_path.clone();
+        let symlinks = self.symlinks;
+        tokio::task::spawn_blocking(move || walk_tree(&root, max_entries, symlinks, show_hidden)).await?
+    }
+
+    // Workspace-relative paths of files and directories matching `pattern`, sorted,
+    // and whether the walk stopped at `max_matches`
+    pub async fn glob(&self, pattern: &str, max_matches: usize) -> Result<(Vec<PathBuf>, bool)> {
+        let matcher = GlobBuilder::new(pattern)
+            .literal_separator(true)
+            .build()?
+            .compile_matcher();
+        let root = self.workspace_path.clone();
+        let symlinks = self.symlinks;
+        tokio::task::spawn_blocking(move || glob_walk(&root, &matcher, max_matches, symlinks)).await?
+    }
+
+    // Drops the listings a directory move made stale: both parents and
+    // everything under either end. Returns the files now under `to`, relative
+    // to it, without ignored directories or links' targets.
+    pub async fn directory_moved(&self, from: &Path, to: &Path) -> Result<Vec<PathBuf>> {
+        self.cache.write().await.retain(|path, _| {
+            !path.starts_with(from)
+                && !path.starts_with(to)
+                && Some(path.as_path()) != from.parent()
+                && Some(path.as_path()) != to.parent()
+        });
+        let root = to.to_path_buf();
+        tokio::task::spawn_blocking(move || list_files(&root)).await?
+    }
+}
+
+fn list_files(root: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let walker = walkdir::WalkDir::new(root)
+        .min_depth(1)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_entry(|e| !is_ignored_path(e.path(), root))
+        .filter_map(Result::ok);
+    for entry in walker {
+        if entry.file_type().is_file() {
+            files.extend(to_relative_path(root, entry.path()));
+        }
+    }
+    Ok(files)
+}
+
+// Ties keep the listing order. An empty query keeps every node.
+fn filter_nodes(nodes: Vec<FileNode>, query: &str) -> Vec<FileNode> {
+    let pattern = Pattern::parse(query, CaseMatching::Smart, Normalization::Smart);
+    let mut matcher = Matcher::new(Config::DEFAULT.match_paths());
+    let mut buf = Vec::new();
+    let mut scored: Vec<(u32, FileNode)> = nodes
+        .into_iter()
+        .filter_map(|node| {
+            let score = pattern.score(Utf32Str::new(&node.name, &mut buf), &mut matcher)?;
+            Some((score, node))
+        })
+        .collect();
+    scored.sort_by_key(|(score, _)| std::cmp::Reverse(*score));
+    scored.into_iter().map(|(_, node)| node).collect()
+}
+
+fn glob_walk(
+    root: &Path,
+    matcher: &GlobMatcher,
+    max_matches: usize,
+    symlinks: SymlinkPolicy,
+) -> Result<(Vec<PathBuf>, bool)> {
+    let mut matches = Vec::new();
+    let walker = walkdir::WalkDir::new(root)
+        .min_depth(1)
+        .follow_links(symlinks != SymlinkPolicy::Never)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_entry(|e| {
+            !is_ignored_path(e.path(), root) && (!e.path_is_symlink() || symlinks.follows(e.path(), root))
+        });
+    for entry in walker {
+        let entry = match entry {
+            // A link back to one of its own ancestors
+            Err(e) if e.loop_ancestor().is_some() => continue,
+            entry => entry?,
+        };
+        let relative = entry.path().strip_prefix(root).unwrap_or(entry.path());
+        if !matcher.is_match(relative) {
+            continue;
+        }
+        if matches.len() == max_matches {
+            return Ok((matches, true));
+        }
+        matches.push(relative.to_path_buf());
+    }
+    Ok((matches, false))
+}
+
+fn walk_tree(
+    root: &Path,
+    max_entries: usize,
+    symlinks: SymlinkPolicy,
+    show_hidden: bool,
+) -> Result<(FileNode, bool)> {
+    let mut listings: HashMap<PathBuf, Vec<FileNode>> = HashMap::new();
+    let mut pending = VecDeque::from([root.to_path_buf()]);
+    // Canonical directories already listed, so cyclic links are only walked once
+    let mut visited = HashSet::from([root.to_path_buf()]);
+    let mut remaining = max_entries;
+    let mut truncated = false;
+
+    while let Some(directory) = pending.pop_front() {
+        // A subdirectory that can't be listed is left unloaded rather than failing the tree
+        let listing = match std::fs::read_dir(&directory) {
+            Ok(listing) => listing,
+            Err(e) if directory != root => {
+                eprintln!("Failed to list {:?}: {}", directory, e);
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let mut entries = listing
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| !is_ignored_path(&entry.path(), root))
+            .filter(|entry| show_hidden || !is_hidden(&entry.file_name(), &entry.path()))
+            .collect::<Vec<_>>();
+        if entries.len() > remaining {
+            truncated = true;
+            break;
+        }
+        entries.sort_by_key(|entry| entry.file_name());
+        remaining -= entries.len();
+
+        let mut nodes = Vec::with_capacity(entries.len());
+        for entry in entries {
+            let Ok(mut metadata) = entry.metadata() else {
+                continue;
+            };
+            let path = entry.path();
+            // Links the policy doesn't follow are listed as files
+            if metadata.file_type().is_symlink() && symlinks.follows(&path, root) {
+                metadata = std::fs::metadata(&path).unwrap_or(metadata);
+            }
+            if metadata.is_dir() && path.canonicalize().is_ok_and(|target| visited.insert(target)) {
+                pending.push_back(path.clone());
+            }
+            nodes.push(FileNode {
+                name: entry.file_name().to_string_lossy().into_owned(),
+                path,
+                is_directory: metadata.is_dir(),
+                size: metadata.len(),
+                children: None,
+                is_loaded: false,
+            });
+        }
+        listings.insert(directory, nodes);
+    }
+
+    let mut root_node = FileNode {
+        name: root
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned(),
+        path: root.to_path_buf(),
+        is_directory: true,
+        size: 0,
+        children: None,
+        is_loaded: false,
+    };
+    attach_children(&mut root_node, &mut listings);
+    Ok((root_node, truncated))
+}
+
+// Drops the hidden entries of a listing
+pub fn retain_visible(nodes: &mut Vec<FileNode>) {
+    nodes.retain(|node| !is_hidden(OsStr::new(&node.name), &node.path));
+}
+
+// Dot files, and on Windows also entries with the hidden attribute
+pub fn is_hidden(name: &OsStr, path: &Path) -> bool {
+    name.to_string_lossy().starts_with('.') || has_hidden_attribute(path)
+}
+
+#[cfg(windows)]
+fn has_hidden_attribute(path: &Path) -> bool {
+    use std::os::windows::fs::MetadataExt;
+    const FILE_ATTRIBUTE_HIDDEN: u32 = 0x2;
+    std::fs::symlink_metadata(path)
+        .is_ok_and(|metadata| metadata.file_attributes() & FILE_ATTRIBUTE_HIDDEN != 0)
+}
+
+#[cfg(not(windows))]
+fn has_hidden_attribute(_path: &Path) -> bool {
+    false
+}
+
+fn attach_children(node: &mut FileNode, listings: &mut HashMap<PathBuf, Vec<FileNode>>) {
+    if let Some(mut children) = listings.remove(&node.path) {
+        for child in &mut children {
+            attach_children(child, listings);
+        }
+        node.children = Some(children);
+        node.is_loaded = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_matches_relative_paths_outside_ignored_directories() -> Result<()> {
+        let workspace = tempfile::tempdir()?;
+        std::fs::create_dir_all(workspace.path().join("src/bin"))?;
+        std::fs::create_dir_all(workspace.path().join("target/debug"))?;
+        std::fs::write(workspace.path().join("src/main.rs"), "")?;
+        std::fs::write(workspace.path().join("src/bin/tool.rs"), "")?;
+        std::fs::write(workspace.path().join("src/notes.md"), "")?;
+        std::fs::write(workspace.path().join("target/debug/build.rs"), "")?;
+        std::fs::write(workspace.path().join("build.rs"), "")?;
+
+        let glob = |pattern: &str, max_matches| {
+            let matcher = GlobBuilder::new(pattern).literal_separator(true).build()?.compile_matcher();
+            glob_walk(workspace.path(), &matcher, max_matches, SymlinkPolicy::WithinWorkspace)
+        };
+
+        let (matches, truncated) = glob("src/**/*.rs", 100)?;
+        assert!(!truncated);
+        assert_eq!(matches, vec![PathBuf::from("src/bin/tool.rs"), PathBuf::from("src/main.rs")]);
+
+        // `*` doesn't cross directories, and target/ is skipped
+        let (matches, _) = glob("*.rs", 100)?;
+        assert_eq!(matches, vec![PathBuf::from("build.rs")]);
+        let (matches, truncated) = glob("**/*.rs", 2)?;
+        assert!(truncated);
+        assert_eq!(matches.len(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_init_reports_its_state() -> Result<()> {
+        let workspace = tempfile::tempdir()?;
+        std::fs::write(workspace.path().join("main.rs"), "")?;
+        let manager = DirectoryManager::new(workspace.path().to_path_buf(), SymlinkPolicy::default())?;
+        assert_eq!(manager.index_state().await, IndexState::Indexing);
+        manager.init().await?;
+        assert_eq!(manager.index_state().await, IndexState::Ready);
+
+        let gone = tempfile::tempdir()?;
+        let manager = DirectoryManager::new(gone.path().to_path_buf(), SymlinkPolicy::default())?;
+        drop(gone);
+        assert!(manager.init().await.is_err());
+        assert!(matches!(manager.index_state().await, IndexState::Failed { .. }));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_filter_directory_ranks_matching_names() -> Result<()> {
+        let workspace = tempfile::tempdir()?;
+        for name in ["main.rs", "lib.rs", "manager.rs", "README.md"] {
+            std::fs::write(workspace.path().join(name), "")?;
+        }
+        std::fs::create_dir(workspace.path().join("macros"))?;
+        let manager = DirectoryManager::new(workspace.path().to_path_buf(), SymlinkPolicy::default())?;
+
+        let names = |nodes: Vec<FileNode>| nodes.into_iter().map(|node| node.name).collect::<Vec<_>>();
+        let path = workspace.path().to_path_buf();
+        assert_eq!(names(manager.filter_directory(&path, "main").await?), vec!["main.rs"]);
+
+        let matches = names(manager.filter_directory(&path, "mrs").await?);
+        assert_eq!(matches.len(), 3);
+        for name in ["main.rs", "manager.rs", "macros"] {
+            assert!(matches.contains(&name.to_string()), "{:?}", matches);
+        }
+
+        assert_eq!(manager.filter_directory(&path, "").await?.len(), 5);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_moved_directory_listings_are_reloaded() -> Result<()> {
+        let workspace = tempfile::tempdir()?;
+        std::fs::create_dir_all(workspace.path().join("src/net/target"))?;
+        std::fs::create_dir(workspace.path().join("lib"))?;
+        std::fs::write(workspace.path().join("src/net/mod.rs"), "")?;
+        std::fs::write(workspace.path().join("src/net/target/out.rs"), "")?;
+        let manager = DirectoryManager::new(workspace.path().to_path_buf(), SymlinkPolicy::default())?;
+        let (src, lib) = (manager.get_workspace_path().join("src"), manager.get_workspace_path().join("lib"));
+        assert_eq!(manager.load_directory(&src).await?.len(), 1);
+        assert!(manager.load_directory(&lib).await?.is_empty());
+
+        std::fs::rename(src.join("net"), lib.join("net"))?;
+        let files = manager.directory_moved(&src.join("net"), &lib.join("net")).await?;
+        assert_eq!(files, vec![PathBuf::from("mod.rs")]);
+        assert!(manager.load_directory(&src).await?.is_empty());
+        assert_eq!(manager.load_directory(&lib).await?.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_full_tree_skips_ignored_and_reports_cut() -> Result<()> {
+        let workspace = tempfile::tempdir()?;
+        std::fs::create_dir_all(workspace.path().join("src/bin"))?;
+        std::fs::create_dir_all(workspace.path().join("target/debug"))?;
+        std::fs::write(workspace.path().join("src/main.rs"), "")?;
+        std::fs::write(workspace.path().join("src/bin/tool.rs"), "")?;
+        std::fs::write(workspace.path().join("Cargo.toml"), "")?;
+
+        let (root, truncated) = walk_tree(workspace.path(), 100, SymlinkPolicy::WithinWorkspace, true)?;
+        assert!(!truncated);
+        let names = |node: &FileNode| -> Vec<String> {
+            node.children.iter().flatten().map(|child| child.name.clone()).collect()
+        };
+        assert_eq!(names(&root), vec!["Cargo.toml", "src"]);
+        let src = &root.children.as_ref().unwrap()[1];
+        assert!(src.is_loaded);
+        assert_eq!(names(src), vec!["bin", "main.rs"]);
+        assert_eq!(names(&src.children.as_ref().unwrap()[0]), vec!["tool.rs"]);
+
+        // Room for the top level and src, but not for src/bin
+        let (root, truncated) = walk_tree(workspace.path(), 4, SymlinkPolicy::WithinWorkspace, true)?;
+        assert!(truncated);
+        let src = &root.children.as_ref().unwrap()[1];
+        let bin = &src.children.as_ref().unwrap()[0];
+        assert!(!bin.is_loaded);
+        assert!(bin.children.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_hidden_entries_can_be_left_out() -> Result<()> {
+        let workspace = tempfile::tempdir()?;
+        std::fs::create_dir_all(workspace.path().join(".config/tool"))?;
+        std::fs::write(workspace.path().join(".env"), "")?;
+        std::fs::write(workspace.path().join("main.rs"), "")?;
+
+        let names = |node: &FileNode| -> Vec<String> {
+            node.children.iter().flatten().map(|child| child.name.clone()).collect()
+        };
+        let (root, _) = walk_tree(workspace.path(), 100, SymlinkPolicy::WithinWorkspace, true)?;
+        assert_eq!(names(&root), vec![".config", ".env", "main.rs"]);
+        // Hidden directories aren't walked, so they don't use up the budget
+        let (root, truncated) = walk_tree(workspace.path(), 1, SymlinkPolicy::WithinWorkspace, false)?;
+        assert!(!truncated);
+        assert_eq!(names(&root), vec!["main.rs"]);
+
+        let mut listing = root.children.unwrap();
+        listing.push(FileNode {
+            name: ".gitignore".to_string(),
+            path: workspace.path().join(".gitignore"),
+            is_directory: false,
+            size: 0,
+            children: None,
+            is_loaded: false,
+        });
+        retain_visible(&mut listing);
+        assert_eq!(listing.len(), 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,234 @@
+// src/file_system/document_cache.rs
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use super::document_manager::DocumentMetadata;
+
+#[derive(Debug)]
+pub struct CacheEntry {
+    pub content: String,
+    pub metadata: DocumentMetadata,
+    // The file's mtime when the entry was stored, to notice external rewrites
+    pub disk_modified: Option<SystemTime>,
+}
+
+// Content of files read while closed, e.g. for previews or language server
+// lookups. Holds at most `max_size` bytes; the least recently used entries go
+// first.
+#[derive(Debug)]
+pub struct PreviewCache {
+    entries: HashMap<PathBuf, CacheEntry>,
+    // Least recently used first
+    queue: VecDeque<PathBuf>,
+    size: u64,
+    max_size: u64,
+}
+
+impl PreviewCache {
+    pub fn new(max_size: u64) -> Self {
+        Self {
+            entries: HashMap::new(),
+            queue: VecDeque::new(),
+            size: 0,
+            max_size,
+        }
+    }
+
+    pub fn get(&self, path: &Path) -> Option<&CacheEntry> {
+        self.entries.get(path)
+    }
+
+    pub fn get_mut(&mut self, path: &Path) -> Option<&mut CacheEntry> {
+        self.entries.get_mut(path)
+    }
+
+    // Marks an entry as used, so it is evicted last
+    pub fn touch(&mut self, path: &Path) {
+        if let Some(index) = self.queue.iter().position(|queued| queued == path) {
+            if let Some(queued) = self.queue.remove(index) {
+                self.queue.push_back(queued);
+            }
+        }
+    }
+
+    // Entries larger than the whole cache aren't stored
+    pub fn insert(&mut self, path: PathBuf, entry: CacheEntry) {
+        self.remove(&path);
+        let size = entry.content.len() as u64;
+        if size > self.max_size {
+            return;
+        }
+        while self.size + size > self.max_size {
+            let Some(oldest) = self.queue.pop_front() else {
+                break;
+            };
+            if let Some(evicted) = self.entries.remove(&oldest) {
+                self.size -= evicted.content.len() as u64;
+            }
+        }
+        self.size += size;
+        self.queue.push_back(path.clone());
+        self.entries.insert(path, entry);
+    }
+
+    pub fn remove(&mut self, path: &Path) -> Option<CacheEntry> {
+        let entry = self.entries.remove(path)?;
+        self.size -= entry.content.len() as u64;
+        self.queue.retain(|queued| queued != path);
+        Some(entry)
+    }
+
+    fn paths(&self) -> Vec<PathBuf> {
+        self.entries.keys().cloned().collect()
+    }
+
+    // Moves an entry to a new path without changing its place in the queue
+    fn rekey(&mut self, from: &Path, to: PathBuf) {
+        if let Some(entry) = self.entries.remove(from) {
+            for queued in self.queue.iter_mut().filter(|queued| *queued == from) {
+                *queued = to.clone();
+            }
+            self.entries.insert(to, entry);
+        }
+    }
+}
+
+// Cached document content, kept apart by whether the document is open. Open
+// documents hold the content being edited, so they are never evicted; reading
+// any number of closed files only ever evicts other closed ones.
+#[derive(Debug)]
+pub struct DocumentCache {
+    open: HashMap<PathBuf, CacheEntry>,
+    preview: PreviewCache,
+}
+
+impl DocumentCache {
+    pub fn new(max_preview_size: u64) -> Self {
+        Self {
+            open: HashMap::new(),
+            preview: PreviewCache::new(max_preview_size),
+        }
+    }
+
+    pub fn get(&self, path: &Path) -> Option<&CacheEntry> {
+        self.open.get(path).or_else(|| self.preview.get(path))
+    }
+
+    pub fn get_mut(&mut self, path: &Path) -> Option<&mut CacheEntry> {
+        match self.open.get_mut(path) {
+            Some(entry) => Some(entry),
+            None => self.preview.get_mut(path),
+        }
+    }
+
+    pub fn touch(&mut self, path: &Path) {
+        self.preview.touch(path);
+    }
+
+    pub fn insert(&mut self, path: PathBuf, entry: CacheEntry, open: bool) {
+        self.remove(&path);
+        if open {
+            self.open.insert(path, entry);
+        } else {
+            self.preview.insert(path, entry);
+        }
+    }
+
+    pub fn remove(&mut self, path: &Path) -> Option<CacheEntry> {
+        self.open.remove(path).or_else(|| self.preview.remove(path))
+    }
+
+    // Moves an entry between the two caches as its document is opened or
+    // closed. A closed document's content stays if it fits the preview limits.
+    pub fn set_open(&mut self, path: &Path, open: bool, max_preview_entry: u64) {
+        if open {
+            if let Some(entry) = self.preview.remove(path) {
+                self.open.insert(path.to_path_buf(), entry);
+            }
+        } else if let Some(entry) = self.open.remove(path) {
+            if entry.content.len() as u64 <= max_preview_entry {
+                self.preview.insert(path.to_path_buf(), entry);
+            }
+        }
+    }
+
+    // Moves the entries of `old_path` and everything under it
+    pub fn rename(&mut self, old_path: &Path, moved_path: impl Fn(&Path) -> Option<PathBuf>) {
+        let affected: Vec<PathBuf> = self
+            .open
+            .keys()
+            .filter(|path| path.starts_with(old_path))
+            .cloned()
+            .collect();
+        for path in affected {
+            if let (Some(entry), Some(target)) = (self.open.remove(&path), moved_path(&path)) {
+                self.open.insert(target, entry);
+            }
+        }
+        for path in self.preview.paths() {
+            if path.starts_with(old_path) {
+                if let Some(target) = moved_path(&path) {
+                    self.preview.rekey(&path, target);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_system::document_manager::{FileEncoding, FileType, LineEnding};
+
+    fn entry(size: usize) -> CacheEntry {
+        CacheEntry {
+            content: "x".repeat(size),
+            metadata: DocumentMetadata {
+                size: size as u64,
+                is_directory: false,
+                is_symlink: false,
+                created_at: None,
+                modified_at: None,
+                readonly: false,
+                file_type: FileType::Text,
+                encoding: FileEncoding {
+                    encoding: "UTF-8".to_string(),
+                    confidence: 1.0,
+                },
+                line_ending: LineEnding::LF,
+                has_bom: false,
+            },
+            disk_modified: None,
+        }
+    }
+
+    #[test]
+    fn test_previews_never_evict_open_documents() {
+        let mut cache = DocumentCache::new(10);
+        let path = |name: &str| PathBuf::from("/ws").join(name);
+        cache.insert(path("a.rs"), entry(4), false);
+        cache.insert(path("b.rs"), entry(4), false);
+        cache.set_open(&path("a.rs"), true, 10);
+        // Open documents may exceed the preview limit
+        cache.insert(path("large.rs"), entry(20), true);
+
+        // Touching b.rs makes c.rs the least recently used
+        cache.insert(path("c.rs"), entry(4), false);
+        cache.touch(&path("b.rs"));
+        cache.insert(path("d.rs"), entry(4), false);
+        assert!(cache.get(&path("a.rs")).is_some());
+        assert!(cache.get(&path("large.rs")).is_some());
+        assert!(cache.get(&path("b.rs")).is_some());
+        assert!(cache.get(&path("c.rs")).is_none());
+
+        // Closing makes them previews again if they fit, evicting b.rs for a.rs
+        cache.set_open(&path("a.rs"), false, 10);
+        cache.set_open(&path("large.rs"), false, 10);
+        assert!(cache.get(&path("large.rs")).is_none());
+        assert!(cache.get(&path("b.rs")).is_none());
+        cache.insert(path("f.rs"), entry(4), false);
+        assert!(cache.get(&path("d.rs")).is_none());
+        assert!(cache.get(&path("a.rs")).is_some());
+    }
+}
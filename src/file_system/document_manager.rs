@@ -1,14 +1,32 @@
 use anyhow::{bail, Context, Result};
-use encoding_rs::{Encoding, UTF_8};
+use encoding_rs::{Encoding, UTF_16BE, UTF_16LE, UTF_8};
 use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, VecDeque};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
 use tokio::fs;
 use tokio::sync::RwLock;
 
+use super::document_cache::{CacheEntry, DocumentCache};
+use super::merge::{three_way_merge, MergeOutcome};
+use crate::lsp::position_encoding::{client_encoding, column_to_byte};
+use crate::utils::path_utils::{is_within, uri_to_path};
+
 // File size thresholds and configuration
-const MAX_FILE_SIZE: u64 = 10 * 1024 * 1024; // 10MB default limit
-const CACHE_SIZE_LIMIT: u64 = 1024 * 1024; // 1MB cache limit per file
+pub const MAX_FILE_SIZE: u64 = 10 * 1024 * 1024; // 10MB default limit
+// Cache limit for files that aren't open, per file and in total
+const CACHE_SIZE_LIMIT: u64 = 1024 * 1024; // 1MB
+// Past versions kept per open document so clients can catch up after a conflict
+const MAX_RETAINED_VERSIONS: usize = 50;
+const MAX_RETAINED_HISTORY_BYTES: usize = 8 * 1024 * 1024;
+// Encoding confidence. chardetng has no numeric score, so these stand for what
+// is known about the guess: certain (BOM, ASCII), valid UTF-8, a guess that beat
+// other candidates, and one that didn't or left undecodable bytes.
+const CONFIDENCE_CERTAIN: f32 = 1.0;
+const CONFIDENCE_UTF8: f32 = 0.95;
+const CONFIDENCE_LIKELY: f32 = 0.6;
+const CONFIDENCE_LOW: f32 = 0.2;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct VersionedDocument {
@@ -41,6 +59,9 @@ pub struct DocumentMetadata {
     pub file_type: FileType,
     pub encoding: FileEncoding,
     pub line_ending: LineEnding,
+    // The file starts with a byte order mark, which is kept out of the content
+    // and written back on save
+    pub has_bom: bool,
 }
 
 #[derive(Debug, Clone)]
@@ -51,6 +72,7 @@ pub struct DocumentState {
     pub is_dirty: bool,
 }
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum LineEnding {
     CRLF,
@@ -58,22 +80,62 @@ pub enum LineEnding {
     Mixed,
 }
 
-#[derive(Debug)]
-struct CacheEntry {
-    content: String,
-    metadata: DocumentMetadata,
-    last_accessed: std::time::Instant, // For LRU cache TODO
-}
-
 #[derive(Debug)]
 pub struct DocumentManager {
     workspace_path: PathBuf, // to check if document is within workspace TODO
     // open_files is a way to check if a file is already open
     document_states: RwLock<HashMap<PathBuf, DocumentState>>,
-    cache: RwLock<HashMap<PathBuf, CacheEntry>>,
-    cache_queue: RwLock<VecDeque<PathBuf>>,
-    max_cache_size: u64,
-    current_cache_size: RwLock<u64>,
+    cache: RwLock<DocumentCache>,
+    // Open documents up to this size are read from disk once and then served
+    // from memory, larger ones are read again on every access
+    max_open_document_size: u64,
+    // Recent (version, content) pairs of open documents, oldest first
+    history: RwLock<HashMap<PathBuf, VecDeque<(i32, String)>>>,
+    // Encodings chosen with ReopenWithEncoding, used instead of detection
+    encoding_overrides: RwLock<HashMap<PathBuf, &'static Encoding>>,
+    // Content reads served from the cache vs. from disk
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+// One step of a workspace edit that was carried out
+#[derive(Debug)]
+pub enum AppliedEdit {
+    // `document` holds the new content and version of open documents, which are
+    // edited in memory; closed files are rewritten on disk
+    Edited {
+        path: PathBuf,
+        document: Option<(String, DocumentMetadata, i32)>,
+    },
+    Created {
+        path: PathBuf,
+        is_directory: bool,
+    },
+    Renamed {
+        old_path: PathBuf,
+        new_path: PathBuf,
+        is_directory: bool,
+        // (old, new) paths of open documents that moved with it
+        moved_documents: Vec<(PathBuf, PathBuf)>,
+    },
+    Deleted {
+        path: PathBuf,
+        is_directory: bool,
+    },
+}
+
+// Steps are applied in order and the first failure stops the rest, matching the
+// `Abort` failure handling advertised to language servers
+#[derive(Debug, Default)]
+pub struct WorkspaceEditOutcome {
+    pub applied: Vec<AppliedEdit>,
+    pub error: Option<anyhow::Error>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -83,30 +145,144 @@ pub struct DiffChange {
     pub removed: bool,
 }
 
+// Returned by open_file for a directory, so callers can list it instead
+#[derive(Debug)]
+pub struct IsADirectory {
+    pub path: PathBuf,
+}
+
+impl std::fmt::Display for IsADirectory {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Path is a directory: {:?}", self.path)
+    }
+}
+
+impl std::error::Error for IsADirectory {}
+
+// What a path that is neither a file nor a directory is, for error messages
+fn special_file_kind(file_type: std::fs::FileType) -> &'static str {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::FileTypeExt;
+        if file_type.is_socket() {
+            return "a socket";
+        }
+        if file_type.is_fifo() {
+            return "a named pipe";
+        }
+        if file_type.is_block_device() || file_type.is_char_device() {
+            return "a device";
+        }
+    }
+    let _ = file_type;
+    "not a regular file"
+}
+
+// Documents are keyed by their canonical path, so a file reached through a
+// symlink or a second root shares one state, cache entry and version. Paths
+// that don't exist (any more) are used as they are.
+async fn document_key(path: &Path) -> PathBuf {
+    fs::canonicalize(path).await.unwrap_or_else(|_| path.to_path_buf())
+}
+
+// Like document_key, but for operations on the directory entry itself: a
+// symlink stays the link, so deleting or renaming it leaves its target alone
+async fn entry_key(path: &Path) -> PathBuf {
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => document_key(parent).await.join(name),
+        _ => document_key(path).await,
+    }
+}
+
+async fn file_modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).await.and_then(|m| m.modified()).ok()
+}
+
+// Null bytes in the first block usually indicate binary content, unless a BOM
+// says the file is UTF-16
+pub async fn is_binary_file(path: &Path) -> Result<bool> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut buffer = vec![0; 512];
+    let n = tokio::io::AsyncReadExt::read(&mut file, &mut buffer).await?;
+    Ok(Encoding::for_bom(&buffer[..n]).is_none() && buffer[..n].contains(&0))
+}
+
+// Rewrites applied to a document's content right before it is written by a save
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SaveNormalization {
+    // End the content with exactly one line break
+    pub insert_final_newline: bool,
+}
+
+// The content a save should write, None if the normalization leaves it as it
+// is. A missing final line break is added in the document's style, several are
+// collapsed into the last one. Empty documents are left alone.
+pub fn normalize_for_save(content: &str, line_ending: &LineEnding, normalization: SaveNormalization) -> Option<String> {
+    if !normalization.insert_final_newline {
+        return None;
+    }
+    let body = content.trim_end_matches(['\r', '\n']);
+    let ending = &content[body.len()..];
+    if body.is_empty() || ending == "\n" || ending == "\r\n" {
+        return None;
+    }
+    let newline = match line_ending {
+        LineEnding::CRLF => "\r\n",
+        // A mixed document keeps the break it already ends with
+        _ if ending.ends_with("\r\n") => "\r\n",
+        _ => "\n",
+    };
+    Some(format!("{}{}", body, newline))
+}
+
+// `content` as the bytes of a file in `encoding`, after a BOM if `bom` is set
+fn encode_text(content: &str, encoding: &'static Encoding, bom: bool) -> Result<Vec<u8>> {
+    // A BOM sent back as text is only written once
+    let content = match bom {
+        true => content.strip_prefix('\u{FEFF}').unwrap_or(content),
+        false => content,
+    };
+    let mut bytes = Vec::with_capacity(content.len() + 3);
+    // encoding_rs only encodes to ASCII-compatible encodings, so UTF-16 is done here
+    if encoding == UTF_16LE || encoding == UTF_16BE {
+        let little_endian = encoding == UTF_16LE;
+        for unit in bom.then_some('\u{FEFF}' as u16).into_iter().chain(content.encode_utf16()) {
+            bytes.extend(if little_endian { unit.to_le_bytes() } else { unit.to_be_bytes() });
+        }
+        return Ok(bytes);
+    }
+
+    if bom && encoding == UTF_8 {
+        bytes.extend_from_slice(b"\xEF\xBB\xBF");
+    }
+    let (encoded, _, had_errors) = encoding.encode(content);
+    if had_errors {
+        bail!("Content can't be represented in {}", encoding.name());
+    }
+    bytes.extend_from_slice(&encoded);
+    Ok(bytes)
+}
+
 impl DocumentManager {
-    pub fn new(workspace_path: PathBuf) -> Result<Self> {
+    pub fn new(workspace_path: PathBuf, max_open_document_size: u64) -> Result<Self> {
         let workspace_path = workspace_path.canonicalize()?;
         println!("Initialized document manager at: {:?}", workspace_path);
 
         Ok(Self {
             workspace_path,
             document_states: RwLock::new(HashMap::new()),
-            cache: RwLock::new(HashMap::new()),
-            cache_queue: RwLock::new(VecDeque::new()),
-            max_cache_size: CACHE_SIZE_LIMIT,
-            current_cache_size: RwLock::new(0),
+            cache: RwLock::new(DocumentCache::new(CACHE_SIZE_LIMIT)),
+            max_open_document_size,
+            history: RwLock::new(HashMap::new()),
+            encoding_overrides: RwLock::new(HashMap::new()),
+            cache_hits: AtomicU64::new(0),
+            cache_misses: AtomicU64::new(0),
         })
     }
 
     // Detect file type (binary or text)
-    async fn detect_file_type(&self, path: &PathBuf) -> Result<FileType> {
-        let mut file = tokio::fs::File::open(path).await?;
-        let mut buffer = vec![0; 512];
-        let n = tokio::io::AsyncReadExt::read(&mut file, &mut buffer).await?;
-        buffer.truncate(n);
-
-        // Check for null bytes which usually indicate binary content
-        if buffer.iter().take(n).any(|&byte| byte == 0) {
+    async fn detect_file_type(&self, path: &Path) -> Result<FileType> {
+        if is_binary_file(path).await? {
             return Ok(FileType::Binary);
         }
 
@@ -120,13 +296,24 @@ impl DocumentManager {
 
     // Detect file encoding
     fn detect_encoding(&self, data: &[u8]) -> FileEncoding {
-        let mut detector = chardetng::EncodingDetector::new();
-        detector.feed(data, true);
-        let encoding = detector.guess(None, true);
+        let (encoding, confidence) = if let Some((encoding, _)) = Encoding::for_bom(data) {
+            (encoding, CONFIDENCE_CERTAIN)
+        } else if data.is_ascii() {
+            // Any ASCII-compatible encoding reads it the same, UTF-8 keeps saves lossless
+            (UTF_8, CONFIDENCE_CERTAIN)
+        } else {
+            let mut detector = chardetng::EncodingDetector::new();
+            detector.feed(data, true);
+            match detector.guess_assess(None, true) {
+                (encoding, _) if encoding == UTF_8 => (encoding, CONFIDENCE_UTF8),
+                (encoding, true) => (encoding, CONFIDENCE_LIKELY),
+                (encoding, false) => (encoding, CONFIDENCE_LOW),
+            }
+        };
 
         FileEncoding {
             encoding: encoding.name().to_string(),
-            confidence: 0.9, // chardetng doesn't provide confidence, so we use a default
+            confidence,
         }
     }
 
@@ -155,11 +342,14 @@ impl DocumentManager {
     }
 
     // file is closed
-    pub async fn close_file(&self, path: &PathBuf) {
+    pub async fn close_file(&self, path: &Path) {
+        let path = &document_key(path).await;
         println!("Closing file: {:?}", path);
         if let Some(state) = self.document_states.write().await.get_mut(path) {
             state.is_open = false;
         }
+        self.set_cache_open(path, false).await;
+        self.history.write().await.remove(path);
         // make lsp call here TODO
     }
 
@@ -170,7 +360,7 @@ impl DocumentManager {
         doc: &VersionedDocument,
         changes: Vec<DiffChange>,
     ) -> Result<VersionedDocument> {
-        let path = &doc.uri.canonicalize()?;
+        let path = &document_key(&doc.uri).await;
         let mut states = self.document_states.write().await;
 
         if let Some(state) = states.get_mut(path) {
@@ -184,81 +374,19 @@ impl DocumentManager {
             }
 
             // Get current content
-            let current_content = {
-                let cache = self.cache.read().await;
-                if let Some(cache_entry) = cache.get(path) {
-                    cache_entry.content.clone()
-                } else {
-                    tokio::fs::read_to_string(path).await?
-                }
-            };
-
-            // Build new content by applying changes sequentially
-            let mut result = String::new();
-            let mut last_position = 0;
-            let chars: Vec<char> = current_content.chars().collect();
-
-            println!("Applying changes to document:");
-            println!("Original content: {}", current_content);
-
-            for change in changes {
-                println!("Processing change: {:?}", change);
-
-                if change.removed {
-                    // Skip the content that's being removed
-                    last_position += change.value.chars().count();
-                } else if !change.added && !change.removed {
-                    // Copy unchanged content
-                    let unchanged_len = change.value.chars().count();
-                    if last_position + unchanged_len > chars.len() {
-                        return Err(anyhow::anyhow!(
-                            "Invalid change: position {} exceeds content length {}",
-                            last_position + unchanged_len,
-                            chars.len()
-                        ));
-                    }
-
-                    // Append the unchanged content
-                    result.extend(chars[last_position..last_position + unchanged_len].iter());
-                    last_position += unchanged_len;
-                } else if change.added {
-                    // Insert new content
-                    result.push_str(&change.value);
-                }
-            }
+            let current_content = self.current_content(path).await?;
 
-            println!("Final content: {}", result);
+            let result = apply_diff_changes(&current_content, changes)?;
 
             // Update cache with new content
-            let metadata = tokio::fs::metadata(path).await?;
-            let doc_metadata = DocumentMetadata {
-                size: metadata.len(),
-                is_directory: metadata.is_dir(),
-                is_symlink: metadata.file_type().is_symlink(),
-                created_at: metadata.created().ok().and_then(|t| {
-                    t.duration_since(std::time::UNIX_EPOCH)
-                        .ok()
-                        .map(|d| d.as_secs())
-                }),
-                modified_at: metadata.modified().ok().and_then(|t| {
-                    t.duration_since(std::time::UNIX_EPOCH)
-                        .ok()
-                        .map(|d| d.as_secs())
-                }),
-                readonly: metadata.permissions().readonly(),
-                file_type: FileType::Text,
-                encoding: FileEncoding {
-                    encoding: "UTF-8".to_string(),
-                    confidence: 1.0,
-                },
-                line_ending: self.detect_line_ending(&result),
-            };
+            let doc_metadata = self.text_document_metadata(path, &result).await?;
 
-            self.cache_content(path.clone(), result, doc_metadata)
+            self.cache_content(path.clone(), result.clone(), doc_metadata, state.is_open)
                 .await?;
 
             // Update state
             state.version += 1;
+            self.record_version(path, state.version, result).await;
             state.is_dirty = true;
             state.last_modification = std::time::SystemTime::now()
                 .duration_since(std::time::UNIX_EPOCH)
@@ -274,58 +402,295 @@ impl DocumentManager {
         }
     }
 
-    pub async fn save_document(&self, doc: &VersionedDocument) -> Result<VersionedDocument> {
-        let path = &doc.uri;
-        let mut states = self.document_states.write().await;
+    // Recovers from a version conflict: the client's changes were made against
+    // `doc.version - 1`, so they are merged with everything the server has seen since
+    pub async fn merge_changes(
+        &self,
+        doc: &VersionedDocument,
+        changes: Vec<DiffChange>,
+    ) -> Result<(VersionedDocument, MergeOutcome)> {
+        let path = &document_key(&doc.uri).await;
+        let base_version = doc
+            .version
+            .checked_sub(1)
+            .ok_or_else(|| anyhow::anyhow!("Invalid document version: {}", doc.version))?;
+        let base = self.get_content_at_version(path, base_version).await?;
+        let client = apply_diff_changes(&base, changes)?;
 
-        if let Some(state) = states.get_mut(path) {
-            if state.version >= doc.version {
-                return Err(anyhow::anyhow!(
-                    "Version conflict: document has been modified. Server: {}, client: {}",
-                    state.version,
-                    doc.version
-                ));
-            }
+        let mut states = self.document_states.write().await;
+        let state = states
+            .get_mut(path)
+            .ok_or_else(|| anyhow::anyhow!("Document not found in states"))?;
 
-            // Get content from cache
-            let content = {
-                let cache = self.cache.read().await;
-                if let Some(cache_entry) = cache.get(path) {
-                    cache_entry.content.clone()
-                } else {
-                    return Err(anyhow::anyhow!("Document content not found in cache"));
-                }
-            };
+        let server = self.current_content(path).await?;
 
-            // Write to file
-            tokio::fs::write(&path, &content).await?;
+        let outcome = three_way_merge(&base, &server, &client);
+        if let MergeOutcome::Merged(merged) = &outcome {
+            let doc_metadata = self.text_document_metadata(path, merged).await?;
+            self.invalidate_cache_for_file(path).await;
+            self.cache_content(path.clone(), merged.clone(), doc_metadata, state.is_open)
+                .await?;
 
-            // Update state
-            state.is_dirty = false;
+            state.version += 1;
+            self.record_version(path, state.version, merged.clone()).await;
+            state.is_dirty = true;
             state.last_modification = std::time::SystemTime::now()
                 .duration_since(std::time::UNIX_EPOCH)
                 .unwrap_or_default()
                 .as_secs();
+        }
 
-            Ok(VersionedDocument {
+        Ok((
+            VersionedDocument {
                 uri: path.clone(),
                 version: state.version,
-            })
-        } else {
-            Err(anyhow::anyhow!("Document not found in states"))
+            },
+            outcome,
+        ))
+    }
+
+    // The cached content of a document, or what is on disk when it isn't cached
+    async fn current_content(&self, path: &Path) -> Result<String> {
+        if let Some(entry) = self.cache.read().await.get(path) {
+            return Ok(entry.content.clone());
         }
+        Ok(self.read_text(path).await?.0)
     }
 
-    pub async fn get_document_content(&self, path: &PathBuf) -> Result<String> {
-        // Try cache first
-        {
+    // Changes from the saved file to the (possibly unsaved) cached content
+    pub async fn diff_with_disk(&self, path: &Path) -> Result<Vec<DiffChange>> {
+        let path = &document_key(path).await;
+        let current = self.get_document_content(path).await?;
+        let (on_disk, _) = self
+            .read_text(path)
+            .await
+            .with_context(|| format!("Failed to read file: {:?}", path))?;
+        Ok(diff_changes(&on_disk, &current))
+    }
+
+    // Metadata for in-memory text content backed by the file at `path`. The
+    // encoding it was read with is kept so saves write it back the same way.
+    async fn text_document_metadata(&self, path: &Path, content: &str) -> Result<DocumentMetadata> {
+        let metadata = tokio::fs::metadata(path).await?;
+        let cached = self
+            .cache
+            .read()
+            .await
+            .get(path)
+            .map(|entry| (entry.metadata.encoding.clone(), entry.metadata.has_bom));
+        let (encoding, has_bom) = match cached {
+            Some(cached) => cached,
+            // Detected from the file like when it is opened
+            None => {
+                let (_, on_disk) = self.read_text(path).await?;
+                (on_disk.encoding, on_disk.has_bom)
+            }
+        };
+        Ok(DocumentMetadata {
+            size: metadata.len(),
+            is_directory: metadata.is_dir(),
+            is_symlink: metadata.file_type().is_symlink(),
+            created_at: metadata.created().ok().and_then(|t| {
+                t.duration_since(std::time::UNIX_EPOCH)
+                    .ok()
+                    .map(|d| d.as_secs())
+            }),
+            modified_at: metadata.modified().ok().and_then(|t| {
+                t.duration_since(std::time::UNIX_EPOCH)
+                    .ok()
+                    .map(|d| d.as_secs())
+            }),
+            readonly: metadata.permissions().readonly(),
+            file_type: FileType::Text,
+            encoding,
+            line_ending: self.detect_line_ending(content),
+            has_bom,
+        })
+    }
+
+    // Applies LSP text edits (e.g. from a WorkspaceEdit) to a document. Open documents
+    // are edited in memory and marked dirty, returning their new content and version;
+    // closed files are rewritten on disk.
+    pub async fn apply_text_edits(
+        &self,
+        path: &Path,
+        edits: &[lsp_types::TextEdit],
+    ) -> Result<Option<(String, DocumentMetadata, i32)>> {
+        let path = &document_key(path).await;
+        if !is_within(path, &self.workspace_path) {
+            bail!("Path is outside of workspace");
+        }
+
+        let mut states = self.document_states.write().await;
+        // A closed document keeps its state, but nothing would save it any more
+        let Some(state) = states.get_mut(path).filter(|state| state.is_open) else {
+            drop(states);
+            let (content, metadata) = self.read_text(path).await?;
+            let updated = apply_text_edits_to_content(&content, edits)?;
+            let encoding = Encoding::for_label(metadata.encoding.encoding.as_bytes()).unwrap_or(UTF_8);
+            tokio::fs::write(path, encode_text(&updated, encoding, metadata.has_bom)?).await?;
+            self.invalidate_cache_for_file(path).await;
+            return Ok(None);
+        };
+
+        let current_content = self.current_content(path).await?;
+        let updated = apply_text_edits_to_content(&current_content, edits)?;
+
+        let doc_metadata = self.text_document_metadata(path, &updated).await?;
+        self.invalidate_cache_for_file(path).await;
+        self.cache_content(path.clone(), updated.clone(), doc_metadata.clone(), state.is_open)
+            .await?;
+
+        state.version += 1;
+        self.record_version(path, state.version, updated.clone()).await;
+        state.is_dirty = true;
+        state.last_modification = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        Ok(Some((updated, doc_metadata, state.version)))
+    }
+
+    // First half of a save: checks the client's version and applies the save
+    // normalization as an edit of its own, returning the new content and
+    // version if it changed anything. Servers and clients can be told about that edit before
+    // write_document puts it on disk.
+    pub async fn normalize_for_save(
+        &self,
+        doc: &VersionedDocument,
+        normalization: SaveNormalization,
+    ) -> Result<Option<(String, DocumentMetadata, i32)>> {
+        let path = &document_key(&doc.uri).await;
+        let mut states = self.document_states.write().await;
+        let Some(state) = states.get_mut(path) else {
+            return Err(anyhow::anyhow!("Document not found in states"));
+        };
+        if state.version >= doc.version {
+            return Err(anyhow::anyhow!(
+                "Version conflict: document has been modified. Server: {}, client: {}",
+                state.version,
+                doc.version
+            ));
+        }
+
+        let (content, line_ending) = {
+            let cache = self.cache.read().await;
+            let Some(cache_entry) = cache.get(path) else {
+                return Err(anyhow::anyhow!("Document content not found in cache"));
+            };
+            (cache_entry.content.clone(), cache_entry.metadata.line_ending.clone())
+        };
+
+        let Some(updated) = normalize_for_save(&content, &line_ending, normalization) else {
+            return Ok(None);
+        };
+        let doc_metadata = self.text_document_metadata(path, &updated).await?;
+        self.invalidate_cache_for_file(path).await;
+        self.cache_content(path.clone(), updated.clone(), doc_metadata.clone(), state.is_open)
+            .await?;
+        state.version += 1;
+        self.record_version(path, state.version, updated.clone()).await;
+        Ok(Some((updated, doc_metadata, state.version)))
+    }
+
+    // Second half of a save: writes the document's current content in the
+    // encoding it was read with
+    pub async fn write_document(&self, path: &Path) -> Result<VersionedDocument> {
+        let path = &document_key(path).await;
+        let mut states = self.document_states.write().await;
+        let Some(state) = states.get_mut(path) else {
+            return Err(anyhow::anyhow!("Document not found in states"));
+        };
+
+        let (content, encoding, has_bom) = {
+            let cache = self.cache.read().await;
+            let Some(cache_entry) = cache.get(path) else {
+                return Err(anyhow::anyhow!("Document content not found in cache"));
+            };
+            (
+                cache_entry.content.clone(),
+                cache_entry.metadata.encoding.encoding.clone(),
+                cache_entry.metadata.has_bom,
+            )
+        };
+
+        let encoding = Encoding::for_label(encoding.as_bytes()).unwrap_or(UTF_8);
+        let bytes = encode_text(&content, encoding, has_bom)?;
+        tokio::fs::write(&path, &bytes).await?;
+        if let Some(entry) = self.cache.write().await.get_mut(path) {
+            entry.disk_modified = file_modified(path).await;
+        }
+
+        // Update state
+        state.is_dirty = false;
+        state.last_modification = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        Ok(VersionedDocument {
+            uri: path.clone(),
+            version: state.version,
+        })
+    }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.cache_hits.load(Ordering::Relaxed),
+            misses: self.cache_misses.load(Ordering::Relaxed),
+        }
+    }
+
+    pub async fn get_document_content(&self, path: &Path) -> Result<String> {
+        let path = &document_key(path).await;
+        // Try cache first. Unsaved edits always win, otherwise the entry is only
+        // used while the file on disk hasn't changed since it was cached.
+        let (is_open, is_dirty) = self
+            .document_states
+            .read()
+            .await
+            .get(path)
+            .map_or((false, false), |state| (state.is_open, state.is_dirty));
+        let disk_modified = file_modified(path).await;
+        let (cached, is_stale) = {
             let cache = self.cache.read().await;
-            if let Some(cache_entry) = cache.get(path) {
-                return Ok(cache_entry.content.clone());
+            match cache.get(path) {
+                Some(entry) if is_dirty || entry.disk_modified == disk_modified => {
+                    (Some(entry.content.clone()), false)
+                }
+                Some(_) => (None, true),
+                None => (None, false),
             }
+        };
+        if let Some(content) = cached {
+            self.cache_hits.fetch_add(1, Ordering::Relaxed);
+            self.cache.write().await.touch(path);
+            return Ok(content);
         }
+        if is_stale {
+            println!("File changed on disk, reloading: {:?}", path);
+            self.invalidate_cache_for_file(path).await;
+        }
+        self.cache_misses.fetch_add(1, Ordering::Relaxed);
 
         // Not in cache, read from file
+        let (content, doc_metadata) = self.read_text(path).await?;
+
+        // Cache if size is within limit. Open documents are read again and again
+        // while being edited, so they get a higher one.
+        let limit = if is_open { self.max_open_document_size } else { CACHE_SIZE_LIMIT };
+        if doc_metadata.size <= limit {
+            self.cache_content(path.clone(), content.clone(), doc_metadata.clone(), is_open)
+                .await?;
+        }
+
+        Ok(content)
+    }
+
+    // Reads and decodes a text file the way it is opened: in its detected (or
+    // chosen) encoding, with a BOM for that encoding left out of the text
+    pub async fn read_text(&self, path: &Path) -> Result<(String, DocumentMetadata)> {
         let metadata = fs::metadata(path)
             .await
             .with_context(|| format!("Failed to read metadata for file: {:?}", path))?;
@@ -355,24 +720,36 @@ impl DocumentManager {
             .await
             .with_context(|| format!("Failed to read file content: {:?}", path))?;
 
-        // Detect encoding
-        let encoding = self.detect_encoding(&content);
+        // Detect encoding, unless the client chose one
+        let mut encoding = match self.encoding_overrides.read().await.get(path) {
+            Some(encoding) => FileEncoding {
+                encoding: encoding.name().to_string(),
+                confidence: CONFIDENCE_CERTAIN,
+            },
+            None => self.detect_encoding(&content),
+        };
 
-        // Convert to string using detected encoding
-        let (content, _, had_errors) = Encoding::for_label(encoding.encoding.as_bytes())
-            .unwrap_or(UTF_8)
-            .decode(&content);
+        // Convert to string using detected encoding. A BOM for that encoding is
+        // left out of the text, one for another encoding is decoded as it is.
+        let decoder = Encoding::for_label(encoding.encoding.as_bytes()).unwrap_or(UTF_8);
+        let bom_len = match Encoding::for_bom(&content) {
+            Some((bom_encoding, len)) if bom_encoding == decoder => len,
+            _ => 0,
+        };
+        let has_bom = bom_len > 0;
+        let (content, had_errors) = decoder.decode_without_bom_handling(&content[bom_len..]);
 
         if had_errors {
             println!(
                 "Warning: Some characters couldn't be decoded in file: {:?}",
                 path
             );
+            // Mixed encodings or a wrong guess
+            encoding.confidence = encoding.confidence.min(CONFIDENCE_LOW);
         }
 
         let content = content.into_owned();
 
-        // Cache the content with metadata
         let doc_metadata = DocumentMetadata {
             size: metadata.len(),
             is_directory: metadata.is_dir(),
@@ -391,23 +768,31 @@ impl DocumentManager {
             file_type,
             encoding,
             line_ending: self.detect_line_ending(&content),
+            has_bom,
         };
 
-        // Cache if size is within limit
-        if metadata.len() <= CACHE_SIZE_LIMIT {
-            self.cache_content(path.clone(), content.clone(), doc_metadata.clone())
-                .await?;
-        }
-
-        Ok(content)
+        Ok((content, doc_metadata))
     }
 
     // Get current content (useful for LSP operations)
-    pub async fn open_file(&self, path: &PathBuf) -> Result<(String, DocumentMetadata, i32)> {
+    pub async fn open_file(&self, path: &Path) -> Result<(String, DocumentMetadata, i32)> {
+        let path = &document_key(path).await;
+        let file_type = fs::metadata(path)
+            .await
+            .with_context(|| format!("Failed to read metadata for file: {:?}", path))?
+            .file_type();
+        if file_type.is_dir() {
+            return Err(IsADirectory { path: path.clone() }.into());
+        }
+        if !file_type.is_file() {
+            bail!("Path is {}: {:?}", special_file_kind(file_type), path);
+        }
+
         // Check if document is already open
         let version = {
             let mut document_states = self.document_states.write().await;
-            if let Some(state) = document_states.get(path) {
+            if let Some(state) = document_states.get_mut(path) {
+                state.is_open = true;
                 state.version
             } else {
                 // Initialize new document state
@@ -432,10 +817,16 @@ impl DocumentManager {
                 0
             }
         };
+        // Content cached while the file was closed stays now that it is open
+        self.set_cache_open(path, true).await;
 
         // Get or read content
         let content = self.get_document_content(path).await?;
 
+        if !self.history.read().await.contains_key(path) {
+            self.record_version(path, version, content.clone()).await;
+        }
+
         // Get metadata from cache or create new
         let metadata = {
             let cache = self.cache.read().await;
@@ -466,6 +857,7 @@ impl DocumentManager {
                         confidence: 1.0,
                     },
                     line_ending: self.detect_line_ending(&content),
+                    has_bom: false,
                 }
             }
         };
@@ -473,51 +865,116 @@ impl DocumentManager {
         Ok((content, metadata, version))
     }
 
-    // Cache management
+    async fn record_version(&self, path: &Path, version: i32, content: String) {
+        let mut history = self.history.write().await;
+        let versions = history.entry(path.to_path_buf()).or_default();
+        versions.push_back((version, content));
+
+        // Drop the oldest versions but always keep the latest one
+        let mut total: usize = versions.iter().map(|(_, content)| content.len()).sum();
+        while versions.len() > 1
+            && (versions.len() > MAX_RETAINED_VERSIONS || total > MAX_RETAINED_HISTORY_BYTES)
+        {
+            if let Some((_, oldest)) = versions.pop_front() {
+                total -= oldest.len();
+            }
+        }
+    }
+
+    // Decodes an open document again with `encoding`, which is also used for
+    // later reads and saves. Unsaved changes would be lost, so they are refused.
+    pub async fn reopen_with_encoding(
+        &self,
+        path: &Path,
+        encoding: &'static Encoding,
+    ) -> Result<(String, DocumentMetadata, i32)> {
+        let path = &document_key(path).await;
+        match self.document_states.read().await.get(path) {
+            Some(state) if !state.is_open => bail!("Document is not open: {:?}", path),
+            Some(state) if state.is_dirty => bail!("File has unsaved changes"),
+            Some(_) => {}
+            None => bail!("Document is not open: {:?}", path),
+        }
+
+        self.encoding_overrides
+            .write()
+            .await
+            .insert(path.clone(), encoding);
+        self.invalidate_cache_for_file(path).await;
+        let content = self.get_document_content(path).await?;
+        let metadata = self
+            .cache
+            .read()
+            .await
+            .get(path)
+            .map(|entry| entry.metadata.clone())
+            .ok_or_else(|| anyhow::anyhow!("File is too large to reopen: {:?}", path))?;
+
+        // The text changed, so language servers and peers need a new version
+        let version = {
+            let mut states = self.document_states.write().await;
+            let state = states
+                .get_mut(path)
+                .ok_or_else(|| anyhow::anyhow!("Document not found in states"))?;
+            state.version += 1;
+            state.version
+        };
+        self.record_version(path, version, content.clone()).await;
+
+        Ok((content, metadata, version))
+    }
+
+    // Content of an open document as of `version`, if it is still retained
+    pub async fn get_content_at_version(&self, path: &Path, version: i32) -> Result<String> {
+        let path = &document_key(path).await;
+        let history = self.history.read().await;
+        let versions = history
+            .get(path)
+            .ok_or_else(|| anyhow::anyhow!("Document is not open: {:?}", path))?;
+
+        match versions.iter().find(|(v, _)| *v == version) {
+            Some((_, content)) => Ok(content.clone()),
+            None => match versions.front() {
+                Some((oldest, _)) if version < *oldest => bail!(
+                    "Version {} is no longer retained, oldest available is {}",
+                    version,
+                    oldest
+                ),
+                _ => bail!("Unknown version {}", version),
+            },
+        }
+    }
+
+    // Cache management. Open documents' content is kept until they are closed,
+    // other files' content only while it fits the preview cache.
     async fn cache_content(
         &self,
         path: PathBuf,
         content: String,
         metadata: DocumentMetadata,
+        open: bool,
     ) -> Result<()> {
-        let mut cache = self.cache.write().await;
-        let mut cache_queue = self.cache_queue.write().await;
-        let mut current_size = self.current_cache_size.write().await;
-
-        // Evict old entries if necessary
-        while *current_size + content.len() as u64 > self.max_cache_size {
-            if let Some(old_path) = cache_queue.pop_front() {
-                if let Some(old_entry) = cache.remove(&old_path) {
-                    *current_size -= old_entry.content.len() as u64;
-                }
-            } else {
-                break;
-            }
-        }
-
-        // Add new entry
-        cache.insert(
-            path.clone(),
-            CacheEntry {
-                content,
-                metadata,
-                last_accessed: std::time::Instant::now(),
-            },
-        );
-
-        cache_queue.push_back(path);
+        let disk_modified = file_modified(&path).await;
+        let entry = CacheEntry {
+            content,
+            metadata,
+            disk_modified,
+        };
+        self.cache.write().await.insert(path, entry, open);
         Ok(())
     }
 
-    pub async fn invalidate_cache_for_file(&self, path: &PathBuf) {
-        let mut cache = self.cache.write().await;
-        if let Some(entry) = cache.remove(path) {
-            *self.current_cache_size.write().await -= entry.content.len() as u64;
-            self.cache_queue.write().await.retain(|p| p != path);
-        }
+    async fn set_cache_open(&self, path: &Path, open: bool) {
+        self.cache.write().await.set_open(path, open, CACHE_SIZE_LIMIT);
     }
 
-    pub async fn get_document_state(&self, path: &PathBuf) -> Result<DocumentState> {
+    pub async fn invalidate_cache_for_file(&self, path: &Path) {
+        let path = &document_key(path).await;
+        self.cache.write().await.remove(path);
+    }
+
+    pub async fn get_document_state(&self, path: &Path) -> Result<DocumentState> {
+        let path = &document_key(path).await;
         let states = self.document_states.read().await;
         states
             .get(path)
@@ -525,10 +982,63 @@ impl DocumentManager {
             .ok_or_else(|| anyhow::anyhow!("Document state not found"))
     }
 
+    // Content and version of an open document as one consistent pair, None if
+    // it isn't open. Edits update both while holding the states lock, so
+    // reading them under it never pairs content with another version.
+    pub async fn snapshot(&self, path: &Path) -> Result<Option<(String, i32)>> {
+        let path = &document_key(path).await;
+        let states = self.document_states.read().await;
+        let Some(state) = states.get(path).filter(|state| state.is_open) else {
+            return Ok(None);
+        };
+        let version = state.version;
+
+        let retained = self
+            .history
+            .read()
+            .await
+            .get(path)
+            .and_then(|versions| versions.iter().rev().find(|(v, _)| *v == version))
+            .map(|(_, content)| content.clone());
+        let content = match retained {
+            Some(content) => content,
+            None => match self.cache.read().await.get(path) {
+                Some(entry) => entry.content.clone(),
+                None => bail!("Content of version {} is not available: {:?}", version, path),
+            },
+        };
+        Ok(Some((content, version)))
+    }
+
+    pub async fn open_paths(&self) -> Vec<PathBuf> {
+        self.document_states
+            .read()
+            .await
+            .iter()
+            .filter(|(_, state)| state.is_open)
+            .map(|(path, _)| path.clone())
+            .collect()
+    }
+
+    // Path, current content and version of every open document
+    pub async fn open_documents(&self) -> Vec<(PathBuf, String, i32)> {
+        let open = self.open_paths().await;
+
+        let mut documents = Vec::with_capacity(open.len());
+        for path in open {
+            match self.snapshot(&path).await {
+                Ok(Some((content, version))) => documents.push((path, content, version)),
+                Ok(None) => {}
+                Err(e) => eprintln!("Failed to read open document {:?}: {}", path, e),
+            }
+        }
+        documents
+    }
+
     pub async fn create_file(&self, path: &PathBuf, is_directory: bool) -> Result<()> {
         // Ensure path is within workspace
         println!("Path: {:?}", path);
-        if !path.starts_with(&self.workspace_path) {
+        if !is_within(path, &self.workspace_path) {
             bail!("Path is outside of workspace");
         }
 
@@ -550,9 +1060,56 @@ impl DocumentManager {
         Ok(())
     }
 
-    pub async fn delete_file(&self, path: &PathBuf) -> Result<()> {
+    // Writes raw bytes, skipping encoding and line ending handling
+    pub async fn save_binary_file(&self, path: &Path, content: &[u8]) -> Result<u64> {
+        // Writes through a symlink go to the document it points to
+        let path = &document_key(path).await;
+        // Ensure path is within workspace
+        if !is_within(path, &self.workspace_path) {
+            bail!("Path is outside of workspace");
+        }
+
+        if path.is_dir() {
+            bail!("Path is a directory");
+        }
+
+        if let Some(state) = self.document_states.read().await.get(path) {
+            if state.is_dirty {
+                bail!("File has unsaved changes");
+            }
+        }
+
+        let parent = path
+            .parent()
+            .ok_or_else(|| anyhow::anyhow!("Path has no parent directory"))?;
+        tokio::fs::create_dir_all(parent).await?;
+
+        // Write next to the target and rename so readers never see a partial file
+        let file_name = path
+            .file_name()
+            .ok_or_else(|| anyhow::anyhow!("Path has no file name"))?
+            .to_string_lossy();
+        let temp_path = parent.join(format!(".{}.{}.tmp", file_name, uuid::Uuid::new_v4()));
+
+        if let Err(e) = tokio::fs::write(&temp_path, content).await {
+            let _ = tokio::fs::remove_file(&temp_path).await;
+            return Err(e.into());
+        }
+        if let Err(e) = tokio::fs::rename(&temp_path, path).await {
+            let _ = tokio::fs::remove_file(&temp_path).await;
+            return Err(e.into());
+        }
+
+        // Any cached text view of this file is now stale
+        self.invalidate_cache_for_file(path).await;
+
+        Ok(content.len() as u64)
+    }
+
+    pub async fn delete_file(&self, path: &Path) -> Result<()> {
+        let path = &entry_key(path).await;
         // Ensure path is within workspace
-        if !path.starts_with(&self.workspace_path) {
+        if !is_within(path, &self.workspace_path) {
             bail!("Path is outside of workspace");
         }
 
@@ -567,6 +1124,14 @@ impl DocumentManager {
                 bail!("File has unsaved changes");
             }
         }
+        self.history
+            .write()
+            .await
+            .retain(|history_path, _| !history_path.starts_with(path));
+        self.encoding_overrides
+            .write()
+            .await
+            .retain(|override_path, _| !override_path.starts_with(path));
 
         // Remove from cache if present
         // self.invalidate_cache_for_file(path).await;
@@ -581,10 +1146,38 @@ impl DocumentManager {
         Ok(())
     }
 
-    pub async fn rename_file(&self, old_path: &PathBuf, new_path: &PathBuf) -> Result<()> {
+    // Paths delete_file would remove, after the same checks it makes
+    pub async fn preview_delete(&self, path: &Path) -> Result<Vec<PathBuf>> {
+        if !is_within(path, &self.workspace_path) {
+            bail!("Path is outside of workspace");
+        }
+        if !path.exists() {
+            bail!("File or directory does not exist");
+        }
+        if self
+            .document_states
+            .read()
+            .await
+            .get(path)
+            .is_some_and(|state| state.is_dirty)
+        {
+            bail!("File has unsaved changes");
+        }
+        affected_paths(path).await
+    }
+
+    // Paths rename_file would move, after the same checks it makes
+    pub async fn preview_rename(&self, old_path: &Path, new_path: &Path) -> Result<Vec<PathBuf>> {
+        self.check_rename(old_path, new_path)?;
+        affected_paths(old_path).await
+    }
+
+    // The checks rename_file makes before touching anything, so callers can
+    // run them before other side effects of a rename
+    pub fn check_rename(&self, old_path: &Path, new_path: &Path) -> Result<()> {
         // Ensure both paths are within workspace
-        if !old_path.starts_with(&self.workspace_path)
-            || !new_path.starts_with(&self.workspace_path)
+        if !is_within(old_path, &self.workspace_path)
+            || !is_within(new_path, &self.workspace_path)
         {
             bail!("Path is outside of workspace");
         }
@@ -596,27 +1189,989 @@ impl DocumentManager {
         if new_path.exists() {
             bail!("Destination already exists");
         }
+        Ok(())
+    }
+
+    // Renames a file or directory, returning the (old, new) paths of any open
+    // documents that moved along with it
+    pub async fn rename_file(
+        &self,
+        old_path: &Path,
+        new_path: &Path,
+    ) -> Result<Vec<(PathBuf, PathBuf)>> {
+        let (old_path, new_path) = (&entry_key(old_path).await, &entry_key(new_path).await);
+        self.check_rename(old_path, new_path)?;
 
         // Create parent directories if they don't exist
         if let Some(parent) = new_path.parent() {
             tokio::fs::create_dir_all(parent).await?;
         }
 
-        // If file is open, update its state
+        // Perform the rename
+        tokio::fs::rename(old_path, new_path).await?;
+
+        let moved_path = |path: &Path| -> Option<PathBuf> {
+            path.strip_prefix(old_path).ok().map(|rest| {
+                if rest.as_os_str().is_empty() {
+                    new_path.clone()
+                } else {
+                    new_path.join(rest)
+                }
+            })
+        };
+
+        // Move state of the file, or of every document inside a renamed directory
+        let mut moved_documents = Vec::new();
         let mut states = self.document_states.write().await;
-        if let Some(state) = states.remove(old_path) {
-            states.insert(new_path.clone(), state);
+        let affected: Vec<PathBuf> = states
+            .keys()
+            .filter(|path| path.starts_with(old_path))
+            .cloned()
+            .collect();
+        for path in affected {
+            if let (Some(state), Some(target)) = (states.remove(&path), moved_path(&path)) {
+                if state.is_open {
+                    moved_documents.push((path, target.clone()));
+                }
+                states.insert(target, state);
+            }
         }
 
-        // Update cache if present
-        let mut cache = self.cache.write().await;
-        if let Some(entry) = cache.remove(old_path) {
-            cache.insert(new_path.clone(), entry);
+        // Update cache entries as well
+        self.cache.write().await.rename(old_path, moved_path);
+
+        let mut history = self.history.write().await;
+        let affected: Vec<PathBuf> = history
+            .keys()
+            .filter(|path| path.starts_with(old_path))
+            .cloned()
+            .collect();
+        for path in affected {
+            if let (Some(versions), Some(target)) = (history.remove(&path), moved_path(&path)) {
+                history.insert(target, versions);
+            }
         }
 
-        // Perform the rename
-        tokio::fs::rename(old_path, new_path).await?;
+        Ok(moved_documents)
+    }
+
+    pub async fn apply_workspace_edit(&self, edit: lsp_types::WorkspaceEdit) -> WorkspaceEditOutcome {
+        let mut outcome = WorkspaceEditOutcome::default();
+        for operation in document_change_operations(edit) {
+            match self.apply_document_change(operation).await {
+                Ok(Some(applied)) => outcome.applied.push(applied),
+                Ok(None) => {}
+                Err(e) => {
+                    outcome.error = Some(e);
+                    break;
+                }
+            }
+        }
+        outcome
+    }
+
+    // Returns None for operations that were skipped because of their options
+    async fn apply_document_change(
+        &self,
+        operation: lsp_types::DocumentChangeOperation,
+    ) -> Result<Option<AppliedEdit>> {
+        use lsp_types::{DocumentChangeOperation, ResourceOp};
+
+        match operation {
+            DocumentChangeOperation::Edit(edit) => {
+                let path = uri_to_path(&edit.text_document.uri)?;
+                if let Some(expected) = edit.text_document.version {
+                    let version = self.get_document_state(&path).await.ok().map(|s| s.version);
+                    if version != Some(expected) {
+                        bail!(
+                            "Edit for {:?} targets version {} but the document is at {:?}",
+                            path,
+                            expected,
+                            version
+                        );
+                    }
+                }
+                let document = self
+                    .apply_text_edits(&path, &text_edits(edit.edits))
+                    .await
+                    .with_context(|| format!("Failed to edit {:?}", path))?;
+                Ok(Some(AppliedEdit::Edited { path, document }))
+            }
+            DocumentChangeOperation::Op(ResourceOp::Create(create)) => {
+                let path = uri_to_path(&create.uri)?;
+                let options = create.options.as_ref();
+                if path.exists() {
+                    if options.and_then(|o| o.overwrite) == Some(true) {
+                        if !is_within(&path, &self.workspace_path) {
+                            bail!("Path is outside of workspace");
+                        }
+                        fs::write(&path, "").await?;
+                        self.invalidate_cache_for_file(&path).await;
+                        return Ok(Some(AppliedEdit::Edited { path, document: None }));
+                    }
+                    if options.and_then(|o| o.ignore_if_exists) == Some(true) {
+                        return Ok(None);
+                    }
+                }
+                self.create_file(&path, false)
+                    .await
+                    .with_context(|| format!("Failed to create {:?}", path))?;
+                Ok(Some(AppliedEdit::Created {
+                    path,
+                    is_directory: false,
+                }))
+            }
+            DocumentChangeOperation::Op(ResourceOp::Rename(rename)) => {
+                let old_path = uri_to_path(&rename.old_uri)?;
+                let new_path = uri_to_path(&rename.new_uri)?;
+                let options = rename.options.as_ref();
+                if new_path.exists() {
+                    if options.and_then(|o| o.overwrite) == Some(true) {
+                        self.delete_file(&new_path).await?;
+                    } else if options.and_then(|o| o.ignore_if_exists) == Some(true) {
+                        return Ok(None);
+                    }
+                }
+                let is_directory = old_path.is_dir();
+                let moved_documents = self
+                    .rename_file(&old_path, &new_path)
+                    .await
+                    .with_context(|| format!("Failed to rename {:?}", old_path))?;
+                Ok(Some(AppliedEdit::Renamed {
+                    old_path,
+                    new_path,
+                    is_directory,
+                    moved_documents,
+                }))
+            }
+            DocumentChangeOperation::Op(ResourceOp::Delete(delete)) => {
+                let path = uri_to_path(&delete.uri)?;
+                let options = delete.options.as_ref();
+                if !path.exists() && options.and_then(|o| o.ignore_if_not_exists) == Some(true) {
+                    return Ok(None);
+                }
+                let is_directory = path.is_dir();
+                if is_directory && options.and_then(|o| o.recursive) != Some(true) {
+                    let mut entries = fs::read_dir(&path).await?;
+                    if entries.next_entry().await?.is_some() {
+                        bail!("Directory is not empty: {:?}", path);
+                    }
+                }
+                self.delete_file(&path)
+                    .await
+                    .with_context(|| format!("Failed to delete {:?}", path))?;
+                Ok(Some(AppliedEdit::Deleted { path, is_directory }))
+            }
+        }
+    }
+}
+
+// Flattens a workspace edit into operations in the order they should be applied.
+// Like other clients, `document_changes` is preferred over `changes` when present.
+pub fn document_change_operations(
+    edit: lsp_types::WorkspaceEdit,
+) -> Vec<lsp_types::DocumentChangeOperation> {
+    use lsp_types::{
+        DocumentChangeOperation, DocumentChanges, OneOf, OptionalVersionedTextDocumentIdentifier,
+        TextDocumentEdit,
+    };
+
+    match edit.document_changes {
+        Some(DocumentChanges::Edits(edits)) => {
+            edits.into_iter().map(DocumentChangeOperation::Edit).collect()
+        }
+        Some(DocumentChanges::Operations(operations)) => operations,
+        None => edit
+            .changes
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(uri, edits)| {
+                DocumentChangeOperation::Edit(TextDocumentEdit {
+                    text_document: OptionalVersionedTextDocumentIdentifier { uri, version: None },
+                    edits: edits.into_iter().map(OneOf::Left).collect(),
+                })
+            })
+            .collect(),
+    }
+}
+
+pub fn text_edits(
+    edits: Vec<lsp_types::OneOf<lsp_types::TextEdit, lsp_types::AnnotatedTextEdit>>,
+) -> Vec<lsp_types::TextEdit> {
+    edits
+        .into_iter()
+        .map(|edit| match edit {
+            lsp_types::OneOf::Left(edit) => edit,
+            lsp_types::OneOf::Right(annotated) => annotated.text_edit,
+        })
+        .collect()
+}
+
+// Rebuilds a document from a full diff of it: unchanged runs are copied from
+// `content`, removed runs skipped and added runs inserted. `content` is walked
+// once with a byte cursor, so large files, e.g. minified bundles on a single
+// line, are never copied into a char buffer or logged on the way.
+//
+// A rope wouldn't make this cheaper: a diff covers the whole document, every
+// unchanged run included, so walking it is O(n) per edit whatever the storage.
+// The cache, version history, saves and merges all work on a `String` too.
+fn apply_diff_changes(content: &str, changes: Vec<DiffChange>) -> Result<String> {
+    let mut result = String::with_capacity(content.len());
+    let mut position = 0;
+
+    for change in changes {
+        if change.added {
+            result.push_str(&change.value);
+            continue;
+        }
+
+        // Removed and unchanged parts advance past the same text in `content`.
+        // It is usually identical, otherwise as many characters are skipped.
+        let rest = &content[position..];
+        let len = if rest.starts_with(&change.value) {
+            change.value.len()
+        } else {
+            let chars = change.value.chars().count();
+            match rest.char_indices().nth(chars) {
+                Some((len, _)) => len,
+                None if rest.chars().count() == chars => rest.len(),
+                None => bail!(
+                    "Invalid change: position {} exceeds content length {}",
+                    content[..position].chars().count() + chars,
+                    content.chars().count()
+                ),
+            }
+        };
+        if !change.removed {
+            result.push_str(&rest[..len]);
+        }
+        position += len;
+    }
+
+    Ok(result)
+}
+
+// Line diff from `old` to `new` in the same shape clients send with ChangeFile
+pub fn diff_changes(old: &str, new: &str) -> Vec<DiffChange> {
+    let diff = similar::TextDiff::from_lines(old, new);
+    let mut changes: Vec<DiffChange> = Vec::new();
+
+    for change in diff.iter_all_changes() {
+        let (added, removed) = match change.tag() {
+            similar::ChangeTag::Equal => (false, false),
+            similar::ChangeTag::Insert => (true, false),
+            similar::ChangeTag::Delete => (false, true),
+        };
+        match changes.last_mut() {
+            Some(last) if last.added == added && last.removed == removed => {
+                last.value.push_str(change.value());
+            }
+            _ => changes.push(DiffChange {
+                value: change.value().to_string(),
+                added,
+                removed,
+            }),
+        }
+    }
+    changes
+}
+
+// Converts an LSP position (UTF-16 code units) into a byte offset within `content`
+fn position_to_offset(content: &str, position: lsp_types::Position) -> Result<usize> {
+    let mut line_start = 0;
+    for _ in 0..position.line {
+        match content[line_start..].find('\n') {
+            Some(index) => line_start += index + 1,
+            None => bail!("Line {} is out of range", position.line),
+        }
+    }
+
+    let line_end = content[line_start..]
+        .find('\n')
+        .map_or(content.len(), |index| line_start + index);
+    // A CRLF line ends before its `\r`, like in validate_position
+    let line_end = match content[line_start..line_end].ends_with('\r') {
+        true => line_end - 1,
+        false => line_end,
+    };
+
+    // Positions past the end of a line refer to the end of that line
+    Ok(line_start + column_to_byte(&content[line_start..line_end], position.character, &client_encoding()))
+}
+
+// Applies non-overlapping edits whose ranges refer to the original content.
+// Inserts at the same position end up in the order they were given, as LSP
+// requires.
+fn apply_text_edits_to_content(content: &str, edits: &[lsp_types::TextEdit]) -> Result<String> {
+    let mut ranges = edits
+        .iter()
+        .enumerate()
+        .map(|(index, edit)| {
+            let start = position_to_offset(content, edit.range.start)?;
+            let end = position_to_offset(content, edit.range.end)?;
+            if start > end {
+                bail!("Invalid edit range {}: start is after end", format_range(&edit.range));
+            }
+            Ok((start, end, index))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    // Apply from the end of the document so earlier offsets stay valid
+    ranges.sort_by(|a, b| b.0.cmp(&a.0).then(b.1.cmp(&a.1)).then(b.2.cmp(&a.2)));
+
+    let mut result = content.to_string();
+    let mut previous: Option<(usize, usize)> = None;
+    for (start, end, index) in ranges {
+        if let Some((previous_start, previous_index)) = previous {
+            if end > previous_start {
+                bail!(
+                    "Overlapping text edits: {} and {}",
+                    format_range(&edits[index].range),
+                    format_range(&edits[previous_index].range)
+                );
+            }
+        }
+        result.replace_range(start..end, &edits[index].new_text);
+        previous = Some((start, index));
+    }
+    Ok(result)
+}
+
+// One-based line:column, as editors show it
+fn format_range(range: &lsp_types::Range) -> String {
+    format!(
+        "{}:{}-{}:{}",
+        range.start.line + 1,
+        range.start.character + 1,
+        range.end.line + 1,
+        range.end.character + 1
+    )
+}
+
+// The path itself and, for a directory, everything below it. Symlinks are
+// listed but not followed, matching what remove_dir_all and rename touch.
+async fn affected_paths(path: &Path) -> Result<Vec<PathBuf>> {
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        walkdir::WalkDir::new(path)
+            .follow_links(false)
+            .into_iter()
+            .map(|entry| Ok(entry?.into_path()))
+            .collect()
+    })
+    .await?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::path_utils::join_workspace_path;
+    use lsp_types::{Position, Range, TextEdit};
+
+    fn edit(start: (u32, u32), end: (u32, u32), text: &str) -> TextEdit {
+        TextEdit {
+            range: Range::new(Position::new(start.0, start.1), Position::new(end.0, end.1)),
+            new_text: text.to_string(),
+        }
+    }
+
+    // Both halves of a save, as the SaveFile handler does them
+    async fn save_document(
+        manager: &DocumentManager,
+        doc: &VersionedDocument,
+        normalization: SaveNormalization,
+    ) -> Result<(VersionedDocument, Option<(String, DocumentMetadata)>)> {
+        let normalized = manager.normalize_for_save(doc, normalization).await?;
+        let saved = manager.write_document(&doc.uri).await?;
+        Ok((saved, normalized.map(|(content, metadata, _)| (content, metadata))))
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_symlinked_path_shares_the_document() -> Result<()> {
+        let workspace = tempfile::tempdir()?;
+        let manager = DocumentManager::new(workspace.path().to_path_buf(), MAX_FILE_SIZE)?;
+        let real = manager.workspace_path.join("main.rs");
+        let link = manager.workspace_path.join("link.rs");
+        std::fs::write(&real, "fn main() {}\n")?;
+        std::os::unix::fs::symlink(&real, &link)?;
+
+        manager.open_file(&real).await?;
+        manager.open_file(&link).await?;
+        let change = |value: &str, added| DiffChange {
+            value: value.to_string(),
+            added,
+            removed: false,
+        };
+        let changed = manager
+            .change_document(
+                &VersionedDocument {
+                    uri: link.clone(),
+                    version: 1,
+                },
+                vec![change("fn main() {}\n", false), change("// edited\n", true)],
+            )
+            .await?;
+
+        assert_eq!(changed.uri, real);
+        assert_eq!(manager.get_document_state(&real).await?.version, 1);
+        assert_eq!(manager.get_document_state(&link).await?.version, 1);
+        assert_eq!(manager.get_document_content(&real).await?, "fn main() {}\n// edited\n");
+        assert_eq!(manager.open_documents().await.len(), 1);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_symlinked_path_is_guarded_on_binary_save_and_delete() -> Result<()> {
+        let workspace = tempfile::tempdir()?;
+        let manager = DocumentManager::new(workspace.path().to_path_buf(), MAX_FILE_SIZE)?;
+        let real = manager.workspace_path.join("main.rs");
+        let link = manager.workspace_path.join("link.rs");
+        std::fs::write(&real, "fn main() {}\n")?;
+        std::os::unix::fs::symlink(&real, &link)?;
+
+        let (content, _, _) = manager.open_file(&real).await?;
+        let doc = VersionedDocument { uri: real.clone(), version: 1 };
+        manager.change_document(&doc, diff_changes(&content, "// edited\n")).await?;
+        assert!(manager.save_binary_file(&link, b"binary").await.is_err());
+
+        save_document(&manager, &VersionedDocument { uri: real.clone(), version: 2 }, SaveNormalization::default()).await?;
+        manager.save_binary_file(&link, b"binary").await?;
+        assert!(std::fs::symlink_metadata(&link)?.file_type().is_symlink());
+        assert_eq!(std::fs::read(&real)?, b"binary");
+
+        // Deleting the link leaves the document it points to open
+        manager.delete_file(&link).await?;
+        assert!(real.exists());
+        assert!(manager.get_document_state(&real).await?.is_open);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_binary_save_refuses_parent_dirs_out_of_the_workspace() -> Result<()> {
+        let root = tempfile::tempdir()?;
+        let workspace = root.path().join("ws");
+        std::fs::create_dir(&workspace)?;
+        let manager = DocumentManager::new(workspace.clone(), MAX_FILE_SIZE)?;
+
+        for path in ["../x.bin", "sub/../../x.bin"] {
+            assert!(join_workspace_path(&manager.workspace_path, path).is_err());
+            assert!(manager.save_binary_file(&manager.workspace_path.join(path), b"binary").await.is_err());
+        }
+        assert!(!root.path().join("x.bin").exists());
+        assert!(!workspace.join("sub").exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_old_versions_are_evicted() -> Result<()> {
+        let workspace = tempfile::tempdir()?;
+        let manager = DocumentManager::new(workspace.path().to_path_buf(), MAX_FILE_SIZE)?;
+        let path = manager.workspace_path.join("main.rs");
+
+        for version in 0..(MAX_RETAINED_VERSIONS as i32 + 10) {
+            manager.record_version(&path, version, format!("v{}", version)).await;
+        }
+
+        assert!(manager.get_content_at_version(&path, 0).await.is_err());
+        assert_eq!(manager.get_content_at_version(&path, 10).await?, "v10");
+        assert!(manager.get_content_at_version(&path, 1000).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_merging_against_a_version_with_nothing_before_it_fails() -> Result<()> {
+        let workspace = tempfile::tempdir()?;
+        let manager = DocumentManager::new(workspace.path().to_path_buf(), MAX_FILE_SIZE)?;
+        let path = manager.workspace_path.join("main.rs");
+        std::fs::write(&path, "fn main() {}\n")?;
+        manager.open_file(&path).await?;
+
+        // Refused instead of overflowing
+        let doc = VersionedDocument { uri: path.clone(), version: i32::MIN };
+        assert!(manager.merge_changes(&doc, vec![]).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_cached_content_is_reloaded_after_external_change() -> Result<()> {
+        let workspace = tempfile::tempdir()?;
+        let manager = DocumentManager::new(workspace.path().to_path_buf(), MAX_FILE_SIZE)?;
+        let path = manager.workspace_path.join("notes.txt");
+
+        std::fs::write(&path, "before")?;
+        assert_eq!(manager.get_document_content(&path).await?, "before");
+
+        std::fs::write(&path, "after")?;
+        // Make sure the mtime moves even on filesystems with coarse timestamps
+        std::fs::File::options()
+            .write(true)
+            .open(&path)?
+            .set_modified(SystemTime::now() + std::time::Duration::from_secs(10))?;
+        assert_eq!(manager.get_document_content(&path).await?, "after");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_open_documents_are_cached_past_the_closed_file_limit() -> Result<()> {
+        let workspace = tempfile::tempdir()?;
+        let manager = DocumentManager::new(workspace.path().to_path_buf(), MAX_FILE_SIZE)?;
+        let large = manager.workspace_path.join("large.txt");
+        let line = "x".repeat(99) + "\n";
+        std::fs::write(&large, line.repeat(20_000))?;
+        let small: Vec<PathBuf> = ["a.txt", "b.txt"]
+            .iter()
+            .map(|name| manager.workspace_path.join(name))
+            .collect();
+        for path in &small {
+            std::fs::write(path, line.repeat(6_000))?;
+        }
+
+        let (content, _, version) = manager.open_file(&large).await?;
+        let doc = VersionedDocument { uri: large.clone(), version: version + 1 };
+        manager.change_document(&doc, diff_changes(&content, &(content.clone() + "edited\n"))).await?;
+        // Reading closed files, together over the limit, evicts neither the edit
+        // nor the cached open document
+        for path in &small {
+            manager.get_document_content(path).await?;
+        }
+        assert!(manager.cache.read().await.get(&small[0]).is_none());
+        let misses = manager.cache_stats().misses;
+        assert!(manager.get_document_content(&large).await?.ends_with("edited\n"));
+        assert_eq!(manager.cache_stats().misses, misses);
+
+        // Once closed it is over the limit and no longer kept
+        manager.close_file(&large).await;
+        assert!(manager.cache.read().await.get(&large).is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_opening_a_previewed_file_protects_it_from_eviction() -> Result<()> {
+        let workspace = tempfile::tempdir()?;
+        let manager = DocumentManager::new(workspace.path().to_path_buf(), MAX_FILE_SIZE)?;
+        let line = "x".repeat(99) + "\n";
+        let paths: Vec<PathBuf> = ["edited.txt", "a.txt", "b.txt"]
+            .iter()
+            .map(|name| manager.workspace_path.join(name))
+            .collect();
+        for path in &paths {
+            std::fs::write(path, line.repeat(6_000))?;
+        }
+
+        // Previewed first, then opened
+        manager.get_document_content(&paths[0]).await?;
+        manager.open_file(&paths[0]).await?;
+        for path in &paths[1..] {
+            manager.get_document_content(path).await?;
+        }
+        let cache = manager.cache.read().await;
+        assert!(cache.get(&paths[0]).is_some());
+        assert!(cache.get(&paths[1]).is_none());
+        assert!(cache.get(&paths[2]).is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_detect_encoding_confidence() -> Result<()> {
+        let workspace = tempfile::tempdir()?;
+        let manager = DocumentManager::new(workspace.path().to_path_buf(), MAX_FILE_SIZE)?;
+
+        let ascii = manager.detect_encoding(b"fn main() {}\n");
+        assert_eq!((ascii.encoding.as_str(), ascii.confidence), ("UTF-8", CONFIDENCE_CERTAIN));
+
+        let utf8 = manager.detect_encoding("let s = \"héllo wörld\";\n".as_bytes());
+        assert_eq!((utf8.encoding.as_str(), utf8.confidence), ("UTF-8", CONFIDENCE_UTF8));
+
+        let bom = manager.detect_encoding(b"\xFF\xFEh\x00i\x00");
+        assert_eq!((bom.encoding.as_str(), bom.confidence), ("UTF-16LE", CONFIDENCE_CERTAIN));
+
+        // A single Latin-1 byte is a guess, not a certainty
+        let latin1 = manager.detect_encoding(b"caf\xE9");
+        assert_ne!(latin1.encoding, "UTF-8");
+        assert!(latin1.confidence < CONFIDENCE_UTF8);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reopen_with_encoding_is_used_for_saves() -> Result<()> {
+        let workspace = tempfile::tempdir()?;
+        let manager = DocumentManager::new(workspace.path().to_path_buf(), MAX_FILE_SIZE)?;
+        let path = manager.workspace_path.join("legacy.txt");
+        // "Привет" in Windows-1251
+        std::fs::write(&path, b"\xCF\xF0\xE8\xE2\xE5\xF2\n")?;
+
+        manager.open_file(&path).await?;
+        let (content, metadata, version) = manager
+            .reopen_with_encoding(&path, encoding_rs::WINDOWS_1251)
+            .await?;
+        assert_eq!(content, "Привет\n");
+        assert_eq!(metadata.encoding.encoding, "windows-1251");
+        assert_eq!(version, 1);
+
+        let doc = VersionedDocument {
+            uri: path.clone(),
+            version: 2,
+        };
+        manager
+            .change_document(&doc, diff_changes(&content, "Привет, мир\n"))
+            .await?;
+        save_document(
+            &manager,
+            &VersionedDocument {
+                uri: path.clone(),
+                version: 3,
+            },
+            SaveNormalization::default(),
+        )
+        .await?;
+        assert_eq!(
+            std::fs::read(&path)?,
+            b"\xCF\xF0\xE8\xE2\xE5\xF2, \xEC\xE8\xF0\n"
+        );
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_snapshots_pair_content_with_its_version() -> Result<()> {
+        let workspace = tempfile::tempdir()?;
+        let manager = std::sync::Arc::new(DocumentManager::new(workspace.path().to_path_buf(), MAX_FILE_SIZE)?);
+        let path = manager.workspace_path.join("counter.txt");
+        std::fs::write(&path, "0")?;
+        manager.open_file(&path).await?;
+
+        // Each version's content is its own number
+        let writer = {
+            let (manager, path) = (std::sync::Arc::clone(&manager), path.clone());
+            tokio::spawn(async move {
+                for version in 1..=200 {
+                    let previous = (version - 1).to_string();
+                    let doc = VersionedDocument { uri: path.clone(), version };
+                    manager.change_document(&doc, diff_changes(&previous, &version.to_string())).await?;
+                    tokio::task::yield_now().await;
+                }
+                Ok::<_, anyhow::Error>(())
+            })
+        };
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let (manager, path) = (std::sync::Arc::clone(&manager), path.clone());
+                tokio::spawn(async move {
+                    for _ in 0..200 {
+                        let (content, version) = manager.snapshot(&path).await?.expect("document is open");
+                        assert_eq!(content, version.to_string());
+                        tokio::task::yield_now().await;
+                    }
+                    Ok::<_, anyhow::Error>(())
+                })
+            })
+            .collect();
+
+        writer.await??;
+        for reader in readers {
+            reader.await??;
+        }
+        assert_eq!(manager.snapshot(&path).await?, Some(("200".to_string(), 200)));
+        manager.close_file(&path).await;
+        assert_eq!(manager.snapshot(&path).await?, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_saves_end_with_exactly_one_final_newline() -> Result<()> {
+        let enforce = SaveNormalization { insert_final_newline: true };
+        let normalize = |content: &str, line_ending| normalize_for_save(content, &line_ending, enforce);
+        assert_eq!(normalize("fn main() {}", LineEnding::LF).as_deref(), Some("fn main() {}\n"));
+        assert_eq!(normalize("a\r\nb", LineEnding::CRLF).as_deref(), Some("a\r\nb\r\n"));
+        assert_eq!(normalize("a\n", LineEnding::LF), None);
+        assert_eq!(normalize("a\r\n", LineEnding::Mixed), None);
+        assert_eq!(normalize("a\n\n\n", LineEnding::LF).as_deref(), Some("a\n"));
+        assert_eq!(normalize("a\r\n\r\n", LineEnding::Mixed).as_deref(), Some("a\r\n"));
+        assert_eq!(normalize("", LineEnding::LF), None);
+        assert_eq!(normalize_for_save("a", &LineEnding::LF, SaveNormalization::default()), None);
+
+        // A save that normalizes is a new version, written with the new content
+        let workspace = tempfile::tempdir()?;
+        let manager = DocumentManager::new(workspace.path().to_path_buf(), MAX_FILE_SIZE)?;
+        let path = manager.workspace_path.join("main.rs");
+        std::fs::write(&path, "fn main() {}\n")?;
+        let (content, _, _) = manager.open_file(&path).await?;
+        let doc = VersionedDocument { uri: path.clone(), version: 1 };
+        manager.change_document(&doc, diff_changes(&content, "fn main() {}\n\n\n")).await?;
+
+        let (saved, normalized) =
+            save_document(&manager, &VersionedDocument { uri: path.clone(), version: 2 }, enforce).await?;
+        assert_eq!(saved.version, 2);
+        assert_eq!(normalized.map(|(content, _)| content).as_deref(), Some("fn main() {}\n"));
+        assert_eq!(std::fs::read_to_string(&path)?, "fn main() {}\n");
+        assert_eq!(manager.get_content_at_version(&path, 2).await?, "fn main() {}\n");
+
+        // Already normalized content is saved as it is
+        let (saved, normalized) =
+            save_document(&manager, &VersionedDocument { uri: path.clone(), version: 3 }, enforce).await?;
+        assert_eq!(saved.version, 2);
+        assert!(normalized.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_bom_is_stripped_and_restored_on_save() -> Result<()> {
+        let workspace = tempfile::tempdir()?;
+        let manager = DocumentManager::new(workspace.path().to_path_buf(), MAX_FILE_SIZE)?;
+        let utf16 = |text: &str| text.encode_utf16().flat_map(u16::to_le_bytes).collect::<Vec<u8>>();
+        let cases = [
+            ("utf8.txt", [&b"\xEF\xBB\xBF"[..], b"caf\xC3\xA9\n"].concat(), "UTF-8"),
+            ("utf16.txt", [&b"\xFF\xFE"[..], &utf16("café\n")].concat(), "UTF-16LE"),
+            ("plain.txt", b"caf\xC3\xA9\n".to_vec(), "UTF-8"),
+        ];
+
+        for (name, bytes, encoding) in cases {
+            let path = manager.workspace_path.join(name);
+            std::fs::write(&path, &bytes)?;
+            let (content, metadata, _) = manager.open_file(&path).await?;
+            assert_eq!(content, "café\n", "{}", name);
+            assert_eq!(metadata.encoding.encoding, encoding);
+            assert_eq!(metadata.has_bom, name != "plain.txt");
+
+            let doc = VersionedDocument { uri: path.clone(), version: 1 };
+            manager.change_document(&doc, diff_changes(&content, "café!\n")).await?;
+            save_document(&manager, &VersionedDocument { uri: path.clone(), version: 2 }, SaveNormalization::default())
+                .await?;
+            let expected = match name {
+                "utf8.txt" => [&b"\xEF\xBB\xBF"[..], b"caf\xC3\xA9!\n"].concat(),
+                "utf16.txt" => [&b"\xFF\xFE"[..], &utf16("café!\n")].concat(),
+                _ => b"caf\xC3\xA9!\n".to_vec(),
+            };
+            assert_eq!(std::fs::read(&path)?, expected, "{}", name);
+        }
+
+        // A BOM the client sent back as text isn't written twice
+        assert_eq!(encode_text("\u{FEFF}x", UTF_8, true)?, b"\xEF\xBB\xBFx");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_disk_diff_and_uncached_changes_decode_the_file() -> Result<()> {
+        let workspace = tempfile::tempdir()?;
+        let manager = DocumentManager::new(workspace.path().to_path_buf(), MAX_FILE_SIZE)?;
+        let path = manager.workspace_path.join("bom.txt");
+        std::fs::write(&path, [&b"\xEF\xBB\xBF"[..], b"caf\xC3\xA9\n"].concat())?;
+
+        let edited = |changes: Vec<DiffChange>| -> Vec<String> {
+            changes.into_iter().filter(|c| c.added || c.removed).map(|c| c.value).collect()
+        };
+        let (content, _, _) = manager.open_file(&path).await?;
+        assert!(edited(manager.diff_with_disk(&path).await?).is_empty());
+
+        // Changed while out of the cache, the base is the decoded text without the BOM
+        manager.invalidate_cache_for_file(&path).await;
+        let doc = VersionedDocument { uri: path.clone(), version: 1 };
+        manager.change_document(&doc, diff_changes(&content, "café!\n")).await?;
+        assert_eq!(manager.get_document_content(&path).await?, "café!\n");
+        assert_eq!(edited(manager.diff_with_disk(&path).await?), ["café\n", "café!\n"]);
+
+        // Files that aren't UTF-8 are compared as decoded text too
+        let latin1 = manager.workspace_path.join("latin1.txt");
+        std::fs::write(&latin1, b"caf\xE9 au lait\n")?;
+        manager.open_file(&latin1).await?;
+        assert!(edited(manager.diff_with_disk(&latin1).await?).is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_text_edits_keep_the_encoding_and_bom() -> Result<()> {
+        let workspace = tempfile::tempdir()?;
+        let manager = DocumentManager::new(workspace.path().to_path_buf(), MAX_FILE_SIZE)?;
+        let utf16 = |text: &str| text.encode_utf16().flat_map(u16::to_le_bytes).collect::<Vec<u8>>();
+        let cases = [
+            ("utf8.txt", [&b"\xEF\xBB\xBF"[..], b"caf\xC3\xA9\n"].concat(), [&b"\xEF\xBB\xBF"[..], b"tea\n"].concat()),
+            ("utf16.txt", [&b"\xFF\xFE"[..], &utf16("café\n")].concat(), [&b"\xFF\xFE"[..], &utf16("tea\n")].concat()),
+        ];
+
+        for (name, bytes, expected) in cases {
+            // Closed files are rewritten in their own encoding
+            let path = manager.workspace_path.join(name);
+            std::fs::write(&path, &bytes)?;
+            assert!(manager.apply_text_edits(&path, &[edit((0, 0), (0, 4), "tea")]).await?.is_none());
+            assert_eq!(std::fs::read(&path)?, expected, "{}", name);
+
+            // An open document that dropped out of the cache is read the same way
+            std::fs::write(&path, &bytes)?;
+            manager.open_file(&path).await?;
+            manager.invalidate_cache_for_file(&path).await;
+            let (content, metadata, _) = manager
+                .apply_text_edits(&path, &[edit((0, 0), (0, 4), "tea")])
+                .await?
+                .expect("the document is open");
+            assert_eq!(content, "tea\n", "{}", name);
+            assert!(metadata.has_bom, "{}", name);
+            manager.close_file(&path).await;
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_text_edits_to_a_closed_document_are_written_to_disk() -> Result<()> {
+        let workspace = tempfile::tempdir()?;
+        let manager = DocumentManager::new(workspace.path().to_path_buf(), MAX_FILE_SIZE)?;
+        let path = manager.workspace_path.join("notes.txt");
+        std::fs::write(&path, "hello\n")?;
+
+        manager.open_file(&path).await?;
+        manager.close_file(&path).await;
+        assert!(manager.apply_text_edits(&path, &[edit((0, 0), (0, 5), "bye")]).await?.is_none());
+        assert_eq!(std::fs::read(&path)?, b"bye\n");
+        assert!(!manager.get_document_state(&path).await?.is_dirty);
+        assert_eq!(manager.get_document_content(&path).await?, "bye\n");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_opening_a_directory_is_a_typed_error() -> Result<()> {
+        let workspace = tempfile::tempdir()?;
+        let manager = DocumentManager::new(workspace.path().to_path_buf(), MAX_FILE_SIZE)?;
+        let dir = manager.workspace_path.join("src");
+        std::fs::create_dir(&dir)?;
+
+        let error = manager.open_file(&dir).await.unwrap_err();
+        let error = error.downcast::<IsADirectory>().expect("typed error");
+        assert_eq!(error.path, dir.canonicalize()?);
+        // Nothing was opened
+        assert!(manager.document_states.read().await.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_preview_delete_lists_directory_contents() -> Result<()> {
+        let workspace = tempfile::tempdir()?;
+        let manager = DocumentManager::new(workspace.path().to_path_buf(), MAX_FILE_SIZE)?;
+        let dir = manager.workspace_path.join("dir");
+        std::fs::create_dir_all(dir.join("nested"))?;
+        std::fs::write(dir.join("a.txt"), "a")?;
+        std::fs::write(dir.join("nested/b.txt"), "b")?;
+
+        let mut affected = manager.preview_delete(&dir).await?;
+        affected.sort();
+        assert_eq!(
+            affected,
+            vec![dir.clone(), dir.join("a.txt"), dir.join("nested"), dir.join("nested/b.txt")]
+        );
+        // Nothing was removed
+        assert!(dir.join("nested/b.txt").exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_workspace_edit_stops_at_first_failure() -> Result<()> {
+        use lsp_types::{
+            CreateFile, DocumentChangeOperation, DocumentChanges, OneOf,
+            OptionalVersionedTextDocumentIdentifier, RenameFile, ResourceOp, TextDocumentEdit,
+            Uri, WorkspaceEdit,
+        };
+        use std::str::FromStr;
 
+        let workspace = tempfile::tempdir()?;
+        let manager = DocumentManager::new(workspace.path().to_path_buf(), MAX_FILE_SIZE)?;
+        let root = manager.workspace_path.clone();
+        let uri = |name: &str| {
+            Uri::from_str(url::Url::from_file_path(root.join(name)).unwrap().as_str()).unwrap()
+        };
+
+        let edit = WorkspaceEdit {
+            document_changes: Some(DocumentChanges::Operations(vec![
+                DocumentChangeOperation::Op(ResourceOp::Create(CreateFile {
+                    uri: uri("a.txt"),
+                    options: None,
+                    annotation_id: None,
+                })),
+                DocumentChangeOperation::Edit(TextDocumentEdit {
+                    text_document: OptionalVersionedTextDocumentIdentifier {
+                        uri: uri("a.txt"),
+                        version: None,
+                    },
+                    edits: vec![OneOf::Left(edit((0, 0), (0, 0), "hello"))],
+                }),
+                DocumentChangeOperation::Op(ResourceOp::Rename(RenameFile {
+                    old_uri: uri("a.txt"),
+                    new_uri: uri("b.txt"),
+                    options: None,
+                    annotation_id: None,
+                })),
+                // Fails: a.txt was renamed away
+                DocumentChangeOperation::Op(ResourceOp::Rename(RenameFile {
+                    old_uri: uri("a.txt"),
+                    new_uri: uri("c.txt"),
+                    options: None,
+                    annotation_id: None,
+                })),
+                DocumentChangeOperation::Op(ResourceOp::Create(CreateFile {
+                    uri: uri("d.txt"),
+                    options: None,
+                    annotation_id: None,
+                })),
+            ])),
+            ..Default::default()
+        };
+
+        let outcome = manager.apply_workspace_edit(edit).await;
+        assert_eq!(outcome.applied.len(), 3);
+        assert!(outcome.error.is_some());
+        assert_eq!(std::fs::read_to_string(root.join("b.txt"))?, "hello");
+        assert!(!root.join("d.txt").exists());
         Ok(())
     }
+
+    #[test]
+    fn test_diff_changes_round_trip() {
+        let old = "fn main() {\n    old();\n}\n";
+        let new = "fn main() {\n    new();\n    more();\n}\n";
+
+        let changes = diff_changes(old, new);
+        assert!(changes.iter().any(|c| c.removed && c.value == "    old();\n"));
+        assert_eq!(apply_diff_changes(old, changes).unwrap(), new);
+    }
+
+    #[test]
+    fn test_diff_changes_on_a_long_minified_line() {
+        let old = format!("{}\n", "var a=\"é🦀\";".repeat(100_000));
+        let new = format!("{}\n", "var a=\"é🦀\";".repeat(99_999) + "var b=1;");
+        assert_eq!(apply_diff_changes(&old, diff_changes(&old, &new)).unwrap(), new);
+
+        // Unchanged text that differs from the server's copy still counts characters
+        let unchanged = |value: &str| DiffChange { value: value.to_string(), added: false, removed: false };
+        let added = DiffChange { value: "!".to_string(), added: true, removed: false };
+        assert_eq!(apply_diff_changes("é🦀x", vec![unchanged("ab"), added.clone()]).unwrap(), "é🦀!");
+        assert!(apply_diff_changes("é🦀", vec![unchanged("abc"), added]).is_err());
+    }
+
+    #[test]
+    fn test_apply_text_edits_to_content() {
+        let content = "use crate::foo;\nlet s = \"é😀x\";\n";
+        let edits = vec![
+            edit((0, 11), (0, 14), "bar"),
+            // The emoji is two UTF-16 code units wide
+            edit((1, 12), (1, 13), "y"),
+        ];
+
+        let result = apply_text_edits_to_content(content, &edits).unwrap();
+        assert_eq!(result, "use crate::bar;\nlet s = \"é😀y\";\n");
+
+        let overlapping = vec![edit((0, 0), (0, 5), ""), edit((0, 3), (0, 8), "")];
+        let error = apply_text_edits_to_content(content, &overlapping).unwrap_err();
+        assert_eq!(error.to_string(), "Overlapping text edits: 1:1-1:6 and 1:4-1:9");
+
+        // Inserts at one position keep their order, also next to a replacement there
+        let inserts = vec![edit((0, 0), (0, 0), "a"), edit((0, 0), (0, 3), "mod"), edit((0, 0), (0, 0), "b")];
+        let result = apply_text_edits_to_content(content, &inserts).unwrap();
+        assert!(result.starts_with("abmod crate::foo;"));
+    }
+
+    #[test]
+    fn test_text_edits_to_the_end_of_a_crlf_line_keep_the_line_ending() {
+        let content = "fn main() {\r\n}\r\n";
+        let edits = vec![edit((0, 3), (0, 99), "run() {}")];
+        let result = apply_text_edits_to_content(content, &edits).unwrap();
+        assert_eq!(result, "fn run() {}\r\n}\r\n");
+    }
 }
@@ -0,0 +1,149 @@
+// src/file_system/event_groups.rs
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+use super::file_event::FileEvent;
+
+// The events of one batch that happened directly inside `directory`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DirectoryEvents {
+    pub directory: PathBuf,
+    pub events: Vec<FileEvent>,
+}
+
+// Groups a batch by parent directory, sorted by directory path, after collapsing
+// several events for the same path into the one that describes the end result:
+// Created then Modified stays Created with the latest metadata, Created then
+// Deleted disappears, anything else keeps the latest event. Renames are kept
+// as they are and filed under the directory they moved into.
+pub fn group_by_directory(events: Vec<FileEvent>) -> Vec<DirectoryEvents> {
+    let mut collapsed: Vec<Option<FileEvent>> = Vec::with_capacity(events.len());
+    let mut latest: HashMap<PathBuf, usize> = HashMap::new();
+
+    for event in events {
+        let path = match &event {
+            FileEvent::Renamed { .. } => {
+                collapsed.push(Some(event));
+                continue;
+            }
+            FileEvent::Created { path, .. }
+            | FileEvent::Modified { path, .. }
+            | FileEvent::Deleted { path, .. } => path.clone(),
+        };
+
+        let Some(&index) = latest.get(&path) else {
+            latest.insert(path, collapsed.len());
+            collapsed.push(Some(event));
+            continue;
+        };
+        collapsed[index] = match (collapsed[index].take(), event) {
+            (
+                Some(FileEvent::Created { path, .. }),
+                FileEvent::Modified {
+                    timestamp_ms,
+                    new_metadata,
+                    ..
+                },
+            ) => Some(FileEvent::Created {
+                path,
+                timestamp_ms,
+                metadata: new_metadata,
+            }),
+            (Some(FileEvent::Created { .. }), FileEvent::Deleted { .. }) => {
+                latest.remove(&path);
+                None
+            }
+            (_, event) => Some(event),
+        };
+    }
+
+    let mut groups: BTreeMap<PathBuf, Vec<FileEvent>> = BTreeMap::new();
+    for event in collapsed.into_iter().flatten() {
+        let path = match &event {
+            FileEvent::Renamed { to, .. } => to,
+            FileEvent::Created { path, .. }
+            | FileEvent::Modified { path, .. }
+            | FileEvent::Deleted { path, .. } => path,
+        };
+        let directory = path.parent().map(Path::to_path_buf).unwrap_or_default();
+        groups.entry(directory).or_default().push(event);
+    }
+    groups
+        .into_iter()
+        .map(|(directory, events)| DirectoryEvents { directory, events })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_system::file_event::{FileMetadata, ModificationType};
+
+    fn metadata(size: u64) -> FileMetadata {
+        FileMetadata {
+            size,
+            is_directory: false,
+            is_symlink: false,
+            created_at: None,
+            modified_at: None,
+            readonly: false,
+            mode: None,
+        }
+    }
+
+    fn created(path: &str) -> FileEvent {
+        FileEvent::Created {
+            path: PathBuf::from(path),
+            timestamp_ms: 0,
+            metadata: metadata(0),
+        }
+    }
+
+    fn modified(path: &str, size: u64) -> FileEvent {
+        FileEvent::Modified {
+            path: PathBuf::from(path),
+            timestamp_ms: 1,
+            modification_type: ModificationType::Content,
+            new_metadata: metadata(size),
+            content_hash: None,
+            previous_permissions: None,
+        }
+    }
+
+    fn deleted(path: &str) -> FileEvent {
+        FileEvent::Deleted {
+            path: PathBuf::from(path),
+            timestamp_ms: 2,
+        }
+    }
+
+    #[test]
+    fn test_events_collapse_and_group_by_directory() {
+        let groups = group_by_directory(vec![
+            created("/ws/target/a.o"),
+            modified("/ws/src/main.rs", 10),
+            created("/ws/target/tmp"),
+            modified("/ws/target/a.o", 42),
+            modified("/ws/src/main.rs", 20),
+            deleted("/ws/target/tmp"),
+            created("/ws/src/lib.rs"),
+        ]);
+
+        let directories: Vec<_> = groups.iter().map(|group| group.directory.clone()).collect();
+        assert_eq!(directories, vec![PathBuf::from("/ws/src"), PathBuf::from("/ws/target")]);
+
+        let src = &groups[0].events;
+        assert_eq!(src.len(), 2);
+        assert!(matches!(&src[0], FileEvent::Modified { new_metadata, .. } if new_metadata.size == 20));
+        assert!(matches!(&src[1], FileEvent::Created { path, .. } if path.ends_with("lib.rs")));
+
+        // The temporary file came and went within the batch
+        let target = &groups[1].events;
+        assert_eq!(target.len(), 1);
+        assert!(matches!(
+            &target[0],
+            FileEvent::Created { path, timestamp_ms: 1, metadata } if path.ends_with("a.o") && metadata.size == 42
+        ));
+    }
+}
@@ -1,7 +1,14 @@
 use serde::{Serialize, Deserialize};
-use std::path::PathBuf;
+use sha1::{Digest, Sha1};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 use std::time::{SystemTime, UNIX_EPOCH};
 
+// Larger files are not hashed on every write
+const MAX_HASHED_FILE_SIZE: u64 = 16 * 1024 * 1024;
+// Paths whose permissions are remembered before the history starts over
+const MAX_TRACKED_PERMISSIONS: usize = 100_000;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FileMetadata {
     pub size: u64,
@@ -10,12 +17,51 @@ pub struct FileMetadata {
     pub created_at: Option<u64>,
     pub modified_at: Option<u64>,
     pub readonly: bool,
+    // Permission bits (mode & 0o7777) on Unix
+    pub mode: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct FilePermissions {
+    pub readonly: bool,
+    pub mode: Option<u32>,
+}
+
+impl FileMetadata {
+    // Follows a symlink for everything but `is_symlink`
+    pub async fn read(path: &Path) -> std::io::Result<Self> {
+        let metadata = tokio::fs::metadata(path).await?;
+        let is_symlink = tokio::fs::symlink_metadata(path)
+            .await
+            .is_ok_and(|link| link.file_type().is_symlink());
+        Ok(FileMetadata {
+            size: metadata.len(),
+            is_directory: metadata.is_dir(),
+            is_symlink,
+            created_at: metadata.created().ok().and_then(|t|
+                t.duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs())),
+            modified_at: metadata.modified().ok().and_then(|t|
+                t.duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs())),
+            readonly: metadata.permissions().readonly(),
+            mode: permission_mode(&metadata),
+        })
+    }
+}
+
+impl FilePermissions {
+    fn of(metadata: &FileMetadata) -> Self {
+        Self {
+            readonly: metadata.readonly,
+            mode: metadata.mode,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ModificationType {
     Content,    // File content was modified
-    Metadata,   // File metadata changed (permissions, timestamps)
+    Metadata,   // File metadata changed (timestamps, or permissions not seen before)
+    Permissions, // Readonly flag or mode bits changed, see previous_permissions
     Name,       // File was renamed
     Other,      // Other modifications
 }
@@ -32,11 +78,25 @@ pub enum FileEvent {
         timestamp_ms: u128,
         modification_type: ModificationType,
         new_metadata: FileMetadata,
+        // Hex SHA-1 of the new bytes, for content changes of files up to
+        // MAX_HASHED_FILE_SIZE. Clients can skip refetching when it matches.
+        content_hash: Option<String>,
+        // What the permissions were before, when they changed from the last
+        // ones seen for this path
+        previous_permissions: Option<FilePermissions>,
     },
     Deleted {
         path: PathBuf,
         timestamp_ms: u128,
     },
+    // Both ends of a rename inside the workspace. Backends that pair renames
+    // (inotify, Windows) report the two halves as Deleted/Modified first.
+    Renamed {
+        from: PathBuf,
+        to: PathBuf,
+        timestamp_ms: u128,
+        metadata: FileMetadata,
+    },
 }
 
 impl FileEvent {
@@ -49,19 +109,10 @@ impl FileEvent {
         println!("Processing notify event: {:?}", event);
 
         async fn get_metadata(path: &PathBuf) -> Option<FileMetadata> {
-            match tokio::fs::metadata(path).await {
+            match FileMetadata::read(path).await {
                 Ok(metadata) => {
                     println!("Got metadata for: {:?}", path);
-                    Some(FileMetadata {
-                        size: metadata.len(),
-                        is_directory: metadata.is_dir(),
-                        is_symlink: metadata.file_type().is_symlink(),
-                        created_at: metadata.created().ok().and_then(|t| 
-                            t.duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs())),
-                        modified_at: metadata.modified().ok().and_then(|t| 
-                            t.duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs())),
-                        readonly: metadata.permissions().readonly(),
-                    })
+                    Some(metadata)
                 },
                 Err(e) => {
                     println!("Failed to get metadata for {:?}: {}", path, e);
@@ -73,28 +124,37 @@ impl FileEvent {
         let result = match event.kind {
             notify::EventKind::Create(_) => {
                 let path = &event.paths[0];
-                match get_metadata(path).await {
-                    Some(metadata) => Some(FileEvent::Created {
-                        path: path.clone(),
-                        timestamp_ms,
-                        metadata,
-                    }),
-                    None => None,
-                }
+                get_metadata(path).await.map(|metadata| FileEvent::Created {
+                    path: path.clone(),
+                    timestamp_ms,
+                    metadata,
+                })
             },
             
             notify::EventKind::Modify(modify_kind) => {
                 let path = &event.paths[0];
                 println!("Processing modify event for path: {:?}, kind: {:?}", path, modify_kind);
                 
+                if let (notify::event::ModifyKind::Name(notify::event::RenameMode::Both), [from, to]) =
+                    (modify_kind, event.paths.as_slice())
+                {
+                    get_metadata(to).await.map(|metadata| FileEvent::Renamed {
+                        from: from.clone(),
+                        to: to.clone(),
+                        timestamp_ms,
+                        metadata,
+                    })
+                }
                 // Special handling for Name modifications which might indicate deletion
-                if matches!(modify_kind, notify::event::ModifyKind::Name(_)) {
+                else if matches!(modify_kind, notify::event::ModifyKind::Name(_)) {
                     match get_metadata(path).await {
                         Some(new_metadata) => Some(FileEvent::Modified {
                             path: path.clone(),
                             timestamp_ms,
                             modification_type: ModificationType::Name,
                             new_metadata,
+                            content_hash: None,
+                            previous_permissions: None,
                         }),
                         None => {
                             // If we can't get metadata, treat it as a deletion
@@ -114,12 +174,14 @@ impl FileEvent {
                                 notify::event::ModifyKind::Metadata(_) => ModificationType::Metadata,
                                 _ => ModificationType::Other,
                             };
-                            
+                            // Hashed by hash_content once modifications are debounced
                             Some(FileEvent::Modified {
                                 path: path.clone(),
                                 timestamp_ms,
                                 modification_type,
                                 new_metadata,
+                                content_hash: None,
+                                previous_permissions: None,
                             })
                         },
                         None => None,
@@ -144,4 +206,200 @@ impl FileEvent {
         println!("Processed event result: {:?}", result);
         result
     }
+
+    // Fills in the content hash of a content modification
+    pub async fn hash_content(&mut self) {
+        if let FileEvent::Modified {
+            path,
+            modification_type: ModificationType::Content,
+            new_metadata,
+            content_hash: hash,
+            ..
+        } = self
+        {
+            *hash = content_hash(path, new_metadata).await;
+        }
+    }
+}
+
+#[cfg(unix)]
+fn permission_mode(metadata: &std::fs::Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(metadata.permissions().mode() & 0o7777)
+}
+
+#[cfg(not(unix))]
+fn permission_mode(_metadata: &std::fs::Metadata) -> Option<u32> {
+    None
+}
+
+// Last permissions seen per path, so metadata events can tell a permission
+// change apart from a timestamp update and report what changed
+#[derive(Debug, Default)]
+pub struct PermissionHistory {
+    known: HashMap<PathBuf, FilePermissions>,
+}
+
+impl PermissionHistory {
+    pub fn track(&mut self, event: &mut FileEvent) {
+        if self.known.len() >= MAX_TRACKED_PERMISSIONS {
+            self.known.clear();
+        }
+        match event {
+            FileEvent::Created { path, metadata, .. } => {
+                self.known.insert(path.clone(), FilePermissions::of(metadata));
+            }
+            FileEvent::Modified {
+                path,
+                modification_type,
+                new_metadata,
+                previous_permissions,
+                ..
+            } => {
+                let current = FilePermissions::of(new_metadata);
+                let previous = self.known.insert(path.clone(), current);
+                if let Some(previous) = previous.filter(|previous| *previous != current) {
+                    if matches!(modification_type, ModificationType::Metadata) {
+                        *modification_type = ModificationType::Permissions;
+                    }
+                    *previous_permissions = Some(previous);
+                }
+            }
+            FileEvent::Deleted { path, .. } => {
+                self.known.remove(path);
+            }
+            FileEvent::Renamed { from, to, metadata, .. } => {
+                self.known.remove(from);
+                self.known.insert(to.clone(), FilePermissions::of(metadata));
+            }
+        }
+    }
+}
+
+async fn content_hash(path: &PathBuf, metadata: &FileMetadata) -> Option<String> {
+    if metadata.is_directory || metadata.size > MAX_HASHED_FILE_SIZE {
+        return None;
+    }
+    let content = tokio::fs::read(path).await.ok()?;
+    let digest = Sha1::digest(&content);
+    Some(digest.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use notify::event::{DataChange, ModifyKind};
+
+    #[tokio::test]
+    async fn test_content_modification_includes_hash() -> anyhow::Result<()> {
+        let workspace = tempfile::tempdir()?;
+        let path = workspace.path().join("file.txt");
+        std::fs::write(&path, "hello")?;
+
+        let event = notify::Event::new(notify::EventKind::Modify(ModifyKind::Data(DataChange::Content)))
+            .add_path(path.clone());
+        let mut modified = FileEvent::from_notify_event(event).await;
+        if let Some(event) = modified.as_mut() {
+            event.hash_content().await;
+        }
+        match modified {
+            Some(FileEvent::Modified { content_hash, .. }) => assert_eq!(
+                content_hash.as_deref(),
+                Some("aaf4c61ddcc5e8a2dabede0f3b482cd9aea9434d")
+            ),
+            other => panic!("unexpected event: {:?}", other),
+        }
+
+        // Metadata-only changes are not hashed
+        let event = notify::Event::new(notify::EventKind::Modify(ModifyKind::Metadata(
+            notify::event::MetadataKind::Any,
+        )))
+        .add_path(path);
+        let mut modified = FileEvent::from_notify_event(event).await;
+        if let Some(event) = modified.as_mut() {
+            event.hash_content().await;
+        }
+        match modified {
+            Some(FileEvent::Modified { content_hash, .. }) => assert!(content_hash.is_none()),
+            other => panic!("unexpected event: {:?}", other),
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_metadata_is_read_for_files_and_directories() -> anyhow::Result<()> {
+        let workspace = tempfile::tempdir()?;
+        let file = workspace.path().join("main.rs");
+        std::fs::write(&file, "fn main() {}")?;
+
+        let metadata = FileMetadata::read(&file).await?;
+        assert_eq!(metadata.size, 12);
+        assert!(!metadata.is_directory);
+        assert!(metadata.modified_at.is_some());
+        assert!(FileMetadata::read(workspace.path()).await?.is_directory);
+        assert!(FileMetadata::read(&workspace.path().join("missing.rs")).await.is_err());
+        assert!(!metadata.is_symlink);
+
+        #[cfg(unix)]
+        {
+            let link = workspace.path().join("link.rs");
+            std::os::unix::fs::symlink(&file, &link)?;
+            let metadata = FileMetadata::read(&link).await?;
+            assert!(metadata.is_symlink);
+            assert_eq!(metadata.size, 12);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_permission_changes_report_previous_permissions() {
+        let metadata = |readonly, mode| FileMetadata {
+            size: 0,
+            is_directory: false,
+            is_symlink: false,
+            created_at: None,
+            modified_at: None,
+            readonly,
+            mode: Some(mode),
+        };
+        let modified = |metadata| FileEvent::Modified {
+            path: PathBuf::from("/ws/file.txt"),
+            timestamp_ms: 0,
+            modification_type: ModificationType::Metadata,
+            new_metadata: metadata,
+            content_hash: None,
+            previous_permissions: None,
+        };
+
+        let mut history = PermissionHistory::default();
+        history.track(&mut FileEvent::Created {
+            path: PathBuf::from("/ws/file.txt"),
+            timestamp_ms: 0,
+            metadata: metadata(false, 0o644),
+        });
+
+        let mut chmod = modified(metadata(true, 0o444));
+        history.track(&mut chmod);
+        match chmod {
+            FileEvent::Modified { modification_type, previous_permissions, .. } => {
+                assert!(matches!(modification_type, ModificationType::Permissions));
+                assert_eq!(
+                    previous_permissions,
+                    Some(FilePermissions { readonly: false, mode: Some(0o644) })
+                );
+            }
+            other => panic!("unexpected event: {:?}", other),
+        }
+
+        // A timestamp update leaves the permissions as they were
+        let mut touch = modified(metadata(true, 0o444));
+        history.track(&mut touch);
+        match touch {
+            FileEvent::Modified { modification_type, previous_permissions, .. } => {
+                assert!(matches!(modification_type, ModificationType::Metadata));
+                assert!(previous_permissions.is_none());
+            }
+            other => panic!("unexpected event: {:?}", other),
+        }
+    }
 }
\ No newline at end of file
@@ -0,0 +1,171 @@
+// src/file_system/merge.rs
+use serde::{Deserialize, Serialize};
+use similar::{capture_diff_slices, Algorithm, DiffTag};
+
+// A region both sides changed differently, `start_line` is zero-based in the base
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MergeConflict {
+    pub start_line: usize,
+    pub base: String,
+    pub server: String,
+    pub client: String,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum MergeOutcome {
+    Merged(String),
+    Conflicts(Vec<MergeConflict>),
+}
+
+// Base lines [start, end) replaced by `lines`
+#[derive(Debug)]
+struct Hunk<'a> {
+    start: usize,
+    end: usize,
+    lines: Vec<&'a str>,
+}
+
+fn split_lines(text: &str) -> Vec<&str> {
+    text.split_inclusive('\n').collect()
+}
+
+fn hunks<'a>(base: &[&str], other: &[&'a str]) -> Vec<Hunk<'a>> {
+    let mut hunks: Vec<Hunk<'a>> = Vec::new();
+    for op in capture_diff_slices(Algorithm::Myers, base, other) {
+        let (tag, old_range, new_range) = op.as_tag_tuple();
+        if tag == DiffTag::Equal {
+            continue;
+        }
+
+        let lines = other[new_range].to_vec();
+        match hunks.last_mut() {
+            // A delete directly followed by an insert is a single replacement
+            Some(last) if last.end == old_range.start => {
+                last.end = old_range.end;
+                last.lines.extend(lines);
+            }
+            _ => hunks.push(Hunk {
+                start: old_range.start,
+                end: old_range.end,
+                lines,
+            }),
+        }
+    }
+    hunks
+}
+
+// Applies the hunks that fall inside base[start..end]
+fn apply_region(base: &[&str], start: usize, end: usize, hunks: &[Hunk]) -> String {
+    let mut result = String::new();
+    let mut position = start;
+    for hunk in hunks {
+        result.push_str(&base[position..hunk.start].concat());
+        result.push_str(&hunk.lines.concat());
+        position = hunk.end;
+    }
+    result.push_str(&base[position..end].concat());
+    result
+}
+
+// Line-based three-way merge of two descendants of `base`. Changes touching the
+// same or adjacent lines only merge when both sides made the same edit.
+pub fn three_way_merge(base: &str, server: &str, client: &str) -> MergeOutcome {
+    let base_lines = split_lines(base);
+    let server_lines = split_lines(server);
+    let client_lines = split_lines(client);
+    let server_hunks = hunks(&base_lines, &server_lines);
+    let client_hunks = hunks(&base_lines, &client_lines);
+
+    let mut merged = String::new();
+    let mut conflicts = Vec::new();
+    let mut position = 0;
+    let (mut i, mut j) = (0, 0);
+
+    while i < server_hunks.len() || j < client_hunks.len() {
+        // Start a region at whichever hunk comes first, then absorb every hunk
+        // from either side that overlaps or touches it
+        let (mut start, mut end) = match (server_hunks.get(i), client_hunks.get(j)) {
+            (Some(s), Some(c)) if s.start <= c.start => (s.start, s.end),
+            (Some(s), None) => (s.start, s.end),
+            (_, Some(c)) => (c.start, c.end),
+            (None, None) => unreachable!(),
+        };
+        let (first_server, first_client) = (i, j);
+        loop {
+            if let Some(hunk) = server_hunks.get(i).filter(|h| h.start <= end) {
+                start = start.min(hunk.start);
+                end = end.max(hunk.end);
+                i += 1;
+            } else if let Some(hunk) = client_hunks.get(j).filter(|h| h.start <= end) {
+                start = start.min(hunk.start);
+                end = end.max(hunk.end);
+                j += 1;
+            } else {
+                break;
+            }
+        }
+
+        merged.push_str(&base_lines[position..start].concat());
+        position = end;
+
+        let server_region = &server_hunks[first_server..i];
+        let client_region = &client_hunks[first_client..j];
+        if server_region.is_empty() || client_region.is_empty() {
+            let region = if server_region.is_empty() { client_region } else { server_region };
+            merged.push_str(&apply_region(&base_lines, start, end, region));
+            continue;
+        }
+
+        let server_text = apply_region(&base_lines, start, end, server_region);
+        let client_text = apply_region(&base_lines, start, end, client_region);
+        if server_text == client_text {
+            merged.push_str(&server_text);
+        } else {
+            conflicts.push(MergeConflict {
+                start_line: start,
+                base: base_lines[start..end].concat(),
+                server: server_text,
+                client: client_text,
+            });
+        }
+    }
+    merged.push_str(&base_lines[position..].concat());
+
+    if conflicts.is_empty() {
+        MergeOutcome::Merged(merged)
+    } else {
+        MergeOutcome::Conflicts(conflicts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_three_way_merge() {
+        let base = "a\nb\nc\nd\ne\n";
+
+        // Edits to separate lines merge cleanly
+        assert_eq!(
+            three_way_merge(base, "A\nb\nc\nd\ne\n", "a\nb\nc\nd\nE\n"),
+            MergeOutcome::Merged("A\nb\nc\nd\nE\n".to_string())
+        );
+
+        // Identical edits on both sides are not a conflict
+        assert_eq!(
+            three_way_merge(base, "a\nB\nc\nd\ne\n", "a\nB\nc\nd\ne\n"),
+            MergeOutcome::Merged("a\nB\nc\nd\ne\n".to_string())
+        );
+
+        assert_eq!(
+            three_way_merge(base, "a\nb\nserver\nd\ne\n", "a\nb\nclient\nd\ne\n"),
+            MergeOutcome::Conflicts(vec![MergeConflict {
+                start_line: 2,
+                base: "c\n".to_string(),
+                server: "server\n".to_string(),
+                client: "client\n".to_string(),
+            }])
+        );
+    }
+}
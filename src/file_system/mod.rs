@@ -1,31 +1,52 @@
 mod directory_manager;
+mod document_cache;
 mod document_manager;
 mod event_batcher;
+mod event_groups;
 mod file_event;
+mod merge;
+mod modify_debouncer;
+mod replace;
+mod tree_updates;
 mod watcher_manager;
+mod workspace_detector;
 
 use anyhow::Result;
 use document_manager::DocumentState;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::sync::broadcast;
 
-pub use directory_manager::{DirectoryManager, FileNode};
-pub use document_manager::{DiffChange, DocumentManager, DocumentMetadata, VersionedDocument};
-pub use file_event::FileEvent;
+use crate::utils::path_utils::{is_within, SymlinkPolicy};
+
+pub use directory_manager::{retain_visible, DirectoryManager, FileNode, IndexState};
+pub use document_manager::{
+    document_change_operations, is_binary_file, CacheStats, text_edits, AppliedEdit, DiffChange,
+    DocumentManager, DocumentMetadata, IsADirectory, SaveNormalization, VersionedDocument,
+    WorkspaceEditOutcome, MAX_FILE_SIZE,
+};
+pub use event_groups::{group_by_directory, DirectoryEvents};
+pub use file_event::{FileEvent, FileMetadata};
+pub use merge::{MergeConflict, MergeOutcome};
+pub use replace::{replace_candidates, scope_matcher, TextMatcher};
+pub use tree_updates::{LoadedTree, TreeUpdate};
+pub use watcher_manager::WatcherStatus;
 use watcher_manager::WatcherManager;
+pub use workspace_detector::{ProjectType, WorkspaceInfo};
+use workspace_detector::WorkspaceDetector;
 
 pub struct FileSystem {
     directory_manager: Arc<DirectoryManager>,
     watcher_manager: WatcherManager,
     document_manager: Arc<DocumentManager>,
+    workspace_detector: WorkspaceDetector,
 }
 
 impl FileSystem {
-    pub fn new(workspace_path: PathBuf) -> Result<Self> {
-        let directory_manager = Arc::new(DirectoryManager::new(workspace_path.clone())?);
-        let document_manager = Arc::new(DocumentManager::new(workspace_path.clone())?);
+    pub fn new(workspace_path: PathBuf, symlinks: SymlinkPolicy, max_open_document_size: u64) -> Result<Self> {
+        let directory_manager = Arc::new(DirectoryManager::new(workspace_path.clone(), symlinks)?);
+        let document_manager = Arc::new(DocumentManager::new(workspace_path.clone(), max_open_document_size)?);
 
         let watcher_manager = WatcherManager::new(
             Arc::clone(&directory_manager),
@@ -34,6 +55,7 @@ impl FileSystem {
         );
 
         Ok(Self {
+            workspace_detector: WorkspaceDetector::new(directory_manager.get_workspace_path().clone()),
             directory_manager,
             watcher_manager,
             document_manager,
@@ -44,31 +66,59 @@ impl FileSystem {
         self.directory_manager.init().await
     }
 
-    pub async fn start_watching(&self) -> Result<()> {
-        self.watcher_manager.start_watching().await
+    pub async fn index_state(&self) -> IndexState {
+        self.directory_manager.index_state().await
+    }
+
+    pub async fn start_watching(&self, poll_interval: Duration, modify_debounce: Duration) -> Result<()> {
+        self.watcher_manager.start_watching(poll_interval, modify_debounce).await
     }
 
     pub fn subscribe(&self) -> broadcast::Receiver<FileEvent> {
         self.watcher_manager.subscribe()
     }
 
+    pub fn subscribe_watcher_status(&self) -> broadcast::Receiver<WatcherStatus> {
+        self.watcher_manager.subscribe_status()
+    }
+
     pub fn get_workspace_path(&self) -> &PathBuf {
         self.directory_manager.get_workspace_path()
     }
 
+    pub async fn workspace_info(&self) -> WorkspaceInfo {
+        WorkspaceInfo {
+            root: self.get_workspace_path().clone(),
+            project_types: self.workspace_detector.detect().await,
+            index_state: self.directory_manager.index_state().await,
+        }
+    }
+
     pub async fn load_directory(&self, path: &PathBuf) -> Result<Vec<FileNode>> {
         self.directory_manager.load_directory(path).await
     }
 
+    pub async fn full_tree(&self, max_entries: usize, show_hidden: bool) -> Result<(FileNode, bool)> {
+        self.directory_manager.full_tree(max_entries, show_hidden).await
+    }
+
+    pub async fn filter_directory(&self, path: &PathBuf, query: &str) -> Result<Vec<FileNode>> {
+        self.directory_manager.filter_directory(path, query).await
+    }
+
+    pub async fn glob(&self, pattern: &str, max_matches: usize) -> Result<(Vec<PathBuf>, bool)> {
+        self.directory_manager.glob(pattern, max_matches).await
+    }
+
     pub async fn refresh_directory(&self, path: &PathBuf) -> Result<Vec<FileNode>> {
         self.directory_manager.refresh_directory(path).await
     }
 
-    pub async fn open_file(&self, path: &PathBuf) -> Result<(String, DocumentMetadata, i32)> {
-        Ok(self.document_manager.open_file(path).await?)
+    pub async fn open_file(&self, path: &Path) -> Result<(String, DocumentMetadata, i32)> {
+        self.document_manager.open_file(path).await
     }
 
-    pub async fn close_file(&self, path: &PathBuf) -> Result<()> {
+    pub async fn close_file(&self, path: &Path) -> Result<()> {
         self.document_manager.close_file(path).await;
         Ok(())
     }
@@ -78,25 +128,76 @@ impl FileSystem {
         document: VersionedDocument,
         changes: Vec<DiffChange>,
     ) -> Result<VersionedDocument> {
-        Ok(self
-            .document_manager
+        self.document_manager
             .change_document(&document, changes)
-            .await?)
+            .await
+    }
+
+    pub async fn merge_changes(
+        &self,
+        document: VersionedDocument,
+        changes: Vec<DiffChange>,
+    ) -> Result<(VersionedDocument, MergeOutcome)> {
+        self.document_manager.merge_changes(&document, changes).await
+    }
+
+    pub async fn normalize_for_save(
+        &self,
+        document: &VersionedDocument,
+        normalization: SaveNormalization,
+    ) -> Result<Option<(String, DocumentMetadata, i32)>> {
+        self.document_manager.normalize_for_save(document, normalization).await
+    }
+
+    pub async fn write_document(&self, path: &Path) -> Result<VersionedDocument> {
+        self.document_manager.write_document(path).await
+    }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        self.document_manager.cache_stats()
+    }
+
+    pub async fn document_snapshot(&self, path: &Path) -> Result<Option<(String, i32)>> {
+        self.document_manager.snapshot(path).await
+    }
+
+    pub async fn get_document_content(&self, path: &Path) -> Result<String> {
+        self.document_manager.get_document_content(path).await
     }
 
-    pub async fn save_document(&self, document: VersionedDocument) -> Result<VersionedDocument> {
-        Ok(self.document_manager.save_document(&document).await?)
+    pub async fn read_text(&self, path: &Path) -> Result<(String, DocumentMetadata)> {
+        self.document_manager.read_text(path).await
     }
 
-    pub async fn get_document_content(&self, path: &PathBuf) -> Result<String> {
-        Ok(self.document_manager.get_document_content(path).await?)
+    pub async fn reopen_with_encoding(
+        &self,
+        path: &Path,
+        encoding: &'static encoding_rs::Encoding,
+    ) -> Result<(String, DocumentMetadata, i32)> {
+        self.document_manager.reopen_with_encoding(path, encoding).await
     }
 
-    pub async fn get_document_state(&self, path: &PathBuf) -> Result<DocumentState> {
+    pub async fn diff_with_disk(&self, path: &Path) -> Result<Vec<DiffChange>> {
+        self.document_manager.diff_with_disk(path).await
+    }
+
+    pub async fn get_content_at_version(&self, path: &Path, version: i32) -> Result<String> {
+        self.document_manager.get_content_at_version(path, version).await
+    }
+
+    pub async fn get_document_state(&self, path: &Path) -> Result<DocumentState> {
         self.document_manager.get_document_state(path).await
     }
 
-    pub async fn invalidate_document_cache(&self, path: &PathBuf) -> Result<()> {
+    pub async fn open_paths(&self) -> Vec<PathBuf> {
+        self.document_manager.open_paths().await
+    }
+
+    pub async fn open_documents(&self) -> Vec<(PathBuf, String, i32)> {
+        self.document_manager.open_documents().await
+    }
+
+    pub async fn invalidate_document_cache(&self, path: &Path) -> Result<()> {
         self.document_manager.invalidate_cache_for_file(path).await;
         Ok(())
     }
@@ -106,12 +207,55 @@ impl FileSystem {
         self.document_manager.create_file(path, is_directory).await
     }
 
+    pub async fn save_binary_file(&self, path: &PathBuf, content: &[u8]) -> Result<u64> {
+        println!("Saving binary file: {:?} ({} bytes)", path, content.len());
+        self.document_manager.save_binary_file(path, content).await
+    }
+
     pub async fn delete_file(&self, path: &PathBuf) -> Result<()> {
         println!("Deleting file: {:?}", path);
         self.document_manager.delete_file(path).await
     }
 
-    pub async fn rename_file(&self, old_path: &PathBuf, new_path: &PathBuf) -> Result<()> {
+    pub async fn preview_delete(&self, path: &Path) -> Result<Vec<PathBuf>> {
+        self.document_manager.preview_delete(path).await
+    }
+
+    pub async fn preview_rename(&self, old_path: &Path, new_path: &Path) -> Result<Vec<PathBuf>> {
+        self.document_manager.preview_rename(old_path, new_path).await
+    }
+
+    pub fn check_rename(&self, old_path: &Path, new_path: &Path) -> Result<()> {
+        self.document_manager.check_rename(old_path, new_path)
+    }
+
+    pub async fn apply_workspace_edit(&self, edit: lsp_types::WorkspaceEdit) -> WorkspaceEditOutcome {
+        self.document_manager.apply_workspace_edit(edit).await
+    }
+
+    // Moves a directory with its subtree. Returns the open documents that moved
+    // and the files now in the directory, relative to it.
+    pub async fn move_directory(
+        &self,
+        from: &Path,
+        to: &Path,
+    ) -> Result<(Vec<(PathBuf, PathBuf)>, Vec<PathBuf>)> {
+        if !from.is_dir() {
+            anyhow::bail!("Not a directory: {:?}", from);
+        }
+        if is_within(to, from) {
+            anyhow::bail!("Can't move a directory into itself");
+        }
+        let moved_documents = self.document_manager.rename_file(from, to).await?;
+        let files = self.directory_manager.directory_moved(from, to).await?;
+        Ok((moved_documents, files))
+    }
+
+    pub async fn rename_file(
+        &self,
+        old_path: &PathBuf,
+        new_path: &PathBuf,
+    ) -> Result<Vec<(PathBuf, PathBuf)>> {
         println!("Renaming file: {:?} -> {:?}", old_path, new_path);
         self.document_manager.rename_file(old_path, new_path).await
     }
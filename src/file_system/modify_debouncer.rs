@@ -0,0 +1,171 @@
+// src/file_system/modify_debouncer.rs
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::time::Instant;
+
+use super::file_event::{FileEvent, ModificationType};
+
+// A file that is written continuously, e.g. a log, is still reported once it
+// has been held for this many windows
+const MAX_HOLD_WINDOWS: u32 = 10;
+
+// Holds back Modified events until their path has been quiet for `window`, so a
+// formatter or code generator rewriting a file several times in a row yields a
+// single event carrying the latest metadata. Other events pass straight
+// through, after any modification still held for the same path.
+pub struct ModifyDebouncer {
+    window: Duration,
+    // Latest held modification per path, when the first one it replaced
+    // arrived, and when it arrived
+    pending: HashMap<PathBuf, (FileEvent, Instant, Instant)>,
+}
+
+impl ModifyDebouncer {
+    // A zero window disables debouncing
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            pending: HashMap::new(),
+        }
+    }
+
+    // Events that can be passed on now
+    pub fn push(&mut self, mut event: FileEvent, now: Instant) -> Vec<FileEvent> {
+        if self.window.is_zero() {
+            return vec![event];
+        }
+        let paths = match &mut event {
+            FileEvent::Modified { path, modification_type, .. } => {
+                let mut first = now;
+                if let Some((held, held_first, _)) = self.pending.remove(path.as_path()) {
+                    first = held_first;
+                    // A content change stays one when only metadata changes after it
+                    if matches!(
+                        held,
+                        FileEvent::Modified { modification_type: ModificationType::Content, .. }
+                    ) {
+                        *modification_type = ModificationType::Content;
+                    }
+                }
+                let path = path.clone();
+                self.pending.insert(path, (event, first, now));
+                return Vec::new();
+            }
+            FileEvent::Created { path, .. } | FileEvent::Deleted { path, .. } => vec![path.clone()],
+            FileEvent::Renamed { from, to, .. } => vec![from.clone(), to.clone()],
+        };
+
+        let mut ready: Vec<FileEvent> = paths
+            .iter()
+            .filter_map(|path| self.pending.remove(path))
+            .map(|(held, _, _)| held)
+            .collect();
+        ready.push(event);
+        ready
+    }
+
+    // Held modifications whose path has been quiet for the window, or that
+    // have been held for the longest allowed
+    pub fn take_quiet(&mut self, now: Instant) -> Vec<FileEvent> {
+        let quiet: Vec<PathBuf> = self
+            .pending
+            .iter()
+            .filter(|(_, (_, first, last))| now >= self.deadline(*first, *last))
+            .map(|(path, _)| path.clone())
+            .collect();
+        let mut ready: Vec<(FileEvent, Instant, Instant)> = quiet
+            .iter()
+            .filter_map(|path| self.pending.remove(path))
+            .collect();
+        ready.sort_by_key(|(_, _, last)| *last);
+        ready.into_iter().map(|(event, _, _)| event).collect()
+    }
+
+    // When the next held modification is passed on
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.pending
+            .values()
+            .map(|(_, first, last)| self.deadline(*first, *last))
+            .min()
+    }
+
+    fn deadline(&self, first: Instant, last: Instant) -> Instant {
+        (last + self.window).min(first + self.window * MAX_HOLD_WINDOWS)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_system::file_event::{FileMetadata, ModificationType};
+
+    fn modified(path: &str, size: u64) -> FileEvent {
+        FileEvent::Modified {
+            path: PathBuf::from(path),
+            timestamp_ms: 0,
+            modification_type: ModificationType::Content,
+            new_metadata: FileMetadata {
+                size,
+                is_directory: false,
+                is_symlink: false,
+                created_at: None,
+                modified_at: None,
+                readonly: false,
+                mode: None,
+            },
+            content_hash: None,
+            previous_permissions: None,
+        }
+    }
+
+    fn size(event: &FileEvent) -> u64 {
+        match event {
+            FileEvent::Modified { new_metadata, .. } => new_metadata.size,
+            _ => panic!("expected a modification, got {:?}", event),
+        }
+    }
+
+    #[test]
+    fn test_modifications_are_emitted_once_quiet() {
+        let window = Duration::from_millis(200);
+        let mut debouncer = ModifyDebouncer::new(window);
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+
+        assert!(debouncer.push(modified("/ws/a.rs", 1), at(0)).is_empty());
+        assert!(debouncer.push(modified("/ws/b.rs", 1), at(50)).is_empty());
+        assert!(debouncer.push(modified("/ws/a.rs", 2), at(100)).is_empty());
+        assert_eq!(debouncer.next_deadline(), Some(at(250)));
+
+        // a.rs was touched again, so only b.rs is quiet by now
+        let quiet = debouncer.take_quiet(at(250));
+        assert_eq!(quiet.len(), 1);
+        assert!(matches!(&quiet[0], FileEvent::Modified { path, .. } if path.ends_with("b.rs")));
+
+        // Deleting a.rs releases its held modification ahead of the deletion
+        let deleted = FileEvent::Deleted {
+            path: PathBuf::from("/ws/a.rs"),
+            timestamp_ms: 3,
+        };
+        let ready = debouncer.push(deleted, at(260));
+        assert_eq!(ready.len(), 2);
+        assert_eq!(size(&ready[0]), 2);
+        assert!(matches!(ready[1], FileEvent::Deleted { .. }));
+        assert_eq!(debouncer.next_deadline(), None);
+
+        // A file written more often than the window is still reported once
+        // it has been held for the longest allowed
+        for ms in (1_000..3_000).step_by(100) {
+            assert!(debouncer.push(modified("/ws/log.txt", ms), at(ms)).is_empty());
+            assert!(debouncer.take_quiet(at(ms + 50)).is_empty());
+        }
+        assert_eq!(debouncer.next_deadline(), Some(at(1_000) + window * MAX_HOLD_WINDOWS));
+        let held = debouncer.take_quiet(at(3_000));
+        assert_eq!(held.len(), 1);
+        assert_eq!(size(&held[0]), 2_900);
+
+        let mut disabled = ModifyDebouncer::new(Duration::ZERO);
+        assert_eq!(disabled.push(modified("/ws/a.rs", 1), at(0)).len(), 1);
+    }
+}
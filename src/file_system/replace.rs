@@ -0,0 +1,138 @@
+// src/file_system/replace.rs
+use anyhow::{bail, Result};
+use globset::{Glob, GlobMatcher};
+use lsp_types::{Position, Range, TextEdit};
+use std::path::{Path, PathBuf};
+
+use crate::utils::path_utils::is_ignored_path;
+
+// Files larger than this are left alone, as in content search
+const MAX_REPLACE_FILE_SIZE: u64 = 1024 * 1024;
+
+pub enum TextMatcher {
+    Literal(String),
+    Regex(regex::Regex),
+}
+
+impl TextMatcher {
+    pub fn new(find: &str, is_regex: bool) -> Result<Self> {
+        if find.is_empty() {
+            bail!("Search text is empty");
+        }
+        Ok(if is_regex {
+            Self::Regex(regex::Regex::new(find)?)
+        } else {
+            Self::Literal(find.to_string())
+        })
+    }
+
+    // One edit per match. For regexes, `$1` / `${name}` in `replace` expand to
+    // the captured groups.
+    pub fn replacement_edits(&self, content: &str, replace: &str) -> Vec<TextEdit> {
+        let matches: Vec<(usize, usize, String)> = match self {
+            Self::Literal(find) => content
+                .match_indices(find.as_str())
+                .map(|(start, text)| (start, start + text.len(), replace.to_string()))
+                .collect(),
+            Self::Regex(regex) => regex
+                .captures_iter(content)
+                .map(|captures| {
+                    let whole = captures.get(0).expect("group 0 always matches");
+                    let mut new_text = String::new();
+                    captures.expand(replace, &mut new_text);
+                    (whole.start(), whole.end(), new_text)
+                })
+                .collect(),
+        };
+
+        let lines = LineIndex::new(content);
+        matches
+            .into_iter()
+            .map(|(start, end, new_text)| TextEdit {
+                range: Range {
+                    start: lines.position(content, start),
+                    end: lines.position(content, end),
+                },
+                new_text,
+            })
+            .collect()
+    }
+}
+
+// Byte offsets of line starts, to turn match offsets into LSP positions
+struct LineIndex {
+    starts: Vec<usize>,
+}
+
+impl LineIndex {
+    fn new(content: &str) -> Self {
+        let starts = std::iter::once(0)
+            .chain(content.match_indices('\n').map(|(index, _)| index + 1))
+            .collect();
+        Self { starts }
+    }
+
+    fn position(&self, content: &str, offset: usize) -> Position {
+        let line = self.starts.partition_point(|&start| start <= offset) - 1;
+        let character = content[self.starts[line]..offset]
+            .chars()
+            .map(|ch| ch.len_utf16() as u32)
+            .sum();
+        Position::new(line as u32, character)
+    }
+}
+
+pub fn scope_matcher(scope: Option<&str>) -> Result<Option<GlobMatcher>> {
+    scope
+        .map(|pattern| Ok(Glob::new(pattern)?.compile_matcher()))
+        .transpose()
+}
+
+// Regular files in the workspace that a replace may touch, skipping the same
+// directories as search and anything too large to be source
+pub async fn replace_candidates(workspace: &Path, scope: Option<GlobMatcher>) -> Result<Vec<PathBuf>> {
+    let workspace = workspace.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        let mut files = Vec::new();
+        for entry in walkdir::WalkDir::new(&workspace)
+            .into_iter()
+            .filter_entry(|e| !is_ignored_path(e.path(), &workspace))
+        {
+            let entry = entry?;
+            if !entry.file_type().is_file() || entry.metadata()?.len() > MAX_REPLACE_FILE_SIZE {
+                continue;
+            }
+            let relative = entry.path().strip_prefix(&workspace).unwrap_or(entry.path());
+            if scope.as_ref().is_some_and(|scope| !scope.is_match(relative)) {
+                continue;
+            }
+            files.push(entry.into_path());
+        }
+        Ok(files)
+    })
+    .await?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replacement_edits_use_utf16_positions_and_captures() -> Result<()> {
+        let content = "let é = foo(1);\nfoo(22);\n";
+        let matcher = TextMatcher::new(r"foo\((\d+)\)", true)?;
+        let edits = matcher.replacement_edits(content, "bar($1)");
+
+        assert_eq!(edits.len(), 2);
+        assert_eq!(edits[0].range, Range::new(Position::new(0, 8), Position::new(0, 14)));
+        assert_eq!(edits[0].new_text, "bar(1)");
+        assert_eq!(edits[1].range, Range::new(Position::new(1, 0), Position::new(1, 7)));
+        assert_eq!(edits[1].new_text, "bar(22)");
+
+        // Literal search treats regex syntax as plain text
+        let literal = TextMatcher::new("(1)", false)?;
+        assert_eq!(literal.replacement_edits(content, "$1").len(), 1);
+        assert_eq!(literal.replacement_edits(content, "$1")[0].new_text, "$1");
+        Ok(())
+    }
+}
@@ -0,0 +1,234 @@
+// src/file_system/tree_updates.rs
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use super::directory_manager::{is_hidden, FileNode};
+use super::file_event::{FileEvent, FileMetadata, ModificationType};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TreeUpdate {
+    Added { parent: PathBuf, node: FileNode },
+    Removed { path: PathBuf },
+    Renamed { from: PathBuf, to: PathBuf },
+}
+
+// Directories one client has listed, so file events can be turned into
+// updates of the tree it is showing
+#[derive(Debug, Default)]
+pub struct LoadedTree {
+    directories: HashSet<PathBuf>,
+    // The client last listed without hidden entries, so updates leave them out too
+    hide_hidden: bool,
+}
+
+impl LoadedTree {
+    pub fn mark_loaded(&mut self, directory: PathBuf) {
+        self.directories.insert(directory);
+    }
+
+    pub fn set_show_hidden(&mut self, show_hidden: bool) {
+        self.hide_hidden = !show_hidden;
+    }
+
+    pub fn show_hidden(&self) -> bool {
+        !self.hide_hidden
+    }
+
+    // The loaded parent of a path the client is shown
+    fn parent_loaded(&self, path: &Path) -> Option<PathBuf> {
+        if self.hide_hidden && is_hidden(path.file_name().unwrap_or_default(), path) {
+            return None;
+        }
+        path.parent()
+            .filter(|parent| self.directories.contains(*parent))
+            .map(Path::to_path_buf)
+    }
+
+    fn forget(&mut self, path: &Path) {
+        self.directories.retain(|directory| !directory.starts_with(path));
+    }
+
+    // Translates one batch of events. The Deleted and Modified(Name) halves of
+    // a rename that is also reported as Renamed in the batch are folded into it.
+    pub fn apply(&mut self, events: &[FileEvent]) -> Vec<TreeUpdate> {
+        let renamed: HashSet<&Path> = events
+            .iter()
+            .filter_map(|event| match event {
+                FileEvent::Renamed { from, to, .. } => Some([from.as_path(), to.as_path()]),
+                _ => None,
+            })
+            .flatten()
+            .collect();
+
+        let mut updates = Vec::new();
+        for event in events {
+            match event {
+                FileEvent::Created { path, metadata, .. }
+                | FileEvent::Modified {
+                    path,
+                    modification_type: ModificationType::Name,
+                    new_metadata: metadata,
+                    ..
+                } => {
+                    if renamed.contains(path.as_path()) {
+                        continue;
+                    }
+                    if let Some(parent) = self.parent_loaded(path) {
+                        updates.push(TreeUpdate::Added {
+                            parent,
+                            node: file_node(path, metadata),
+                        });
+                    }
+                }
+                FileEvent::Modified { .. } => {}
+                FileEvent::Deleted { path, .. } => {
+                    if renamed.contains(path.as_path()) {
+                        continue;
+                    }
+                    if self.parent_loaded(path).is_some() {
+                        updates.push(TreeUpdate::Removed { path: path.clone() });
+                    }
+                    self.forget(path);
+                }
+                FileEvent::Renamed { from, to, metadata, .. } => {
+                    match (self.parent_loaded(from), self.parent_loaded(to)) {
+                        (Some(_), Some(_)) => updates.push(TreeUpdate::Renamed {
+                            from: from.clone(),
+                            to: to.clone(),
+                        }),
+                        (Some(_), None) => updates.push(TreeUpdate::Removed { path: from.clone() }),
+                        (None, Some(parent)) => updates.push(TreeUpdate::Added {
+                            parent,
+                            node: file_node(to, metadata),
+                        }),
+                        (None, None) => {}
+                    }
+
+                    // Loaded directories below a moved directory move along with it
+                    let moved: Vec<PathBuf> = self
+                        .directories
+                        .iter()
+                        .filter_map(|directory| directory.strip_prefix(from).ok())
+                        .map(|rest| to.join(rest))
+                        .collect();
+                    self.forget(from);
+                    self.directories.extend(moved);
+                }
+            }
+        }
+        updates
+    }
+}
+
+fn file_node(path: &Path, metadata: &FileMetadata) -> FileNode {
+    FileNode {
+        name: path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned(),
+        path: path.to_path_buf(),
+        is_directory: metadata.is_directory,
+        size: metadata.size,
+        children: None,
+        is_loaded: false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metadata(is_directory: bool) -> FileMetadata {
+        FileMetadata {
+            size: 0,
+            is_directory,
+            is_symlink: false,
+            created_at: None,
+            modified_at: None,
+            readonly: false,
+            mode: None,
+        }
+    }
+
+    #[test]
+    fn test_rename_halves_fold_into_one_update() {
+        let mut tree = LoadedTree::default();
+        tree.mark_loaded(PathBuf::from("/ws"));
+        tree.mark_loaded(PathBuf::from("/ws/old/nested"));
+
+        let events = vec![
+            FileEvent::Deleted {
+                path: PathBuf::from("/ws/old"),
+                timestamp_ms: 0,
+            },
+            FileEvent::Modified {
+                path: PathBuf::from("/ws/new"),
+                timestamp_ms: 0,
+                modification_type: ModificationType::Name,
+                new_metadata: metadata(true),
+                content_hash: None,
+                previous_permissions: None,
+            },
+            FileEvent::Renamed {
+                from: PathBuf::from("/ws/old"),
+                to: PathBuf::from("/ws/new"),
+                timestamp_ms: 0,
+                metadata: metadata(true),
+            },
+            // Not loaded by the client
+            FileEvent::Created {
+                path: PathBuf::from("/ws/unloaded/file.txt"),
+                timestamp_ms: 0,
+                metadata: metadata(false),
+            },
+        ];
+
+        assert_eq!(
+            tree.apply(&events),
+            vec![TreeUpdate::Renamed {
+                from: PathBuf::from("/ws/old"),
+                to: PathBuf::from("/ws/new"),
+            }]
+        );
+
+        // The loaded subdirectory moved with its parent
+        let created = FileEvent::Created {
+            path: PathBuf::from("/ws/new/nested/file.txt"),
+            timestamp_ms: 0,
+            metadata: metadata(false),
+        };
+        assert!(matches!(
+            tree.apply(&[created]).as_slice(),
+            [TreeUpdate::Added { parent, .. }] if parent == Path::new("/ws/new/nested")
+        ));
+    }
+
+    #[test]
+    fn test_hidden_entries_stay_out_when_hidden() {
+        let mut tree = LoadedTree::default();
+        tree.mark_loaded(PathBuf::from("/ws"));
+        tree.set_show_hidden(false);
+
+        let events = vec![
+            FileEvent::Created {
+                path: PathBuf::from("/ws/.env"),
+                timestamp_ms: 0,
+                metadata: metadata(false),
+            },
+            FileEvent::Renamed {
+                from: PathBuf::from("/ws/.env.example"),
+                to: PathBuf::from("/ws/env.example"),
+                timestamp_ms: 0,
+                metadata: metadata(false),
+            },
+        ];
+        assert!(matches!(
+            tree.apply(&events).as_slice(),
+            [TreeUpdate::Added { node, .. }] if node.name == "env.example"
+        ));
+
+        tree.set_show_hidden(true);
+        assert_eq!(tree.apply(&events[..1]).len(), 1);
+    }
+}
@@ -1,16 +1,41 @@
 use tokio::sync::{broadcast, mpsc, RwLock};
+use std::path::Path;
 use std::sync::Arc;
 use std::time::Duration;
+use tokio::time::Instant;
 use anyhow::Result;
-use notify::{Watcher, RecursiveMode, Event};
+use notify::{ErrorKind, Event, PollWatcher, RecursiveMode, Watcher};
 
 use crate::file_system::event_batcher::EventBatcher;
-use crate::file_system::file_event::FileEvent;
+use crate::file_system::file_event::{FileEvent, PermissionHistory};
 use super::directory_manager::DirectoryManager;
 use super::event_batcher::spawn_timeout_checker;
+use super::modify_debouncer::ModifyDebouncer;
+
+// Watching is retried with exponential backoff after a failure
+const INITIAL_RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+// How often a healthy watcher checks that the workspace root still exists
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum WatcherStatus {
+    // Events may be missing until Restored is sent
+    Error { message: String },
+    Restored,
+    // Native watching hit an OS limit, changes are now found by polling
+    Degraded { reason: String },
+}
+
+enum WatchFailure {
+    // The OS refused more watches, e.g. inotify's max_user_watches
+    LimitReached(String),
+    Other(String),
+}
 
 pub struct WatcherManager {
     event_sender: broadcast::Sender<FileEvent>,
+    status_sender: broadcast::Sender<WatcherStatus>,
     event_batcher: Arc<RwLock<EventBatcher>>,
     directory_manager: Arc<DirectoryManager>,
 }
@@ -43,14 +68,20 @@ impl WatcherManager {
         // Spawn the timeout checker
         spawn_timeout_checker(Arc::clone(&event_batcher));
 
+        let (status_sender, _) = broadcast::channel(16);
+
         Self {
             event_sender: event_tx,
+            status_sender,
             event_batcher,
             directory_manager,
         }
     }
 
-    pub async fn start_watching(&self) -> Result<()> {
+    // `poll_interval` is used if native watching runs into the OS watch limit.
+    // Modified events are passed on once their file has been quiet for
+    // `modify_debounce`.
+    pub async fn start_watching(&self, poll_interval: Duration, modify_debounce: Duration) -> Result<()> {
         let workspace_path = self.directory_manager.get_workspace_path().clone();
         let (tx, mut rx) = mpsc::channel(100);
         
@@ -58,35 +89,58 @@ impl WatcherManager {
         let directory_manager = Arc::clone(&self.directory_manager);
         let event_batcher = Arc::clone(&self.event_batcher);
         
+        let status_sender = self.status_sender.clone();
         std::thread::spawn(move || {
-            let tx = tx.clone();
-            let mut watcher = notify::recommended_watcher(move |res: Result<Event, notify::Error>| {
-                if let Ok(event) = res {
-                    println!("Watcher sending event to channel: {:?}", event);
-                    let _ = tx.blocking_send(event);
-                }
-            }).unwrap();
-
-            watcher.watch(&workspace_path, RecursiveMode::Recursive).unwrap();
-            std::thread::park();
+            watch_with_retry(&workspace_path, tx, status_sender, poll_interval)
         });
         
         tokio::spawn(async move {
-            while let Some(event) = rx.recv().await {
-                println!("Received event in processor: {:?}", event);
-                if let Some(file_event) = FileEvent::from_notify_event(event).await {
-                    // Get the parent directory path for cache invalidation
-                    let parent = match &file_event {
+            let mut permissions = PermissionHistory::default();
+            let mut debouncer = ModifyDebouncer::new(modify_debounce);
+            loop {
+                let deadline = debouncer.next_deadline();
+                let received = tokio::select! {
+                    event = rx.recv() => match event {
+                        Some(event) => Some(event),
+                        None => break,
+                    },
+                    _ = tokio::time::sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => None,
+                };
+
+                let ready = match received {
+                    Some(event) => {
+                        println!("Received event in processor: {:?}", event);
+                        // The OS watcher always follows links, events that only happened
+                        // behind one the policy doesn't follow are dropped
+                        let symlinks = directory_manager.symlink_policy();
+                        let workspace = directory_manager.get_workspace_path();
+                        if !event.paths.iter().any(|path| symlinks.reaches(path, workspace)) {
+                            continue;
+                        }
+                        let Some(file_event) = FileEvent::from_notify_event(event).await else {
+                            continue;
+                        };
+                        debouncer.push(file_event, Instant::now())
+                    }
+                    None => debouncer.take_quiet(Instant::now()),
+                };
+
+                // Only what survives debouncing is compared and hashed
+                for mut file_event in ready {
+                    permissions.track(&mut file_event);
+                    file_event.hash_content().await;
+
+                    // Get the parent directory paths for cache invalidation
+                    let paths = match &file_event {
                         FileEvent::Created { path, .. } |
                         FileEvent::Modified { path, .. } |
-                        FileEvent::Deleted { path, .. } => {
-                            path.parent().map(|p| p.to_path_buf())
-                        }
+                        FileEvent::Deleted { path, .. } => vec![path],
+                        FileEvent::Renamed { from, to, .. } => vec![from, to],
                     };
 
-                    if let Some(parent) = parent {
+                    for parent in paths.into_iter().filter_map(|path| path.parent()) {
                         println!("Invalidating cache for parent: {:?}", parent);
-                        directory_manager.invalidate_cache(&parent).await;
+                        directory_manager.invalidate_cache(&parent.to_path_buf()).await;
                     }
                     
                     println!("Sending event to batcher: {:?}", file_event);
@@ -101,4 +155,169 @@ impl WatcherManager {
     pub fn subscribe(&self) -> broadcast::Receiver<FileEvent> {
         self.event_sender.subscribe()
     }
-}
\ No newline at end of file
+
+    pub fn subscribe_status(&self) -> broadcast::Receiver<WatcherStatus> {
+        self.status_sender.subscribe()
+    }
+}
+
+// Runs on a dedicated thread for the life of the process. Whenever the watcher
+// can't be created, reports an error, or the workspace root disappears, the
+// failure is broadcast and watching is set up again after a backoff. Running
+// into the OS watch limit switches to polling for good.
+fn watch_with_retry(
+    workspace_path: &Path,
+    tx: mpsc::Sender<Event>,
+    status_sender: broadcast::Sender<WatcherStatus>,
+    poll_interval: Duration,
+) {
+    let mut retry_delay = INITIAL_RETRY_DELAY;
+    let mut failed = false;
+    let mut polling = None;
+    loop {
+        let failure = watch_until_failure(workspace_path, tx.clone(), polling, || {
+            if std::mem::take(&mut failed) {
+                println!("File watcher restored for {:?}", workspace_path);
+                let _ = status_sender.send(WatcherStatus::Restored);
+            }
+            retry_delay = INITIAL_RETRY_DELAY;
+        });
+
+        let error = match failure {
+            // The event processor is gone, nobody is listening anymore
+            None => return,
+            Some(WatchFailure::LimitReached(reason)) if polling.is_none() => {
+                eprintln!("{}, falling back to polling every {:?}", reason, poll_interval);
+                let _ = status_sender.send(WatcherStatus::Degraded { reason });
+                polling = Some(poll_interval);
+                continue;
+            }
+            Some(WatchFailure::LimitReached(error) | WatchFailure::Other(error)) => error,
+        };
+
+        eprintln!("File watcher failed, retrying in {:?}: {}", retry_delay, error);
+        let _ = status_sender.send(WatcherStatus::Error { message: error });
+        failed = true;
+        std::thread::sleep(retry_delay);
+        retry_delay = (retry_delay * 2).min(MAX_RETRY_DELAY);
+    }
+}
+
+fn failure(context: &str, error: notify::Error) -> WatchFailure {
+    let message = format!("{}: {}", context, error);
+    match error.kind {
+        ErrorKind::MaxFilesWatch => WatchFailure::LimitReached(message),
+        _ => WatchFailure::Other(message),
+    }
+}
+
+// Watches until something goes wrong and returns what it was, or None once
+// events can no longer be delivered. With `polling` set, a PollWatcher scanning
+// at that interval is used instead of the native one. `on_started` runs once
+// watching works.
+fn watch_until_failure(
+    workspace_path: &Path,
+    tx: mpsc::Sender<Event>,
+    polling: Option<Duration>,
+    on_started: impl FnOnce(),
+) -> Option<WatchFailure> {
+    let (error_tx, error_rx) = std::sync::mpsc::channel();
+    let events = tx.clone();
+    let handler = move |res: Result<Event, notify::Error>| match res {
+        Ok(event) => {
+            println!("Watcher sending event to channel: {:?}", event);
+            let _ = tx.blocking_send(event);
+        }
+        Err(e) => {
+            let _ = error_tx.send(e);
+        }
+    };
+    let watcher: notify::Result<Box<dyn Watcher>> = match polling {
+        Some(interval) => PollWatcher::new(
+            handler,
+            notify::Config::default().with_poll_interval(interval),
+        )
+        .map(|watcher| Box::new(watcher) as Box<dyn Watcher>),
+        None => notify::recommended_watcher(handler)
+            .map(|watcher| Box::new(watcher) as Box<dyn Watcher>),
+    };
+    let mut watcher = match watcher {
+        Ok(watcher) => watcher,
+        Err(e) => return Some(failure("Failed to create file watcher", e)),
+    };
+
+    if let Err(e) = watcher.watch(workspace_path, RecursiveMode::Recursive) {
+        return Some(failure(&format!("Failed to watch {:?}", workspace_path), e));
+    }
+    on_started();
+
+    loop {
+        match error_rx.recv_timeout(HEALTH_CHECK_INTERVAL) {
+            Ok(error) => return Some(failure("File watcher error", error)),
+            Err(std::sync::mpsc::RecvTimeoutError::Timeout) => {
+                if events.is_closed() {
+                    return None;
+                }
+                if !workspace_path.exists() {
+                    return Some(WatchFailure::Other(format!(
+                        "Workspace {:?} is no longer available",
+                        workspace_path
+                    )));
+                }
+            }
+            Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => return None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_watcher_retries_until_workspace_exists() -> Result<()> {
+        let root = tempfile::tempdir()?;
+        let workspace_path = root.path().join("workspace");
+        let (tx, _rx) = mpsc::channel(16);
+        let (status_sender, mut status) = broadcast::channel(16);
+
+        let path = workspace_path.clone();
+        std::thread::spawn(move || {
+            watch_with_retry(&path, tx, status_sender, Duration::from_millis(100))
+        });
+
+        let first = tokio::time::timeout(Duration::from_secs(5), status.recv()).await??;
+        assert!(matches!(first, WatcherStatus::Error { .. }));
+
+        std::fs::create_dir(&workspace_path)?;
+        let restored = tokio::time::timeout(Duration::from_secs(5), status.recv()).await??;
+        assert_eq!(restored, WatcherStatus::Restored);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_polling_fallback_reports_changes() -> Result<()> {
+        let workspace = tempfile::tempdir()?;
+        let (tx, mut rx) = mpsc::channel(16);
+
+        let path = workspace.path().to_path_buf();
+        std::thread::spawn(move || {
+            watch_until_failure(&path, tx, Some(Duration::from_millis(50)), || {})
+        });
+        // Give the poller its initial scan before changing anything
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        std::fs::write(workspace.path().join("new.txt"), "new")?;
+
+        // The directory's own modification may be reported first
+        tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let event = rx.recv().await.expect("watcher stopped");
+                if event.paths.iter().any(|p| p.ends_with("new.txt")) {
+                    break;
+                }
+            }
+        })
+        .await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,99 @@
+// src/file_system/workspace_detector.rs
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+use super::directory_manager::IndexState;
+
+// Kinds of project recognized by a marker file or directory in the workspace root
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum ProjectType {
+    Cargo,
+    Npm,
+    Go,
+    Python,
+    Git,
+}
+
+impl ProjectType {
+    // Any of these in the root marks the project type
+    fn markers(&self) -> &'static [&'static str] {
+        match self {
+            ProjectType::Cargo => &["Cargo.toml"],
+            ProjectType::Npm => &["package.json"],
+            ProjectType::Go => &["go.mod"],
+            ProjectType::Python => &["pyproject.toml", "setup.py", "requirements.txt"],
+            // A directory for repositories, a file for worktrees and submodules
+            ProjectType::Git => &[".git"],
+        }
+    }
+}
+
+const PROJECT_TYPES: [ProjectType; 5] = [
+    ProjectType::Cargo,
+    ProjectType::Npm,
+    ProjectType::Go,
+    ProjectType::Python,
+    ProjectType::Git,
+];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WorkspaceInfo {
+    pub root: PathBuf,
+    pub project_types: Vec<ProjectType>,
+    pub index_state: IndexState,
+}
+
+// Tells what kind of project the workspace is from the files in its root. Only
+// reads, and looks again on every call since marker files can come and go.
+pub struct WorkspaceDetector {
+    root: PathBuf,
+}
+
+impl WorkspaceDetector {
+    pub fn new(root: PathBuf) -> Self {
+        Self { root }
+    }
+
+    // Project types whose markers are in the root
+    pub async fn detect(&self) -> Vec<ProjectType> {
+        let mut project_types = Vec::new();
+        for project_type in PROJECT_TYPES {
+            for marker in project_type.markers() {
+                if exists(&self.root.join(marker)).await {
+                    project_types.push(project_type);
+                    break;
+                }
+            }
+        }
+        project_types
+    }
+}
+
+async fn exists(path: &Path) -> bool {
+    tokio::fs::try_exists(path).await.unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_project_types_are_detected_from_markers() -> anyhow::Result<()> {
+        let workspace = tempfile::tempdir()?;
+        let detector = WorkspaceDetector::new(workspace.path().to_path_buf());
+        assert!(detector.detect().await.is_empty());
+
+        std::fs::write(workspace.path().join("Cargo.toml"), "[package]\n")?;
+        std::fs::write(workspace.path().join("requirements.txt"), "")?;
+        std::fs::create_dir(workspace.path().join(".git"))?;
+        // Markers only count in the root
+        std::fs::create_dir(workspace.path().join("web"))?;
+        std::fs::write(workspace.path().join("web/package.json"), "{}")?;
+
+        assert_eq!(
+            detector.detect().await,
+            vec![ProjectType::Cargo, ProjectType::Python, ProjectType::Git]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,397 @@
+// src/git/git_manager.rs
+use anyhow::Result;
+use git2::{DiffOptions, ErrorCode, Oid, Patch, Repository, Status, StatusOptions};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{broadcast, RwLock};
+
+use crate::file_system::FileEvent;
+use crate::utils::path_utils::is_within;
+use crate::git::{
+    BlameLine, GitDiffBase, GitDiffHunk, GitDiffLine, GitFileStatus, GitLineKind, GitMessage,
+};
+
+// File events tend to come in bursts (saves, checkouts, builds), so status is
+// only recomputed once things have been quiet for this long
+const STATUS_DEBOUNCE: Duration = Duration::from_millis(300);
+
+pub struct GitManager {
+    workspace_path: PathBuf,
+    event_sender: broadcast::Sender<GitMessage>,
+    last_status: RwLock<Option<Vec<GitFileStatus>>>,
+}
+
+impl GitManager {
+    pub fn new(workspace_path: PathBuf) -> Arc<Self> {
+        let (event_sender, _) = broadcast::channel(16);
+        Arc::new(Self {
+            workspace_path,
+            event_sender,
+            last_status: RwLock::new(None),
+        })
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<GitMessage> {
+        self.event_sender.subscribe()
+    }
+
+    // Status of every changed path in the workspace, empty when the workspace
+    // is not inside a git repository
+    pub async fn status(&self) -> Result<Vec<GitFileStatus>> {
+        let workspace_path = self.workspace_path.clone();
+        tokio::task::spawn_blocking(move || match open_repository(&workspace_path) {
+            Some(repo) => read_status(&repo, &workspace_path),
+            None => Ok(Vec::new()),
+        })
+        .await?
+    }
+
+    // Last status seen by the watcher, computed now if there is none yet
+    pub async fn current_status(&self) -> Result<Vec<GitFileStatus>> {
+        if let Some(files) = self.last_status.read().await.as_ref() {
+            return Ok(files.clone());
+        }
+        self.status().await
+    }
+
+    // Blames `content`, the current (possibly unsaved) text of the file at `path`
+    pub async fn blame(&self, path: PathBuf, content: String) -> Result<Vec<BlameLine>> {
+        let workspace_path = self.workspace_path.clone();
+        tokio::task::spawn_blocking(move || {
+            let repo = open_repository(&workspace_path)
+                .ok_or_else(|| anyhow::anyhow!("Workspace is not a git repository"))?;
+            read_blame(&repo, &path, &content)
+        })
+        .await?
+    }
+
+    // Diff of the file on disk against HEAD or the index
+    pub async fn diff(&self, path: PathBuf, base: GitDiffBase) -> Result<Vec<GitDiffHunk>> {
+        let workspace_path = self.workspace_path.clone();
+        tokio::task::spawn_blocking(move || {
+            let repo = open_repository(&workspace_path)
+                .ok_or_else(|| anyhow::anyhow!("Workspace is not a git repository"))?;
+            read_diff(&repo, &path, base)
+        })
+        .await?
+    }
+
+    // Recomputes the status whenever files change and broadcasts it if it differs
+    pub fn watch(self: &Arc<Self>, mut file_events: broadcast::Receiver<FileEvent>) {
+        let manager = Arc::clone(self);
+        tokio::spawn(async move {
+            loop {
+                match file_events.recv().await {
+                    Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+
+                tokio::time::sleep(STATUS_DEBOUNCE).await;
+                while !matches!(
+                    file_events.try_recv(),
+                    Err(broadcast::error::TryRecvError::Empty | broadcast::error::TryRecvError::Closed)
+                ) {}
+
+                if let Err(e) = manager.refresh().await {
+                    eprintln!("Failed to refresh git status: {}", e);
+                }
+            }
+        });
+    }
+
+    async fn refresh(&self) -> Result<()> {
+        let files = self.status().await?;
+
+        let mut last_status = self.last_status.write().await;
+        if last_status.as_ref() == Some(&files) {
+            return Ok(());
+        }
+        *last_status = Some(files.clone());
+        let _ = self.event_sender.send(GitMessage::StatusChanged { files });
+        Ok(())
+    }
+}
+
+fn open_repository(workspace_path: &Path) -> Option<Repository> {
+    Repository::discover(workspace_path).ok()
+}
+
+fn read_status(repo: &Repository, workspace_path: &Path) -> Result<Vec<GitFileStatus>> {
+    // Bare repositories have nothing to decorate
+    let Some(workdir) = repo.workdir() else {
+        return Ok(Vec::new());
+    };
+
+    let mut options = StatusOptions::new();
+    options
+        .include_untracked(true)
+        .recurse_untracked_dirs(true)
+        .exclude_submodules(true);
+
+    let mut files = Vec::new();
+    for entry in repo.statuses(Some(&mut options))?.iter() {
+        let Some(relative) = entry.path() else {
+            continue;
+        };
+        // The workspace may be a subdirectory of the repository
+        let path = workdir.join(relative);
+        if !is_within(&path, workspace_path) {
+            continue;
+        }
+
+        let status = entry.status();
+        files.push(GitFileStatus {
+            path,
+            staged: status.intersects(
+                Status::INDEX_NEW
+                    | Status::INDEX_MODIFIED
+                    | Status::INDEX_DELETED
+                    | Status::INDEX_RENAMED
+                    | Status::INDEX_TYPECHANGE,
+            ),
+            modified: status
+                .intersects(Status::WT_MODIFIED | Status::WT_RENAMED | Status::WT_TYPECHANGE),
+            untracked: status.contains(Status::WT_NEW),
+            deleted: status.intersects(Status::WT_DELETED | Status::INDEX_DELETED),
+            conflicted: status.contains(Status::CONFLICTED),
+        });
+    }
+    Ok(files)
+}
+
+fn read_blame(repo: &Repository, path: &Path, content: &str) -> Result<Vec<BlameLine>> {
+    let workdir = repo
+        .workdir()
+        .ok_or_else(|| anyhow::anyhow!("Repository has no working directory"))?;
+    let relative = path.strip_prefix(workdir)?;
+    let line_count = content.lines().count();
+
+    let local_line = |line: usize| BlameLine {
+        line: line as u32,
+        local: true,
+        commit: None,
+        author: None,
+        timestamp: None,
+        summary: None,
+    };
+
+    let blame = match repo.blame_file(relative, None) {
+        Ok(blame) => blame,
+        // Untracked file or no commits yet, everything is local
+        Err(e) if matches!(e.code(), ErrorCode::NotFound | ErrorCode::UnbornBranch) => {
+            return Ok((0..line_count).map(local_line).collect());
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let blame = blame.blame_buffer(content.as_bytes())?;
+
+    let mut summaries: HashMap<Oid, Option<String>> = HashMap::new();
+    let mut lines = Vec::with_capacity(line_count);
+    for line in 0..line_count {
+        let Some(hunk) = blame.get_line(line + 1) else {
+            lines.push(local_line(line));
+            continue;
+        };
+        let commit_id = hunk.final_commit_id();
+        if commit_id.is_zero() {
+            lines.push(local_line(line));
+            continue;
+        }
+
+        let summary = summaries
+            .entry(commit_id)
+            .or_insert_with(|| {
+                repo.find_commit(commit_id)
+                    .ok()
+                    .and_then(|commit| commit.summary().map(str::to_string))
+            })
+            .clone();
+        let signature = hunk.final_signature();
+        lines.push(BlameLine {
+            line: line as u32,
+            local: false,
+            commit: Some(commit_id.to_string()),
+            author: signature.name().map(str::to_string),
+            timestamp: Some(signature.when().seconds()),
+            summary,
+        });
+    }
+    Ok(lines)
+}
+
+fn read_diff(repo: &Repository, path: &Path, base: GitDiffBase) -> Result<Vec<GitDiffHunk>> {
+    let workdir = repo
+        .workdir()
+        .ok_or_else(|| anyhow::anyhow!("Repository has no working directory"))?;
+    let relative = path.strip_prefix(workdir)?;
+
+    let mut options = DiffOptions::new();
+    options
+        .pathspec(relative)
+        .disable_pathspec_match(true)
+        .include_untracked(true)
+        .show_untracked_content(true);
+
+    let diff = match base {
+        GitDiffBase::Head => {
+            // Without commits everything is compared against an empty tree
+            let head = match repo.head() {
+                Ok(head) => Some(head.peel_to_tree()?),
+                Err(e) if e.code() == ErrorCode::UnbornBranch => None,
+                Err(e) => return Err(e.into()),
+            };
+            repo.diff_tree_to_workdir_with_index(head.as_ref(), Some(&mut options))?
+        }
+        GitDiffBase::Index => repo.diff_index_to_workdir(None, Some(&mut options))?,
+    };
+
+    let mut hunks = Vec::new();
+    for delta_index in 0..diff.deltas().len() {
+        // Binary files have no patch
+        let Some(patch) = Patch::from_diff(&diff, delta_index)? else {
+            continue;
+        };
+        for hunk_index in 0..patch.num_hunks() {
+            let (hunk, line_count) = patch.hunk(hunk_index)?;
+            let mut lines = Vec::with_capacity(line_count);
+            for line_index in 0..line_count {
+                let line = patch.line_in_hunk(hunk_index, line_index)?;
+                let kind = match line.origin() {
+                    '+' => GitLineKind::Added,
+                    '-' => GitLineKind::Removed,
+                    ' ' => GitLineKind::Context,
+                    // "No newline at end of file" markers
+                    _ => continue,
+                };
+                lines.push(GitDiffLine {
+                    kind,
+                    content: String::from_utf8_lossy(line.content()).into_owned(),
+                    old_line: line.old_lineno(),
+                    new_line: line.new_lineno(),
+                });
+            }
+            hunks.push(GitDiffHunk {
+                old_start: hunk.old_start(),
+                old_lines: hunk.old_lines(),
+                new_start: hunk.new_start(),
+                new_lines: hunk.new_lines(),
+                lines,
+            });
+        }
+    }
+    Ok(hunks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_status_reports_staged_modified_and_untracked() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let root = dir.path().canonicalize()?;
+        let repo = Repository::init(&root)?;
+
+        std::fs::write(root.join("tracked.txt"), "one\n")?;
+        std::fs::write(root.join("staged.txt"), "new\n")?;
+        let mut index = repo.index()?;
+        index.add_path(Path::new("tracked.txt"))?;
+        let tree = repo.find_tree(index.write_tree()?)?;
+        let signature = git2::Signature::now("Test", "test@example.com")?;
+        repo.commit(Some("HEAD"), &signature, &signature, "initial", &tree, &[])?;
+
+        index.add_path(Path::new("staged.txt"))?;
+        index.write()?;
+        std::fs::write(root.join("tracked.txt"), "two\n")?;
+        std::fs::write(root.join("untracked.txt"), "?\n")?;
+
+        let mut files = read_status(&repo, &root)?;
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+        let flags: Vec<_> = files
+            .iter()
+            .map(|f| (f.path.file_name().unwrap().to_str().unwrap(), f.staged, f.modified, f.untracked))
+            .collect();
+        assert_eq!(
+            flags,
+            vec![
+                ("staged.txt", true, false, false),
+                ("tracked.txt", false, true, false),
+                ("untracked.txt", false, false, true),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_blame_marks_unsaved_lines_as_local() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let root = dir.path().canonicalize()?;
+        let repo = Repository::init(&root)?;
+
+        std::fs::write(root.join("file.txt"), "one\ntwo\n")?;
+        let mut index = repo.index()?;
+        index.add_path(Path::new("file.txt"))?;
+        let tree = repo.find_tree(index.write_tree()?)?;
+        let signature = git2::Signature::now("Test", "test@example.com")?;
+        repo.commit(Some("HEAD"), &signature, &signature, "initial", &tree, &[])?;
+
+        let lines = read_blame(&repo, &root.join("file.txt"), "one\nchanged\nthree\n")?;
+        let local: Vec<_> = lines.iter().map(|l| l.local).collect();
+        assert_eq!(local, vec![false, true, true]);
+        assert_eq!(lines[0].author.as_deref(), Some("Test"));
+        assert_eq!(lines[0].summary.as_deref(), Some("initial"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_diff_hunks_against_head_and_index() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let root = dir.path().canonicalize()?;
+        let repo = Repository::init(&root)?;
+        let path = root.join("file.txt");
+
+        let mut lines: Vec<String> = (1..=20).map(|n| format!("line {}", n)).collect();
+        std::fs::write(&path, lines.join("\n") + "\n")?;
+        let mut index = repo.index()?;
+        index.add_path(Path::new("file.txt"))?;
+        let tree = repo.find_tree(index.write_tree()?)?;
+        let signature = git2::Signature::now("Test", "test@example.com")?;
+        repo.commit(Some("HEAD"), &signature, &signature, "initial", &tree, &[])?;
+
+        // A staged modification, then unstaged removal and addition further down
+        lines[1] = "line two".to_string();
+        std::fs::write(&path, lines.join("\n") + "\n")?;
+        index.add_path(Path::new("file.txt"))?;
+        index.write()?;
+        lines.remove(9);
+        lines.insert(17, "added".to_string());
+        std::fs::write(&path, lines.join("\n") + "\n")?;
+
+        let changes = |base| -> Result<Vec<(Vec<String>, Vec<String>)>> {
+            Ok(read_diff(&repo, &path, base)?
+                .into_iter()
+                .map(|hunk| {
+                    let content = |kind| {
+                        hunk.lines
+                            .iter()
+                            .filter(|line| line.kind == kind)
+                            .map(|line| line.content.trim_end().to_string())
+                            .collect::<Vec<_>>()
+                    };
+                    (content(GitLineKind::Removed), content(GitLineKind::Added))
+                })
+                .collect())
+        };
+        let modified = (vec!["line 2".to_string()], vec!["line two".to_string()]);
+        let removed = (vec!["line 10".to_string()], vec![]);
+        let added = (vec![], vec!["added".to_string()]);
+        assert_eq!(changes(GitDiffBase::Head)?, vec![modified, removed.clone(), added.clone()]);
+        assert_eq!(changes(GitDiffBase::Index)?, vec![removed, added]);
+
+        let hunks = read_diff(&repo, &path, GitDiffBase::Index)?;
+        assert_eq!((hunks[0].old_start, hunks[0].old_lines, hunks[0].new_lines), (7, 7, 6));
+        Ok(())
+    }
+}
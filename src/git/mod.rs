@@ -0,0 +1,5 @@
+mod types;
+mod git_manager;
+
+pub use types::*;
+pub use git_manager::GitManager;
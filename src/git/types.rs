@@ -0,0 +1,70 @@
+use serde::{Serialize, Deserialize};
+use std::path::PathBuf;
+
+// Version control state of a single changed path; clean files are not listed
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GitFileStatus {
+    pub path: PathBuf,
+    // Changes recorded in the index
+    pub staged: bool,
+    // Unstaged changes in the working tree
+    pub modified: bool,
+    pub untracked: bool,
+    pub deleted: bool,
+    pub conflicted: bool,
+}
+
+// Blame for one line of the current content. Lines that differ from HEAD,
+// including unsaved edits, are `local` and have no commit information.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BlameLine {
+    // Zero-based line number
+    pub line: u32,
+    pub local: bool,
+    pub commit: Option<String>,
+    pub author: Option<String>,
+    // Seconds since the Unix epoch
+    pub timestamp: Option<i64>,
+    pub summary: Option<String>,
+}
+
+// What the working copy is compared against
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum GitDiffBase {
+    #[default]
+    Head,
+    // The staged version, so only unstaged changes show up
+    Index,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum GitLineKind {
+    Context,
+    Added,
+    Removed,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GitDiffLine {
+    pub kind: GitLineKind,
+    pub content: String,
+    pub old_line: Option<u32>,
+    pub new_line: Option<u32>,
+}
+
+// Line numbers are one-based, as in unified diff hunk headers
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GitDiffHunk {
+    pub old_start: u32,
+    pub old_lines: u32,
+    pub new_start: u32,
+    pub new_lines: u32,
+    pub lines: Vec<GitDiffLine>,
+}
+
+#[derive(Clone, Debug)]
+pub enum GitMessage {
+    StatusChanged {
+        files: Vec<GitFileStatus>,
+    },
+}
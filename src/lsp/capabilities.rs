@@ -1,13 +1,80 @@
 // generated by ai
 use lsp_types::{*, CompletionItemTag};
+use serde::{Deserialize, Serialize};
 
-pub fn get_client_capabilities() -> ClientCapabilities {
-    ClientCapabilities {
+use super::position_encoding::supported_encodings;
+
+// What the frontend can render, sent with Initialize. Anything left out is
+// assumed to be supported.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FrontendCapabilities {
+    // Markdown in hover, completion and signature help documentation
+    pub markdown: bool,
+    // Completion items with snippet placeholders
+    pub snippets: bool,
+}
+
+impl Default for FrontendCapabilities {
+    fn default() -> Self {
+        Self {
+            markdown: true,
+            snippets: true,
+        }
+    }
+}
+
+impl FrontendCapabilities {
+    // What all of `frontends` can handle, everything when there are none
+    pub fn common<'a>(frontends: impl IntoIterator<Item = &'a FrontendCapabilities>) -> Self {
+        frontends.into_iter().fold(Self::default(), |common, frontend| Self {
+            markdown: common.markdown && frontend.markdown,
+            snippets: common.snippets && frontend.snippets,
+        })
+    }
+
+    // Whether a server told it can use `self` may send something `other` can't handle
+    pub fn exceeds(&self, other: &FrontendCapabilities) -> bool {
+        (self.markdown && !other.markdown) || (self.snippets && !other.snippets)
+    }
+}
+
+pub fn get_client_capabilities(frontend: &FrontendCapabilities) -> ClientCapabilities {
+    let mut capabilities = ClientCapabilities {
         workspace: Some(get_workspace_capabilities()),
         text_document: Some(get_text_document_capabilities()),
         window: Some(get_window_capabilities()),
         general: Some(get_general_capabilities()),
         ..Default::default()
+    };
+    restrict_to_frontend(&mut capabilities, frontend);
+    capabilities
+}
+
+// Stops servers from sending content the frontend can't handle
+fn restrict_to_frontend(capabilities: &mut ClientCapabilities, frontend: &FrontendCapabilities) {
+    let Some(text_document) = capabilities.text_document.as_mut() else {
+        return;
+    };
+    if !frontend.markdown {
+        let plain_text = || Some(vec![MarkupKind::PlainText]);
+        if let Some(hover) = text_document.hover.as_mut() {
+            hover.content_format = plain_text();
+        }
+        if let Some(item) = text_document.completion.as_mut().and_then(|c| c.completion_item.as_mut()) {
+            item.documentation_format = plain_text();
+        }
+        if let Some(signature) = text_document.signature_help.as_mut().and_then(|s| s.signature_information.as_mut()) {
+            signature.documentation_format = plain_text();
+        }
+        if let Some(general) = capabilities.general.as_mut() {
+            general.markdown = None;
+        }
+    }
+    if !frontend.snippets {
+        if let Some(item) = text_document.completion.as_mut().and_then(|c| c.completion_item.as_mut()) {
+            item.snippet_support = Some(false);
+        }
     }
 }
 
@@ -33,6 +100,14 @@ fn get_workspace_capabilities() -> WorkspaceClientCapabilities {
             relative_pattern_support: Some(true),
         }),
         symbol: Some(get_workspace_symbol_capabilities()),
+        file_operations: Some(WorkspaceFileOperationsClientCapabilities {
+            dynamic_registration: Some(false),
+            did_create: Some(true),
+            will_rename: Some(true),
+            did_rename: Some(true),
+            did_delete: Some(true),
+            ..Default::default()
+        }),
         ..Default::default()
     }
 }
@@ -68,6 +143,12 @@ fn get_text_document_capabilities() -> TextDocumentClientCapabilities {
         range_formatting: Some(DocumentRangeFormattingClientCapabilities {
             dynamic_registration: Some(true),
         }),
+        rename: Some(RenameClientCapabilities {
+            dynamic_registration: Some(true),
+            prepare_support: Some(false),
+            prepare_support_default_behavior: None,
+            honors_change_annotations: Some(false),
+        }),
         publish_diagnostics: Some(get_publish_diagnostics_capabilities()),
         ..Default::default()
     }
@@ -91,6 +172,7 @@ fn get_general_capabilities() -> GeneralClientCapabilities {
             version: Some(String::from("1.1.0")),
             allowed_tags: None,
         }),
+        position_encodings: Some(supported_encodings()),
         ..Default::default()
     }
 }
@@ -162,7 +244,6 @@ fn get_publish_diagnostics_capabilities() -> PublishDiagnosticsClientCapabilitie
         version_support: Some(true),
         code_description_support: Some(true),
         data_support: Some(true),
-        ..Default::default()
     }
 }
 
@@ -242,4 +323,43 @@ fn get_completion_item_kinds() -> Vec<CompletionItemKind> {
         CompletionItemKind::OPERATOR,
         CompletionItemKind::TYPE_PARAMETER,
     ]
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capabilities_follow_the_frontend() {
+        let full = get_client_capabilities(&FrontendCapabilities::default());
+        let text_document = full.text_document.unwrap();
+        assert_eq!(
+            text_document.hover.unwrap().content_format,
+            Some(vec![MarkupKind::Markdown, MarkupKind::PlainText])
+        );
+        assert_eq!(text_document.completion.unwrap().completion_item.unwrap().snippet_support, Some(true));
+
+        let frontend: FrontendCapabilities = serde_json::from_str(r#"{"markdown": false}"#).unwrap();
+        assert!(frontend.snippets);
+        let plain = get_client_capabilities(&FrontendCapabilities { snippets: false, ..frontend });
+        let text_document = plain.text_document.unwrap();
+        assert_eq!(text_document.hover.unwrap().content_format, Some(vec![MarkupKind::PlainText]));
+        let item = text_document.completion.unwrap().completion_item.unwrap();
+        assert_eq!(item.documentation_format, Some(vec![MarkupKind::PlainText]));
+        assert_eq!(item.snippet_support, Some(false));
+        assert!(plain.general.unwrap().markdown.is_none());
+    }
+
+    #[test]
+    fn test_common_capabilities_are_what_every_frontend_handles() {
+        let plain = FrontendCapabilities { markdown: false, snippets: true };
+        let no_snippets = FrontendCapabilities { markdown: true, snippets: false };
+        assert_eq!(FrontendCapabilities::common([]), FrontendCapabilities::default());
+        let common = FrontendCapabilities::common([&plain, &no_snippets]);
+        assert_eq!(common, FrontendCapabilities { markdown: false, snippets: false });
+
+        assert!(FrontendCapabilities::default().exceeds(&plain));
+        assert!(!common.exceeds(&plain));
+        assert!(!plain.exceeds(&plain));
+    }
+}
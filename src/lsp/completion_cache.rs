@@ -0,0 +1,171 @@
+// src/lsp/completion_cache.rs
+use lsp_types::{CompletionList, Position};
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy)]
+pub struct CompletionCacheConfig {
+    // Responses kept, 0 disables the cache
+    pub capacity: usize,
+    pub ttl: Duration,
+}
+
+impl Default for CompletionCacheConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 16,
+            ttl: Duration::from_secs(2),
+        }
+    }
+}
+
+// Recent completion responses by document, position and document version, so
+// a client asking again for the same thing, e.g. after refocusing, doesn't
+// cost the server another request. Only documents whose version the server was
+// told about are cached, and a new version drops the document's responses.
+pub struct CompletionCache {
+    config: CompletionCacheConfig,
+    state: Mutex<CacheState>,
+}
+
+#[derive(Default)]
+struct CacheState {
+    // Latest version sent to the servers per open document
+    versions: HashMap<PathBuf, i32>,
+    // Oldest first
+    entries: VecDeque<CachedCompletions>,
+}
+
+struct CachedCompletions {
+    path: PathBuf,
+    position: Position,
+    version: i32,
+    completions: Option<CompletionList>,
+    stored_at: Instant,
+}
+
+impl CompletionCache {
+    pub fn new(config: CompletionCacheConfig) -> Self {
+        Self {
+            config,
+            state: Mutex::new(CacheState::default()),
+        }
+    }
+
+    pub fn set_version(&self, path: &Path, version: i32) {
+        let mut state = self.lock();
+        state.versions.insert(path.to_path_buf(), version);
+        state.entries.retain(|entry| entry.path != path || entry.version == version);
+    }
+
+    pub fn forget(&self, path: &Path) {
+        let mut state = self.lock();
+        state.versions.remove(path);
+        state.entries.retain(|entry| entry.path != path);
+    }
+
+    pub fn clear(&self) {
+        self.lock().entries.clear();
+    }
+
+    // Version to store a response under, None if it can't be cached
+    pub fn version(&self, path: &Path) -> Option<i32> {
+        if self.config.capacity == 0 {
+            return None;
+        }
+        self.lock().versions.get(path).copied()
+    }
+
+    // The cached response for the current version of the document, if it is
+    // still fresh. The outer Option tells whether there was one.
+    pub fn get(&self, path: &Path, position: Position, now: Instant) -> Option<Option<CompletionList>> {
+        let mut state = self.lock();
+        let version = *state.versions.get(path)?;
+        let ttl = self.config.ttl;
+        state.entries.retain(|entry| now.duration_since(entry.stored_at) < ttl);
+        state
+            .entries
+            .iter()
+            .find(|entry| entry.path == path && entry.position == position && entry.version == version)
+            .map(|entry| entry.completions.clone())
+    }
+
+    pub fn insert(
+        &self,
+        path: &Path,
+        position: Position,
+        version: i32,
+        completions: Option<CompletionList>,
+        now: Instant,
+    ) {
+        let mut state = self.lock();
+        // The document changed while the server was answering
+        if state.versions.get(path) != Some(&version) {
+            return;
+        }
+        state
+            .entries
+            .retain(|entry| entry.path != path || entry.position != position);
+        state.entries.push_back(CachedCompletions {
+            path: path.to_path_buf(),
+            position,
+            version,
+            completions,
+            stored_at: now,
+        });
+        while state.entries.len() > self.config.capacity {
+            state.entries.pop_front();
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, CacheState> {
+        // The state stays consistent even if a holder panicked
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_responses_expire_and_follow_the_version() {
+        let cache = CompletionCache::new(CompletionCacheConfig {
+            capacity: 2,
+            ttl: Duration::from_secs(2),
+        });
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let path = Path::new("/ws/main.rs");
+        let list = |label: &str| {
+            Some(CompletionList {
+                is_incomplete: false,
+                items: vec![lsp_types::CompletionItem::new_simple(label.to_string(), String::new())],
+            })
+        };
+
+        // Documents the servers weren't told about aren't cached
+        assert_eq!(cache.version(path), None);
+        cache.set_version(path, 1);
+        cache.insert(path, Position::new(0, 1), 1, list("a"), at(0));
+        assert_eq!(cache.get(path, Position::new(0, 1), at(1)), Some(list("a")));
+        assert_eq!(cache.get(path, Position::new(0, 2), at(1)), None);
+        assert_eq!(cache.get(path, Position::new(0, 1), at(2)), None);
+
+        // A new version drops the old responses, late ones for it aren't stored
+        cache.insert(path, Position::new(0, 1), 1, None, at(3));
+        cache.set_version(path, 2);
+        assert_eq!(cache.get(path, Position::new(0, 1), at(3)), None);
+        cache.insert(path, Position::new(0, 1), 1, list("stale"), at(3));
+        assert_eq!(cache.get(path, Position::new(0, 1), at(3)), None);
+
+        // The oldest response goes first once full
+        for column in 0..3 {
+            cache.insert(path, Position::new(1, column), 2, None, at(4));
+        }
+        assert_eq!(cache.get(path, Position::new(1, 0), at(4)), None);
+        assert_eq!(cache.get(path, Position::new(1, 2), at(4)), Some(None));
+    }
+}
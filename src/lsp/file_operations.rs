@@ -0,0 +1,70 @@
+// src/lsp/file_operations.rs
+use globset::GlobBuilder;
+use lsp_types::{FileOperationFilter, FileOperationPatternKind};
+use std::path::Path;
+
+// Checks a path against the filters a server registered for a file operation
+pub fn matches_filters(filters: &[FileOperationFilter], path: &Path, is_directory: bool) -> bool {
+    let path_str = path.to_string_lossy();
+
+    filters.iter().any(|filter| {
+        if filter.scheme.as_deref().is_some_and(|scheme| scheme != "file") {
+            return false;
+        }
+
+        let pattern = &filter.pattern;
+        match pattern.matches {
+            Some(FileOperationPatternKind::File) if is_directory => return false,
+            Some(FileOperationPatternKind::Folder) if !is_directory => return false,
+            _ => {}
+        }
+
+        let ignore_case = pattern
+            .options
+            .as_ref()
+            .and_then(|options| options.ignore_case)
+            .unwrap_or(false);
+
+        match GlobBuilder::new(&pattern.glob)
+            .literal_separator(true)
+            .case_insensitive(ignore_case)
+            .build()
+        {
+            Ok(glob) => glob.compile_matcher().is_match(path_str.as_ref()),
+            Err(e) => {
+                eprintln!("Invalid file operation glob {:?}: {}", pattern.glob, e);
+                false
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lsp_types::FileOperationPattern;
+
+    fn filter(glob: &str, matches: Option<FileOperationPatternKind>) -> FileOperationFilter {
+        FileOperationFilter {
+            scheme: Some("file".to_string()),
+            pattern: FileOperationPattern {
+                glob: glob.to_string(),
+                matches,
+                options: None,
+            },
+        }
+    }
+
+    #[test]
+    fn test_matches_filters() {
+        let filters = vec![
+            filter("**/*.rs", Some(FileOperationPatternKind::File)),
+            filter("**", Some(FileOperationPatternKind::Folder)),
+        ];
+
+        assert!(matches_filters(&filters, Path::new("/workspace/src/lib.rs"), false));
+        assert!(!matches_filters(&filters, Path::new("/workspace/README.md"), false));
+        assert!(matches_filters(&filters, Path::new("/workspace/src"), true));
+        assert!(!matches_filters(&[], Path::new("/workspace/src/lib.rs"), false));
+    }
+}
@@ -1,24 +1,103 @@
-use std::collections::HashMap;
-use std::path::PathBuf;
-use std::sync::Arc;
-use tokio::sync::RwLock;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
+use tokio::sync::{broadcast, RwLock};
 use anyhow::{Result, Context};
+use futures::future::BoxFuture;
+use futures::stream::{self, StreamExt};
 use lsp_types::*;
-use tokio::process::Command;
+use tokio::net::TcpStream;
+use tokio::process::{Child, Command};
 use std::ffi::OsStr;
+use std::time::{Duration, Instant};
 use url::Url;
 
-use super::{lsp_server::LspServer, types::LspConfiguration};
+use super::file_operations::matches_filters;
+use crate::utils::metrics::{HistogramSnapshot, LatencyRegistry};
+use crate::utils::path_utils::uri_to_path;
+use super::{
+    capabilities::{get_client_capabilities, FrontendCapabilities},
+    completion_cache::{CompletionCache, CompletionCacheConfig},
+    lsp_server::{LspServer, ServerConnection},
+    position_encoding::{DocumentContent, PositionConverter},
+    types::{
+        CancellationToken, DiagnosticCounts, LspConfiguration, LspError, LspProcessState, LspServerInfo,
+        LspServerState, LspStartPolicy, LspStartTrigger, LspStatus, LspTransport,
+    },
+};
+
+// ContentModified is a transient race with an in-flight edit, so it is retried
+// a bounded number of times before being reported
+const CONTENT_MODIFIED_RETRIES: usize = 1;
+const CONTENT_MODIFIED_RETRY_DELAY: Duration = Duration::from_millis(50);
+
+// Positions accepted by one BatchHover, and how many of them are in flight at once
+pub const MAX_BATCH_HOVER_POSITIONS: usize = 200;
+const BATCH_HOVER_CONCURRENCY: usize = 8;
+
+// Documents open in the editor as (path, content, version), replayed to servers
+// that (re)start so they don't begin without them
+pub type OpenDocuments = Arc<dyn Fn() -> BoxFuture<'static, Vec<(PathBuf, String, i32)>> + Send + Sync>;
+// Just the paths of the open documents, to decide whether a server may start
+pub type OpenPaths = Arc<dyn Fn() -> BoxFuture<'static, Vec<PathBuf>> + Send + Sync>;
+
+// Whether server_for starts a server that isn't running
+#[derive(Clone, Copy)]
+enum ServerStart {
+    Always,
+    // Only while a document the server handles is open
+    IfDocumentOpen,
+    Never,
+}
+
+// Usage of a running server, to find idle ones
+struct ServerActivity {
+    last_used: Instant,
+    open_documents: HashSet<PathBuf>,
+}
 
 pub struct LspManager {
     workspace_path: PathBuf,
     extension_map: HashMap<String, String>,
-    server_configs: HashMap<String, LspConfiguration>,
+    server_configs: RwLock<HashMap<String, LspConfiguration>>,
     active_servers: RwLock<HashMap<String, Arc<LspServer>>>,
+    // Diagnostics published by any of the servers
+    diagnostics_sender: broadcast::Sender<PublishDiagnosticsParams>,
+    // Totals of the latest diagnostics per file, for tree decorations
+    diagnostic_counts: RwLock<HashMap<PathBuf, DiagnosticCounts>>,
+    // Servers without open documents or requests for this long are shut down
+    idle_timeout: Option<Duration>,
+    activity: RwLock<HashMap<String, ServerActivity>>,
+    // Round trip time of requests to the servers, by LSP method
+    request_latencies: LatencyRegistry,
+    max_pending_requests: usize,
+    status_sender: broadcast::Sender<LspStatus>,
+    // Servers already reported as not installed, so it is only reported once
+    not_installed: RwLock<HashSet<String>>,
+    open_documents: OnceLock<OpenDocuments>,
+    open_paths: OnceLock<OpenPaths>,
+    document_content: OnceLock<DocumentContent>,
+    // What each connection's frontend can handle. Servers are shared, so they
+    // are started with what all of them have in common.
+    frontend_capabilities: RwLock<HashMap<uuid::Uuid, FrontendCapabilities>>,
+    // What each running server was started with
+    started_capabilities: RwLock<HashMap<String, FrontendCapabilities>>,
+    start_trigger: LspStartTrigger,
+    // Servers aren't started before this, those wanted earlier are started then
+    start_after: Instant,
+    deferred_starts: RwLock<HashSet<String>>,
+    completion_cache: CompletionCache,
 }
 
 impl LspManager {
-    pub fn new(workspace_path: PathBuf, configs: Vec<LspConfiguration>) -> Self {
+    pub fn new(
+        workspace_path: PathBuf,
+        configs: Vec<LspConfiguration>,
+        idle_timeout: Option<Duration>,
+        max_pending_requests: usize,
+        start_policy: LspStartPolicy,
+        completion_cache: CompletionCacheConfig,
+    ) -> Self {
         let mut extension_map = HashMap::new();
         let mut server_configs = HashMap::new();
 
@@ -30,15 +109,165 @@ impl LspManager {
             server_configs.insert(server_name, config);
         }
 
+        let (diagnostics_sender, _) = broadcast::channel(256);
+        let (status_sender, _) = broadcast::channel(16);
+
         Self {
             workspace_path,
             extension_map,
-            server_configs,
+            server_configs: RwLock::new(server_configs),
             active_servers: RwLock::new(HashMap::new()),
+            diagnostics_sender,
+            diagnostic_counts: RwLock::new(HashMap::new()),
+            idle_timeout,
+            activity: RwLock::new(HashMap::new()),
+            request_latencies: LatencyRegistry::default(),
+            max_pending_requests,
+            status_sender,
+            not_installed: RwLock::new(HashSet::new()),
+            open_documents: OnceLock::new(),
+            open_paths: OnceLock::new(),
+            document_content: OnceLock::new(),
+            frontend_capabilities: RwLock::new(HashMap::new()),
+            started_capabilities: RwLock::new(HashMap::new()),
+            start_trigger: start_policy.trigger,
+            start_after: Instant::now() + start_policy.delay,
+            deferred_starts: RwLock::new(HashSet::new()),
+            completion_cache: CompletionCache::new(completion_cache),
+        }
+    }
+
+    // Where to find the open documents for servers that start later on
+    pub fn set_open_documents(&self, open_documents: OpenDocuments) {
+        let _ = self.open_documents.set(open_documents);
+    }
+
+    pub fn set_open_paths(&self, open_paths: OpenPaths) {
+        let _ = self.open_paths.set(open_paths);
+    }
+
+    pub fn set_document_content(&self, document_content: DocumentContent) {
+        let _ = self.document_content.set(document_content);
+    }
+
+    // Capabilities of a connection's frontend. Servers already running are only
+    // restarted when they were told they may send something it can't handle.
+    pub async fn set_frontend_capabilities(&self, connection: uuid::Uuid, capabilities: FrontendCapabilities) {
+        let common = {
+            let mut frontends = self.frontend_capabilities.write().await;
+            frontends.insert(connection, capabilities);
+            FrontendCapabilities::common(frontends.values())
+        };
+
+        let exceeding: Vec<String> = self
+            .started_capabilities
+            .read()
+            .await
+            .iter()
+            .filter(|(_, started)| started.exceeds(&common))
+            .map(|(server_name, _)| server_name.clone())
+            .collect();
+        for server_name in exceeding {
+            if let Err(e) = self.restart_server(&server_name).await {
+                eprintln!("Failed to restart LSP server {} with new capabilities: {}", server_name, e);
+            }
         }
     }
 
+    // The connection is gone. Running servers keep what they were started with,
+    // servers started later may claim more again.
+    pub async fn remove_frontend_capabilities(&self, connection: uuid::Uuid) {
+        self.frontend_capabilities.write().await.remove(&connection);
+    }
+
+    pub fn request_latencies(&self) -> BTreeMap<String, HistogramSnapshot> {
+        self.request_latencies.snapshot()
+    }
+
+    pub fn subscribe_diagnostics(&self) -> broadcast::Receiver<PublishDiagnosticsParams> {
+        self.diagnostics_sender.subscribe()
+    }
+
+    pub fn subscribe_status(&self) -> broadcast::Receiver<LspStatus> {
+        self.status_sender.subscribe()
+    }
+
+    // Keeps the diagnostic counts up to date with what servers publish
+    pub fn track_diagnostics(self: &Arc<Self>) {
+        let manager = Arc::clone(self);
+        let mut diagnostics = self.subscribe_diagnostics();
+        tokio::spawn(async move {
+            loop {
+                match diagnostics.recv().await {
+                    Ok(params) => {
+                        let Ok(path) = uri_to_path(&params.uri) else {
+                            continue;
+                        };
+                        let counts = DiagnosticCounts::from_diagnostics(&params.diagnostics);
+                        manager.diagnostic_counts.write().await.insert(path, counts);
+                    }
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        eprintln!("Diagnostic counts missed {} updates", skipped);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        });
+    }
+
+    // None until a server has published diagnostics for the file
+    pub async fn diagnostic_counts(&self, path: &Path) -> Option<DiagnosticCounts> {
+        self.diagnostic_counts.read().await.get(path).copied()
+    }
+
+    // The server for a document being opened, started if it isn't running
     pub async fn get_server(&self, path: &PathBuf) -> Result<Option<Arc<LspServer>>> {
+        self.server_for(path, ServerStart::Always).await
+    }
+
+    // The server for a request or notification about `path`. Whether one that
+    // isn't running is started depends on the start trigger.
+    async fn request_server(&self, path: &PathBuf) -> Result<Option<Arc<LspServer>>> {
+        let start = match self.start_trigger {
+            LspStartTrigger::Request => ServerStart::Always,
+            LspStartTrigger::Open => ServerStart::IfDocumentOpen,
+        };
+        self.server_for(path, start).await
+    }
+
+    // Whether the editor has a document open that `server_name` handles
+    async fn has_open_document(&self, server_name: &str) -> bool {
+        let Some(open_paths) = self.open_paths.get() else {
+            return false;
+        };
+        open_paths()
+            .await
+            .iter()
+            .any(|path| self.server_name(path).map(String::as_str) == Some(server_name))
+    }
+
+    // Starts the servers that were wanted during the start delay once it is over.
+    // Open documents are replayed to them as they start.
+    pub fn start_deferred_servers(self: &Arc<Self>) {
+        let manager = Arc::clone(self);
+        tokio::spawn(async move {
+            tokio::time::sleep_until(manager.start_after.into()).await;
+            let deferred: Vec<String> = manager.deferred_starts.write().await.drain().collect();
+            for server_name in deferred {
+                if manager.active_servers.read().await.contains_key(&server_name) {
+                    continue;
+                }
+                match manager.initialize_server(&server_name).await {
+                    Ok(_) => manager.touch(&server_name, |_| {}).await,
+                    Err(e) => eprintln!("Failed to initialize server for {}: {}", server_name, e),
+                }
+            }
+        });
+    }
+
+    // A server that exited is always restarted, one that isn't running only
+    // as `start` allows
+    async fn server_for(&self, path: &PathBuf, start: ServerStart) -> Result<Option<Arc<LspServer>>> {
         // Get file extension
         let extension = path
             .extension()
@@ -56,19 +285,41 @@ impl LspManager {
         };
 
         // First check active servers
-        {
+        let existing = {
             let active_servers = self.active_servers.read().await;
             println!("Current active servers: {:?}", active_servers.keys().collect::<Vec<_>>());
-            if let Some(server) = active_servers.get(server_name) {
+            active_servers.get(server_name).cloned()
+        };
+        if let Some(server) = existing {
+            // A server that crashed is replaced below
+            if !server.has_exited().await {
                 println!("Found existing server for: {}", server_name);
-                return Ok(Some(Arc::clone(server)));
+                self.touch(server_name, |_| {}).await;
+                return Ok(Some(server));
+            }
+            eprintln!("LSP server {} has exited, restarting it", server_name);
+        } else {
+            let may_start = match start {
+                ServerStart::Always => true,
+                ServerStart::IfDocumentOpen => self.has_open_document(server_name).await,
+                ServerStart::Never => false,
+            };
+            if !may_start {
+                return Ok(None);
             }
         }
 
+        if Instant::now() < self.start_after {
+            println!("Deferring start of LSP server: {}", server_name);
+            self.deferred_starts.write().await.insert(server_name.clone());
+            return Ok(None);
+        }
+
         // Initialize new server with proper error handling
         match self.initialize_server(server_name).await {
             Ok(server) => {
                 println!("Successfully initialized server for: {}", server_name);
+                self.touch(server_name, |_| {}).await;
                 Ok(Some(server))
             }
             Err(e) => {
@@ -80,27 +331,31 @@ impl LspManager {
     }
 
     async fn initialize_server(&self, server_name: &str) -> Result<Arc<LspServer>> {
-        let config = self.server_configs.get(server_name)
+        let config = self.server_configs.read().await.get(server_name).cloned()
             .ok_or_else(|| anyhow::anyhow!("No config found for server: {}", server_name))?;
     
-        println!("Initializing LSP server: {} at path: {:?}", server_name, config.server_path);
-    
-        // Start server process
-        let mut command = Command::new(&config.server_path);
-        command
-            .args(&config.server_args)
-            .stdin(std::process::Stdio::piped())
-            .stdout(std::process::Stdio::piped())
-            .stderr(std::process::Stdio::piped());
-    
-        let process = command.spawn()
-            .context(format!("Failed to start LSP server process for {}", server_name))?;
+        let connection = match &config.transport {
+            LspTransport::Stdio => ServerConnection::Process(self.spawn_server(server_name, &config).await?),
+            LspTransport::Tcp { host, port } => {
+                println!("Connecting to LSP server: {} at {}:{}", server_name, host, port);
+                let stream = TcpStream::connect((host.as_str(), *port))
+                    .await
+                    .with_context(|| format!("Failed to connect to LSP server {} at {}:{}", server_name, host, port))?;
+                ServerConnection::Tcp(stream)
+            }
+        };
     
+        let frontend = FrontendCapabilities::common(self.frontend_capabilities.read().await.values());
+
         // Initialize server
         let server = match LspServer::initialize(
-            process,
+            connection,
             self.workspace_path.clone(),
             config.initialization_options.clone(),
+            get_client_capabilities(&frontend),
+            self.diagnostics_sender.clone(),
+            self.position_converter(),
+            self.max_pending_requests,
         ).await {
             Ok(server) => {
                 println!("Successfully initialized LSP server for {}", server_name);
@@ -112,52 +367,324 @@ impl LspManager {
             }
         };
     
+        // Before the server is handed out, so no request reaches it first
+        self.replay_open_documents(server_name, &server).await;
+
         // Store in active servers
         {
             let mut active_servers = self.active_servers.write().await;
             println!("Successfully storing server '{}' in active_servers", server_name);
             active_servers.insert(server_name.to_string(), Arc::clone(&server));
         }
+        self.started_capabilities.write().await.insert(server_name.to_string(), frontend);
     
         Ok(server)
     }
 
+    async fn replay_open_documents(&self, server_name: &str, server: &LspServer) {
+        let Some(open_documents) = self.open_documents.get() else {
+            return;
+        };
+        for (path, content, version) in open_documents().await {
+            if self.server_name(&path).map(String::as_str) != Some(server_name) {
+                continue;
+            }
+            let params = match self.did_open_params(&path, &content, version).await {
+                Ok(params) => params,
+                Err(e) => {
+                    eprintln!("Failed to reopen {:?}: {}", path, e);
+                    continue;
+                }
+            };
+            if let Err(e) = server.send_notification("textDocument/didOpen", params).await {
+                eprintln!("Failed to reopen {:?}: {}", path, e);
+                continue;
+            }
+            self.touch(server_name, |documents| {
+                documents.insert(path);
+            })
+            .await;
+        }
+    }
+
+    async fn spawn_server(&self, server_name: &str, config: &LspConfiguration) -> Result<Child> {
+        println!("Initializing LSP server: {} at path: {:?}", server_name, config.server_path);
+
+        // Start server process
+        let mut command = Command::new(&config.server_path);
+        command
+            .args(&config.server_args)
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped())
+            // Drop can only ask the server to exit, this makes sure it does
+            .kill_on_drop(true);
+
+        let process = match command.spawn() {
+            Ok(process) => process,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                // Still tried on every request, the server may get installed meanwhile
+                if self.not_installed.write().await.insert(server_name.to_string()) {
+                    let _ = self.status_sender.send(LspStatus {
+                        name: server_name.to_string(),
+                        state: LspServerState::NotInstalled,
+                    });
+                }
+                return Err(anyhow::anyhow!(
+                    "LSP server {} is not installed: {:?} was not found",
+                    server_name,
+                    config.server_path
+                ));
+            }
+            Err(e) => {
+                return Err(e).context(format!("Failed to start LSP server process for {}", server_name))
+            }
+        };
+        self.not_installed.write().await.remove(server_name);
+        Ok(process)
+    }
+
+    // Marks a server as used now, `update` can adjust its open documents
+    async fn touch(&self, server_name: &str, update: impl FnOnce(&mut HashSet<PathBuf>)) {
+        let mut activity = self.activity.write().await;
+        let entry = activity
+            .entry(server_name.to_string())
+            .or_insert_with(|| ServerActivity {
+                last_used: Instant::now(),
+                open_documents: HashSet::new(),
+            });
+        entry.last_used = Instant::now();
+        update(&mut entry.open_documents);
+    }
+
+    // File extensions (without the dot) and the server handling them
+    pub fn supported_extensions(&self) -> BTreeMap<String, String> {
+        self.extension_map
+            .iter()
+            .map(|(ext, server_name)| (ext.clone(), server_name.clone()))
+            .collect()
+    }
+
+    fn server_name(&self, path: &Path) -> Option<&String> {
+        let ext = path.extension().and_then(OsStr::to_str)?;
+        self.extension_map.get(ext)
+    }
+
+    // Periodically shuts down servers that have been idle for `idle_timeout`.
+    // They are started again by the next request that needs them.
+    pub fn start_idle_shutdown(self: &Arc<Self>) {
+        let Some(idle_timeout) = self.idle_timeout else {
+            return;
+        };
+        let manager = Arc::clone(self);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval((idle_timeout / 4).max(Duration::from_millis(100)));
+            loop {
+                interval.tick().await;
+                manager.shutdown_idle_servers(idle_timeout).await;
+            }
+        });
+    }
+
+    async fn shutdown_idle_servers(&self, idle_timeout: Duration) {
+        let idle_servers: Vec<(String, Arc<LspServer>)> = {
+            let mut activity = self.activity.write().await;
+            let mut active_servers = self.active_servers.write().await;
+            let idle: Vec<String> = activity
+                .iter()
+                .filter(|(_, a)| a.open_documents.is_empty() && a.last_used.elapsed() >= idle_timeout)
+                .map(|(name, _)| name.clone())
+                .collect();
+            idle.into_iter()
+                .filter_map(|name| {
+                    activity.remove(&name);
+                    active_servers.remove(&name).map(|server| (name, server))
+                })
+                .collect()
+        };
+
+        for (name, server) in idle_servers {
+            println!("Shutting down idle LSP server: {}", name);
+            if let Err(e) = server.shutdown().await {
+                eprintln!("Failed to shut down LSP server {}: {}", name, e);
+            }
+        }
+    }
+
+    // Settings are kept for future restarts and pushed to the running server.
+    // When one of the config's restart_settings changed the server is restarted
+    // instead.
+    pub async fn update_settings(&self, server_name: &str, settings: serde_json::Value) -> Result<()> {
+        let restart = {
+            let mut configs = self.server_configs.write().await;
+            let config = configs.get_mut(server_name)
+                .ok_or_else(|| anyhow::anyhow!("No config found for server: {}", server_name))?;
+            let restart = config.requires_restart(config.initialization_options.as_ref(), &settings);
+            config.initialization_options = Some(settings.clone());
+            restart
+        };
+
+        let server = self.active_servers.read().await.get(server_name).cloned();
+        match server {
+            Some(_) if restart => self.restart_server(server_name).await,
+            Some(server) => server.update_settings(settings).await,
+            None => Ok(()),
+        }
+    }
+
+    // Servers that have been started, by name
+    pub async fn list_servers(&self) -> Vec<LspServerInfo> {
+        let servers: Vec<(String, Arc<LspServer>)> = self
+            .active_servers
+            .read()
+            .await
+            .iter()
+            .map(|(name, server)| (name.clone(), Arc::clone(server)))
+            .collect();
+
+        let mut infos = Vec::with_capacity(servers.len());
+        for (name, server) in servers {
+            let state = match server.has_exited().await {
+                true => LspProcessState::Exited,
+                false => LspProcessState::Running,
+            };
+            let open_documents = self
+                .activity
+                .read()
+                .await
+                .get(&name)
+                .map_or(0, |activity| activity.open_documents.len());
+            infos.push(LspServerInfo {
+                name,
+                state,
+                process_id: server.process_id().await,
+                open_documents,
+            });
+        }
+        infos.sort_by(|a, b| a.name.cmp(&b.name));
+        infos
+    }
+
+    // Shuts a server down on request. Like after an idle shutdown, the next
+    // request for its files starts it again and reopens the open documents.
+    pub async fn stop_server(&self, server_name: &str) -> Result<()> {
+        if !self.shut_down_server(server_name).await {
+            anyhow::bail!("LSP server {} is not running", server_name);
+        }
+        let _ = self.status_sender.send(LspStatus {
+            name: server_name.to_string(),
+            state: LspServerState::Stopped,
+        });
+        Ok(())
+    }
+
+    // Whether there was a server to shut down
+    async fn shut_down_server(&self, server_name: &str) -> bool {
+        let server = self.active_servers.write().await.remove(server_name);
+        self.activity.write().await.remove(server_name);
+        self.started_capabilities.write().await.remove(server_name);
+        let Some(server) = server else {
+            return false;
+        };
+        // Another server will answer differently
+        self.completion_cache.clear();
+        if let Err(e) = server.shutdown().await {
+            eprintln!("Failed to shut down LSP server {}: {}", server_name, e);
+        }
+        true
+    }
+
+    // Graceful shutdown followed by a fresh start with the current config,
+    // which reopens the open documents
+    async fn restart_server(&self, server_name: &str) -> Result<()> {
+        println!("Restarting LSP server: {}", server_name);
+        self.shut_down_server(server_name).await;
+
+        self.initialize_server(server_name).await?;
+        self.touch(server_name, |_| {}).await;
+        let _ = self.status_sender.send(LspStatus {
+            name: server_name.to_string(),
+            state: LspServerState::Restarted,
+        });
+        Ok(())
+    }
+
+    async fn language_id(&self, path: &Path) -> String {
+        let Some(ext) = path.extension().and_then(OsStr::to_str) else {
+            return "plaintext".to_string();
+        };
+
+        let configs = self.server_configs.read().await;
+        match self.extension_map.get(ext).and_then(|name| configs.get(name)) {
+            Some(config) => config.language_id(ext),
+            None => ext.to_string(),
+        }
+    }
+
+    async fn did_open_params(&self, path: &Path, content: &str, version: i32) -> Result<serde_json::Value> {
+        Ok(serde_json::json!({
+            "textDocument": {
+                "uri": file_uri(path)?,
+                "languageId": self.language_id(path).await,
+                "version": version,
+                "text": content
+            }
+        }))
+    }
+
+    async fn is_open_on_server(&self, server_name: &str, path: &Path) -> bool {
+        self.activity
+            .read()
+            .await
+            .get(server_name)
+            .is_some_and(|activity| activity.open_documents.contains(path))
+    }
+
     pub async fn notify_document_opened(
         &self,
         path: &PathBuf,
         content: &str,
         version: i32,
     ) -> Result<()> {
+        self.completion_cache.set_version(path, version);
         let server = self.get_server(path).await?;
-
-        let file_uri = Url::from_file_path(path)
-            .map_err(|_| anyhow::anyhow!("Failed to create URI from path: {:?}", path))?
-            .to_string();
-
-        let params = serde_json::json!({
-            "textDocument": {
-                "uri": file_uri,
-                "languageId": path.extension()
-                    .and_then(OsStr::to_str)
-                    .unwrap_or("plaintext"),
-                "version": version,
-                "text": content
-            }
-        });
+        let params = self.did_open_params(path, content, version).await?;
 
         if let Some(server) = server {
+            let Some(name) = self.server_name(path) else {
+                return Ok(());
+            };
+            // A server started for this document already got it replayed
+            if self.is_open_on_server(name, path).await {
+                return Ok(());
+            }
             server.send_notification("textDocument/didOpen", params).await?;
+            self.touch(name, |documents| {
+                documents.insert(path.clone());
+            })
+            .await;
         }
         Ok(())
     }
 
+    // didOpen for several documents, e.g. when a client restores its tabs. Sent one
+    // after another so a server that isn't running yet is only started once.
+    pub async fn notify_documents_opened(&self, documents: &[(&PathBuf, &str, i32)]) {
+        for (path, content, version) in documents {
+            if let Err(e) = self.notify_document_opened(path, content, *version).await {
+                eprintln!("LSP notification failed for {:?}: {}", path, e);
+            }
+        }
+    }
+
     pub async fn notify_document_changed(
         &self,
         path: &PathBuf,
         changes: Vec<TextDocumentContentChangeEvent>,
         version: i32,
     ) -> Result<()> {
-        let server = self.get_server(path).await?;
+        self.completion_cache.set_version(path, version);
+        let server = self.request_server(path).await?;
 
         let file_uri = Url::from_file_path(path)
             .map_err(|_| anyhow::anyhow!("Failed to create URI from path: {:?}", path))?
@@ -172,7 +699,7 @@ impl LspManager {
         });
 
         if let Some(server) = server {
-            server.send_notification("textDocument/didOpen", params).await?;
+            server.send_notification("textDocument/didChange", params).await?;
         }
         Ok(())
     }
@@ -181,9 +708,8 @@ impl LspManager {
         &self,
         path: &PathBuf,
         text: &str,
-        version: i32,
     ) -> Result<()> {
-        let server = self.get_server(path).await?;
+        let server = self.request_server(path).await?;
 
         let file_uri = Url::from_file_path(path)
             .map_err(|_| anyhow::anyhow!("Failed to create URI from path: {:?}", path))?
@@ -191,14 +717,186 @@ impl LspManager {
 
         let params = serde_json::json!({
             "textDocument": {
-                "uri": file_uri,
-                "version": version
+                "uri": file_uri
             },
             "text": text
         });
 
         if let Some(server) = server {
-            server.send_notification("textDocument/didOpen", params).await?;
+            server.send_notification("textDocument/didSave", params).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn notify_document_closed(&self, path: &PathBuf) -> Result<()> {
+        self.completion_cache.forget(path);
+        // Closing a document never starts a server
+        let server = self.server_for(path, ServerStart::Never).await?;
+
+        let file_uri = Url::from_file_path(path)
+            .map_err(|_| anyhow::anyhow!("Failed to create URI from path: {:?}", path))?
+            .to_string();
+
+        let params = serde_json::json!({
+            "textDocument": {
+                "uri": file_uri
+            }
+        });
+
+        if let Some(server) = server {
+            server.send_notification("textDocument/didClose", params).await?;
+            if let Some(name) = self.server_name(path) {
+                self.touch(name, |documents| {
+                    documents.remove(path);
+                })
+                .await;
+            }
+        }
+        Ok(())
+    }
+
+    // Running servers whose registered file operation filters match the path.
+    // File operations never start a server on their own.
+    async fn servers_for_file_operation(
+        &self,
+        path: &Path,
+        is_directory: bool,
+        select: impl Fn(&WorkspaceFileOperationsServerCapabilities) -> Option<&FileOperationRegistrationOptions>,
+    ) -> Vec<Arc<LspServer>> {
+        let servers: Vec<Arc<LspServer>> = self.active_servers.read().await.values().cloned().collect();
+        let mut matching = Vec::new();
+
+        for server in servers {
+            let Some(capabilities) = server.capabilities().await else {
+                continue;
+            };
+            let registration = capabilities
+                .workspace
+                .as_ref()
+                .and_then(|workspace| workspace.file_operations.as_ref())
+                .and_then(&select);
+
+            if let Some(registration) = registration {
+                if matches_filters(&registration.filters, path, is_directory) {
+                    matching.push(server);
+                }
+            }
+        }
+        matching
+    }
+
+    // Asks servers for edits (e.g. module path fixups) before a rename happens
+    pub async fn will_rename_files(
+        &self,
+        old_path: &Path,
+        new_path: &Path,
+        is_directory: bool,
+    ) -> Result<Vec<WorkspaceEdit>> {
+        let servers = self
+            .servers_for_file_operation(old_path, is_directory, |ops| ops.will_rename.as_ref())
+            .await;
+        if servers.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let params = serde_json::to_value(RenameFilesParams {
+            files: vec![file_rename(old_path, new_path)?],
+        })?;
+
+        let mut edits = Vec::new();
+        for server in servers {
+            let started = Instant::now();
+            let response = server.send_request("workspace/willRenameFiles", params.clone()).await?;
+            self.request_latencies
+                .record("workspace/willRenameFiles", started.elapsed());
+            if let Some(error) = LspError::from_response(&response) {
+                return Err(error.into());
+            }
+            if let Some(result) = response.get("result").filter(|r| !r.is_null()) {
+                let mut result = result.clone();
+                self.position_converter()
+                    .to_client(&mut result, None, &server.position_encoding().await)
+                    .await;
+                edits.push(serde_json::from_value(result)?);
+            }
+        }
+        Ok(edits)
+    }
+
+    pub async fn notify_files_renamed(
+        &self,
+        old_path: &Path,
+        new_path: &Path,
+        is_directory: bool,
+    ) -> Result<()> {
+        self.notify_renames(&[(old_path.to_path_buf(), new_path.to_path_buf(), is_directory)])
+            .await
+    }
+
+    // didRenameFiles for a moved directory, with an entry for each file in it
+    // as well, so servers can fix up e.g. module paths file by file. `files`
+    // are relative to the directory.
+    pub async fn notify_directory_moved(&self, old_path: &Path, new_path: &Path, files: &[PathBuf]) -> Result<()> {
+        let mut renames = vec![(old_path.to_path_buf(), new_path.to_path_buf(), true)];
+        renames.extend(
+            files
+                .iter()
+                .map(|file| (old_path.join(file), new_path.join(file), false)),
+        );
+        self.notify_renames(&renames).await
+    }
+
+    // One didRenameFiles per server, with the renames its filters match
+    async fn notify_renames(&self, renames: &[(PathBuf, PathBuf, bool)]) -> Result<()> {
+        let mut notifications: Vec<(Arc<LspServer>, Vec<FileRename>)> = Vec::new();
+        for (old_path, new_path, is_directory) in renames {
+            // The old path no longer exists, so filters are checked against the new one
+            let servers = self
+                .servers_for_file_operation(new_path, *is_directory, |ops| ops.did_rename.as_ref())
+                .await;
+            for server in servers {
+                let rename = file_rename(old_path, new_path)?;
+                match notifications.iter_mut().find(|(known, _)| Arc::ptr_eq(known, &server)) {
+                    Some((_, files)) => files.push(rename),
+                    None => notifications.push((server, vec![rename])),
+                }
+            }
+        }
+
+        for (server, files) in notifications {
+            let params = serde_json::to_value(RenameFilesParams { files })?;
+            server.send_notification("workspace/didRenameFiles", params).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn notify_file_created(&self, path: &Path, is_directory: bool) -> Result<()> {
+        let servers = self
+            .servers_for_file_operation(path, is_directory, |ops| ops.did_create.as_ref())
+            .await;
+
+        let params = serde_json::to_value(CreateFilesParams {
+            files: vec![FileCreate { uri: file_uri(path)? }],
+        })?;
+
+        for server in servers {
+            server.send_notification("workspace/didCreateFiles", params.clone()).await?;
+        }
+        Ok(())
+    }
+
+    // `is_directory` must be determined by the caller before the path is removed
+    pub async fn notify_file_deleted(&self, path: &Path, is_directory: bool) -> Result<()> {
+        let servers = self
+            .servers_for_file_operation(path, is_directory, |ops| ops.did_delete.as_ref())
+            .await;
+
+        let params = serde_json::to_value(DeleteFilesParams {
+            files: vec![FileDelete { uri: file_uri(path)? }],
+        })?;
+
+        for server in servers {
+            server.send_notification("workspace/didDeleteFiles", params.clone()).await?;
         }
         Ok(())
     }
@@ -208,33 +906,87 @@ impl LspManager {
         path: &PathBuf,
         method: &str,
         position: Position,
+        cancel: Option<&CancellationToken>,
     ) -> Result<Option<T>> {
-        if let Some(server) = self.get_server(path).await? {
+        self.send_position_request(path, method, position, serde_json::Map::new(), cancel)
+            .await
+    }
+
+    fn position_converter(&self) -> PositionConverter {
+        PositionConverter::new(self.document_content.get().cloned(), self.open_paths.get().cloned())
+    }
+
+    // Text document position request, `extra_params` are added next to the position
+    async fn send_position_request<T: serde::de::DeserializeOwned>(
+        &self,
+        path: &PathBuf,
+        method: &str,
+        position: Position,
+        extra_params: serde_json::Map<String, serde_json::Value>,
+        cancel: Option<&CancellationToken>,
+    ) -> Result<Option<T>> {
+        if let Some(server) = self.request_server(path).await? {
             let file_uri = Url::from_file_path(path)
                 .map_err(|_| anyhow::anyhow!("Failed to create URI from path: {:?}", path))?
                 .to_string();
 
-            let params = serde_json::json!({
+            // Clients count columns in UTF-16 code units, the server in
+            // whatever it negotiated
+            let encoding = server.position_encoding().await;
+            let positions = self.position_converter();
+            let position = positions.to_server(path, position, &encoding).await?;
+            let mut params = serde_json::json!({
                 "textDocument": {
                     "uri": file_uri
                 },
                 "position": position
             });
+            if let Some(object) = params.as_object_mut() {
+                object.extend(extra_params);
+            }
+
+            let started = Instant::now();
+            let mut attempt = 0;
+            let response = loop {
+                if cancel.is_some_and(|token| token.is_cancelled()) {
+                    return Err(LspError::request_cancelled().into());
+                }
+                // While the server is indexing, errors and empty results mean
+                // it isn't ready rather than that there is nothing
+                let response = server.send_cancellable_request(method, params.clone(), cancel).await?;
+
+                // Errors are returned as LspError so callers can react to the code
+                match LspError::from_response(&response) {
+                    Some(error)
+                        if error.is_content_modified() && attempt < CONTENT_MODIFIED_RETRIES =>
+                    {
+                        attempt += 1;
+                        println!("{} hit ContentModified, retrying ({}/{})", method, attempt, CONTENT_MODIFIED_RETRIES);
+                        server.flush().await?;
+                        tokio::time::sleep(CONTENT_MODIFIED_RETRY_DELAY).await;
+                    }
+                    Some(error) if !error.is_request_cancelled() && server.is_busy().await => {
+                        return Err(LspError::not_ready(method).into());
+                    }
+                    Some(error) => return Err(error.into()),
+                    None => break response,
+                }
+            };
+            self.request_latencies.record(method, started.elapsed());
 
-            let response = server.send_request(method, params).await?;
-            
             // Extract result from JSON-RPC response
             if let Some(result) = response.get("result") {
                 if result.is_null() {
+                    if server.is_busy().await {
+                        return Err(LspError::not_ready(method).into());
+                    }
                     return Ok(None);
                 }
-                return Ok(Some(serde_json::from_value(result.clone())?));
-            }
-            
-            if let Some(error) = response.get("error") {
-                return Err(anyhow::anyhow!("LSP error: {:?}", error));
+                let mut result = result.clone();
+                positions.to_client(&mut result, Some(&file_uri), &encoding).await;
+                return Ok(Some(serde_json::from_value(result)?));
             }
-            
+
             Ok(None)
         } else {
             Ok(None)
@@ -244,24 +996,544 @@ impl LspManager {
     pub async fn get_completions(
         &self,
         path: &PathBuf,
-        position: Position
+        position: Position,
+        cancel: Option<&CancellationToken>,
     ) -> Result<Option<CompletionList>> {
-        self.send_request_with_uri(path, "textDocument/completion", position).await
+        // Asked again for an unchanged document, e.g. after a refocus
+        if let Some(completions) = self.completion_cache.get(path, position, Instant::now()) {
+            return Ok(completions);
+        }
+        let version = self.completion_cache.version(path);
+        let completions: Option<CompletionList> = self
+            .send_request_with_uri(path, "textDocument/completion", position, cancel)
+            .await?;
+        if let Some(version) = version {
+            self.completion_cache
+                .insert(path, position, version, completions.clone(), Instant::now());
+        }
+        Ok(completions)
     }
 
     pub async fn get_hover(
         &self,
         path: &PathBuf,
-        position: Position
+        position: Position,
+        cancel: Option<&CancellationToken>,
     ) -> Result<Option<Hover>> {
-        self.send_request_with_uri(path, "textDocument/hover", position).await
+        self.send_request_with_uri(path, "textDocument/hover", position, cancel).await
+    }
+
+    // Hovers for all positions, in the same order. Fails as a whole if any of
+    // the requests does.
+    pub async fn get_hovers(
+        &self,
+        path: &PathBuf,
+        positions: Vec<Position>,
+        cancel: Option<&CancellationToken>,
+    ) -> Result<Vec<Option<Hover>>> {
+        if positions.len() > MAX_BATCH_HOVER_POSITIONS {
+            anyhow::bail!(
+                "Batch of {} positions exceeds the limit of {}",
+                positions.len(),
+                MAX_BATCH_HOVER_POSITIONS
+            );
+        }
+
+        stream::iter(positions)
+            .map(|position| self.get_hover(path, position, cancel))
+            .buffered(BATCH_HOVER_CONCURRENCY)
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect()
     }
 
     pub async fn get_definition(
         &self,
         path: &PathBuf,
-        position: Position
+        position: Position,
+        cancel: Option<&CancellationToken>,
     ) -> Result<Option<Vec<Location>>> {
-        self.send_request_with_uri(path, "textDocument/definition", position).await
+        self.send_request_with_uri(path, "textDocument/definition", position, cancel).await
+    }
+
+    // Edits renaming the symbol at `position`, they are not applied here
+    pub async fn get_rename_edit(
+        &self,
+        path: &PathBuf,
+        position: Position,
+        new_name: &str,
+    ) -> Result<Option<WorkspaceEdit>> {
+        let mut extra_params = serde_json::Map::new();
+        extra_params.insert("newName".to_string(), new_name.into());
+        self.send_position_request(path, "textDocument/rename", position, extra_params, None)
+            .await
     }
-}
\ No newline at end of file
+}
+
+fn file_uri(path: &Path) -> Result<String> {
+    Ok(Url::from_file_path(path)
+        .map_err(|_| anyhow::anyhow!("Failed to create URI from path: {:?}", path))?
+        .to_string())
+}
+
+fn file_rename(old_path: &Path, new_path: &Path) -> Result<FileRename> {
+    Ok(FileRename {
+        old_uri: file_uri(old_path)?,
+        new_uri: file_uri(new_path)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lsp::test_support::{fake_server, fake_tcp_server, stdio_config, tcp_config};
+    use crate::lsp::types::{DEFAULT_MAX_PENDING_REQUESTS, LSP_BUSY};
+
+    #[tokio::test]
+    async fn test_idle_server_is_shut_down_and_restarted() -> Result<()> {
+        let workspace = tempfile::tempdir()?;
+        let config = stdio_config(&fake_server(":", ":"));
+        let manager = Arc::new(LspManager::new(
+            workspace.path().to_path_buf(),
+            vec![config],
+            Some(Duration::from_millis(200)),
+            DEFAULT_MAX_PENDING_REQUESTS,
+            LspStartPolicy::default(),
+            CompletionCacheConfig::default(),
+        ));
+        manager.start_idle_shutdown();
+
+        let path = workspace.path().join("main.fake");
+        let first = manager.get_server(&path).await?.expect("server should start");
+
+        tokio::time::sleep(Duration::from_millis(800)).await;
+        assert!(manager.active_servers.read().await.is_empty());
+        assert!(first.has_exited().await);
+
+        let second = manager.get_server(&path).await?.expect("server should restart");
+        assert!(!Arc::ptr_eq(&first, &second));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_servers_are_listed_and_stopped() -> Result<()> {
+        let workspace = tempfile::tempdir()?;
+        let config = stdio_config(&fake_server(":", ":"));
+        let manager = LspManager::new(
+            workspace.path().to_path_buf(),
+            vec![config],
+            None,
+            DEFAULT_MAX_PENDING_REQUESTS,
+            LspStartPolicy::default(),
+            CompletionCacheConfig::default(),
+        );
+        let mut status = manager.subscribe_status();
+        assert!(manager.list_servers().await.is_empty());
+
+        let path = workspace.path().join("main.fake");
+        let server = manager.get_server(&path).await?.expect("server should start");
+        let servers = manager.list_servers().await;
+        assert_eq!(servers.len(), 1);
+        assert_eq!((servers[0].name.as_str(), servers[0].state), ("fake", LspProcessState::Running));
+        assert!(servers[0].process_id.is_some());
+
+        manager.stop_server("fake").await?;
+        assert!(server.has_exited().await);
+        assert!(manager.list_servers().await.is_empty());
+        assert_eq!(status.try_recv()?.state, LspServerState::Stopped);
+        assert!(manager.stop_server("fake").await.is_err());
+
+        // Started again on demand
+        assert!(manager.get_server(&path).await?.is_some());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_servers_start_on_open_after_the_delay() -> Result<()> {
+        let workspace = tempfile::tempdir()?;
+        let (port, _) = fake_tcp_server(&[]).await?;
+        let manager = Arc::new(LspManager::new(
+            workspace.path().to_path_buf(),
+            vec![tcp_config(port)],
+            None,
+            DEFAULT_MAX_PENDING_REQUESTS,
+            LspStartPolicy {
+                trigger: LspStartTrigger::Open,
+                delay: Duration::from_millis(300),
+            },
+            CompletionCacheConfig::default(),
+        ));
+        let path = workspace.path().join("main.fake");
+
+        let lookups = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        manager.set_open_paths({
+            let lookups = Arc::clone(&lookups);
+            Arc::new(move || {
+                lookups.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                Box::pin(async { Vec::new() })
+            })
+        });
+
+        // A hover over a file that isn't open doesn't start anything
+        assert!(manager.get_hover(&path, Position::new(0, 0), None).await?.is_none());
+        assert!(manager.deferred_starts.read().await.is_empty());
+        assert_eq!(lookups.load(std::sync::atomic::Ordering::Relaxed), 1);
+
+        // Opening it during the delay defers the start until the delay is over
+        manager.notify_document_opened(&path, "", 0).await?;
+        assert!(manager.active_servers.read().await.is_empty());
+        manager.start_deferred_servers();
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        assert_eq!(manager.list_servers().await.len(), 1);
+
+        // Requests to a running server don't look at the open documents
+        manager.get_hover(&path, Position::new(0, 0), None).await?;
+        assert_eq!(lookups.load(std::sync::atomic::Ordering::Relaxed), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_missing_server_is_reported_once() -> Result<()> {
+        let workspace = tempfile::tempdir()?;
+        let config = LspConfiguration {
+            name: "missing".to_string(),
+            file_extensions: vec!["fake".to_string()],
+            server_path: PathBuf::from("websocket-ide-missing-language-server"),
+            server_args: vec![],
+            initialization_options: None,
+            language_ids: HashMap::new(),
+            transport: LspTransport::Stdio,
+            restart_settings: vec![],
+        };
+        let manager = LspManager::new(
+            workspace.path().to_path_buf(),
+            vec![config],
+            None,
+            DEFAULT_MAX_PENDING_REQUESTS,
+            LspStartPolicy::default(),
+            CompletionCacheConfig::default(),
+        );
+        let mut status = manager.subscribe_status();
+        let path = workspace.path().join("main.fake");
+
+        assert!(manager.get_server(&path).await?.is_none());
+        assert!(manager.get_server(&path).await?.is_none());
+
+        let reported = status.try_recv()?;
+        assert_eq!(reported.name, "missing");
+        assert_eq!(reported.state, LspServerState::NotInstalled);
+        assert!(status.try_recv().is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_server_reached_over_tcp() -> Result<()> {
+        let (port, mut messages) = fake_tcp_server(&[]).await?;
+        let workspace = tempfile::tempdir()?;
+        let manager = LspManager::new(
+            workspace.path().to_path_buf(),
+            vec![tcp_config(port)],
+            None,
+            DEFAULT_MAX_PENDING_REQUESTS,
+            LspStartPolicy::default(),
+            CompletionCacheConfig::default(),
+        );
+        let path = workspace.path().join("main.fake");
+
+        let server = manager.get_server(&path).await?.expect("server should connect");
+        assert!(manager.get_hover(&path, Position::new(0, 0), None).await?.is_none());
+        assert!(!server.has_exited().await);
+
+        server.shutdown().await?;
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !server.has_exited().await {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await?;
+        // The daemon may be shared, so it is only disconnected from
+        while let Ok(message) = messages.try_recv() {
+            assert_ne!(message["method"], "shutdown");
+            assert_ne!(message["method"], "exit");
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_directory_move_renames_each_file() -> Result<()> {
+        let (port, mut messages) = fake_tcp_server(&[]).await?;
+        let workspace = tempfile::tempdir()?;
+        let manager = LspManager::new(
+            workspace.path().to_path_buf(),
+            vec![tcp_config(port)],
+            None,
+            DEFAULT_MAX_PENDING_REQUESTS,
+            LspStartPolicy::default(),
+            CompletionCacheConfig::default(),
+        );
+        let server = manager
+            .get_server(&workspace.path().join("main.fake"))
+            .await?
+            .expect("server should connect");
+        let renames = FileOperationRegistrationOptions {
+            filters: vec![FileOperationFilter {
+                scheme: None,
+                pattern: FileOperationPattern {
+                    glob: "**".to_string(),
+                    matches: None,
+                    options: None,
+                },
+            }],
+        };
+        server
+            .set_capabilities(ServerCapabilities {
+                workspace: Some(WorkspaceServerCapabilities {
+                    workspace_folders: None,
+                    file_operations: Some(WorkspaceFileOperationsServerCapabilities {
+                        did_rename: Some(renames),
+                        ..Default::default()
+                    }),
+                }),
+                ..Default::default()
+            })
+            .await;
+
+        let (old, new) = (workspace.path().join("old"), workspace.path().join("new"));
+        let files = [PathBuf::from("a.fake"), PathBuf::from("sub/b.fake")];
+        manager.notify_directory_moved(&old, &new, &files).await?;
+        let renamed = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let message = messages.recv().await.expect("the server stays connected");
+                if message["method"] == "workspace/didRenameFiles" {
+                    break message["params"]["files"].clone();
+                }
+            }
+        })
+        .await?;
+        let new_uris: Vec<_> = renamed
+            .as_array()
+            .expect("a list of renames")
+            .iter()
+            .map(|rename| rename["newUri"].as_str().unwrap_or_default().to_string())
+            .collect();
+        let expected: Vec<_> = [new.clone(), new.join("a.fake"), new.join("sub/b.fake")]
+            .iter()
+            .map(|path| Url::from_file_path(path).unwrap().to_string())
+            .collect();
+        assert_eq!(new_uris, expected);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_frontend_capabilities_only_restart_servers_that_claim_too_much() -> Result<()> {
+        let (port, _) = fake_tcp_server(&[]).await?;
+        let workspace = tempfile::tempdir()?;
+        let manager = LspManager::new(
+            workspace.path().to_path_buf(),
+            vec![tcp_config(port)],
+            None,
+            DEFAULT_MAX_PENDING_REQUESTS,
+            LspStartPolicy::default(),
+            CompletionCacheConfig::default(),
+        );
+        manager.get_server(&workspace.path().join("main.fake")).await?;
+        let mut status = manager.subscribe_status();
+        let (first, second) = (uuid::Uuid::new_v4(), uuid::Uuid::new_v4());
+
+        // Capabilities the server was started with leave it alone
+        manager.set_frontend_capabilities(first, FrontendCapabilities::default()).await;
+        assert!(status.try_recv().is_err());
+
+        let plain = FrontendCapabilities {
+            markdown: false,
+            ..FrontendCapabilities::default()
+        };
+        manager.set_frontend_capabilities(second, plain.clone()).await;
+        let restarted = status.try_recv()?;
+        assert_eq!((restarted.name.as_str(), restarted.state), ("daemon", LspServerState::Restarted));
+
+        // Neither another Initialize from the first connection nor the plain
+        // one leaving take back what the restarted server already does without
+        manager.set_frontend_capabilities(first, FrontendCapabilities::default()).await;
+        manager.remove_frontend_capabilities(second).await;
+        manager.set_frontend_capabilities(second, plain).await;
+        assert!(status.try_recv().is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_restarted_server_gets_open_documents_again() -> Result<()> {
+        let (port, mut messages) = fake_tcp_server(&[]).await?;
+        let workspace = tempfile::tempdir()?;
+        let manager = LspManager::new(
+            workspace.path().to_path_buf(),
+            vec![tcp_config(port)],
+            None,
+            DEFAULT_MAX_PENDING_REQUESTS,
+            LspStartPolicy::default(),
+            CompletionCacheConfig::default(),
+        );
+        let documents = vec![
+            (workspace.path().join("a.fake"), "a".to_string(), 3),
+            (workspace.path().join("b.fake"), "b".to_string(), 1),
+            (workspace.path().join("notes.txt"), "other".to_string(), 1),
+        ];
+        manager.set_open_documents(Arc::new(move || {
+            let documents = documents.clone();
+            Box::pin(async move { documents })
+        }));
+
+        manager.get_server(&workspace.path().join("a.fake")).await?;
+        manager
+            .update_settings("daemon", serde_json::json!({ "cargo": { "features": "all" } }))
+            .await?;
+
+        let mut opened = Vec::new();
+        while let Ok(Some(message)) = tokio::time::timeout(Duration::from_millis(300), messages.recv()).await {
+            if message["method"] == "textDocument/didOpen" {
+                let uri = message["params"]["textDocument"]["uri"].as_str().unwrap_or_default();
+                opened.push(uri.rsplit('/').next().unwrap_or_default().to_string());
+            }
+        }
+        // Once when the server first started and once after the restart
+        assert_eq!(opened, ["a.fake", "b.fake", "a.fake", "b.fake"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_repeated_completion_is_answered_from_the_cache() -> Result<()> {
+        let (port, mut messages) = fake_tcp_server(&[]).await?;
+        let workspace = tempfile::tempdir()?;
+        let manager = LspManager::new(
+            workspace.path().to_path_buf(),
+            vec![tcp_config(port)],
+            None,
+            DEFAULT_MAX_PENDING_REQUESTS,
+            LspStartPolicy::default(),
+            CompletionCacheConfig::default(),
+        );
+        let path = workspace.path().join("main.fake");
+        manager.notify_document_opened(&path, "fn", 1).await?;
+
+        let at = Position::new(0, 2);
+        manager.get_completions(&path, at, None).await?;
+        manager.get_completions(&path, at, None).await?;
+        // Another position, and the same one after an edit, go to the server
+        manager.get_completions(&path, Position::new(0, 1), None).await?;
+        manager.notify_document_changed(&path, vec![], 2).await?;
+        manager.get_completions(&path, at, None).await?;
+
+        let mut requested = 0;
+        while let Ok(Some(message)) = tokio::time::timeout(Duration::from_millis(300), messages.recv()).await {
+            if message["method"] == "textDocument/completion" {
+                requested += 1;
+            }
+        }
+        assert_eq!(requested, 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_requests_beyond_the_pending_limit_are_refused() -> Result<()> {
+        let (port, _messages) = fake_tcp_server(&["textDocument/hover"]).await?;
+        let workspace = tempfile::tempdir()?;
+        let manager = Arc::new(LspManager::new(
+            workspace.path().to_path_buf(),
+            vec![tcp_config(port)],
+            None,
+            2,
+            LspStartPolicy::default(),
+            CompletionCacheConfig::default(),
+        ));
+        let path = workspace.path().join("main.fake");
+        manager.get_server(&path).await?;
+
+        let cancel = CancellationToken::default();
+        let waiting: Vec<_> = (0..2)
+            .map(|_| {
+                let (manager, path, cancel) = (Arc::clone(&manager), path.clone(), cancel.clone());
+                tokio::spawn(async move { manager.get_hover(&path, Position::new(0, 0), Some(&cancel)).await })
+            })
+            .collect();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let error = manager
+            .get_hover(&path, Position::new(0, 0), None)
+            .await
+            .expect_err("a third request should be refused")
+            .downcast::<LspError>()?;
+        assert_eq!(error.code, LSP_BUSY);
+
+        // Cancelled requests free their slots again
+        cancel.cancel();
+        for request in waiting {
+            assert!(request.await?.is_err());
+        }
+        let hover = tokio::time::timeout(
+            Duration::from_millis(200),
+            manager.get_hover(&path, Position::new(0, 0), None),
+        )
+        .await;
+        assert!(hover.is_err(), "the slot is free, so the request waits for the silent server");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_batch_hover_is_aligned_and_capped() -> Result<()> {
+        let workspace = tempfile::tempdir()?;
+        let config = stdio_config(&fake_server(":", ":"));
+        let manager = LspManager::new(
+            workspace.path().to_path_buf(),
+            vec![config],
+            None,
+            DEFAULT_MAX_PENDING_REQUESTS,
+            LspStartPolicy::default(),
+            CompletionCacheConfig::default(),
+        );
+        let path = workspace.path().join("main.fake");
+
+        let positions: Vec<_> = (0..20).map(|line| Position::new(line, 0)).collect();
+        let hovers = manager.get_hovers(&path, positions, None).await?;
+        assert_eq!(hovers.len(), 20);
+        assert!(hovers.iter().all(Option::is_none));
+
+        let too_many = vec![Position::new(0, 0); MAX_BATCH_HOVER_POSITIONS + 1];
+        assert!(manager.get_hovers(&path, too_many, None).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_requests_while_indexing_are_not_ready() -> Result<()> {
+        let workspace = tempfile::tempdir()?;
+        // Indexing from startup until a document is saved, which starts a check
+        let config = stdio_config(&fake_server(
+            "progress begin startup Indexing",
+            "progress end startup; progress begin check 'cargo check'",
+        ));
+        let manager = LspManager::new(
+            workspace.path().to_path_buf(),
+            vec![config],
+            None,
+            DEFAULT_MAX_PENDING_REQUESTS,
+            LspStartPolicy::default(),
+            CompletionCacheConfig::default(),
+        );
+        let path = workspace.path().join("main.fake");
+        manager.notify_document_opened(&path, "fn", 1).await?;
+
+        let error = manager
+            .get_completions(&path, Position::new(0, 2), None)
+            .await
+            .expect_err("an empty answer while indexing isn't final")
+            .downcast::<LspError>()?;
+        assert!(error.is_not_ready());
+        assert_eq!(error.feature(), Some("textDocument/completion"));
+
+        // Once indexing has ended an empty answer is just that, even while a
+        // later check is still in progress
+        manager.notify_document_saved(&path, "fn").await?;
+        assert!(manager.get_completions(&path, Position::new(0, 2), None).await?.is_none());
+        Ok(())
+    }
+}
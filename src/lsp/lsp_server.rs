@@ -1,33 +1,67 @@
 // src/lsp/lsp_server.rs
 
 use lsp_types::*;
-use tokio::io::{BufReader, BufWriter, AsyncWriteExt, AsyncBufReadExt, AsyncReadExt};
+use tokio::io::{AsyncRead, AsyncWrite, BufReader, BufWriter, AsyncWriteExt, AsyncBufReadExt, AsyncReadExt};
+use tokio::net::TcpStream;
 use std::sync::Arc;
 use anyhow::Result;
 use serde_json::Value;
-use tokio::process::{Child, ChildStdin, ChildStdout};
-use tokio::sync::RwLock;
-use std::collections::HashMap;
+use tokio::process::Child;
+use tokio::sync::{broadcast, RwLock};
+use std::collections::{HashMap, HashSet};
 use std::str::FromStr;
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::path::PathBuf;
-use crate::lsp::capabilities::get_client_capabilities;
+use crate::lsp::position_encoding::PositionConverter;
+use crate::lsp::request_queue::{WritePriority, WriteQueue};
+use crate::lsp::types::{CancellationToken, LspError, METHOD_NOT_FOUND};
 use lsp_types::ServerCapabilities;
 
 
+// How long a server gets to answer `shutdown` and then to exit after `exit`
+const SHUTDOWN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+type ServerReader = BufReader<Box<dyn AsyncRead + Send + Unpin>>;
+type ServerWriter = BufWriter<Box<dyn AsyncWrite + Send + Unpin>>;
+
+// How a server is reached, both use the same Content-Length framing
+pub enum ServerConnection {
+    // A child process spoken to over stdin/stdout
+    Process(Child),
+    // A server that is already running, e.g. as a daemon
+    Tcp(TcpStream),
+}
+
 pub struct LspServer {
-    _process: Child,
+    // None for servers reached over TCP
+    process: tokio::sync::Mutex<Option<Child>>,
+    // Set once reading from the server fails, e.g. the connection was closed
+    disconnected: AtomicBool,
     client_capabilities: ClientCapabilities,
     server_capabilities: RwLock<Option<ServerCapabilities>>,
     request_counter: AtomicU64,
     pending_requests: RwLock<HashMap<u64, tokio::sync::oneshot::Sender<Value>>>,
-    writer: Arc<tokio::sync::Mutex<BufWriter<ChildStdin>>>,  // Changed to Mutex
+    // Requests beyond this many unanswered ones fail right away with LspError::busy
+    max_pending_requests: usize,
+    writer: Arc<tokio::sync::Mutex<ServerWriter>>,  // Changed to Mutex
+    // Messages waiting while another one is written
+    write_queue: std::sync::Mutex<WriteQueue>,
     message_handler: Arc<MessageHandler>,
+    // Answered to workspace/configuration requests
+    settings: RwLock<Option<Value>>,
+    diagnostics: broadcast::Sender<PublishDiagnosticsParams>,
+    // Re-counts diagnostic ranges for clients
+    positions: PositionConverter,
+    // Tokens of $/progress work that has begun and not yet ended, counting
+    // startup work and anything titled as indexing but not e.g. a check on save
+    active_progress: RwLock<HashSet<String>>,
+    // Set once all startup progress has ended
+    quiescent: AtomicBool,
 }
 
 // Separate struct for message handling
 struct MessageHandler {
-    reader: tokio::sync::Mutex<BufReader<ChildStdout>>,
+    reader: tokio::sync::Mutex<ServerReader>,
 }
 
 impl MessageHandler {
@@ -71,44 +105,69 @@ impl MessageHandler {
 
 impl LspServer {
     pub async fn initialize(
-        mut process: Child,
+        connection: ServerConnection,
         workspace_path: PathBuf,
         initialization_options: Option<serde_json::Value>,
+        client_capabilities: ClientCapabilities,
+        diagnostics: broadcast::Sender<PublishDiagnosticsParams>,
+        positions: PositionConverter,
+        max_pending_requests: usize,
     ) -> Result<Arc<Self>> {
         println!("Starting LSP server initialization");
 
-        // Capture stderr for debugging
-        let stderr = process.stderr.take()
-            .ok_or_else(|| anyhow::anyhow!("Failed to get stderr handle"))?;
+        let (process, reader, writer): (
+            Option<Child>,
+            Box<dyn AsyncRead + Send + Unpin>,
+            Box<dyn AsyncWrite + Send + Unpin>,
+        ) = match connection {
+            ServerConnection::Process(mut process) => {
+                // Capture stderr for debugging
+                let stderr = process.stderr.take()
+                    .ok_or_else(|| anyhow::anyhow!("Failed to get stderr handle"))?;
+
+                tokio::spawn(async move {
+                    let mut reader = BufReader::new(stderr);
+                    let mut line = String::new();
+                    while let Ok(n) = reader.read_line(&mut line).await {
+                        if n == 0 { break; }
+                        eprintln!("LSP stderr: {}", line.trim());
+                        line.clear();
+                    }
+                });
 
-        tokio::spawn(async move {
-            let mut reader = BufReader::new(stderr);
-            let mut line = String::new();
-            while let Ok(n) = reader.read_line(&mut line).await {
-                if n == 0 { break; }
-                eprintln!("LSP stderr: {}", line.trim());
-                line.clear();
+                let stdin = process.stdin.take()
+                    .ok_or_else(|| anyhow::anyhow!("Failed to get stdin handle"))?;
+                let stdout = process.stdout.take()
+                    .ok_or_else(|| anyhow::anyhow!("Failed to get stdout handle"))?;
+                (Some(process), Box::new(stdout), Box::new(stdin))
             }
-        });
-
-        let stdin = process.stdin.take()
-            .ok_or_else(|| anyhow::anyhow!("Failed to get stdin handle"))?;
-        let stdout = process.stdout.take()
-            .ok_or_else(|| anyhow::anyhow!("Failed to get stdout handle"))?;
+            ServerConnection::Tcp(stream) => {
+                let (read_half, write_half) = stream.into_split();
+                (None, Box::new(read_half), Box::new(write_half))
+            }
+        };
 
-        let writer = Arc::new(tokio::sync::Mutex::new(BufWriter::new(stdin)));
+        let writer = Arc::new(tokio::sync::Mutex::new(BufWriter::new(writer)));
         let message_handler = Arc::new(MessageHandler {
-            reader: tokio::sync::Mutex::new(BufReader::new(stdout)),
+            reader: tokio::sync::Mutex::new(BufReader::new(reader)),
         });
 
         let server = Arc::new(Self {
-            _process: process,
-            client_capabilities: get_client_capabilities(),
+            process: tokio::sync::Mutex::new(process),
+            disconnected: AtomicBool::new(false),
+            client_capabilities,
             server_capabilities: RwLock::new(None),
             request_counter: AtomicU64::new(0),
             pending_requests: RwLock::new(HashMap::new()),
+            max_pending_requests,
             writer,
+            write_queue: std::sync::Mutex::new(WriteQueue::default()),
             message_handler,
+            settings: RwLock::new(initialization_options.clone()),
+            diagnostics,
+            positions,
+            active_progress: RwLock::new(HashSet::new()),
+            quiescent: AtomicBool::new(false),
         });
 
         // Start message handler before sending initialize
@@ -167,8 +226,8 @@ impl LspServer {
 
         // Extract the capabilities from the result
         let server_capabilities = match init_result.get("result") {
-            Some(result) => match serde_json::from_value::<ServerCapabilities>(result.clone()) {
-                Ok(caps) => Some(caps),
+            Some(result) => match serde_json::from_value::<InitializeResult>(result.clone()) {
+                Ok(result) => Some(result.capabilities),
                 Err(e) => {
                     eprintln!("Failed to parse server capabilities: {}", e);
                     None
@@ -201,18 +260,105 @@ impl LspServer {
     }
 
     async fn send_message(&self, msg: String) -> Result<()> {
-        let content_length = msg.len();
-        let header = format!("Content-Length: {}\r\n\r\n{}", content_length, msg);
-        
-        //println!("Sending message: {}", header);  // Debug log
-        
+        self.write_message(msg, WritePriority::Barrier).await
+    }
+
+    // Queues the message and writes it once the writer is free. Whoever holds
+    // the writer writes everything queued meanwhile, most urgent first, so a
+    // hover doesn't wait behind a burst of background requests.
+    async fn write_message(&self, msg: String, priority: WritePriority) -> Result<()> {
+        self.queue().push(msg, priority);
+
         let mut writer = self.writer.lock().await;
-        writer.write_all(header.as_bytes()).await?;
+        loop {
+            // Not held across the write
+            let next = self.queue().pop();
+            let Some(msg) = next else {
+                break;
+            };
+            let header = format!("Content-Length: {}\r\n\r\n{}", msg.len(), msg);
+            writer.write_all(header.as_bytes()).await?;
+        }
         writer.flush().await?;
         
         Ok(())
     }
 
+    fn queue(&self) -> std::sync::MutexGuard<'_, WriteQueue> {
+        // The queue stays consistent even if a holder panicked
+        self.write_queue.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    // Graceful shutdown: a `shutdown` request followed by the `exit` notification.
+    // The process is killed if it doesn't exit on its own. A server reached over
+    // TCP may be shared, so it isn't asked to shut down and only the connection closes.
+    pub async fn shutdown(&self) -> Result<()> {
+        if self.process.lock().await.is_none() {
+            self.writer.lock().await.shutdown().await?;
+            return Ok(());
+        }
+
+        match tokio::time::timeout(SHUTDOWN_TIMEOUT, self.send_request("shutdown", Value::Null)).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => eprintln!("LSP shutdown request failed: {}", e),
+            Err(_) => eprintln!("LSP shutdown request timed out"),
+        }
+
+        let mut process = self.process.lock().await;
+        let Some(process) = process.as_mut() else {
+            return Ok(());
+        };
+        if let Err(e) = self.send_notification("exit", Value::Null).await {
+            eprintln!("Failed to send LSP exit notification: {}", e);
+        }
+        if tokio::time::timeout(SHUTDOWN_TIMEOUT, process.wait()).await.is_err() {
+            eprintln!("LSP server did not exit, killing it");
+            process.kill().await?;
+        }
+        Ok(())
+    }
+
+    pub async fn has_exited(&self) -> bool {
+        match self.process.lock().await.as_mut() {
+            Some(process) => !matches!(process.try_wait(), Ok(None)),
+            None => self.disconnected.load(Ordering::SeqCst),
+        }
+    }
+
+    pub async fn process_id(&self) -> Option<u32> {
+        self.process.lock().await.as_ref().and_then(Child::id)
+    }
+
+    // Whether the server reported work in progress, its answers may be incomplete until it is done
+    pub async fn is_busy(&self) -> bool {
+        !self.active_progress.read().await.is_empty()
+    }
+
+    pub async fn capabilities(&self) -> Option<ServerCapabilities> {
+        self.server_capabilities.read().await.clone()
+    }
+
+    #[cfg(test)]
+    pub async fn set_capabilities(&self, capabilities: ServerCapabilities) {
+        *self.server_capabilities.write().await = Some(capabilities);
+    }
+
+    // What the server counts position columns in, UTF-16 unless it chose otherwise
+    pub async fn position_encoding(&self) -> PositionEncodingKind {
+        self.server_capabilities
+            .read()
+            .await
+            .as_ref()
+            .and_then(|capabilities| capabilities.position_encoding.clone())
+            .unwrap_or(PositionEncodingKind::UTF16)
+    }
+
+    // Waits for any message currently being written and flushes it to the server
+    pub async fn flush(&self) -> Result<()> {
+        self.writer.lock().await.flush().await?;
+        Ok(())
+    }
+
     async fn handle_messages(&self) -> Result<()> {
         loop {
             match self.message_handler.read_message().await {
@@ -227,21 +373,28 @@ impl LspServer {
 
                     println!("Received message: {:?}", parsed);  // Debug log
 
-                    if let Some(id) = parsed.get("id").and_then(|id| id.as_u64()) {
+                    if let Some(method) = parsed.get("method").and_then(|m| m.as_str()) {
+                        if let Some(id) = parsed.get("id") {
+                            // This is a request from the server
+                            self.handle_server_request(id.clone(), method, parsed.get("params"))
+                                .await?;
+                        } else {
+                            // This is a notification
+                            self.handle_notification(parsed).await?;
+                        }
+                    } else if let Some(id) = parsed.get("id").and_then(|id| id.as_u64()) {
                         // This is a response
                         if let Some(sender) = self.pending_requests.write().await.remove(&id) {
-                            if let Some(error) = parsed.get("error") {
-                                eprintln!("LSP error response: {:?}", error);
+                            if let Some(error) = LspError::from_response(&parsed) {
+                                eprintln!("LSP error response for request {}: {}", id, error);
                             }
                             let _ = sender.send(parsed);
                         }
-                    } else if parsed.get("method").is_some() {
-                        // This is a notification
-                        self.handle_notification(parsed).await?;
                     }
                 },
                 Err(e) => {
                     eprintln!("Error reading message: {}", e);
+                    self.disconnected.store(true, Ordering::SeqCst);
                     return Err(e);
                 }
             }
@@ -249,6 +402,17 @@ impl LspServer {
     }
 
     pub async fn send_request(&self, method: &str, params: Value) -> Result<Value> {
+        self.send_cancellable_request(method, params, None).await
+    }
+
+    // Like `send_request`, but cancelling the token drops the waiter, sends
+    // `$/cancelRequest` to the server and fails with a RequestCancelled LspError
+    pub async fn send_cancellable_request(
+        &self,
+        method: &str,
+        params: Value,
+        cancel: Option<&CancellationToken>,
+    ) -> Result<Value> {
         let id = self.request_counter.fetch_add(1, Ordering::SeqCst);
         
         let request = serde_json::json!({
@@ -260,26 +424,144 @@ impl LspServer {
 
         // Use oneshot channel for this specific request
         let (response_tx, response_rx) = tokio::sync::oneshot::channel();
-        self.pending_requests.write().await.insert(id, response_tx);
+        {
+            let mut pending_requests = self.pending_requests.write().await;
+            if pending_requests.len() >= self.max_pending_requests {
+                return Err(LspError::busy(self.max_pending_requests).into());
+            }
+            pending_requests.insert(id, response_tx);
+        }
 
         // Send the request
-        self.send_message(request.to_string()).await?;
+        self.write_message(request.to_string(), WritePriority::for_request(method))
+            .await?;
+
+        let cancelled = async {
+            match cancel {
+                Some(token) => token.cancelled().await,
+                None => std::future::pending().await,
+            }
+        };
 
         // Wait for response with timeout
-        match tokio::time::timeout(std::time::Duration::from_secs(30), response_rx).await {
+        let response = tokio::select! {
+            response = tokio::time::timeout(std::time::Duration::from_secs(30), response_rx) => response,
+            _ = cancelled => {
+                self.pending_requests.write().await.remove(&id);
+                self.send_notification("$/cancelRequest", serde_json::json!({ "id": id }))
+                    .await?;
+                return Err(LspError::request_cancelled().into());
+            }
+        };
+
+        match response {
             Ok(Ok(response)) => Ok(response),
             Ok(Err(_)) => Err(anyhow::anyhow!("Response channel closed")),
-            Err(_) => Err(anyhow::anyhow!("Request timed out")),
+            Err(_) => {
+                self.pending_requests.write().await.remove(&id);
+                Err(anyhow::anyhow!("Request timed out"))
+            }
         }
     }
 
     
 
+    async fn handle_server_request(
+        &self,
+        id: Value,
+        method: &str,
+        params: Option<&Value>,
+    ) -> Result<()> {
+        println!("Received server request: {}", method);
+
+        let response = match method {
+            "workspace/configuration" => {
+                let settings = self.settings.read().await;
+                let items = params
+                    .and_then(|p| p.get("items"))
+                    .and_then(|items| items.as_array())
+                    .map(|items| items.as_slice())
+                    .unwrap_or_default();
+
+                let result: Vec<Value> = items
+                    .iter()
+                    .map(|item| {
+                        let section = item.get("section").and_then(|s| s.as_str());
+                        lookup_setting(settings.as_ref(), section)
+                    })
+                    .collect();
+
+                serde_json::json!({ "jsonrpc": "2.0", "id": id, "result": result })
+            }
+            "client/registerCapability"
+            | "client/unregisterCapability"
+            | "window/workDoneProgress/create" => {
+                serde_json::json!({ "jsonrpc": "2.0", "id": id, "result": null })
+            }
+            _ => serde_json::json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": {
+                    "code": METHOD_NOT_FOUND,
+                    "message": format!("Unsupported request: {}", method)
+                }
+            }),
+        };
+
+        self.send_message(response.to_string()).await
+    }
+
+    // Replaces the server settings and tells the server to pull them again
+    pub async fn update_settings(&self, settings: Value) -> Result<()> {
+        *self.settings.write().await = Some(settings.clone());
+        self.send_notification(
+            "workspace/didChangeConfiguration",
+            serde_json::json!({ "settings": settings }),
+        )
+        .await
+    }
+
     async fn handle_notification(&self, notification: Value) -> Result<()> {
         if let Some(method) = notification.get("method").and_then(|m| m.as_str()) {
             match method {
                 "textDocument/publishDiagnostics" => {
                     println!("Received diagnostics: {:?}", notification);
+                    let mut params = notification.get("params").cloned().unwrap_or_default();
+                    if let Some(uri) = params.get("uri").and_then(Value::as_str).map(String::from) {
+                        self.positions
+                            .open_document_to_client(&mut params, &uri, &self.position_encoding().await)
+                            .await;
+                    }
+                    match serde_json::from_value::<PublishDiagnosticsParams>(params) {
+                        Ok(params) => {
+                            let _ = self.diagnostics.send(params);
+                        }
+                        Err(e) => eprintln!("Invalid publishDiagnostics params: {}", e),
+                    }
+                }
+                "$/progress" => {
+                    let params = notification.get("params");
+                    let token = params.and_then(|p| p.get("token")).map(Value::to_string);
+                    let value = params.and_then(|p| p.get("value"));
+                    let kind = value.and_then(|value| value.get("kind")).and_then(Value::as_str);
+                    let title = value.and_then(|value| value.get("title")).and_then(Value::as_str);
+                    if let Some(token) = token {
+                        match kind {
+                            Some("begin")
+                                if !self.quiescent.load(Ordering::SeqCst)
+                                    || title.is_some_and(is_indexing_title) =>
+                            {
+                                self.active_progress.write().await.insert(token);
+                            }
+                            Some("end") => {
+                                let mut active = self.active_progress.write().await;
+                                if active.remove(&token) && active.is_empty() {
+                                    self.quiescent.store(true, Ordering::SeqCst);
+                                }
+                            }
+                            _ => {}
+                        }
+                    }
                 }
                 _ => {
                     println!("Received notification: {}", method);
@@ -290,6 +572,9 @@ impl LspServer {
     }
 
     pub async fn send_notification(&self, method: &str, params: Value) -> Result<()> {
+        // A malformed notification can crash the server, so reject it here instead
+        validate_notification(method, &params)?;
+
         let notification = serde_json::json!({
             "jsonrpc": "2.0",
             "method": method,
@@ -301,9 +586,163 @@ impl LspServer {
 }
 
 impl Drop for LspServer {
+    // A server dropped without shutdown(), e.g. along with its manager, is still
+    // told to exit. kill_on_drop stops it if it doesn't in time or no runtime is left.
     fn drop(&mut self) {
-        // Best effort to shutdown gracefully
-        let _ = self.send_notification("shutdown", serde_json::json!({}));
-        let _ = self.send_notification("exit", serde_json::json!({}));
+        let Some(mut process) = self.process.get_mut().take() else {
+            return;
+        };
+        if !matches!(process.try_wait(), Ok(None)) {
+            return;
+        }
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let writer = Arc::clone(&self.writer);
+        runtime.spawn(async move {
+            let exit = serde_json::json!({ "jsonrpc": "2.0", "method": "exit" }).to_string();
+            let mut writer = writer.lock().await;
+            let header = format!("Content-Length: {}\r\n\r\n{}", exit.len(), exit);
+            if writer.write_all(header.as_bytes()).await.is_ok() && writer.flush().await.is_ok() {
+                let _ = tokio::time::timeout(SHUTDOWN_TIMEOUT, process.wait()).await;
+            }
+        });
+    }
+}
+
+// Resolves a dotted configuration section against the settings object. Servers
+// usually ask for their own top-level section (e.g. "rust-analyzer"), so the
+// settings stand in for a first key they don't have. Anything else missing is
+// null.
+fn lookup_setting(settings: Option<&Value>, section: Option<&str>) -> Value {
+    let Some(settings) = settings else {
+        return Value::Null;
+    };
+    let Some(section) = section else {
+        return settings.clone();
+    };
+
+    let mut keys = section.split('.');
+    let top = keys.next().and_then(|key| settings.get(key)).unwrap_or(settings);
+    keys.try_fold(top, |value, key| value.get(key))
+        .cloned()
+        .unwrap_or(Value::Null)
+}
+
+// Progress reported after startup only counts as busy when it reindexes, e.g.
+// rust-analyzer's "Indexing" or jdtls's "Loading workspace"
+fn is_indexing_title(title: &str) -> bool {
+    let title = title.to_lowercase();
+    title.contains("index") || title.contains("loading")
+}
+
+// Fields the spec marks as required for the document sync notifications we send
+fn validate_notification(method: &str, params: &Value) -> Result<()> {
+    let required: &[&str] = match method {
+        "textDocument/didOpen" => &[
+            "textDocument.uri",
+            "textDocument.languageId",
+            "textDocument.version",
+            "textDocument.text",
+        ],
+        "textDocument/didChange" => &["textDocument.uri", "textDocument.version", "contentChanges"],
+        "textDocument/didSave" | "textDocument/didClose" => &["textDocument.uri"],
+        _ => &[],
+    };
+
+    for field in required {
+        let value = field
+            .split('.')
+            .try_fold(params, |value, key| value.get(key));
+        if value.is_none_or(Value::is_null) {
+            anyhow::bail!("Refusing to send {}: missing field {}", method, field);
+        }
     }
-}
\ No newline at end of file
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_missing_setting_sections_are_null() {
+        let settings = serde_json::json!({ "cargo": { "features": "all" } });
+        let lookup = |section| lookup_setting(Some(&settings), Some(section));
+        assert_eq!(lookup("rust-analyzer"), settings);
+        assert_eq!(lookup("rust-analyzer.cargo.features"), "all");
+        assert_eq!(lookup("cargo.features"), "all");
+        assert_eq!(lookup("rust-analyzer.checkOnSave"), Value::Null);
+        assert_eq!(lookup("cargo.target"), Value::Null);
+    }
+
+    #[test]
+    fn test_did_open_requires_language_id() {
+        let mut params = serde_json::json!({
+            "textDocument": {
+                "uri": "file:///workspace/main.rs",
+                "languageId": "rust",
+                "version": 0,
+                "text": "fn main() {}"
+            }
+        });
+        assert!(validate_notification("textDocument/didOpen", &params).is_ok());
+
+        params["textDocument"].as_object_mut().unwrap().remove("languageId");
+        let err = validate_notification("textDocument/didOpen", &params).unwrap_err();
+        assert!(err.to_string().contains("languageId"));
+
+        // Methods without known requirements pass through untouched
+        assert!(validate_notification("initialized", &serde_json::json!({})).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_queued_hover_is_written_before_inlay_hints() -> Result<()> {
+        use crate::lsp::completion_cache::CompletionCacheConfig;
+        use crate::lsp::lsp_manager::LspManager;
+        use crate::lsp::test_support::{fake_tcp_server, tcp_config};
+        use crate::lsp::types::{LspStartPolicy, DEFAULT_MAX_PENDING_REQUESTS};
+
+        let workspace = tempfile::tempdir()?;
+        let (port, mut received) = fake_tcp_server(&[]).await?;
+        let manager = LspManager::new(
+            workspace.path().to_path_buf(),
+            vec![tcp_config(port)],
+            None,
+            DEFAULT_MAX_PENDING_REQUESTS,
+            LspStartPolicy::default(),
+            CompletionCacheConfig::default(),
+        );
+        let server = manager
+            .get_server(&workspace.path().join("main.fake"))
+            .await?
+            .expect("the fake server is configured");
+
+        // Both requests are queued while the writer is busy
+        let writer = server.writer.lock().await;
+        let requests: Vec<_> = ["textDocument/inlayHint", "textDocument/hover"]
+            .into_iter()
+            .map(|method| {
+                let server = Arc::clone(&server);
+                tokio::spawn(async move { server.send_request(method, Value::Null).await })
+            })
+            .collect();
+        while server.queue().len() < 2 {
+            tokio::task::yield_now().await;
+        }
+        drop(writer);
+        for request in requests {
+            request.await??;
+        }
+
+        let mut written = Vec::new();
+        while written.len() < 2 {
+            let message = received.recv().await.expect("the server stays connected");
+            if let Some(method @ ("textDocument/inlayHint" | "textDocument/hover")) = message["method"].as_str() {
+                written.push(method.to_string());
+            }
+        }
+        assert_eq!(written, ["textDocument/hover", "textDocument/inlayHint"]);
+        Ok(())
+    }
+}
@@ -0,0 +1,111 @@
+// src/lsp/markdown.rs
+use lsp_types::{Hover, HoverContents, MarkedString, MarkupContent, MarkupKind};
+use pulldown_cmark::{Event, Parser, Tag, TagEnd};
+
+// Renders markdown as readable plain text: markup is dropped, code is kept
+// verbatim, blocks are separated by blank lines and list items get a bullet
+pub fn markdown_to_plain_text(markdown: &str) -> String {
+    let mut text = String::new();
+    // Numbers of the ordered lists we are in, None for bullet lists
+    let mut lists: Vec<Option<u64>> = Vec::new();
+
+    for event in Parser::new(markdown) {
+        match event {
+            Event::Start(Tag::Paragraph | Tag::Heading { .. } | Tag::CodeBlock(_) | Tag::BlockQuote(_))
+                if lists.is_empty() =>
+            {
+                start_block(&mut text);
+            }
+            Event::Start(Tag::List(start)) => {
+                if lists.is_empty() {
+                    start_block(&mut text);
+                }
+                lists.push(start);
+            }
+            Event::End(TagEnd::List(_)) => {
+                lists.pop();
+            }
+            Event::Start(Tag::Item) => {
+                if !text.is_empty() && !text.ends_with('\n') {
+                    text.push('\n');
+                }
+                text.push_str(&"  ".repeat(lists.len().saturating_sub(1)));
+                match lists.last_mut() {
+                    Some(Some(number)) => {
+                        text.push_str(&format!("{}. ", number));
+                        *number += 1;
+                    }
+                    _ => text.push_str("- "),
+                }
+            }
+            Event::Text(content) | Event::Code(content) => text.push_str(&content),
+            Event::SoftBreak | Event::HardBreak => text.push('\n'),
+            Event::Rule => {
+                start_block(&mut text);
+                text.push_str("---");
+            }
+            _ => {}
+        }
+    }
+    text.trim_end().to_string()
+}
+
+fn start_block(text: &mut String) {
+    let trimmed = text.trim_end_matches('\n').len();
+    text.truncate(trimmed);
+    if !text.is_empty() {
+        text.push_str("\n\n");
+    }
+}
+
+// The hover with all markdown contents turned into plain text
+pub fn hover_to_plain_text(hover: Hover) -> Hover {
+    let plain = |marked: MarkedString| match marked {
+        MarkedString::String(markdown) => markdown_to_plain_text(&markdown),
+        MarkedString::LanguageString(code) => code.value,
+    };
+    let value = match hover.contents {
+        HoverContents::Scalar(marked) => plain(marked),
+        HoverContents::Array(marked) => marked.into_iter().map(plain).collect::<Vec<_>>().join("\n\n"),
+        HoverContents::Markup(MarkupContent {
+            kind: MarkupKind::Markdown,
+            value,
+        }) => markdown_to_plain_text(&value),
+        HoverContents::Markup(MarkupContent { value, .. }) => value,
+    };
+    Hover {
+        contents: HoverContents::Markup(MarkupContent {
+            kind: MarkupKind::PlainText,
+            value,
+        }),
+        range: hover.range,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_markdown_hover_becomes_plain_text() {
+        let hover = Hover {
+            contents: HoverContents::Markup(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value: "```rust\nfn len(&self) -> usize\n```\n\n---\n\n# Length\nReturns the **number** of \
+                        [elements](https://doc.rust-lang.org), see `Vec::len`.\n\n* one\n* two\n\n1. first\n2. second"
+                    .to_string(),
+            }),
+            range: None,
+        };
+
+        let HoverContents::Markup(content) = hover_to_plain_text(hover).contents else {
+            panic!("expected markup contents");
+        };
+        assert_eq!(content.kind, MarkupKind::PlainText);
+        assert_eq!(
+            content.value,
+            "fn len(&self) -> usize\n\n---\n\nLength\n\nReturns the number of elements, see Vec::len.\n\n\
+             - one\n- two\n\n1. first\n2. second"
+        );
+    }
+}
@@ -2,3 +2,10 @@ pub mod lsp_manager;
 pub mod lsp_server;
 pub mod types;
 pub mod capabilities;
+pub mod completion_cache;
+pub mod file_operations;
+pub mod markdown;
+pub mod position_encoding;
+pub mod request_queue;
+#[cfg(test)]
+pub mod test_support;
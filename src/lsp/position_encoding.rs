@@ -0,0 +1,410 @@
+// src/lsp/position_encoding.rs
+use anyhow::Result;
+use futures::future::BoxFuture;
+use lsp_types::{Position, PositionEncodingKind};
+use serde_json::{Map, Value};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use super::lsp_manager::OpenPaths;
+use super::types::LspError;
+
+// Current content of a file, unsaved edits included, to convert positions for
+// servers that don't count in UTF-16
+pub type DocumentContent = Arc<dyn Fn(PathBuf) -> BoxFuture<'static, Result<String>> + Send + Sync>;
+
+// Positions exchanged with clients count UTF-16 code units, LSP's default
+pub fn client_encoding() -> PositionEncodingKind {
+    PositionEncodingKind::UTF16
+}
+
+// Encodings offered to servers, most preferred first
+pub fn supported_encodings() -> Vec<PositionEncodingKind> {
+    vec![
+        PositionEncodingKind::UTF8,
+        PositionEncodingKind::UTF16,
+        PositionEncodingKind::UTF32,
+    ]
+}
+
+// Width of `ch` in the units `encoding` counts. Unknown encodings are counted
+// as UTF-16, which servers must support.
+fn units(ch: char, encoding: &PositionEncodingKind) -> u32 {
+    if *encoding == PositionEncodingKind::UTF8 {
+        ch.len_utf8() as u32
+    } else if *encoding == PositionEncodingKind::UTF32 {
+        1
+    } else {
+        ch.len_utf16() as u32
+    }
+}
+
+// Byte offset within `line` of column `character`. A column inside a character
+// moves to its end, one past the end of the line to the end of the line.
+pub fn column_to_byte(line: &str, character: u32, encoding: &PositionEncodingKind) -> usize {
+    let mut column = 0;
+    for (index, ch) in line.char_indices() {
+        if column >= character {
+            return index;
+        }
+        column += units(ch, encoding);
+    }
+    line.len()
+}
+
+// Column of the byte offset `byte` within `line`
+pub fn byte_to_column(line: &str, byte: usize, encoding: &PositionEncodingKind) -> u32 {
+    line.char_indices()
+        .take_while(|(index, _)| *index < byte)
+        .map(|(_, ch)| units(ch, encoding))
+        .sum()
+}
+
+// `position` in `content` with its column counted in `to` instead of `from`.
+// Lines past the end of the content are left as they are.
+pub fn convert_position(
+    content: &str,
+    position: Position,
+    from: &PositionEncodingKind,
+    to: &PositionEncodingKind,
+) -> Position {
+    if from == to {
+        return position;
+    }
+    match content.split('\n').nth(position.line as usize) {
+        Some(line) => convert_on_line(line, position, from, to),
+        None => position,
+    }
+}
+
+fn convert_on_line(line: &str, position: Position, from: &PositionEncodingKind, to: &PositionEncodingKind) -> Position {
+    let byte = column_to_byte(line, position.character, from);
+    Position {
+        line: position.line,
+        character: byte_to_column(line, byte, to),
+    }
+}
+
+// A client position checked against the document: a column past the end of
+// its line moves to the end of the line (before the `\r` of a CRLF line), a
+// line the document doesn't have is an error
+pub fn validate_position(content: &str, position: Position) -> Result<Position, LspError> {
+    let line_count = content.split('\n').count();
+    let Some(line) = content.split('\n').nth(position.line as usize) else {
+        return Err(LspError::invalid_position(position, line_count));
+    };
+    let line = line.strip_suffix('\r').unwrap_or(line);
+    let line_end = byte_to_column(line, line.len(), &client_encoding());
+    Ok(Position {
+        line: position.line,
+        character: position.character.min(line_end),
+    })
+}
+
+// The closest position the document has: past the last line moves to the end
+// of the document, past the end of a line to the end of the line. Also tells
+// whether the position had to move. A CRLF line ends before its `\r`.
+pub fn clamp_position(content: &str, position: Position) -> (Position, bool) {
+    let lines: Vec<&str> = content.split('\n').collect();
+    let last_line = lines.len() - 1;
+    let line_number = (position.line as usize).min(last_line);
+    let line = lines[line_number].strip_suffix('\r').unwrap_or(lines[line_number]);
+    let line_end = byte_to_column(line, line.len(), &client_encoding());
+    let clamped = match position.line as usize > last_line {
+        true => Position::new(last_line as u32, line_end),
+        false => Position::new(position.line, position.character.min(line_end)),
+    };
+    (clamped, clamped != position)
+}
+
+// Converts positions between clients and a server that negotiated an encoding
+// other than theirs, reading the documents they are in
+#[derive(Clone, Default)]
+pub struct PositionConverter {
+    document_content: Option<DocumentContent>,
+    // Documents open in the editor, see `open_document_to_client`
+    open_paths: Option<OpenPaths>,
+}
+
+impl PositionConverter {
+    pub fn new(document_content: Option<DocumentContent>, open_paths: Option<OpenPaths>) -> Self {
+        Self {
+            document_content,
+            open_paths,
+        }
+    }
+
+    // A client position in `path`, validated against the document and
+    // converted for a server counting in `encoding`
+    pub async fn to_server(
+        &self,
+        path: &Path,
+        position: Position,
+        encoding: &PositionEncodingKind,
+    ) -> Result<Position, LspError> {
+        let Some(content) = self.content(path).await else {
+            return Ok(position);
+        };
+        let position = validate_position(&content, position)?;
+        Ok(convert_position(&content, position, &client_encoding(), encoding))
+    }
+
+    // Rewrites every position in a server response or notification for the
+    // client. A position is in the document named by the closest enclosing
+    // `uri`, `targetUri`, `textDocument.uri` or `changes` key, otherwise in
+    // `default_uri`.
+    pub async fn to_client(&self, value: &mut Value, default_uri: Option<&str>, encoding: &PositionEncodingKind) {
+        if *encoding == client_encoding() {
+            return;
+        }
+
+        let mut uris = HashSet::new();
+        visit_positions(value, default_uri, &mut |uri, _| {
+            if let Some(uri) = uri {
+                uris.insert(uri.to_string());
+            }
+        });
+        let mut contents = HashMap::new();
+        for uri in uris {
+            let Some(path) = url::Url::parse(&uri).ok().and_then(|url| url.to_file_path().ok()) else {
+                continue;
+            };
+            if let Some(content) = self.content(&path).await {
+                contents.insert(uri, content);
+            }
+        }
+        let lines: HashMap<&str, Vec<&str>> = contents
+            .iter()
+            .map(|(uri, content)| (uri.as_str(), content.split('\n').collect()))
+            .collect();
+
+        visit_positions(value, default_uri, &mut |uri, object| {
+            let Some(lines) = uri.and_then(|uri| lines.get(uri)) else {
+                return;
+            };
+            let Ok(position) = serde_json::from_value::<Position>(Value::Object(object.clone())) else {
+                return;
+            };
+            if let Some(line) = lines.get(position.line as usize) {
+                let converted = convert_on_line(line, position, encoding, &client_encoding());
+                object.insert("character".to_string(), converted.character.into());
+            }
+        });
+    }
+
+    // `to_client` for a notification the server sent on its own about the
+    // document at `uri`, e.g. diagnostics. Servers can publish those for every
+    // file in the workspace, so only open documents are read to convert them.
+    pub async fn open_document_to_client(&self, value: &mut Value, uri: &str, encoding: &PositionEncodingKind) {
+        if *encoding == client_encoding() {
+            return;
+        }
+        let Some(path) = url::Url::parse(uri).ok().and_then(|url| url.to_file_path().ok()) else {
+            return;
+        };
+        let Some(open_paths) = &self.open_paths else {
+            return;
+        };
+        if open_paths().await.contains(&path) {
+            self.to_client(value, Some(uri), encoding).await;
+        }
+    }
+
+    async fn content(&self, path: &Path) -> Option<String> {
+        let document_content = self.document_content.as_ref()?;
+        match document_content(path.to_path_buf()).await {
+            Ok(content) => Some(content),
+            Err(e) => {
+                eprintln!("Failed to read {:?} to convert positions: {}", path, e);
+                None
+            }
+        }
+    }
+}
+
+fn is_position(object: &Map<String, Value>) -> bool {
+    object.len() == 2
+        && object.get("line").is_some_and(Value::is_u64)
+        && object.get("character").is_some_and(Value::is_u64)
+}
+
+fn object_uri(object: &Map<String, Value>) -> Option<String> {
+    object
+        .get("uri")
+        .or_else(|| object.get("targetUri"))
+        .or_else(|| object.get("textDocument").and_then(|document| document.get("uri")))
+        .and_then(Value::as_str)
+        .map(String::from)
+}
+
+// Calls `visit` with every position object in `value` and the URI of its document
+fn visit_positions(
+    value: &mut Value,
+    uri: Option<&str>,
+    visit: &mut impl FnMut(Option<&str>, &mut Map<String, Value>),
+) {
+    match value {
+        Value::Array(items) => {
+            for item in items {
+                visit_positions(item, uri, visit);
+            }
+        }
+        Value::Object(object) if is_position(object) => visit(uri, object),
+        Value::Object(object) => {
+            let own_uri = object_uri(object);
+            let outer_uri = uri;
+            let uri = own_uri.as_deref().or(outer_uri);
+            for (key, child) in object.iter_mut() {
+                match (key.as_str(), child) {
+                    // WorkspaceEdit.changes is keyed by document
+                    ("changes", Value::Object(changes)) => {
+                        for (change_uri, edits) in changes.iter_mut() {
+                            visit_positions(edits, Some(change_uri), visit);
+                        }
+                    }
+                    // A LocationLink's origin is in the document that was asked about
+                    ("originSelectionRange", child) => visit_positions(child, outer_uri, visit),
+                    (_, child) => visit_positions(child, uri, visit),
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_utf16_and_utf8_columns_around_emoji_and_accents() {
+        // é is 2 UTF-8 bytes and 1 UTF-16 unit, 🦀 is 4 bytes and 2 units
+        let content = "fn main() {}\nlet café = \"🦀\"; crab";
+        let utf8 = PositionEncodingKind::UTF8;
+        let utf16 = PositionEncodingKind::UTF16;
+        let at = |line, character| Position { line, character };
+
+        // `=` after café
+        assert_eq!(convert_position(content, at(1, 9), &utf16, &utf8), at(1, 10));
+        // `crab` after the emoji
+        assert_eq!(convert_position(content, at(1, 17), &utf16, &utf8), at(1, 20));
+        assert_eq!(convert_position(content, at(1, 20), &utf8, &utf16), at(1, 17));
+        // ASCII lines are the same in both
+        assert_eq!(convert_position(content, at(0, 3), &utf16, &utf8), at(0, 3));
+
+        // Halfway into the emoji lands after it, past the line end at its end
+        let line = "\"🦀\"";
+        assert_eq!(column_to_byte(line, 2, &utf16), 5);
+        assert_eq!(column_to_byte(line, 40, &utf16), line.len());
+        assert_eq!(byte_to_column(line, 5, &utf16), 3);
+        assert_eq!(convert_position(content, at(7, 2), &utf16, &utf8), at(7, 2));
+    }
+
+    #[test]
+    fn test_positions_are_clamped_to_the_document() {
+        let content = "let café = 1;\n\nfn main() {}\n";
+        let at = |line, character| Position { line, character };
+
+        assert_eq!(validate_position(content, at(0, 4)).unwrap(), at(0, 4));
+        // Columns count UTF-16 units, so the line ends at 13
+        assert_eq!(validate_position(content, at(0, 99)).unwrap(), at(0, 13));
+        assert_eq!(validate_position(content, at(1, 3)).unwrap(), at(1, 0));
+        // The empty line after the final newline still exists
+        assert_eq!(validate_position(content, at(3, 0)).unwrap(), at(3, 0));
+        assert_eq!(validate_position("fn main\r\n}\r", at(0, 99)).unwrap(), at(0, 7));
+        assert_eq!(validate_position("fn main\r\n}\r", at(1, 1)).unwrap(), at(1, 1));
+
+        let error = validate_position(content, at(4, 0)).unwrap_err();
+        assert_eq!(error.code, crate::lsp::types::INVALID_POSITION);
+        assert_eq!(error.data, Some(serde_json::json!({ "line_count": 4 })));
+
+        // Clamping moves lines past the end to the end of the document instead
+        assert_eq!(clamp_position(content, at(2, 3)), (at(2, 3), false));
+        assert_eq!(clamp_position(content, at(0, 99)), (at(0, 13), true));
+        assert_eq!(clamp_position(content, at(9, 2)), (at(3, 0), true));
+        assert_eq!(clamp_position("fn é", at(5, 0)), (at(0, 4), true));
+        // The end of a CRLF line is before the \r
+        assert_eq!(clamp_position("fn main\r\n}\r", at(0, 99)), (at(0, 7), true));
+        assert_eq!(clamp_position("fn main\r\n}\r", at(4, 0)), (at(1, 1), true));
+        assert_eq!(clamp_position("fn main\r\n", at(0, 7)), (at(0, 7), false));
+    }
+
+    #[tokio::test]
+    async fn test_server_positions_are_converted_for_each_encoding() {
+        let documents: HashMap<PathBuf, &'static str> = HashMap::from([
+            (PathBuf::from("/ws/main.rs"), "let crab = \"🦀\"; x"),
+            (PathBuf::from("/ws/lib.rs"), "// café\nfn é() {}"),
+        ]);
+        let converter = PositionConverter::new(
+            Some(Arc::new(move |path: PathBuf| {
+                let content = documents.get(&path).map(|content| content.to_string());
+                Box::pin(async move { content.ok_or_else(|| anyhow::anyhow!("not open")) })
+            })),
+            None,
+        );
+
+        // Columns of `x` in main.rs and of `(` in lib.rs in each encoding
+        let columns = [
+            (PositionEncodingKind::UTF8, 20, 6),
+            (PositionEncodingKind::UTF16, 18, 5),
+            (PositionEncodingKind::UTF32, 17, 5),
+        ];
+        for (encoding, x, paren) in columns {
+            let at_x = Position { line: 0, character: 18 };
+            let sent = converter.to_server(Path::new("/ws/main.rs"), at_x, &encoding).await.unwrap();
+            assert_eq!(sent.character, x, "{:?}", encoding);
+
+            let mut response = serde_json::json!([
+                { "uri": "file:///ws/lib.rs", "range": {
+                    "start": { "line": 1, "character": paren },
+                    "end": { "line": 1, "character": paren },
+                } },
+                { "targetUri": "file:///ws/lib.rs",
+                  "targetRange": { "start": { "line": 1, "character": paren }, "end": { "line": 1, "character": paren } },
+                  "originSelectionRange": { "start": { "line": 0, "character": x }, "end": { "line": 0, "character": x } } },
+                { "changes": { "file:///ws/main.rs": [
+                    { "range": { "start": { "line": 0, "character": x }, "end": { "line": 0, "character": x } }, "newText": "" },
+                ] } },
+            ]);
+            converter.to_client(&mut response, Some("file:///ws/main.rs"), &encoding).await;
+            assert_eq!(response[0]["range"]["start"]["character"], 5, "{:?}", encoding);
+            assert_eq!(response[1]["targetRange"]["end"]["character"], 5, "{:?}", encoding);
+            assert_eq!(response[1]["originSelectionRange"]["start"]["character"], 18, "{:?}", encoding);
+            assert_eq!(response[2]["changes"]["file:///ws/main.rs"][0]["range"]["end"]["character"], 18);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_only_open_documents_are_read_for_diagnostics() {
+        let reads = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let converter = PositionConverter::new(
+            Some(Arc::new({
+                let reads = Arc::clone(&reads);
+                move |path: PathBuf| {
+                    reads.lock().unwrap().push(path);
+                    Box::pin(async { Ok("let crab = \"🦀\"; x".to_string()) })
+                }
+            })),
+            Some(Arc::new(|| Box::pin(async { vec![PathBuf::from("/ws/main.rs")] }))),
+        );
+        let diagnostics = |uri: &str| {
+            serde_json::json!({ "uri": uri, "diagnostics": [
+                { "range": { "start": { "line": 0, "character": 20 }, "end": { "line": 0, "character": 20 } } },
+            ] })
+        };
+
+        let mut open = diagnostics("file:///ws/main.rs");
+        converter
+            .open_document_to_client(&mut open, "file:///ws/main.rs", &PositionEncodingKind::UTF8)
+            .await;
+        assert_eq!(open["diagnostics"][0]["range"]["start"]["character"], 18);
+
+        let mut closed = diagnostics("file:///ws/lib.rs");
+        converter
+            .open_document_to_client(&mut closed, "file:///ws/lib.rs", &PositionEncodingKind::UTF8)
+            .await;
+        assert_eq!(closed["diagnostics"][0]["range"]["start"]["character"], 20);
+        assert_eq!(*reads.lock().unwrap(), vec![PathBuf::from("/ws/main.rs")]);
+    }
+}
@@ -0,0 +1,88 @@
+// src/lsp/request_queue.rs
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+// How urgently a message waiting for the server's input should be written
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum WritePriority {
+    // Requests a user is waiting on, e.g. a hover
+    Interactive,
+    // Requests that only decorate the editor, e.g. inlay hints
+    Background,
+    // Notifications and responses. Nothing queued is moved past them, so a
+    // request never reaches the server before an edit sent ahead of it, or
+    // after one sent behind it.
+    Barrier,
+}
+
+impl WritePriority {
+    pub fn for_request(method: &str) -> Self {
+        match method {
+            "textDocument/inlayHint"
+            | "textDocument/foldingRange"
+            | "textDocument/documentSymbol"
+            | "textDocument/documentColor"
+            | "textDocument/documentLink"
+            | "textDocument/codeLens" => WritePriority::Background,
+            _ if method.starts_with("textDocument/semanticTokens") => WritePriority::Background,
+            _ => WritePriority::Interactive,
+        }
+    }
+}
+
+// Messages waiting for the writer. Between two barriers interactive requests
+// go first; otherwise messages leave in the order they came.
+#[derive(Default)]
+pub struct WriteQueue {
+    queued: BinaryHeap<Reverse<(u64, WritePriority, u64, String)>>,
+    // Barriers queued so far, everything queued after one sorts after it
+    barriers: u64,
+    sequence: u64,
+}
+
+impl WriteQueue {
+    pub fn push(&mut self, message: String, priority: WritePriority) {
+        self.sequence += 1;
+        self.queued
+            .push(Reverse((self.barriers, priority, self.sequence, message)));
+        if priority == WritePriority::Barrier {
+            self.barriers += 1;
+        }
+    }
+
+    pub fn pop(&mut self) -> Option<String> {
+        self.queued.pop().map(|Reverse((_, _, _, message))| message)
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.queued.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_interactive_requests_overtake_background_ones_between_barriers() {
+        let mut queue = WriteQueue::default();
+        let mut push = |message: &str, method: Option<&str>| {
+            let priority = method.map_or(WritePriority::Barrier, WritePriority::for_request);
+            queue.push(message.to_string(), priority);
+        };
+        // Queued while the writer was busy
+        push("tokens", Some("textDocument/semanticTokens/full"));
+        push("hints", Some("textDocument/inlayHint"));
+        push("hover", Some("textDocument/hover"));
+        push("didChange", None);
+        push("folding", Some("textDocument/foldingRange"));
+        push("completion", Some("textDocument/completion"));
+
+        let written: Vec<String> = std::iter::from_fn(|| queue.pop()).collect();
+        assert_eq!(
+            written,
+            ["hover", "tokens", "hints", "didChange", "completion", "folding"]
+        );
+    }
+}
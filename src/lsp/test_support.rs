@@ -0,0 +1,116 @@
+// src/lsp/test_support.rs
+// Fake language servers for tests
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use anyhow::Result;
+
+use crate::lsp::types::{LspConfiguration, LspTransport};
+
+// Answers every request with a null result and exits on the exit
+// notification. `on_initialized` and `on_save` run when those notifications
+// arrive, e.g. `progress begin <token> <title>` to report work in progress.
+pub fn fake_server(on_initialized: &str, on_save: &str) -> String {
+    format!(
+        r#"
+send() {{ printf 'Content-Length: %d\r\n\r\n%s' "${{#1}}" "$1"; }}
+progress() {{ send "{{\"jsonrpc\":\"2.0\",\"method\":\"\$/progress\",\"params\":{{\"token\":\"$2\",\"value\":{{\"kind\":\"$1\",\"title\":\"$3\"}}}}}}"; }}
+while true; do
+    len=0
+    while read -r line; do
+        line=$(printf '%s' "$line" | tr -d '\r')
+        [ -z "$line" ] && break
+        case "$line" in Content-Length:*) len=${{line#Content-Length: }} ;; esac
+    done
+    [ "$len" -eq 0 ] && exit 0
+    body=$(dd bs=1 count="$len" 2>/dev/null)
+    case "$body" in
+        *'"method":"exit"'*) exit 0 ;;
+        *'"method":"initialized"'*) {on_initialized} ;;
+        *'"method":"textDocument/didSave"'*) {on_save} ;;
+    esac
+    id=$(printf '%s' "$body" | sed -n 's/^{{"id":\([0-9]*\),.*/\1/p')
+    [ -n "$id" ] && send "{{\"jsonrpc\":\"2.0\",\"id\":$id,\"result\":null}}"
+done
+"#
+    )
+}
+
+pub fn stdio_config(script: &str) -> LspConfiguration {
+    LspConfiguration {
+        name: "fake".to_string(),
+        file_extensions: vec!["fake".to_string()],
+        server_path: PathBuf::from("sh"),
+        server_args: vec!["-c".to_string(), script.to_string()],
+        initialization_options: None,
+        language_ids: HashMap::new(),
+        transport: LspTransport::Stdio,
+        restart_settings: vec![],
+    }
+}
+
+// Answers every request with a null result like `fake_server`, but over TCP,
+// and passes on every message it receives. Requests for `silent` methods
+// are never answered.
+pub async fn fake_tcp_server(
+    silent: &'static [&'static str],
+) -> Result<(u16, tokio::sync::mpsc::UnboundedReceiver<serde_json::Value>)> {
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port();
+    let (messages, received) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let messages = messages.clone();
+            tokio::spawn(async move {
+                let (read_half, mut write_half) = stream.into_split();
+                let mut reader = BufReader::new(read_half);
+                loop {
+                    let mut content_length = 0;
+                    loop {
+                        let mut line = String::new();
+                        if reader.read_line(&mut line).await? == 0 {
+                            return Ok::<_, anyhow::Error>(());
+                        }
+                        match line.trim().strip_prefix("Content-Length: ") {
+                            Some(length) => content_length = length.parse()?,
+                            None if line.trim().is_empty() => break,
+                            None => {}
+                        }
+                    }
+                    let mut body = vec![0; content_length];
+                    reader.read_exact(&mut body).await?;
+                    let message: serde_json::Value = serde_json::from_slice(&body)?;
+                    let method = message.get("method").and_then(|method| method.as_str());
+                    let answered = method.is_some_and(|method| !silent.contains(&method));
+                    if let (Some(id), true) = (message.get("id"), answered) {
+                        let reply = serde_json::json!({ "jsonrpc": "2.0", "id": id, "result": null }).to_string();
+                        write_half
+                            .write_all(format!("Content-Length: {}\r\n\r\n{}", reply.len(), reply).as_bytes())
+                            .await?;
+                    }
+                    let _ = messages.send(message);
+                }
+            });
+        }
+    });
+    Ok((port, received))
+}
+
+pub fn tcp_config(port: u16) -> LspConfiguration {
+    LspConfiguration {
+        name: "daemon".to_string(),
+        file_extensions: vec!["fake".to_string()],
+        server_path: PathBuf::new(),
+        server_args: vec![],
+        initialization_options: None,
+        language_ids: HashMap::new(),
+        transport: LspTransport::Tcp {
+            host: "127.0.0.1".to_string(),
+            port,
+        },
+        restart_settings: vec!["cargo".to_string()],
+    }
+}
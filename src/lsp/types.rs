@@ -1,17 +1,368 @@
-use std::path::PathBuf;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use anyhow::{Context, Result};
+use tokio::sync::Notify;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct LspConfiguration {
     pub name: String,
     pub file_extensions: Vec<String>,
+    // Unused for servers reached over TCP
+    #[serde(default)]
     pub server_path: PathBuf,
+    #[serde(default)]
     pub server_args: Vec<String>,
+    // Sent on initialize and answered to workspace/configuration requests
+    #[serde(default)]
     pub initialization_options: Option<serde_json::Value>,
+    // File extension -> LSP languageId (e.g. "rs" -> "rust")
+    #[serde(default)]
+    pub language_ids: HashMap<String, String>,
+    #[serde(default)]
+    pub transport: LspTransport,
+    // Dotted settings (e.g. "cargo.features") the server only reads on startup;
+    // changing one through UpdateLspConfig restarts the server
+    #[serde(default)]
+    pub restart_settings: Vec<String>,
+}
+
+// How the server is reached: a process started with `server_path` and
+// `server_args`, or a connection to a server that is already running
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum LspTransport {
+    #[default]
+    Stdio,
+    Tcp { host: String, port: u16 },
+}
+
+impl LspConfiguration {
+    pub fn defaults() -> Vec<Self> {
+        vec![
+            LspConfiguration {
+                name: "rust-analyzer".to_string(),
+                file_extensions: vec!["rs".to_string()],
+                server_path: PathBuf::from("rust-analyzer"),
+                server_args: vec![],
+                initialization_options: None,
+                language_ids: HashMap::from([("rs".to_string(), "rust".to_string())]),
+                transport: LspTransport::Stdio,
+                restart_settings: vec!["cargo".to_string(), "procMacro".to_string()],
+            },
+            // Add more language servers as needed
+        ]
+    }
+
+    // Whether going from `old` to `new` settings changes any of `restart_settings`
+    pub fn requires_restart(&self, old: Option<&Value>, new: &Value) -> bool {
+        let setting = |settings: Option<&Value>, path: &str| {
+            path.split('.')
+                .try_fold(settings?, |value, key| value.get(key))
+                .cloned()
+        };
+        self.restart_settings
+            .iter()
+            .any(|path| setting(old, path) != setting(Some(new), path))
+    }
+
+    // Falls back to the extension itself, which strict servers may reject
+    pub fn language_id(&self, extension: &str) -> String {
+        match self.language_ids.get(extension) {
+            Some(language_id) => language_id.clone(),
+            None => {
+                eprintln!(
+                    "Warning: no languageId mapped for extension '{}' in {}, using the extension",
+                    extension, self.name
+                );
+                extension.to_string()
+            }
+        }
+    }
+
+    // Reads a JSON array of server configurations
+    pub fn load_from_file(path: &Path) -> Result<Vec<Self>> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read LSP config: {:?}", path))?;
+        serde_json::from_str(&content)
+            .with_context(|| format!("Failed to parse LSP config: {:?}", path))
+    }
+}
+
+// What starts a language server that isn't running
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum LspStartTrigger {
+    // Opening one of its files. Requests start it only while one is open, so
+    // e.g. a hover over a closed file doesn't begin indexing the project.
+    #[default]
+    Open,
+    // Any request or notification about one of its files
+    Request,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LspStartPolicy {
+    pub trigger: LspStartTrigger,
+    // Servers wanted before this long after startup are started once it has
+    // passed, leaving the initial directory load to itself
+    pub delay: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum LspServerState {
+    // The server's executable wasn't found, features for its files do nothing
+    NotInstalled,
+    // The server was stopped and started again, e.g. for a settings change
+    Restarted,
+    // A client stopped the server, the next request for its files starts it again
+    Stopped,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LspStatus {
+    pub name: String,
+    pub state: LspServerState,
+}
+
+// Whether a started server is still up. One that exited is started again by
+// the next request for its files.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum LspProcessState {
+    Running,
+    Exited,
+}
+
+// A language server the manager has started, for ListLspServers
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LspServerInfo {
+    pub name: String,
+    pub state: LspProcessState,
+    // None for servers reached over TCP
+    pub process_id: Option<u32>,
+    pub open_documents: usize,
+}
+
+// Severity totals of the latest diagnostics published for a file
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct DiagnosticCounts {
+    pub errors: usize,
+    pub warnings: usize,
+}
+
+impl DiagnosticCounts {
+    pub fn from_diagnostics(diagnostics: &[lsp_types::Diagnostic]) -> Self {
+        let mut counts = Self::default();
+        for diagnostic in diagnostics {
+            match diagnostic.severity {
+                Some(lsp_types::DiagnosticSeverity::ERROR) => counts.errors += 1,
+                Some(lsp_types::DiagnosticSeverity::WARNING) => counts.warnings += 1,
+                _ => {}
+            }
+        }
+        counts
+    }
+}
+
+// JSON-RPC error code that has no constant in lsp_types::error_codes
+pub const METHOD_NOT_FOUND: i64 = -32601;
+// Our own code, from the range JSON-RPC leaves to implementations, for requests
+// refused because too many are already waiting for the server
+pub const LSP_BUSY: i64 = -32001;
+// Also ours, for positions on a line the document doesn't have
+pub const INVALID_POSITION: i64 = -32002;
+// And for requests the server couldn't answer properly while still indexing
+pub const LSP_NOT_READY: i64 = -32003;
+
+pub const DEFAULT_MAX_PENDING_REQUESTS: usize = 256;
+
+// Error object from a JSON-RPC response, kept intact so callers can act on the code
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LspError {
+    pub code: i64,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<Value>,
+}
+
+impl LspError {
+    // Returns the error carried by a response, if any
+    pub fn from_response(response: &Value) -> Option<Self> {
+        let error = response.get("error")?;
+        Some(serde_json::from_value(error.clone()).unwrap_or_else(|_| Self {
+            code: lsp_types::error_codes::UNKNOWN_ERROR_CODE,
+            message: error.to_string(),
+            data: None,
+        }))
+    }
+
+    // Returned to the caller of a request that was cancelled before it completed
+    pub fn request_cancelled() -> Self {
+        Self {
+            code: lsp_types::error_codes::REQUEST_CANCELLED,
+            message: "Request cancelled".to_string(),
+            data: None,
+        }
+    }
+
+    pub fn busy(max_pending_requests: usize) -> Self {
+        Self {
+            code: LSP_BUSY,
+            message: format!(
+                "Language server is busy, {} requests are already pending",
+                max_pending_requests
+            ),
+            data: None,
+        }
+    }
+
+    pub fn invalid_position(position: lsp_types::Position, line_count: usize) -> Self {
+        Self {
+            code: INVALID_POSITION,
+            message: format!(
+                "Line {} is past the end of the document, which has {} lines",
+                position.line + 1,
+                line_count
+            ),
+            data: Some(serde_json::json!({ "line_count": line_count })),
+        }
+    }
+
+    // `method` had no useful answer because the server is still working, e.g.
+    // indexing right after startup. Worth retrying once it is done.
+    pub fn not_ready(method: &str) -> Self {
+        Self {
+            code: LSP_NOT_READY,
+            message: format!("Language server is still indexing, {} is not available yet", method),
+            data: Some(serde_json::json!({ "feature": method })),
+        }
+    }
+
+    pub fn is_not_ready(&self) -> bool {
+        self.code == LSP_NOT_READY
+    }
+
+    // The method a not ready error is about
+    pub fn feature(&self) -> Option<&str> {
+        self.data.as_ref()?.get("feature")?.as_str()
+    }
+
+    pub fn is_content_modified(&self) -> bool {
+        self.code == lsp_types::error_codes::CONTENT_MODIFIED
+    }
+
+    pub fn is_request_cancelled(&self) -> bool {
+        self.code == lsp_types::error_codes::REQUEST_CANCELLED
+    }
+}
+
+impl fmt::Display for LspError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "LSP error {}: {}", self.code, self.message)
+    }
+}
+
+impl std::error::Error for LspError {}
+
+// Cancels an in-flight LSP request; clones share the same state
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    inner: Arc<CancellationState>,
+}
+
+#[derive(Debug, Default)]
+struct CancellationState {
+    cancelled: AtomicBool,
+    notify: Notify,
+}
+
+impl CancellationToken {
+    pub fn cancel(&self) {
+        self.inner.cancelled.store(true, Ordering::SeqCst);
+        self.inner.notify.notify_waiters();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::SeqCst)
+    }
+
+    // Resolves once `cancel` has been called
+    pub async fn cancelled(&self) {
+        loop {
+            let notified = self.inner.notify.notified();
+            tokio::pin!(notified);
+            // Register before checking the flag so a concurrent cancel isn't missed
+            notified.as_mut().enable();
+            if self.is_cancelled() {
+                return;
+            }
+            notified.await;
+        }
+    }
 }
 
 // #[derive(Debug, Clone, Serialize, Deserialize)]
 // pub struct Position {
 //     pub line: u32,
 //     pub character: u32,
-// }
\ No newline at end of file
+// }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_cancellation_token_wakes_waiters() {
+        let token = CancellationToken::default();
+        let waiter = tokio::spawn({
+            let token = token.clone();
+            async move { token.cancelled().await }
+        });
+
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        token.cancel();
+        tokio::time::timeout(Duration::from_secs(1), waiter)
+            .await
+            .expect("waiter was not woken")
+            .unwrap();
+
+        // Already cancelled tokens resolve immediately
+        tokio::time::timeout(Duration::from_secs(1), token.cancelled())
+            .await
+            .expect("cancelled token did not resolve");
+    }
+
+    #[test]
+    fn test_diagnostic_counts_by_severity() {
+        let diagnostic = |severity| lsp_types::Diagnostic {
+            severity,
+            ..Default::default()
+        };
+        let counts = DiagnosticCounts::from_diagnostics(&[
+            diagnostic(Some(lsp_types::DiagnosticSeverity::ERROR)),
+            diagnostic(Some(lsp_types::DiagnosticSeverity::WARNING)),
+            diagnostic(Some(lsp_types::DiagnosticSeverity::ERROR)),
+            diagnostic(Some(lsp_types::DiagnosticSeverity::HINT)),
+            diagnostic(None),
+        ]);
+        assert_eq!(counts, DiagnosticCounts { errors: 2, warnings: 1 });
+    }
+
+    #[test]
+    fn test_only_restart_settings_require_a_restart() {
+        let config = &LspConfiguration::defaults()[0];
+        let old = serde_json::json!({ "cargo": { "features": [] }, "checkOnSave": true });
+
+        let check = serde_json::json!({ "cargo": { "features": [] }, "checkOnSave": false });
+        assert!(!config.requires_restart(Some(&old), &check));
+
+        let features = serde_json::json!({ "cargo": { "features": "all" }, "checkOnSave": true });
+        assert!(config.requires_restart(Some(&old), &features));
+        assert!(config.requires_restart(None, &features));
+    }
+}
@@ -5,10 +5,13 @@ mod lsp;
 mod utils;
 mod terminal;
 mod search;
+mod git;
+mod session;
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use clap::Parser;
 use std::path::PathBuf;
+use std::time::Duration;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -18,6 +21,119 @@ struct Args {
     
     #[arg(short, long, default_value = "8080")]
     port: u16,
+
+    /// JSON file with language server configurations
+    #[arg(long)]
+    lsp_config: Option<PathBuf>,
+
+    /// Maximum number of file system events batched into one message
+    #[arg(long, default_value = "100")]
+    fs_batch_size: usize,
+
+    /// Maximum time in milliseconds file system events are held before being sent
+    #[arg(long, default_value = "100")]
+    fs_batch_interval_ms: u64,
+
+    /// Report changes to a file only once it hasn't been modified for this many milliseconds (0 disables)
+    #[arg(long, default_value = "200")]
+    fs_modify_debounce_ms: u64,
+
+    /// Only allow clients to set the environment variables listed with --terminal-env-allow
+    #[arg(long)]
+    restrict_terminal_env: bool,
+
+    /// Environment variable clients may set on new terminals (repeatable, comma separated)
+    #[arg(long, value_delimiter = ',')]
+    terminal_env_allow: Vec<String>,
+
+    /// Environment variable clients may not set on new terminals, on top of loader and shell startup variables like LD_PRELOAD (repeatable, comma separated)
+    #[arg(long, value_delimiter = ',')]
+    terminal_env_deny: Vec<String>,
+
+    /// Additional directory clients may open files from read-only, e.g. ~/.cargo/registry (repeatable)
+    #[arg(long)]
+    allow_path: Vec<PathBuf>,
+
+    /// Poll interval in milliseconds used when the OS file watch limit is reached
+    #[arg(long, default_value = "2000")]
+    watch_poll_interval_ms: u64,
+
+    /// Stop a language server after this many seconds without open documents or requests
+    #[arg(long)]
+    lsp_idle_timeout: Option<u64>,
+
+    /// Unanswered requests per language server before new ones fail as busy
+    #[arg(long, default_value_t = lsp::types::DEFAULT_MAX_PENDING_REQUESTS, value_parser = positive_usize)]
+    lsp_max_pending_requests: usize,
+
+    /// What starts a language server: opening one of its files, or any request about one
+    #[arg(long, value_enum, default_value_t = lsp::types::LspStartTrigger::Open)]
+    lsp_start_trigger: lsp::types::LspStartTrigger,
+
+    /// Don't start language servers until this many milliseconds after startup
+    #[arg(long, default_value = "0")]
+    lsp_start_delay_ms: u64,
+
+    /// On save, end text documents with exactly one line break in their own style
+    #[arg(long)]
+    insert_final_newline: bool,
+
+    /// Open documents up to this many bytes are kept in memory rather than read
+    /// from disk on every access
+    #[arg(long, default_value_t = file_system::MAX_FILE_SIZE)]
+    open_document_cache_max_bytes: u64,
+
+    /// Completion responses kept to answer repeated requests, 0 disables the cache
+    #[arg(long, default_value = "16")]
+    completion_cache_size: usize,
+
+    /// How long a cached completion response is reused, in milliseconds
+    #[arg(long, default_value = "2000")]
+    completion_cache_ttl_ms: u64,
+
+    /// Which symlinks directory listings, search and the file watcher follow
+    #[arg(long, value_enum, default_value_t = utils::path_utils::SymlinkPolicy::WithinWorkspace)]
+    follow_symlinks: utils::path_utils::SymlinkPolicy,
+
+    /// Keep a disconnected client's session this many seconds for it to resume
+    #[arg(long, default_value = "300")]
+    session_timeout: u64,
+
+    /// Maximum number of open terminals and running commands
+    #[arg(long, default_value_t = terminal::types::DEFAULT_MAX_TERMINALS)]
+    max_terminals: usize,
+
+    /// Directory for terminal output logs, relative paths are inside the workspace [default: a temp directory]
+    #[arg(long)]
+    terminal_log_dir: Option<PathBuf>,
+
+    /// Size in bytes at which a terminal output log is rotated
+    #[arg(long, default_value_t = terminal::types::DEFAULT_MAX_TERMINAL_LOG_BYTES, value_parser = clap::value_parser!(u64).range(1..))]
+    terminal_log_max_bytes: u64,
+
+    /// How often a running search is checked for new matches, in milliseconds
+    #[arg(long, default_value_t = search::DEFAULT_POLL_INTERVAL_MS, value_parser = clap::value_parser!(u64).range(1..))]
+    search_poll_interval_ms: u64,
+
+    /// Longest the search matcher may block on each check, in milliseconds
+    #[arg(long, default_value_t = search::DEFAULT_TICK_TIMEOUT_MS, value_parser = clap::value_parser!(u64).range(1..))]
+    search_tick_timeout_ms: u64,
+
+    /// Number of results sent per SearchResults message
+    #[arg(long, default_value_t = search::DEFAULT_BATCH_SIZE, value_parser = positive_usize)]
+    search_batch_size: usize,
+
+    /// Matched lines longer than this many characters are shortened around the match in search results
+    #[arg(long, default_value_t = search::DEFAULT_MAX_RESULT_LINE_LENGTH, value_parser = positive_usize)]
+    search_max_line_length: usize,
+}
+
+fn positive_usize(value: &str) -> Result<usize, String> {
+    match value.parse::<usize>() {
+        Ok(0) => Err("must be at least 1".to_string()),
+        Ok(value) => Ok(value),
+        Err(e) => Err(e.to_string()),
+    }
 }
 
 
@@ -26,6 +142,67 @@ async fn main() -> Result<()> {
     let args = Args::parse();
     let workspace_path = PathBuf::from(args.workspace);
     
-    let server = server::Server::new(workspace_path, args.port)?;
+    let lsp_configs = match args.lsp_config {
+        Some(path) => lsp::types::LspConfiguration::load_from_file(&path)?,
+        None => lsp::types::LspConfiguration::defaults(),
+    };
+
+    let allowed_paths = args
+        .allow_path
+        .iter()
+        .map(|path| {
+            path.canonicalize()
+                .with_context(|| format!("Invalid --allow-path {:?}", path))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let config = server::ServerConfig {
+        fs_batch_size: args.fs_batch_size.max(1),
+        fs_batch_interval: Duration::from_millis(args.fs_batch_interval_ms),
+        allowed_paths,
+        watch_poll_interval: Duration::from_millis(args.watch_poll_interval_ms.max(100)),
+        fs_modify_debounce: Duration::from_millis(args.fs_modify_debounce_ms),
+        lsp_idle_timeout: args.lsp_idle_timeout.map(Duration::from_secs),
+        lsp_max_pending_requests: args.lsp_max_pending_requests,
+        lsp_start: lsp::types::LspStartPolicy {
+            trigger: args.lsp_start_trigger,
+            delay: Duration::from_millis(args.lsp_start_delay_ms),
+        },
+        save_normalization: file_system::SaveNormalization {
+            insert_final_newline: args.insert_final_newline,
+        },
+        max_open_document_cache_size: args.open_document_cache_max_bytes,
+        lsp_completion_cache: lsp::completion_cache::CompletionCacheConfig {
+            capacity: args.completion_cache_size,
+            ttl: Duration::from_millis(args.completion_cache_ttl_ms),
+        },
+        session_timeout: Duration::from_secs(args.session_timeout),
+        follow_symlinks: args.follow_symlinks,
+        terminal: terminal::types::TerminalConfig {
+            // Names allowed explicitly are taken off the built-in denylist
+            denied_env: terminal::types::default_denied_env()
+                .into_iter()
+                .filter(|name| !args.terminal_env_allow.contains(name))
+                .chain(args.terminal_env_deny)
+                .collect(),
+            allowed_env: args
+                .restrict_terminal_env
+                .then(|| args.terminal_env_allow.into_iter().collect()),
+            max_terminals: args.max_terminals,
+            log_dir: args
+                .terminal_log_dir
+                .map(|dir| workspace_path.join(dir))
+                .unwrap_or_else(terminal::types::default_log_dir),
+            max_log_bytes: args.terminal_log_max_bytes,
+        },
+        search: search::SearchConfig {
+            poll_interval: Duration::from_millis(args.search_poll_interval_ms),
+            tick_timeout_ms: args.search_tick_timeout_ms,
+            batch_size: args.search_batch_size,
+            max_line_length: args.search_max_line_length,
+        },
+    };
+
+    let server = server::Server::new(workspace_path, args.port, lsp_configs, config)?;
     server.start().await
 }
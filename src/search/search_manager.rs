@@ -1,47 +1,72 @@
 // src/search/search_manager.rs
 use std::sync::Arc;
+use std::collections::BTreeSet;
 use std::path::{Path, PathBuf};
 use std::time::Duration;
-use tokio::sync::{broadcast, RwLock};
-use tokio::time::interval;
-use nucleo::{Config, Nucleo, Utf32String};
+use tokio::sync::{broadcast, mpsc, Notify, RwLock};
+use tokio::task::JoinHandle;
+use tokio::time::{interval, MissedTickBehavior};
+use nucleo::{Config, Injector, Matcher, Nucleo};
 use nucleo::pattern::{CaseMatching, Normalization};
 use anyhow::Result;
 use tokio::fs;
 
-use crate::search::{SearchMessage, SearchResultItem};
+use crate::file_system::is_binary_file;
+use crate::utils::metrics::{HistogramSnapshot, LatencyHistogram};
+use crate::utils::path_utils::{is_ignored_path, SymlinkPolicy};
+use crate::search::{
+    MatchKind, SearchConfig, SearchMessage, SearchMode, SearchResultItem, SearchState,
+};
 
-const BATCH_SIZE: usize = 50;
-const TICK_TIMEOUT_MS: u64 = 10;
-const POLL_INTERVAL_MS: u64 = 100;
 const SEARCH_TIMEOUT_SECS: u64 = 10;
 const MAX_FILE_SIZE: u64 = 1024 * 1024; // 1MB
-
-#[derive(Clone, PartialEq, Debug)]
-enum SearchMode {
-    Filename,
-    Content,
-}
-
+// Minified files can fit in a single huge line, which makes matching and
+// highlighting pathological; anything past this many chars is not indexed
+const MAX_INDEXED_LINE_LENGTH: usize = 1000;
+// Paths found by the directory walk waiting to be read and injected
+const INDEX_QUEUE_SIZE: usize = 1024;
+// Default minimum score per query character. A matched character scores 16
+// plus bonuses, so this only drops matches whose gaps cost over half of that.
+const DEFAULT_MIN_SCORE_PER_CHAR: u32 = 8;
 
 #[derive(Clone)]
 struct LineContent {
     path: PathBuf,
     line_number: u32,
     line: String,
+    truncated: bool,
+    kind: MatchKind,
 }
 
 pub struct SearchManager {
     workspace_path: PathBuf,
+    config: SearchConfig,
+    symlinks: SymlinkPolicy,
     searcher: Arc<RwLock<Nucleo<LineContent>>>,
     event_sender: broadcast::Sender<SearchMessage>,
     last_query: Arc<RwLock<Option<String>>>,
+    // Id the client gave the running search, carried by its results and errors
+    search_id: RwLock<String>,
     is_searching: Arc<RwLock<bool>>,
     current_mode: Arc<RwLock<SearchMode>>,
+    // Matches scoring below this are not reported
+    min_score: RwLock<u32>,
+    // Extensions the index is limited to, None for every file
+    file_types: RwLock<Option<BTreeSet<String>>>,
+    // Rescores matched items, nucleo's snapshot doesn't expose their scores
+    matcher: RwLock<Matcher>,
+    // When the running search was last started or refined, and how long
+    // searches took to complete from there
+    search_started: RwLock<Option<std::time::Instant>>,
+    durations: LatencyHistogram,
+    // Wakes the parked polling task when a search starts
+    wake: Arc<Notify>,
+    // Background indexing for the current mode, results are delivered while it runs
+    indexing_task: RwLock<Option<JoinHandle<()>>>,
 }
 
 impl SearchManager {
-    pub fn new(workspace_path: PathBuf) -> Arc<Self> {
+    pub fn new(workspace_path: PathBuf, config: SearchConfig, symlinks: SymlinkPolicy) -> Arc<Self> {
         let (event_sender, _) = broadcast::channel(100);
 
         let notify = Arc::new(|| {});
@@ -56,41 +81,62 @@ impl SearchManager {
 
         let manager = Arc::new(Self {
             workspace_path,
+            config,
+            symlinks,
             searcher: Arc::new(RwLock::new(searcher)),
             event_sender,
             last_query: Arc::new(RwLock::new(None)),
+            search_id: RwLock::new(String::new()),
             is_searching: Arc::new(RwLock::new(false)),
             current_mode: Arc::new(RwLock::new(SearchMode::Filename)),
+            min_score: RwLock::new(0),
+            file_types: RwLock::new(None),
+            matcher: RwLock::new(Matcher::new(Config::DEFAULT.match_paths())),
+            search_started: RwLock::new(None),
+            durations: LatencyHistogram::default(),
+            wake: Arc::new(Notify::new()),
+            indexing_task: RwLock::new(None),
         });
 
-        // Create polling task for search results
-        let manager_clone = Arc::clone(&manager);
+        // Polls for results while a search runs and is parked in between. It
+        // only holds a weak reference, so it ends once the manager is dropped.
+        let weak = Arc::downgrade(&manager);
+        let wake = Arc::clone(&manager.wake);
+        let poll_interval = manager.config.poll_interval;
         tokio::spawn(async move {
-            let mut interval = interval(Duration::from_millis(POLL_INTERVAL_MS));
+            let mut interval = interval(poll_interval);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
             let mut search_start: Option<std::time::Instant> = None;
-            
+
             loop {
-                interval.tick().await;
-                let is_searching = *manager_clone.is_searching.read().await;
-                
-                if is_searching {
-                    if search_start.is_none() {
-                        search_start = Some(std::time::Instant::now());
-                    }
+                let is_searching = match weak.upgrade() {
+                    Some(manager) => *manager.is_searching.read().await,
+                    None => return,
+                };
+                if !is_searching {
+                    search_start = None;
+                    wake.notified().await;
+                    interval.reset();
+                    continue;
+                }
 
-                    if let Some(start) = search_start {
-                        if start.elapsed() > Duration::from_secs(SEARCH_TIMEOUT_SECS) {
-                            println!("Search timed out after {} seconds", SEARCH_TIMEOUT_SECS);
-                            *manager_clone.is_searching.write().await = false;
-                            continue;
-                        }
-                    }
+                interval.tick().await;
+                let Some(manager) = weak.upgrade() else {
+                    return;
+                };
+                let start = *search_start.get_or_insert_with(std::time::Instant::now);
+                if start.elapsed() > Duration::from_secs(SEARCH_TIMEOUT_SECS) {
+                    println!("Search timed out after {} seconds", SEARCH_TIMEOUT_SECS);
+                    *manager.is_searching.write().await = false;
+                    continue;
+                }
 
-                    if let Err(e) = manager_clone.process_results().await {
-                        eprintln!("Error processing results: {}", e);
-                    }
-                } else {
-                    search_start = None;
+                if let Err(e) = manager.process_results().await {
+                    eprintln!("Error processing results: {}", e);
+                    let _ = manager.event_sender.send(SearchMessage::Error {
+                        search_id: manager.search_id.read().await.clone(),
+                        error: e.to_string(),
+                    });
                 }
             }
         });
@@ -98,123 +144,184 @@ impl SearchManager {
         manager
     }
 
-    async fn initialize_files(&self, search_mode: &SearchMode) -> Result<()> {
-        let searcher = self.searcher.read().await;
-        let injector = searcher.injector();
+    async fn initialize_files(&self, search_mode: &SearchMode, file_types: Option<BTreeSet<String>>) -> Result<()> {
+        // Injectors don't need the searcher lock, so it is only held long enough to
+        // create one and matching can keep ticking while the workspace is indexed
+        let injector = self.searcher.read().await.injector();
         let mut count = 0;
-        
-        for entry in walkdir::WalkDir::new(&self.workspace_path)
-            .follow_links(true)
-            .into_iter()
-            .filter_entry(|e| !Self::is_ignored(e.path())) 
-        {
-            let entry = entry?;
-            if !entry.file_type().is_file() {
-                continue;
-            }
 
-            let path = entry.path().to_path_buf();
-            
-            match search_mode {
-                SearchMode::Content => {
-                    // Check file size before reading
-                    if let Ok(metadata) = fs::metadata(&path).await {
-                        if metadata.len() > MAX_FILE_SIZE {
-                            println!("Skipping large file: {:?}", path);
-                            continue;
-                        }
+        let index_names = matches!(search_mode, SearchMode::Filename | SearchMode::Both);
+        let index_content = matches!(search_mode, SearchMode::Content | SearchMode::Both);
 
-                        match fs::read_to_string(&path).await {
-                            Ok(content) => {
-                                for (line_number, line) in content.lines().enumerate() {
-                                    let line_content = LineContent {
-                                        path: path.clone(),
-                                        line_number: (line_number + 1) as u32,
-                                        line: line.to_string(),
-                                    };
-
-                                    injector.push(line_content, |content, columns| {
-                                        // Only use single column - content for content search
-                                        columns[0] = content.line.clone().into();
-                                    });
-                                }
-                            }
-                            Err(e) => {
-                                println!("Error reading file {:?}: {}", path, e);
-                                continue;
-                            }
-                        }
-                    }
+        let (path_tx, mut path_rx) = mpsc::channel::<PathBuf>(INDEX_QUEUE_SIZE);
+        let workspace_path = self.workspace_path.clone();
+        let symlinks = self.symlinks;
+        let walker = tokio::task::spawn_blocking(move || -> Result<()> {
+            for entry in walkdir::WalkDir::new(&workspace_path)
+                .follow_links(symlinks != SymlinkPolicy::Never)
+                .into_iter()
+                .filter_entry(|e| {
+                    !is_ignored_path(e.path(), &workspace_path)
+                        && (!e.path_is_symlink() || symlinks.follows(e.path(), &workspace_path))
+                })
+            {
+                let entry = match entry {
+                    // A link back to one of its own ancestors
+                    Err(e) if e.loop_ancestor().is_some() => continue,
+                    entry => entry?,
+                };
+                if !entry.file_type().is_file() {
+                    continue;
                 }
-                SearchMode::Filename => {
-                    let line_content = LineContent {
-                        path: path.clone(),
-                        line_number: 0,
-                        line: String::new(),
-                    };
-
-                    injector.push(line_content, |content, columns| {
-                        // Only use single column - path for filename search
-                        columns[0] = content.path.to_string_lossy().to_string().into();
-                    });
+                if file_types.as_ref().is_some_and(|types| !has_file_type(entry.path(), types)) {
+                    continue;
                 }
+                if path_tx.blocking_send(entry.into_path()).is_err() {
+                    // Indexing was cancelled
+                    break;
+                }
+            }
+            Ok(())
+        });
+
+        while let Some(path) = path_rx.recv().await {
+            if index_names {
+                let line_content = LineContent {
+                    path: path.clone(),
+                    line_number: 0,
+                    line: String::new(),
+                    truncated: false,
+                    kind: MatchKind::Filename,
+                };
+
+                injector.push(line_content, |content, columns| {
+                    // Only use single column - path for filename matches
+                    columns[0] = content.path.to_string_lossy().to_string().into();
+                });
+            }
+
+            if index_content {
+                Self::inject_file_content(&injector, &path).await;
             }
             count += 1;
         }
+        walker.await??;
 
         println!("Injected {} files for mode {:?}", count, search_mode);
         Ok(())
     }
 
+    async fn is_indexing(&self) -> bool {
+        self.indexing_task
+            .read()
+            .await
+            .as_ref()
+            .is_some_and(|task| !task.is_finished())
+    }
 
-    fn is_ignored(path: &Path) -> bool {
-        path.components().any(|c| {
-            let s = c.as_os_str().to_string_lossy();
-            s == ".git" || s == "node_modules" || s == "target"
-        })
+    async fn stop_indexing(&self) {
+        if let Some(task) = self.indexing_task.write().await.take() {
+            task.abort();
+        }
+    }
+
+    async fn inject_file_content(injector: &Injector<LineContent>, path: &PathBuf) {
+        // Check file size before reading
+        let Ok(metadata) = fs::metadata(path).await else {
+            return;
+        };
+        if metadata.len() > MAX_FILE_SIZE {
+            println!("Skipping large file: {:?}", path);
+            return;
+        }
+
+        // Binary assets would only fail to decode below
+        if is_binary_file(path).await.unwrap_or(true) {
+            return;
+        }
+
+        match fs::read_to_string(path).await {
+            Ok(content) => {
+                for (line_number, line) in content.lines().enumerate() {
+                    let (line, truncated) = truncate_line(line);
+                    let line_content = LineContent {
+                        path: path.clone(),
+                        line_number: (line_number + 1) as u32,
+                        line,
+                        truncated,
+                        kind: MatchKind::Content,
+                    };
+
+                    injector.push(line_content, |content, columns| {
+                        // Only use single column - line text for content matches
+                        columns[0] = content.line.clone().into();
+                    });
+                }
+            }
+            Err(e) => {
+                println!("Error reading file {:?}: {}", path, e);
+            }
+        }
     }
 
     pub async fn create_search(
         self: Arc<Self>,
+        search_id: &str,
         query: &str,
-        search_content: bool,
+        new_mode: SearchMode,
+        min_score: Option<u32>,
+        file_types: Option<Vec<String>>,
     ) -> Result<()> {
-        let new_mode = if search_content {
-            SearchMode::Content
-        } else {
-            SearchMode::Filename
+        *self.min_score.write().await = min_score.unwrap_or_else(|| default_min_score(query));
+        let file_types = normalize_file_types(file_types);
+        let file_types_changed = {
+            let mut current = self.file_types.write().await;
+            let changed = *current != file_types;
+            *current = file_types.clone();
+            changed
         };
-    
+        *self.search_started.write().await = Some(std::time::Instant::now());
+        *self.search_id.write().await = search_id.to_string();
+
         let mut current_mode = self.current_mode.write().await;
         let mut last_query = self.last_query.write().await;
         let mode_changed = *current_mode != new_mode;
         *current_mode = new_mode.clone();
     
         // Determine if we need to reinitialize
-        let initialization_needed = mode_changed;
+        let initialization_needed = mode_changed || file_types_changed;
     
         let should_reparse = if let Some(last) = last_query.as_ref() {
-            query.starts_with(last) && !mode_changed
+            query.starts_with(last) && !initialization_needed
         } else {
             false
         };
     
         if initialization_needed {
             println!("Starting new search with mode: {:?}", new_mode);
+            self.stop_indexing().await;
             self.searcher.write().await.restart(true);
-            
-            // Initialize files and wait for completion
-            if let Err(e) = self.initialize_files(&new_mode).await {
-                eprintln!("Failed to initialize files: {}", e);
-                return Err(e);
-            }
-    
-            // After initialization, set up the search pattern
+
+            // Index in the background; matches are reported as files are injected
+            let manager = Arc::clone(&self);
+            let search_id = search_id.to_string();
+            let task = tokio::spawn(async move {
+                if let Err(e) = manager.initialize_files(&new_mode, file_types).await {
+                    eprintln!("Failed to initialize files: {}", e);
+                    let _ = manager.event_sender.send(SearchMessage::Error {
+                        search_id,
+                        error: e.to_string(),
+                    });
+                }
+            });
+            *self.indexing_task.write().await = Some(task);
+
             let mut searcher = self.searcher.write().await;
             searcher.pattern.reparse(0, query, CaseMatching::Smart, Normalization::Smart, false);
             
             *last_query = Some(query.to_string());
             *self.is_searching.write().await = true;
+            self.wake.notify_one();
         } else {
             println!("Continuing search");
             let mut searcher = self.searcher.write().await;
@@ -222,65 +329,89 @@ impl SearchManager {
             
             *last_query = Some(query.to_string());
             *self.is_searching.write().await = true;
+            self.wake.notify_one();
         }
         
         Ok(())
     }
 
     async fn process_results(&self) -> Result<()> {
+        // Checked before ticking so items injected right before indexing finished are matched
+        let indexing = self.is_indexing().await;
         let mut searcher = self.searcher.write().await;
-        let current_mode = self.current_mode.read().await;
-        
-        let status = searcher.tick(TICK_TIMEOUT_MS);
+
+        let status = searcher.tick(self.config.tick_timeout_ms);
         let snapshot = searcher.snapshot();
         let matched_count = snapshot.matched_item_count();
-        let is_done = !status.running;
+        let is_done = !status.running && !indexing;
 
-        if matched_count > 0 {
-            let mut current_batch = Vec::with_capacity(BATCH_SIZE);
-            
-            for item in snapshot.matched_items(0..matched_count) {
-                let line_content = &item.data;
-                
-                match *current_mode {
-                    SearchMode::Content => {
-                        current_batch.push(SearchResultItem {
-                            path: line_content.path.to_string_lossy().to_string(),
-                            line_number: line_content.line_number,
-                            content: line_content.line.clone(),
-                        });
-                    }
-                    SearchMode::Filename => {
-                        current_batch.push(SearchResultItem {
-                            path: line_content.path.to_string_lossy().to_string(),
-                            line_number: 0,
-                            content: String::new(),
-                        });
-                    }
-                }
+        // Nothing new to report since the last tick
+        if !status.changed && !is_done {
+            return Ok(());
+        }
 
-                if current_batch.len() >= BATCH_SIZE {
-                    let message = SearchMessage::Results {
-                        search_id: String::new(),
-                        items: current_batch,
-                        is_complete: false,
-                    };
-                    let _ = self.event_sender.send(message);
-                    current_batch = Vec::with_capacity(BATCH_SIZE);
-                }
-            }
+        let min_score = *self.min_score.read().await;
+        let search_id = self.search_id.read().await.clone();
+        let mut matcher = self.matcher.write().await;
+        // Nucleo keeps matches ordered by score, best first, so scoring stops
+        // at the first one below the minimum
+        let scored: Vec<_> = snapshot
+            .matched_items(0..matched_count)
+            .map_while(|item| {
+                let score = snapshot.pattern().score(item.matcher_columns, &mut matcher)?;
+                (score >= min_score).then_some((item, score))
+            })
+            .collect();
+
+        if !scored.is_empty() {
+            let batch_count = scored.len().div_ceil(self.config.batch_size);
+            for (index, batch) in scored.chunks(self.config.batch_size).enumerate() {
+                // Highlights and excerpts are only worked out for the batch being sent
+                let items = batch
+                    .iter()
+                    .map(|(item, score)| {
+                        let mut indices = Vec::new();
+                        snapshot.pattern().column_pattern(0).indices(
+                            item.matcher_columns[0].slice(..),
+                            &mut matcher,
+                            &mut indices,
+                        );
+                        indices.sort_unstable();
+                        indices.dedup();
 
-            if !current_batch.is_empty() {
+                        let line_content = item.data;
+                        let excerpt = match line_content.kind {
+                            MatchKind::Content => excerpt(&line_content.line, indices, self.config.max_line_length),
+                            // Filename entries carry an empty line and match the path
+                            MatchKind::Filename => Excerpt {
+                                content: String::new(),
+                                offset: 0,
+                                match_indices: indices,
+                                cut: false,
+                            },
+                        };
+                        SearchResultItem {
+                            path: line_content.path.to_string_lossy().to_string(),
+                            line_number: line_content.line_number,
+                            content: excerpt.content,
+                            truncated: line_content.truncated || excerpt.cut,
+                            content_offset: excerpt.offset,
+                            match_indices: excerpt.match_indices,
+                            match_kind: line_content.kind.clone(),
+                            score: *score,
+                        }
+                    })
+                    .collect();
                 let message = SearchMessage::Results {
-                    search_id: String::new(),
-                    items: current_batch,
-                    is_complete: is_done,
+                    search_id: search_id.clone(),
+                    items,
+                    is_complete: is_done && index + 1 == batch_count,
                 };
                 let _ = self.event_sender.send(message);
             }
         } else if is_done {
             let message = SearchMessage::Results {
-                search_id: String::new(),
+                search_id,
                 items: vec![],
                 is_complete: true,
             };
@@ -289,6 +420,9 @@ impl SearchManager {
 
         if is_done {
             *self.is_searching.write().await = false;
+            if let Some(started) = self.search_started.write().await.take() {
+                self.durations.record(started.elapsed());
+            }
         }
 
         Ok(())
@@ -296,11 +430,309 @@ impl SearchManager {
 
     pub async fn close_search(&self) {
         *self.is_searching.write().await = false;
+        self.stop_indexing().await;
         let mut searcher = self.searcher.write().await;
         searcher.restart(true);
     }
 
+    pub async fn state(&self) -> SearchState {
+        let mode = self.current_mode.read().await.clone();
+        let query = self.last_query.read().await.clone();
+        let is_searching = *self.is_searching.read().await;
+        let searcher = self.searcher.read().await;
+        let snapshot = searcher.snapshot();
+        SearchState {
+            query,
+            mode,
+            is_searching,
+            indexed_items: snapshot.item_count(),
+            matched_items: snapshot.matched_item_count(),
+        }
+    }
+
+    // Id the client gave the latest search
+    pub async fn search_id(&self) -> String {
+        self.search_id.read().await.clone()
+    }
+
+    pub fn durations(&self) -> HistogramSnapshot {
+        self.durations.snapshot()
+    }
+
     pub fn subscribe(&self) -> broadcast::Receiver<SearchMessage> {
         self.event_sender.subscribe()
     }
-}
\ No newline at end of file
+}
+
+impl Drop for SearchManager {
+    fn drop(&mut self) {
+        // Lets a parked polling task notice the manager is gone
+        self.wake.notify_one();
+    }
+}
+
+// Extensions as compared against file names: lowercased, without a leading
+// dot. An empty list doesn't limit the search.
+fn normalize_file_types(file_types: Option<Vec<String>>) -> Option<BTreeSet<String>> {
+    let types: BTreeSet<String> = file_types?
+        .iter()
+        .map(|file_type| file_type.trim().trim_start_matches('.').to_lowercase())
+        .filter(|file_type| !file_type.is_empty())
+        .collect();
+    (!types.is_empty()).then_some(types)
+}
+
+fn has_file_type(path: &Path, file_types: &BTreeSet<String>) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| file_types.contains(&extension.to_lowercase()))
+}
+
+fn default_min_score(query: &str) -> u32 {
+    let chars = query.chars().filter(|c| !c.is_whitespace()).count() as u32;
+    chars * DEFAULT_MIN_SCORE_PER_CHAR
+}
+
+// The part of a matched line that is sent to clients
+struct Excerpt {
+    content: String,
+    // Characters of the line before `content`
+    offset: u32,
+    match_indices: Vec<u32>,
+    cut: bool,
+}
+
+// Lines over `max_chars` characters are cut to that many, starting a little
+// before the first matched character, with an ellipsis on each side that was
+// cut. `indices` are character positions in the line and are moved to point
+// into the excerpt.
+fn excerpt(line: &str, indices: Vec<u32>, max_chars: usize) -> Excerpt {
+    let chars: Vec<char> = line.chars().collect();
+    if chars.len() <= max_chars {
+        return Excerpt {
+            content: line.to_string(),
+            offset: 0,
+            match_indices: indices,
+            cut: false,
+        };
+    }
+
+    let first_match = indices.first().map_or(0, |&index| index as usize);
+    let start = first_match.saturating_sub(max_chars / 4).min(chars.len() - max_chars);
+    let end = start + max_chars;
+
+    let mut content = String::new();
+    let mut shift = start as u32;
+    if start > 0 {
+        content.push('…');
+        shift -= 1;
+    }
+    content.extend(&chars[start..end]);
+    if end < chars.len() {
+        content.push('…');
+    }
+    Excerpt {
+        content,
+        offset: start as u32,
+        match_indices: indices
+            .into_iter()
+            .filter(|&index| (start..end).contains(&(index as usize)))
+            .map(|index| index - shift)
+            .collect(),
+        cut: true,
+    }
+}
+
+fn truncate_line(line: &str) -> (String, bool) {
+    match line.char_indices().nth(MAX_INDEXED_LINE_LENGTH) {
+        Some((end, _)) => (line[..end].to_string(), true),
+        None => (line.to_string(), false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_single_line_giant_file_is_truncated() -> Result<()> {
+        let workspace = tempfile::tempdir()?;
+        std::fs::write(workspace.path().join("bundle.min.js"), "x".repeat(500_000))?;
+
+        let manager = SearchManager::new(
+            workspace.path().to_path_buf(),
+            SearchConfig::default(),
+            SymlinkPolicy::default(),
+        );
+        manager.initialize_files(&SearchMode::Content, None).await?;
+
+        let mut searcher = manager.searcher.write().await;
+        searcher.tick(1000);
+        let snapshot = searcher.snapshot();
+        assert_eq!(snapshot.item_count(), 1);
+
+        let item = snapshot.get_item(0).unwrap();
+        assert!(item.data.truncated);
+        assert_eq!(item.data.line.chars().count(), MAX_INDEXED_LINE_LENGTH);
+        Ok(())
+    }
+
+    #[test]
+    fn test_long_lines_are_cut_around_the_match() {
+        let line = format!("{}needle{}", "a".repeat(100), "b".repeat(100));
+        let indices: Vec<u32> = (100..106).collect();
+
+        let cut = excerpt(&line, indices.clone(), 40);
+        assert!(cut.cut);
+        assert_eq!(cut.offset, 90);
+        assert_eq!(cut.content, format!("…{}needle{}…", "a".repeat(10), "b".repeat(24)));
+        // Still pointing at `needle`, behind the ellipsis
+        assert_eq!(cut.match_indices, (11..17).collect::<Vec<u32>>());
+
+        // A match near the end keeps the window inside the line
+        let tail = excerpt(&line, vec![205], 40);
+        assert_eq!((tail.offset, tail.match_indices.as_slice()), (166, &[40][..]));
+        assert!(tail.content.starts_with('…') && !tail.content.ends_with('…'));
+
+        let short = excerpt("let needle = 1;", vec![4, 5], 40);
+        assert!(!short.cut);
+        assert_eq!(short.content, "let needle = 1;");
+    }
+
+    #[tokio::test]
+    async fn test_state_reports_current_search() -> Result<()> {
+        let workspace = tempfile::tempdir()?;
+        let manager = SearchManager::new(
+            workspace.path().to_path_buf(),
+            SearchConfig::default(),
+            SymlinkPolicy::default(),
+        );
+        assert!(manager.state().await.query.is_none());
+
+        Arc::clone(&manager).create_search("", "needle", SearchMode::Both, None, None).await?;
+        let state = manager.state().await;
+        assert_eq!(state.query.as_deref(), Some("needle"));
+        assert_eq!(state.mode, SearchMode::Both);
+        assert!(state.is_searching);
+
+        manager.close_search().await;
+        assert!(!manager.state().await.is_searching);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_weak_matches_are_filtered() -> Result<()> {
+        let workspace = tempfile::tempdir()?;
+        std::fs::write(
+            workspace.path().join("notes.txt"),
+            format!(
+                "fn parse_config() {{}}\n{}\n",
+                // Every character found, but far apart and mid-word
+                "pconfig".chars().map(|c| format!("{}{}", "x".repeat(20), c)).collect::<String>()
+            ),
+        )?;
+        let manager = SearchManager::new(
+            workspace.path().to_path_buf(),
+            SearchConfig::default(),
+            SymlinkPolicy::default(),
+        );
+        let mut results = manager.subscribe();
+        Arc::clone(&manager)
+            .create_search("config-search", "pconfig", SearchMode::Content, None, None)
+            .await?;
+
+        let mut items = Vec::new();
+        loop {
+            let message = tokio::time::timeout(Duration::from_secs(5), results.recv()).await??;
+            if let SearchMessage::Results { search_id, items: batch, is_complete } = message {
+                assert_eq!(search_id, "config-search");
+                items.extend(batch);
+                if is_complete {
+                    break;
+                }
+            }
+        }
+
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].line_number, 1);
+        assert!(items[0].score >= default_min_score("pconfig"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_file_types_limit_indexed_files() -> Result<()> {
+        let workspace = tempfile::tempdir()?;
+        std::fs::write(workspace.path().join("main.rs"), "let needle = 1;\n")?;
+        std::fs::write(workspace.path().join("Cargo.TOML"), "needle = true\n")?;
+        std::fs::write(workspace.path().join("notes.md"), "needle\n")?;
+        std::fs::write(workspace.path().join("needle"), "needle\n")?;
+        let manager = SearchManager::new(
+            workspace.path().to_path_buf(),
+            SearchConfig::default(),
+            SymlinkPolicy::default(),
+        );
+        let mut results = manager.subscribe();
+
+        let searches = [
+            (Some(vec![".rs".to_string(), "toml".to_string()]), vec!["Cargo.TOML", "main.rs"]),
+            // Changing the filter reindexes, an empty one searches everything
+            (Some(Vec::new()), vec!["Cargo.TOML", "main.rs", "needle", "notes.md"]),
+        ];
+        for (file_types, expected) in searches {
+            Arc::clone(&manager)
+                .create_search("", "needle", SearchMode::Content, None, file_types)
+                .await?;
+            let mut names = Vec::new();
+            loop {
+                let message = tokio::time::timeout(Duration::from_secs(5), results.recv()).await??;
+                if let SearchMessage::Results { items, is_complete, .. } = message {
+                    names.extend(items.iter().map(|item| Path::new(&item.path).file_name().unwrap().to_string_lossy().to_string()));
+                    if is_complete {
+                        break;
+                    }
+                }
+            }
+            names.sort();
+            names.dedup();
+            assert_eq!(names, expected);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_polling_parks_between_searches_and_ends_with_manager() -> Result<()> {
+        let workspace = tempfile::tempdir()?;
+        std::fs::write(workspace.path().join("main.rs"), "fn main() {}\n")?;
+        let manager = SearchManager::new(
+            workspace.path().to_path_buf(),
+            SearchConfig::default(),
+            SymlinkPolicy::default(),
+        );
+        let mut results = manager.subscribe();
+
+        // Each search has to wake the parked polling task again
+        for query in ["main", "fn"] {
+            Arc::clone(&manager)
+                .create_search("", query, SearchMode::Content, None, None)
+                .await?;
+            loop {
+                let message = tokio::time::timeout(Duration::from_secs(5), results.recv()).await??;
+                if let SearchMessage::Results { is_complete: true, .. } = message {
+                    break;
+                }
+            }
+            assert!(!manager.state().await.is_searching);
+        }
+
+        // The polling task may still be finishing its last iteration
+        let weak = Arc::downgrade(&manager);
+        drop(manager);
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while weak.strong_count() > 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await?;
+        Ok(())
+    }
+}
@@ -1,20 +1,33 @@
 use serde::{Serialize, Deserialize};
-use tokio::sync::mpsc;
-use std::path::PathBuf;
+use std::time::Duration;
 
-use crate::server::ServerMessage;
+pub const DEFAULT_POLL_INTERVAL_MS: u64 = 100;
+pub const DEFAULT_TICK_TIMEOUT_MS: u64 = 10;
+pub const DEFAULT_BATCH_SIZE: usize = 50;
+pub const DEFAULT_MAX_RESULT_LINE_LENGTH: usize = 500;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct SearchOptions {
-    pub query: String,
-    pub case_sensitive: bool,
+// Cadence of result delivery, trading result latency against CPU use
+#[derive(Debug, Clone)]
+pub struct SearchConfig {
+    // How often a running search is checked for new matches
+    pub poll_interval: Duration,
+    // Longest the matcher may block on each check
+    pub tick_timeout_ms: u64,
+    // Results per SearchResults message
+    pub batch_size: usize,
+    // Longer matched lines are cut down to this many characters around the match
+    pub max_line_length: usize,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct SearchResult {
-    pub file_path: PathBuf,
-    pub line_number: u32,
-    pub line_content: String,
+impl Default for SearchConfig {
+    fn default() -> Self {
+        Self {
+            poll_interval: Duration::from_millis(DEFAULT_POLL_INTERVAL_MS),
+            tick_timeout_ms: DEFAULT_TICK_TIMEOUT_MS,
+            batch_size: DEFAULT_BATCH_SIZE,
+            max_line_length: DEFAULT_MAX_RESULT_LINE_LENGTH,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -24,9 +37,18 @@ pub enum SearchStatus {
     Error { message: String },
 }
 
-struct ActiveSearch {
-    receiver: mpsc::Receiver<ServerMessage>,
-    _task: tokio::task::JoinHandle<()>,
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub enum SearchMode {
+    Filename,
+    Content,
+    // Matches file names and line contents in one ranked result set
+    Both,
+}
+
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub enum MatchKind {
+    Filename,
+    Content,
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
@@ -34,6 +56,29 @@ pub struct SearchResultItem {
     pub path: String,
     pub line_number: u32,
     pub content: String,
+    // Set when `content` isn't the whole line, because it was cut at the
+    // indexing length limit or shortened around the match
+    pub truncated: bool,
+    // Characters of the line before the first one in `content`, not counting
+    // the leading ellipsis a shortened line starts with
+    pub content_offset: u32,
+    // Positions of the matched characters in `content`, or in `path` for
+    // filename matches
+    pub match_indices: Vec<u32>,
+    pub match_kind: MatchKind,
+    // Nucleo's match score, higher is more relevant
+    pub score: u32,
+}
+
+// What the search manager is currently doing, for clients restoring a search panel
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct SearchState {
+    pub query: Option<String>,
+    pub mode: SearchMode,
+    pub is_searching: bool,
+    // Items indexed and matched as of the last result tick
+    pub indexed_items: u32,
+    pub matched_items: u32,
 }
 
 #[derive(Clone)]
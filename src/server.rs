@@ -1,73 +1,223 @@
-use lsp_types::{CompletionList, Hover, Position};
+use lsp_types::Position;
 // src/server.rs
 
 use anyhow::Result;
 use futures_util::{SinkExt, StreamExt};
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::sync::Arc;
-use std::{path::PathBuf, time::Duration};
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
 use tokio::{
     net::{TcpListener, TcpStream},
-    sync::mpsc,
+    sync::{broadcast, mpsc},
     time::Instant,
 };
 use tokio_tungstenite::{accept_async, tungstenite::Message};
 
-use crate::lsp::{lsp_manager::LspManager, types::LspConfiguration};
+use crate::lsp::{
+    capabilities::FrontendCapabilities,
+    completion_cache::CompletionCacheConfig,
+    lsp_manager::LspManager,
+    markdown::hover_to_plain_text,
+    position_encoding::clamp_position,
+    types::{
+        CancellationToken, DiagnosticCounts, LspConfiguration, LspError, LspServerInfo, LspServerState,
+        LspStartPolicy,
+        DEFAULT_MAX_PENDING_REQUESTS,
+    },
+};
 use crate::{
-    file_system::{DiffChange, DocumentMetadata},
-    search::{SearchManager, SearchOptions, SearchResultItem},
+    file_system::{
+        document_change_operations, text_edits, AppliedEdit, DiffChange, DocumentMetadata,
+        IsADirectory, LoadedTree, MergeConflict, MergeOutcome, TextMatcher, TreeUpdate,
+    },
+    search::{SearchManager, SearchResultItem},
 };
 
-use crate::file_system::{FileEvent, FileNode, FileSystem, VersionedDocument};
-use crate::utils::path_utils::{canonicalize_document_path, get_full_path, join_workspace_path};
+use crate::file_system::{
+    group_by_directory, retain_visible, CacheStats, DirectoryEvents, FileEvent, FileMetadata, FileNode, FileSystem, IndexState, ProjectType,
+    SaveNormalization, VersionedDocument, WatcherStatus,
+};
+use crate::utils::path_utils::{
+    canonicalize_document_path, get_full_path, get_readable_path, is_within, join_workspace_path,
+    path_to_uri, uri_to_path, SymlinkPolicy,
+};
 
 use crate::terminal::{
+    screen::ScreenSnapshot,
     terminal_manager::TerminalManager,
-    types::{TerminalMessage, TerminalSize},
+    types::{TerminalConfig, TerminalError, TerminalErrorCode, TerminalMessage, TerminalSize},
 };
 
-use crate::search::{SearchMessage, SearchStatus};
+use crate::search::{SearchConfig, SearchMessage, SearchMode, SearchState, SearchStatus};
+
+use crate::session::DetachedSessions;
+use crate::utils::metrics::{HistogramSnapshot, LatencyRegistry};
+use crate::git::{BlameLine, GitDiffBase, GitDiffHunk, GitFileStatus, GitManager, GitMessage};
+
+fn show_hidden_default() -> bool {
+    true
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(tag = "type", content = "content")]
 pub enum ClientMessage {
     GetDirectory {
         path: String,
+        // Whether dot files (and hidden files on Windows) are listed
+        #[serde(default = "show_hidden_default")]
+        show_hidden: bool,
+    },
+    // Fuzzy-matches the names in one directory, for filtering a folder in the tree
+    FilterDirectory {
+        path: String,
+        query: String,
+    },
+    // The whole workspace tree in one response
+    GetFullTree {
+        #[serde(default)]
+        max_entries: Option<usize>,
+        #[serde(default = "show_hidden_default")]
+        show_hidden: bool,
     },
     RefreshDirectory {
         path: String,
+        #[serde(default = "show_hidden_default")]
+        show_hidden: bool,
+    },
+    // GetDirectory with optional git status and diagnostic counts per entry
+    GetDirectoryRich {
+        path: String,
+        #[serde(default)]
+        git: bool,
+        #[serde(default)]
+        diagnostics: bool,
+        #[serde(default = "show_hidden_default")]
+        show_hidden: bool,
     },
     OpenFile {
         path: String,
     },
+    OpenFiles {
+        paths: Vec<String>,
+    },
+    // Metadata for many paths in one round-trip, e.g. to check recent files
+    StatPaths {
+        paths: Vec<String>,
+    },
+    // Sends a file's bytes in DocumentChunks, e.g. for files too large to open
+    OpenFileStream {
+        path: String,
+    },
+    // Opens a file to show `line` and `character` (UTF-16), e.g. to jump to an error
+    OpenFileAt {
+        path: String,
+        line: u32,
+        character: u32,
+    },
     CloseFile {
         path: String,
     },
+    // `encoding` is an encoding_rs / WHATWG label, e.g. "windows-1251"
+    ReopenWithEncoding {
+        path: String,
+        encoding: String,
+    },
     ChangeFile {
         document: VersionedDocument,
         changes: Vec<DiffChange>,
     },
+    MergeChanges {
+        document: VersionedDocument,
+        changes: Vec<DiffChange>,
+    },
     SaveFile {
         document: VersionedDocument,
     },
+    SaveBinaryFile {
+        path: String,
+        content: Vec<u8>,
+    },
     // New LSP messages
     Completion {
         path: String,
         position: Position,
     },
+    // `plain_text` overrides whether markdown hovers are converted to plain
+    // text, which by default follows the `markdown` capability from Initialize
     Hover {
         path: String,
         position: Position,
+        #[serde(default)]
+        plain_text: Option<bool>,
+    },
+    BatchHover {
+        path: String,
+        positions: Vec<Position>,
+        #[serde(default)]
+        plain_text: Option<bool>,
     },
     Definition {
         path: String,
         position: Position,
     },
+    PeekDefinition {
+        path: String,
+        position: Position,
+        #[serde(default)]
+        context_lines: Option<u32>,
+    },
+    RenamePreview {
+        path: String,
+        position: Position,
+        new_name: String,
+    },
+    ApplyWorkspaceEdit {
+        edit: lsp_types::WorkspaceEdit,
+    },
+    // Edits of one document, e.g. from formatting or a code action. With
+    // `version` they are rejected if the document has changed since.
+    ApplyTextEdits {
+        path: String,
+        edits: Vec<lsp_types::TextEdit>,
+        #[serde(default)]
+        version: Option<i32>,
+    },
+    // Replace All across the workspace. `scope` is a glob relative to the
+    // workspace root; without `confirm` only the matches are counted.
+    ReplaceInFiles {
+        find: String,
+        replace: String,
+        #[serde(default)]
+        is_regex: bool,
+        #[serde(default)]
+        scope: Option<String>,
+        #[serde(default)]
+        confirm: bool,
+    },
 
     CreateTerminal {
         cols: u16,
         rows: u16,
+        #[serde(default)]
+        env: Option<HashMap<String, String>>,
+        // Also write the output to a log file on the server
+        #[serde(default)]
+        log_to_file: bool,
+        // Leave the log file on disk after the terminal closes
+        #[serde(default)]
+        keep_log: bool,
+    },
+    RunCommand {
+        command: String,
+        #[serde(default)]
+        args: Vec<String>,
+        // Relative to the workspace, defaults to the workspace root
+        #[serde(default)]
+        cwd: Option<String>,
     },
     ResizeTerminal {
         id: String,
@@ -78,75 +228,329 @@ pub enum ClientMessage {
         id: String,
         data: Vec<u8>,
     },
+    GetTerminalBuffer {
+        id: String,
+    },
+    GetTerminalLog {
+        id: String,
+        #[serde(default)]
+        from_offset: u64,
+    },
     CloseTerminal {
         id: String,
     },
     Search {
+        // Echoed as the search_id of the search's results and errors
+        #[serde(default)]
+        id: String,
         query: String,
         search_content: bool,
+        // Overrides `search_content` when present
+        #[serde(default)]
+        mode: Option<SearchMode>,
+        // Hides weaker matches; defaults to 8 per non-space query character
+        #[serde(default)]
+        min_score: Option<u32>,
+        // Extensions to search, e.g. ["rs", "toml"]; empty searches every file
+        #[serde(default)]
+        file_types: Option<Vec<String>>,
     },
     CreateFile {
         path: String,
         is_directory: bool,
     },
+    // Without a path, diagnostics for every file are sent
+    SubscribeDiagnostics {
+        #[serde(default)]
+        path: Option<String>,
+    },
+    // Without a path, all subscriptions are dropped
+    UnsubscribeDiagnostics {
+        #[serde(default)]
+        path: Option<String>,
+    },
+    // Translate file events into TreeNode* updates of the listed directories
+    SubscribeTreeUpdates {},
+    UnsubscribeTreeUpdates {},
+    // Receive file events grouped by directory, with repeated events collapsed
+    SetFileEventGrouping {
+        grouped: bool,
+    },
+    GitStatus {},
+    GitBlame {
+        path: String,
+    },
+    GitDiff {
+        path: String,
+        #[serde(default)]
+        base: GitDiffBase,
+    },
+    // With `dry_run`, nothing is changed and the affected paths are
+    // answered with OperationPreview
     DeleteFile {
         path: String,
+        #[serde(default)]
+        dry_run: bool,
+    },
+    // Moves a directory with its subtree, telling language servers about every
+    // file in it
+    MoveDirectory {
+        from: String,
+        to: String,
     },
     RenameFile {
         old_path: String,
         new_path: String,
+        #[serde(default)]
+        dry_run: bool,
+    },
+    DiffWithDisk {
+        path: String,
+    },
+    GetDocumentAtVersion {
+        path: String,
+        version: i32,
     },
     CancelSearch {},
+    // Drops everything in flight for this connection, e.g. when the user
+    // switches to another folder
+    CancelAll {},
+    GetMetrics {},
+    // Rebinds this connection to a session from an earlier connection
+    Resume {
+        session_id: String,
+    },
+    GetSearchStatus {
+        #[serde(default)]
+        id: String,
+    },
+    UpdateLspConfig {
+        name: String,
+        settings: serde_json::Value,
+    },
+    GetSupportedExtensions {},
+    // What kind of project the workspace is, from marker files in its root
+    GetWorkspaceInfo {},
+    ListLspServers {},
+    // Shuts a misbehaving server down, the next request for its files starts it again
+    StopLspServer {
+        name: String,
+    },
+    // Sent at connection time so language servers only get capabilities the
+    // frontend can handle
+    Initialize {
+        #[serde(default)]
+        capabilities: FrontendCapabilities,
+    },
+    // Workspace-relative glob such as `src/**/*.rs`
+    Glob {
+        pattern: String,
+    },
+}
+
+impl ClientMessage {
+    // Language server queries that may be superseded by a newer one
+    fn is_lsp_query(&self) -> bool {
+        matches!(
+            self,
+            ClientMessage::Completion { .. }
+                | ClientMessage::Hover { .. }
+                | ClientMessage::BatchHover { .. }
+                | ClientMessage::Definition { .. }
+                | ClientMessage::PeekDefinition { .. }
+        )
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(tag = "type", content = "content")]
 pub enum ServerMessage {
     Success {},
+    // First message on every connection, and the reply to a successful Resume
+    SessionEstablished {
+        session_id: String,
+        resumed: bool,
+    },
     DirectoryContent {
         path: PathBuf,
         content: Vec<FileNode>,
     },
+    FilteredDirectory {
+        path: PathBuf,
+        query: String,
+        content: Vec<FileNode>,
+    },
     FileSystemEvents {
         events: Vec<FileEvent>,
     },
+    GroupedFileSystemEvents {
+        directories: Vec<DirectoryEvents>,
+    },
+    FullTree {
+        root: FileNode,
+        // Set when max_entries was reached and some directories weren't listed
+        truncated: bool,
+    },
+    DirectoryRichContent {
+        path: PathBuf,
+        content: Vec<RichFileNode>,
+    },
+    // File watching failed; events may be missing until WatcherRestored, so
+    // clients should refresh what they show periodically in the meantime
+    WatcherError {
+        message: String,
+    },
+    WatcherRestored {},
+    WatcherDegraded {
+        reason: String,
+    },
+    TreeNodeAdded {
+        parent: PathBuf,
+        node: FileNode,
+    },
+    TreeNodeRemoved {
+        path: PathBuf,
+    },
+    TreeNodeRenamed {
+        from: PathBuf,
+        to: PathBuf,
+    },
     DocumentPreview {
         path: PathBuf,
         content: String,
         metadata: DocumentMetadata,
     },
+    // Opens an OpenFileStream; exactly `total_size` bytes follow in
+    // `chunk_count` DocumentChunks
+    DocumentStreamStart {
+        path: PathBuf,
+        total_size: u64,
+        chunk_count: u64,
+    },
     DocumentChunk {
         path: PathBuf,
         content: Vec<u8>,
         offset: u64,
     },
+    // All chunks were sent and the file didn't change while they were read
+    DocumentStreamEnd {
+        path: PathBuf,
+    },
     DocumentContent {
         path: PathBuf,
         content: String,
         metadata: DocumentMetadata,
         version: i32,
     },
+    // One entry per requested path, None if it doesn't exist or can't be read
+    PathStats {
+        stats: Vec<Option<FileMetadata>>,
+    },
+    // Follows the DocumentContent for an OpenFileAt with where to scroll to:
+    // the requested position, moved into the document if it was outside it
+    OpenFileTarget {
+        path: PathBuf,
+        position: lsp_types::Position,
+        clamped: bool,
+    },
+    // Another client changed or saved a document; refetch it if it's open here
+    DocumentChangedByPeer {
+        path: PathBuf,
+        version: i32,
+        saved: bool,
+    },
+    DocumentsOpened {
+        documents: Vec<OpenFileResult>,
+    },
+    DiffWithDiskResponse {
+        path: PathBuf,
+        changes: Vec<DiffChange>,
+    },
+    DocumentAtVersion {
+        path: PathBuf,
+        version: i32,
+        content: String,
+    },
     SaveSuccess {
         document: VersionedDocument,
     },
     ChangeSuccess {
         document: VersionedDocument,
     },
+    MergeSuccess {
+        document: VersionedDocument,
+        content: String,
+    },
+    MergeConflict {
+        document: VersionedDocument,
+        conflicts: Vec<MergeConflict>,
+    },
+    BinarySaveSuccess {
+        path: PathBuf,
+        size: u64,
+    },
+    // None when the server has nothing for the position, as opposed to an empty list
     CompletionResponse {
-        completions: lsp_types::CompletionList,
+        completions: Option<lsp_types::CompletionList>,
     },
+    // None when there is nothing to show, so no empty tooltip is opened
     HoverResponse {
-        hover: lsp_types::Hover,
+        hover: Option<lsp_types::Hover>,
+    },
+    // Aligned with the requested positions, None where there is nothing to show
+    BatchHoverResponse {
+        hovers: Vec<Option<lsp_types::Hover>>,
     },
     DefinitionResponse {
         locations: Vec<lsp_types::Location>,
     },
+    PeekDefinitionResponse {
+        location: Option<lsp_types::Location>,
+        snippet: Option<CodeSnippet>,
+    },
+    RenamePreview {
+        edit: Option<lsp_types::WorkspaceEdit>,
+        locations: Vec<EditLocation>,
+    },
+    OperationPreview {
+        affected: Vec<PathBuf>,
+    },
+    WorkspaceEditApplied {
+        results: Vec<FileEditResult>,
+    },
+    ReplaceProgress {
+        processed: usize,
+        total: usize,
+    },
+    ReplaceInFilesSummary {
+        applied: bool,
+        files: Vec<ReplacedFile>,
+        failed: Vec<ReplaceFailure>,
+    },
 
     Error {
         message: String,
     },
+    LspError {
+        code: i64,
+        message: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        data: Option<serde_json::Value>,
+    },
+    // The language server is still indexing and couldn't answer `feature`
+    // (an LSP method) yet; clients can show that and retry
+    LspNotReady {
+        feature: String,
+    },
+    // A `request` (client message type) was superseded or cancelled, and
+    // won't get its response
+    LspRequestCancelled {
+        request: String,
+    },
     TerminalCreated {
         terminal_id: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        log_path: Option<PathBuf>,
     },
     TerminalOutput {
         terminal_id: String,
@@ -155,82 +559,525 @@ pub enum ServerMessage {
     TerminalClosed {
         id: String,
     },
+    TerminalBuffer {
+        terminal_id: String,
+        screen: ScreenSnapshot,
+    },
+    TerminalLog {
+        terminal_id: String,
+        // Where `data` starts and where the next page does
+        offset: u64,
+        next_offset: u64,
+        total_bytes: u64,
+        data: Vec<u8>,
+    },
+    CommandStarted {
+        terminal_id: String,
+    },
+    CommandFinished {
+        terminal_id: String,
+        exit_code: u32,
+        duration_ms: u64,
+    },
     TerminalError {
         terminal_id: String,
         error: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        code: Option<TerminalErrorCode>,
     },
     SearchStatus {
         status: SearchStatus,
     },
+    Diagnostics {
+        path: PathBuf,
+        version: Option<i32>,
+        diagnostics: Vec<lsp_types::Diagnostic>,
+    },
+    GitStatus {
+        files: Vec<GitFileStatus>,
+    },
+    GitBlame {
+        path: PathBuf,
+        // Document version the line numbers refer to, None if the file isn't open
+        version: Option<i32>,
+        lines: Vec<BlameLine>,
+    },
+    GitDiff {
+        path: PathBuf,
+        base: GitDiffBase,
+        hunks: Vec<GitDiffHunk>,
+    },
     SearchResults {
         search_id: String,
         items: Vec<SearchResultItem>,
         is_complete: bool,
     },
+    Metrics {
+        active_connections: usize,
+        // Handling time of client messages, by message type
+        messages: BTreeMap<String, HistogramSnapshot>,
+        // Round trip time of language server requests, by LSP method
+        lsp_requests: BTreeMap<String, HistogramSnapshot>,
+        document_cache: CacheStats,
+        // Time from starting or refining a search until its final results
+        searches: HistogramSnapshot,
+    },
+    SearchState {
+        search_id: String,
+        #[serde(flatten)]
+        state: SearchState,
+    },
+    // A language server can't be used, e.g. so the UI can offer to install it
+    LspStatus {
+        name: String,
+        state: LspServerState,
+    },
+    GlobMatches {
+        pattern: String,
+        // Relative to the workspace, sorted
+        paths: Vec<PathBuf>,
+        // Set when there were more than MAX_GLOB_MATCHES matches
+        truncated: bool,
+    },
+    LspServers {
+        servers: Vec<LspServerInfo>,
+    },
+    WorkspaceInfo {
+        root: PathBuf,
+        project_types: Vec<ProjectType>,
+        index_state: IndexState,
+    },
+    SupportedExtensions {
+        // Extension without the dot -> language server name
+        extensions: BTreeMap<String, String>,
+    },
+    Initialized {
+        capabilities: FrontendCapabilities,
+    },
+}
+
+// Outcome for one of the paths in an OpenFiles request
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", content = "content")]
+pub enum OpenFileResult {
+    Opened {
+        path: PathBuf,
+        content: String,
+        metadata: DocumentMetadata,
+        version: i32,
+    },
+    // The path was a directory, which is listed instead
+    Directory {
+        path: PathBuf,
+        content: Vec<FileNode>,
+    },
+    Failed {
+        path: String,
+        error: String,
+    },
+}
+
+// Outcome of one step of ApplyWorkspaceEdit. Steps stop at the first failure,
+// which is reported last.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", content = "content")]
+pub enum FileEditResult {
+    // `version` is set for open documents
+    Edited {
+        path: PathBuf,
+        version: Option<i32>,
+    },
+    Created {
+        path: PathBuf,
+    },
+    Renamed {
+        old_path: PathBuf,
+        new_path: PathBuf,
+    },
+    Deleted {
+        path: PathBuf,
+    },
+    Failed {
+        error: String,
+    },
+}
+
+// A directory entry with the decorations a file tree shows. Each is None when
+// not requested or not available (no repository, no language server data).
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RichFileNode {
+    #[serde(flatten)]
+    pub node: FileNode,
+    pub git: Option<GitFileStatus>,
+    pub diagnostics: Option<DiagnosticCounts>,
+}
+
+// `version` is set when the file is an open document
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReplacedFile {
+    pub path: PathBuf,
+    pub replacements: usize,
+    pub version: Option<i32>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReplaceFailure {
+    pub path: PathBuf,
+    pub error: String,
+}
+
+// Current text of a line that an edit will touch, `line` is zero-based
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EditLocation {
+    pub path: PathBuf,
+    pub line: u32,
+    pub text: String,
+}
+
+// Source lines around a location, `start_line` is the zero-based line of the first entry
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CodeSnippet {
+    pub start_line: u32,
+    pub lines: Vec<String>,
+}
+
+const DEFAULT_PEEK_CONTEXT_LINES: u32 = 3;
+const MAX_PEEK_CONTEXT_LINES: u32 = 50;
+// Upper bound on the paths in a single OpenFiles request
+const MAX_OPEN_FILES: usize = 100;
+// Entries listed by GetFullTree unless the client asks for another cap
+const DEFAULT_FULL_TREE_ENTRIES: usize = 10_000;
+// Paths returned by one Glob
+const MAX_GLOB_MATCHES: usize = 10_000;
+// ReplaceInFiles reports progress after this many files
+const REPLACE_PROGRESS_INTERVAL: usize = 50;
+
+// Bytes in each DocumentChunk of an OpenFileStream
+const STREAM_CHUNK_BYTES: u64 = 64 * 1024;
+
+// Bound on messages queued for a single connection's writer task
+const OUTGOING_QUEUE_SIZE: usize = 256;
+
+// The connection's only writer: each message goes out as one whole frame, in
+// the order it was queued. Returns the sink once every sender is gone.
+async fn write_messages<S>(mut write: S, mut rx: mpsc::Receiver<ServerMessage>) -> S
+where
+    S: futures_util::Sink<Message> + Unpin,
+    S::Error: std::fmt::Display,
+{
+    while let Some(message) = rx.recv().await {
+        let text = match serde_json::to_string(&message) {
+            Ok(text) => text,
+            Err(e) => {
+                eprintln!("Failed to serialize message: {}", e);
+                continue;
+            }
+        };
+        if let Err(e) = write.send(Message::Text(text)).await {
+            eprintln!("Failed to write to websocket: {}", e);
+            break;
+        }
+    }
+    write
+}
+
+type MessageSender = mpsc::Sender<ServerMessage>;
+
+// Runtime options set from the command line
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    // File system events are flushed to a client once this many are buffered...
+    pub fs_batch_size: usize,
+    // ...or once this much time has passed since the last flush
+    pub fs_batch_interval: Duration,
+    // Directories outside the workspace that files may be opened from read-only
+    pub allowed_paths: Vec<PathBuf>,
+    // Scan interval of the polling watcher used when the OS watch limit is hit
+    pub watch_poll_interval: Duration,
+    // Modifications of a file are reported once it has been quiet this long
+    pub fs_modify_debounce: Duration,
+    // Language servers idle for this long are stopped until needed again
+    pub lsp_idle_timeout: Option<Duration>,
+    // Unanswered requests per language server before new ones are refused
+    pub lsp_max_pending_requests: usize,
+    // What starts language servers, and how long after startup at the earliest
+    pub lsp_start: LspStartPolicy,
+    // Recent completion responses answered again without asking the server
+    pub lsp_completion_cache: CompletionCacheConfig,
+    // Rewrites made to documents as they are saved
+    pub save_normalization: SaveNormalization,
+    // Open documents up to this size are kept in memory instead of re-read
+    pub max_open_document_cache_size: u64,
+    // How long a disconnected client's session can be resumed
+    pub session_timeout: Duration,
+    // Which symlinks directory listings, search and the file watcher follow
+    pub follow_symlinks: SymlinkPolicy,
+    pub terminal: TerminalConfig,
+    pub search: SearchConfig,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            fs_batch_size: 100,
+            fs_batch_interval: Duration::from_millis(100),
+            allowed_paths: Vec::new(),
+            watch_poll_interval: Duration::from_secs(2),
+            fs_modify_debounce: Duration::from_millis(200),
+            lsp_idle_timeout: None,
+            lsp_max_pending_requests: DEFAULT_MAX_PENDING_REQUESTS,
+            lsp_start: LspStartPolicy::default(),
+            lsp_completion_cache: CompletionCacheConfig::default(),
+            save_normalization: SaveNormalization::default(),
+            max_open_document_cache_size: crate::file_system::MAX_FILE_SIZE,
+            session_timeout: Duration::from_secs(300),
+            follow_symlinks: SymlinkPolicy::default(),
+            terminal: TerminalConfig::default(),
+            search: SearchConfig::default(),
+        }
+    }
+}
+
+// Document update made through one connection, forwarded to all the others
+#[derive(Debug, Clone)]
+struct DocumentEvent {
+    origin: uuid::Uuid,
+    path: PathBuf,
+    version: i32,
+    saved: bool,
+}
+
+// Files a connection wants diagnostics for
+#[derive(Default)]
+struct DiagnosticsSubscription {
+    all: bool,
+    paths: HashSet<PathBuf>,
+}
+
+// State owned by a single websocket connection
+struct ConnectionState {
+    id: uuid::Uuid,
+    // Latest in-flight LSP query of each kind; a newer query of the same kind
+    // supersedes it, e.g. moving the cursor cancels the previous hover
+    lsp_requests: tokio::sync::Mutex<HashMap<&'static str, CancellationToken>>,
+    // Files outside the workspace that a definition lookup pointed to. The
+    // client may open these read-only, e.g. to jump into standard library sources.
+    definition_targets: tokio::sync::Mutex<HashSet<PathBuf>>,
+    diagnostics: tokio::sync::Mutex<DiagnosticsSubscription>,
+    // Directories this client listed, and whether it wants tree updates for them
+    loaded_tree: tokio::sync::Mutex<LoadedTree>,
+    tree_updates: std::sync::atomic::AtomicBool,
+    // Send GroupedFileSystemEvents instead of FileSystemEvents
+    grouped_events: std::sync::atomic::AtomicBool,
+    // The frontend can't render markdown
+    plain_text_hovers: std::sync::atomic::AtomicBool,
+    // Sent with Initialize, handed to the language servers again on Resume
+    frontend_capabilities: tokio::sync::Mutex<Option<FrontendCapabilities>>,
+    // File streams still sending chunks
+    streams: tokio::sync::Mutex<Vec<tokio::task::AbortHandle>>,
+}
+
+impl ConnectionState {
+    fn new() -> Self {
+        Self {
+            id: uuid::Uuid::new_v4(),
+            lsp_requests: Default::default(),
+            definition_targets: Default::default(),
+            diagnostics: Default::default(),
+            loaded_tree: Default::default(),
+            tree_updates: Default::default(),
+            grouped_events: Default::default(),
+            plain_text_hovers: Default::default(),
+            frontend_capabilities: Default::default(),
+            streams: Default::default(),
+        }
+    }
+
+    fn wants_plain_text(&self, requested: Option<bool>) -> bool {
+        requested.unwrap_or_else(|| self.plain_text_hovers.load(std::sync::atomic::Ordering::Relaxed))
+    }
+
+    // Tree updates for a batch of file events, empty unless subscribed
+    async fn tree_updates(&self, events: &[FileEvent]) -> Vec<ServerMessage> {
+        let mut tree = self.loaded_tree.lock().await;
+        let updates = tree.apply(events);
+        if !self.tree_updates.load(std::sync::atomic::Ordering::Relaxed) {
+            return Vec::new();
+        }
+        updates
+            .into_iter()
+            .map(|update| match update {
+                TreeUpdate::Added { parent, node } => ServerMessage::TreeNodeAdded { parent, node },
+                TreeUpdate::Removed { path } => ServerMessage::TreeNodeRemoved { path },
+                TreeUpdate::Renamed { from, to } => ServerMessage::TreeNodeRenamed { from, to },
+            })
+            .collect()
+    }
+
+    async fn wants_diagnostics(&self, path: &Path) -> bool {
+        let subscription = self.diagnostics.lock().await;
+        subscription.all || subscription.paths.contains(path)
+    }
+
+    async fn begin_lsp_request(&self, kind: &'static str) -> CancellationToken {
+        let token = CancellationToken::default();
+        if let Some(previous) = self.lsp_requests.lock().await.insert(kind, token.clone()) {
+            previous.cancel();
+        }
+        token
+    }
+
+    async fn allow_definition_targets(&self, locations: &[lsp_types::Location]) {
+        let mut targets = self.definition_targets.lock().await;
+        for location in locations {
+            if let Ok(path) = uri_to_path(&location.uri).and_then(|p| Ok(p.canonicalize()?)) {
+                targets.insert(path);
+            }
+        }
+    }
+
+    async fn is_definition_target(&self, path: &Path) -> bool {
+        self.definition_targets.lock().await.contains(path)
+    }
+
+    async fn cancel_lsp_requests(&self) {
+        for (_, token) in self.lsp_requests.lock().await.drain() {
+            token.cancel();
+        }
+    }
+
+    async fn track_stream(&self, stream: tokio::task::AbortHandle) {
+        let mut streams = self.streams.lock().await;
+        streams.retain(|stream| !stream.is_finished());
+        streams.push(stream);
+    }
+
+    async fn cancel_streams(&self) {
+        for stream in self.streams.lock().await.drain(..) {
+            stream.abort();
+        }
+    }
 }
 
 pub struct Server {
     port: u16,
+    config: ServerConfig,
     file_system: Arc<FileSystem>,
     lsp_manager: Arc<LspManager>,
     terminal_manager: Arc<TerminalManager>,
     search_manager: Arc<SearchManager>,
+    // The connection that started the running search, which CancelAll may close
+    search_owner: Arc<tokio::sync::Mutex<Option<uuid::Uuid>>>,
+    git_manager: Arc<GitManager>,
+    document_events: broadcast::Sender<DocumentEvent>,
+    sessions: Arc<DetachedSessions<Arc<ConnectionState>>>,
+    active_connections: Arc<std::sync::atomic::AtomicUsize>,
+    message_latencies: Arc<LatencyRegistry>,
+}
+
+// Just the tag of a client message, for metrics
+#[derive(Deserialize)]
+struct MessageType {
+    #[serde(rename = "type")]
+    name: String,
 }
 
 
 impl Server {
-    pub fn new(workspace_path: PathBuf, port: u16) -> Result<Self> {
+    pub fn new(
+        workspace_path: PathBuf,
+        port: u16,
+        lsp_configs: Vec<LspConfiguration>,
+        config: ServerConfig,
+    ) -> Result<Self> {
         // canonicalize workspace path
         let workspace_path = workspace_path.canonicalize()?;
-        let file_system = Arc::new(FileSystem::new(workspace_path.clone())?);
-
-        let lsp_configs = vec![
-            LspConfiguration {
-                name: "rust-analyzer".to_string(),
-                file_extensions: vec!["rs".to_string()],
-                server_path: PathBuf::from("rust-analyzer"),
-                server_args: vec![],
-                initialization_options: None,
-            },
-            // Add more language servers as needed
-        ];
+        let file_system = Arc::new(FileSystem::new(
+            workspace_path.clone(),
+            config.follow_symlinks,
+            config.max_open_document_cache_size,
+        )?);
+
         let mut new_path = workspace_path.clone();
         if !new_path.is_absolute() {
             new_path = workspace_path.canonicalize()?;
         }
 
-        let lsp_manager = Arc::new(LspManager::new(new_path, lsp_configs));
-        let terminal_manager = Arc::new(TerminalManager::new());
-        let search_manager = SearchManager::new(workspace_path.clone());
-
-
-        let terminal_manager = Arc::new(TerminalManager::new());
-        let search_manager = SearchManager::new(workspace_path.clone());
+        let lsp_manager = Arc::new(LspManager::new(
+            new_path,
+            lsp_configs,
+            config.lsp_idle_timeout,
+            config.lsp_max_pending_requests,
+            config.lsp_start,
+            config.lsp_completion_cache,
+        ));
+        lsp_manager.set_open_documents({
+            let file_system = Arc::clone(&file_system);
+            Arc::new(move || {
+                let file_system = Arc::clone(&file_system);
+                Box::pin(async move { file_system.open_documents().await })
+            })
+        });
+        lsp_manager.set_open_paths({
+            let file_system = Arc::clone(&file_system);
+            Arc::new(move || {
+                let file_system = Arc::clone(&file_system);
+                Box::pin(async move { file_system.open_paths().await })
+            })
+        });
+        lsp_manager.set_document_content({
+            let file_system = Arc::clone(&file_system);
+            Arc::new(move |path| {
+                let file_system = Arc::clone(&file_system);
+                // Open documents as of the version language servers were sent
+                Box::pin(async move {
+                    match file_system.document_snapshot(&path).await? {
+                        Some((content, _)) => Ok(content),
+                        None => file_system.get_document_content(&path).await,
+                    }
+                })
+            })
+        });
+        let terminal_manager = Arc::new(TerminalManager::new(config.terminal.clone()));
+        let search_manager = SearchManager::new(
+            workspace_path.clone(),
+            config.search.clone(),
+            config.follow_symlinks,
+        );
+        let git_manager = GitManager::new(workspace_path.clone());
+        let (document_events, _) = broadcast::channel(100);
+        let sessions = Arc::new(DetachedSessions::new(config.session_timeout));
 
         Ok(Self {
             port,
+            config,
             file_system,
             lsp_manager,
             terminal_manager,
             search_manager,
+            search_owner: Default::default(),
+            git_manager,
+            document_events,
+            sessions,
+            active_connections: Default::default(),
+            message_latencies: Default::default(),
         })
     }
 
     async fn handle_client_message(
         &self,
         message: ClientMessage,
-        write: &mut futures_util::stream::SplitSink<
-            tokio_tungstenite::WebSocketStream<TcpStream>,
-            tokio_tungstenite::tungstenite::Message,
-        >,
+        tx: &MessageSender,
+        connection: &ConnectionState,
     ) -> Result<()> {
         let response = match message {
             ClientMessage::GetDirectory {
                 path: relative_path,
+                show_hidden,
             } => {
                 println!("Received GetDirectory message: {:?}", relative_path);
                 match get_full_path(self.file_system.get_workspace_path(), &relative_path) {
-                    Ok(full_path) => match self.file_system.load_directory(&full_path).await {
+                    Ok(full_path) => match self.list_directory(&full_path, Some(show_hidden), connection).await {
                         Ok(content) => {
                             println!("Loaded directory: {:?}", full_path);
                             ServerMessage::DirectoryContent {
@@ -247,12 +1094,41 @@ impl Server {
                     },
                 }
             }
-            ClientMessage::RefreshDirectory {
-                path: relative_path,
-            } => match get_full_path(self.file_system.get_workspace_path(), &relative_path) {
+            ClientMessage::GetFullTree { max_entries, show_hidden } => {
+                let max_entries = max_entries.unwrap_or(DEFAULT_FULL_TREE_ENTRIES);
+                match self.file_system.full_tree(max_entries, show_hidden).await {
+                    Ok((root, truncated)) => {
+                        let mut loaded_tree = connection.loaded_tree.lock().await;
+                        loaded_tree.set_show_hidden(show_hidden);
+                        let mut nodes = vec![&root];
+                        while let Some(node) = nodes.pop() {
+                            if let Some(children) = node.children.as_ref() {
+                                loaded_tree.mark_loaded(node.path.clone());
+                                nodes.extend(children);
+                            }
+                        }
+                        drop(loaded_tree);
+                        ServerMessage::FullTree { root, truncated }
+                    }
+                    Err(e) => ServerMessage::Error {
+                        message: format!("Failed to walk workspace: {}", e),
+                    },
+                }
+            }
+            ClientMessage::RefreshDirectory {
+                path: relative_path,
+                show_hidden,
+            } => match get_full_path(self.file_system.get_workspace_path(), &relative_path) {
                 Ok(full_path) => match self.file_system.refresh_directory(&full_path).await {
-                    Ok(content) => {
+                    Ok(mut content) => {
+                        let mut loaded_tree = connection.loaded_tree.lock().await;
+                        loaded_tree.set_show_hidden(show_hidden);
+                        if !show_hidden {
+                            retain_visible(&mut content);
+                        }
                         println!("Refreshed directory: {:?}", full_path);
+                        loaded_tree.mark_loaded(full_path.clone());
+                        drop(loaded_tree);
                         ServerMessage::DirectoryContent {
                             path: full_path,
                             content,
@@ -266,8 +1142,52 @@ impl Server {
                     message: format!("Invalid path: {}", e),
                 },
             },
+            ClientMessage::FilterDirectory {
+                path: relative_path,
+                query,
+            } => match get_full_path(self.file_system.get_workspace_path(), &relative_path) {
+                Ok(full_path) => match self.file_system.filter_directory(&full_path, &query).await {
+                    Ok(mut content) => {
+                        if !connection.loaded_tree.lock().await.show_hidden() {
+                            retain_visible(&mut content);
+                        }
+                        ServerMessage::FilteredDirectory {
+                            path: full_path,
+                            query,
+                            content,
+                        }
+                    }
+                    Err(e) => ServerMessage::Error {
+                        message: format!("Failed to load directory: {}", e),
+                    },
+                },
+                Err(e) => ServerMessage::Error {
+                    message: format!("Invalid path: {}", e),
+                },
+            },
+            ClientMessage::GetDirectoryRich {
+                path: relative_path,
+                git,
+                diagnostics,
+                show_hidden,
+            } => match get_full_path(self.file_system.get_workspace_path(), &relative_path) {
+                Ok(full_path) => match self.list_directory(&full_path, Some(show_hidden), connection).await {
+                    Ok(nodes) => {
+                        ServerMessage::DirectoryRichContent {
+                            path: full_path,
+                            content: self.decorate_nodes(nodes, git, diagnostics).await,
+                        }
+                    }
+                    Err(e) => ServerMessage::Error {
+                        message: format!("Failed to load directory: {}", e),
+                    },
+                },
+                Err(e) => ServerMessage::Error {
+                    message: format!("Invalid path: {}", e),
+                },
+            },
             ClientMessage::CloseFile { path } => {
-                match get_full_path(self.file_system.get_workspace_path(), &path) {
+                match self.readable_path(&path, connection).await {
                     Ok(full_path) => {
                         // Validate file was open
                         let document_state = self
@@ -277,32 +1197,16 @@ impl Server {
                             .map_err(|e| anyhow::anyhow!("Failed to get document state: {}", e))?;
 
                         if !document_state.is_open {
-                            return Ok(write
-                                .send(Message::Text(serde_json::to_string(
-                                    &ServerMessage::Error {
-                                        message: format!("File was not open: {}", path),
-                                    },
-                                )?))
+                            return Ok(tx
+                                .send(ServerMessage::Error {
+                                    message: format!("File was not open: {}", path),
+                                })
                                 .await?);
                         }
 
                         // Notify LSP first
-                        if let Some(server) = self.lsp_manager.get_server(&full_path).await? {
-                            if let Err(e) = server
-                                .send_notification(
-                                    "textDocument/didClose",
-                                    serde_json::json!({
-                                        "textDocument": {
-                                            "uri": full_path.to_str().ok_or_else(|| {
-                                                anyhow::anyhow!("Invalid UTF-8 in path")
-                                            })?
-                                        }
-                                    }),
-                                )
-                                .await
-                            {
-                                eprintln!("LSP close notification failed: {}", e);
-                            }
+                        if let Err(e) = self.lsp_manager.notify_document_closed(&full_path).await {
+                            eprintln!("LSP close notification failed: {}", e);
                         }
 
                         // Clean up resources
@@ -324,49 +1228,208 @@ impl Server {
                     },
                 }
             }
-            ClientMessage::OpenFile { path } => {
-                match get_full_path(self.file_system.get_workspace_path(), &path) {
-                    Ok(full_path) => {
-                        // Validate file exists and is readable before opening
-                        if !full_path.exists() {
-                            ServerMessage::Error {
-                                message: format!("File does not exist: {}", path),
-                            }
-                        } else if !full_path.is_file() {
-                            ServerMessage::Error {
-                                message: format!("Path is not a file: {}", path),
+            ClientMessage::ReopenWithEncoding { path, encoding } => {
+                let Some(encoding) = encoding_rs::Encoding::for_label(encoding.as_bytes()) else {
+                    return Ok(tx
+                        .send(ServerMessage::Error {
+                            message: format!("Unknown encoding: {}", encoding),
+                        })
+                        .await?);
+                };
+                match self.readable_path(&path, connection).await {
+                    Ok(full_path) => match self
+                        .file_system
+                        .reopen_with_encoding(&full_path, encoding)
+                        .await
+                    {
+                        Ok((content, metadata, version)) => {
+                            let lsp_change = lsp_types::TextDocumentContentChangeEvent {
+                                range: None,
+                                range_length: None,
+                                text: content.clone(),
+                            };
+                            if let Err(e) = self
+                                .lsp_manager
+                                .notify_document_changed(&full_path, vec![lsp_change], version)
+                                .await
+                            {
+                                eprintln!("LSP change notification failed: {}", e);
                             }
-                        } else {
-                            match self.file_system.open_file(&full_path).await {
-                                Ok((content, metadata, version)) => {
-                                    // First notify LSP before sending content to client
-                                    if let Err(e) = self
-                                        .lsp_manager
-                                        .notify_document_opened(&full_path, &content, version)
-                                        .await
-                                    {
-                                        eprintln!("LSP notification failed: {}", e);
-                                    }
 
-                                    // Track file state for synchronization
-                                    ServerMessage::DocumentContent {
-                                        path: full_path,
-                                        content,
-                                        metadata,
-                                        version,
-                                    }
-                                }
-                                Err(e) => ServerMessage::Error {
-                                    message: format!("Failed to open file: {}", e),
-                                },
+                            self.notify_peers(connection, &full_path, version, false);
+                            ServerMessage::DocumentContent {
+                                path: full_path,
+                                content,
+                                metadata,
+                                version,
                             }
                         }
-                    }
+                        Err(e) => ServerMessage::Error {
+                            message: format!("Failed to reopen file: {}", e),
+                        },
+                    },
                     Err(e) => ServerMessage::Error {
                         message: format!("Invalid path: {}", e),
                     },
                 }
             }
+            ClientMessage::OpenFile { path } => match self.open_document(&path, connection).await {
+                Ok((full_path, content, metadata, version)) => {
+                    // First notify LSP before sending content to client
+                    if let Err(e) = self
+                        .lsp_manager
+                        .notify_document_opened(&full_path, &content, version)
+                        .await
+                    {
+                        eprintln!("LSP notification failed: {}", e);
+                    }
+
+                    // Track file state for synchronization
+                    ServerMessage::DocumentContent {
+                        path: full_path,
+                        content,
+                        metadata,
+                        version,
+                    }
+                }
+                Err(e) => match e.downcast::<IsADirectory>() {
+                    Ok(IsADirectory { path }) => match self.list_directory(&path, None, connection).await {
+                        Ok(content) => ServerMessage::DirectoryContent { path, content },
+                        Err(e) => ServerMessage::Error {
+                            message: format!("Failed to load directory: {}", e),
+                        },
+                    },
+                    Err(e) => ServerMessage::Error {
+                        message: e.to_string(),
+                    },
+                },
+            },
+            ClientMessage::StatPaths { paths } => {
+                if paths.len() > MAX_OPEN_FILES {
+                    ServerMessage::Error {
+                        message: format!("Too many paths requested: {} (max {})", paths.len(), MAX_OPEN_FILES),
+                    }
+                } else {
+                    let mut stats = Vec::with_capacity(paths.len());
+                    for path in paths {
+                        // Paths outside the workspace are reported like missing ones
+                        let stat = match self.readable_path(&path, connection).await {
+                            Ok(full_path) => FileMetadata::read(&full_path).await.ok(),
+                            Err(_) => None,
+                        };
+                        stats.push(stat);
+                    }
+                    ServerMessage::PathStats { stats }
+                }
+            }
+            ClientMessage::OpenFileStream { path } => match self.readable_path(&path, connection).await {
+                Ok(full_path) => {
+                    // Chunks go out in the background so other requests aren't held up
+                    let tx = tx.clone();
+                    let stream = tokio::spawn(async move {
+                        if let Err(e) = stream_file(&full_path, &tx).await {
+                            let _ = tx
+                                .send(ServerMessage::Error {
+                                    message: format!("Failed to stream {:?}: {}", full_path, e),
+                                })
+                                .await;
+                        }
+                    });
+                    connection.track_stream(stream.abort_handle()).await;
+                    ServerMessage::Success {}
+                }
+                Err(e) => ServerMessage::Error {
+                    message: format!("Invalid path: {}", e),
+                },
+            },
+            ClientMessage::OpenFileAt { path, line, character } => {
+                match self.open_document(&path, connection).await {
+                    Ok((full_path, content, metadata, version)) => {
+                        if let Err(e) = self
+                            .lsp_manager
+                            .notify_document_opened(&full_path, &content, version)
+                            .await
+                        {
+                            eprintln!("LSP notification failed: {}", e);
+                        }
+
+                        let (position, clamped) =
+                            clamp_position(&content, lsp_types::Position { line, character });
+                        tx.send(ServerMessage::DocumentContent {
+                            path: full_path.clone(),
+                            content,
+                            metadata,
+                            version,
+                        })
+                        .await?;
+                        ServerMessage::OpenFileTarget {
+                            path: full_path,
+                            position,
+                            clamped,
+                        }
+                    }
+                    Err(e) => ServerMessage::Error {
+                        message: e.to_string(),
+                    },
+                }
+            }
+            ClientMessage::OpenFiles { paths } => {
+                if paths.len() > MAX_OPEN_FILES {
+                    ServerMessage::Error {
+                        message: format!(
+                            "Too many files requested: {} (max {})",
+                            paths.len(),
+                            MAX_OPEN_FILES
+                        ),
+                    }
+                } else {
+                    let mut documents = Vec::with_capacity(paths.len());
+                    for path in paths {
+                        documents.push(match self.open_document(&path, connection).await {
+                            Ok((full_path, content, metadata, version)) => OpenFileResult::Opened {
+                                path: full_path,
+                                content,
+                                metadata,
+                                version,
+                            },
+                            Err(e) => match e.downcast::<IsADirectory>() {
+                                Ok(IsADirectory { path: full_path }) => {
+                                    match self.list_directory(&full_path, None, connection).await {
+                                        Ok(content) => OpenFileResult::Directory {
+                                            path: full_path,
+                                            content,
+                                        },
+                                        Err(e) => OpenFileResult::Failed {
+                                            path,
+                                            error: format!("Failed to load directory: {}", e),
+                                        },
+                                    }
+                                }
+                                Err(e) => OpenFileResult::Failed {
+                                    path,
+                                    error: e.to_string(),
+                                },
+                            },
+                        });
+                    }
+
+                    let opened: Vec<_> = documents
+                        .iter()
+                        .filter_map(|document| match document {
+                            OpenFileResult::Opened {
+                                path,
+                                content,
+                                version,
+                                ..
+                            } => Some((path, content.as_str(), *version)),
+                            OpenFileResult::Directory { .. } | OpenFileResult::Failed { .. } => None,
+                        })
+                        .collect();
+                    self.lsp_manager.notify_documents_opened(&opened).await;
+
+                    ServerMessage::DocumentsOpened { documents }
+                }
+            }
 
             ClientMessage::ChangeFile { document, changes } => {
 
@@ -376,12 +1439,10 @@ impl Server {
                 ) {
                     Ok(p) => p,
                     Err(e) => {
-                        return Ok(write
-                            .send(Message::Text(serde_json::to_string(
-                                &ServerMessage::Error {
-                                    message: format!("Invalid document path: {}", e),
-                                },
-                            )?))
+                        return Ok(tx
+                            .send(ServerMessage::Error {
+                                message: format!("Invalid document path: {}", e),
+                            })
                             .await?)
                     }
                 };
@@ -392,8 +1453,13 @@ impl Server {
                     .await
                 {
                     Ok(new_document) => {
-                        // Get updated content for LSP
-                        match self.file_system.get_document_content(&path).await {
+                        // Content of exactly this version, a concurrent change may
+                        // already have made a newer one
+                        match self
+                            .file_system
+                            .get_content_at_version(&path, new_document.version)
+                            .await
+                        {
                             Ok(content) => {
                                 // Convert to LSP format - now we send the full content
                                 // as a single change since we're working with line-based diffs
@@ -416,6 +1482,7 @@ impl Server {
                                     eprintln!("LSP change notification failed: {}", e);
                                 }
 
+                                self.notify_peers(connection, &path, new_document.version, false);
                                 ServerMessage::ChangeSuccess {
                                     document: new_document,
                                 }
@@ -439,61 +1506,104 @@ impl Server {
                 ) {
                     Ok(p) => p,
                     Err(e) => {
-                        return Ok(write
-                            .send(Message::Text(serde_json::to_string(
-                                &ServerMessage::Error {
-                                    message: format!("Invalid document path: {}", e),
-                                },
-                            )?))
+                        return Ok(tx
+                            .send(ServerMessage::Error {
+                                message: format!("Invalid document path: {}", e),
+                            })
                             .await?)
                     }
                 };
 
-                // Get content before saving for LSP notification
-                match self.file_system.get_document_content(&path).await {
-                    Ok(content) => {
-                        match self.file_system.save_document(document.clone()).await {
-                            Ok(new_document) => {
-                                // Notify LSP about save
-                                if let Err(e) = self
-                                    .lsp_manager
-                                    .notify_document_saved(&path, &content, new_document.version)
-                                    .await
-                                {
-                                    eprintln!("LSP save notification failed: {}", e);
-                                }
+                // Saving can rewrite the content, which is a change of its own that
+                // servers and the client hear about before it's on disk
+                let normalized = match self
+                    .file_system
+                    .normalize_for_save(&document, self.config.save_normalization)
+                    .await
+                {
+                    Ok(normalized) => normalized,
+                    Err(e) => {
+                        return Ok(tx
+                            .send(ServerMessage::Error {
+                                message: format!("Failed to save document: {}", e),
+                            })
+                            .await?)
+                    }
+                };
+                if let Some((content, metadata, version)) = normalized {
+                    let lsp_change = lsp_types::TextDocumentContentChangeEvent {
+                        range: None,
+                        range_length: None,
+                        text: content.clone(),
+                    };
+                    if let Err(e) = self
+                        .lsp_manager
+                        .notify_document_changed(&path, vec![lsp_change], version)
+                        .await
+                    {
+                        eprintln!("LSP change notification failed: {}", e);
+                    }
+                    tx.send(ServerMessage::DocumentContent {
+                        path: path.clone(),
+                        content,
+                        metadata,
+                        version,
+                    })
+                    .await?;
+                }
 
-                                ServerMessage::SaveSuccess {
-                                    document: new_document,
+                match self.file_system.write_document(&path).await {
+                    Ok(new_document) => {
+                        // Notify LSP about save
+                        match self.file_system.get_document_content(&path).await {
+                            Ok(content) => {
+                                if let Err(e) = self.lsp_manager.notify_document_saved(&path, &content).await {
+                                    eprintln!("LSP save notification failed: {}", e);
                                 }
                             }
+                            Err(e) => eprintln!("Failed to get document content: {}", e),
+                        }
+
+                        self.notify_peers(connection, &path, new_document.version, true);
+                        ServerMessage::SaveSuccess {
+                            document: new_document,
+                        }
+                    }
+                    Err(e) => ServerMessage::Error {
+                        message: format!("Failed to save document: {}", e),
+                    },
+                }
+            }
+            ClientMessage::SaveBinaryFile { path, content } => {
+                match join_workspace_path(self.file_system.get_workspace_path(), &path) {
+                    Ok(full_path) => {
+                        match self.file_system.save_binary_file(&full_path, &content).await {
+                            Ok(size) => ServerMessage::BinarySaveSuccess {
+                                path: full_path,
+                                size,
+                            },
                             Err(e) => ServerMessage::Error {
-                                message: format!("Failed to save document: {}", e),
+                                message: format!("Failed to save binary file: {}", e),
                             },
                         }
                     }
                     Err(e) => ServerMessage::Error {
-                        message: format!("Failed to get document content: {}", e),
+                        message: format!("Invalid path: {}", e),
                     },
                 }
             }
             ClientMessage::Completion { path, position } => {
                 println!("Received completion request: {:?}", path);
-                match get_full_path(self.file_system.get_workspace_path(), &path) {
+                match self.readable_path(&path, connection).await {
                     Ok(full_path) => {
-                        match self.lsp_manager.get_completions(&full_path, position).await {
-                            Ok(Some(completions)) => {
-                                ServerMessage::CompletionResponse { completions }
-                            }
-                            Ok(None) => ServerMessage::CompletionResponse {
-                                completions: CompletionList {
-                                    is_incomplete: false,
-                                    items: vec![],
-                                },
-                            },
-                            Err(e) => ServerMessage::Error {
-                                message: e.to_string(),
-                            },
+                        let cancel = connection.begin_lsp_request("completion").await;
+                        match self
+                            .lsp_manager
+                            .get_completions(&full_path, position, Some(&cancel))
+                            .await
+                        {
+                            Ok(completions) => ServerMessage::CompletionResponse { completions },
+                            Err(e) => lsp_error_message(e, "Completion"),
                         }
                     }
                     Err(e) => ServerMessage::Error {
@@ -502,24 +1612,48 @@ impl Server {
                 }
             }
 
-            ClientMessage::Hover { path, position } => {
+            ClientMessage::Hover { path, position, plain_text } => {
                 println!("Received hover request: {:?}", path);
 
 
-                match get_full_path(self.file_system.get_workspace_path(), &path) {
-                    Ok(full_path) => match self.lsp_manager.get_hover(&full_path, position).await {
-                        Ok(Some(hover)) => ServerMessage::HoverResponse { hover },
-                        Ok(None) => ServerMessage::HoverResponse {
-                            hover: Hover {
-                                contents: lsp_types::HoverContents::Scalar(
-                                    lsp_types::MarkedString::String(String::new()),
-                                ),
-                                range: None,
-                            },
+                match self.readable_path(&path, connection).await {
+                    Ok(full_path) => match self
+                        .lsp_manager
+                        .get_hover(
+                            &full_path,
+                            position,
+                            Some(&connection.begin_lsp_request("hover").await),
+                        )
+                        .await
+                    {
+                        Ok(hover) if connection.wants_plain_text(plain_text) => ServerMessage::HoverResponse {
+                            hover: hover.map(hover_to_plain_text),
                         },
-                        Err(e) => ServerMessage::Error {
-                            message: e.to_string(),
+                        Ok(hover) => ServerMessage::HoverResponse { hover },
+                        Err(e) => lsp_error_message(e, "Hover"),
+                    },
+                    Err(e) => ServerMessage::Error {
+                        message: format!("Invalid path: {}", e),
+                    },
+                }
+            }
+
+            ClientMessage::BatchHover { path, positions, plain_text } => {
+                match self.readable_path(&path, connection).await {
+                    Ok(full_path) => match self
+                        .lsp_manager
+                        .get_hovers(
+                            &full_path,
+                            positions,
+                            Some(&connection.begin_lsp_request("batch_hover").await),
+                        )
+                        .await
+                    {
+                        Ok(hovers) if connection.wants_plain_text(plain_text) => ServerMessage::BatchHoverResponse {
+                            hovers: hovers.into_iter().map(|hover| hover.map(hover_to_plain_text)).collect(),
                         },
+                        Ok(hovers) => ServerMessage::BatchHoverResponse { hovers },
+                        Err(e) => lsp_error_message(e, "BatchHover"),
                     },
                     Err(e) => ServerMessage::Error {
                         message: format!("Invalid path: {}", e),
@@ -529,14 +1663,57 @@ impl Server {
 
             ClientMessage::Definition { path, position } => {
                 println!("Received definition request: {:?}", path);
-                match get_full_path(self.file_system.get_workspace_path(), &path) {
+                match self.readable_path(&path, connection).await {
                     Ok(full_path) => {
-                        match self.lsp_manager.get_definition(&full_path, position).await {
-                            Ok(Some(locations)) => ServerMessage::DefinitionResponse { locations },
+                        let cancel = connection.begin_lsp_request("definition").await;
+                        match self
+                            .lsp_manager
+                            .get_definition(&full_path, position, Some(&cancel))
+                            .await
+                        {
+                            Ok(Some(locations)) => {
+                                connection.allow_definition_targets(&locations).await;
+                                ServerMessage::DefinitionResponse { locations }
+                            }
                             Ok(None) => ServerMessage::DefinitionResponse { locations: vec![] },
-                            Err(e) => ServerMessage::Error {
-                                message: e.to_string(),
-                            },
+                            Err(e) => lsp_error_message(e, "Definition"),
+                        }
+                    }
+                    Err(e) => ServerMessage::Error {
+                        message: format!("Invalid path: {}", e),
+                    },
+                }
+            }
+            ClientMessage::PeekDefinition {
+                path,
+                position,
+                context_lines,
+            } => {
+                println!("Received peek definition request: {:?}", path);
+                match self.readable_path(&path, connection).await {
+                    Ok(full_path) => {
+                        let context_lines = context_lines
+                            .unwrap_or(DEFAULT_PEEK_CONTEXT_LINES)
+                            .min(MAX_PEEK_CONTEXT_LINES);
+                        let cancel = connection.begin_lsp_request("peek_definition").await;
+                        match self
+                            .peek_definition(&full_path, position, context_lines, &cancel, connection)
+                            .await
+                        {
+                            Ok(response) => {
+                                // Only targets the client could read anyway, see definition_snippet
+                                if let ServerMessage::PeekDefinitionResponse {
+                                    location: Some(location),
+                                    snippet: Some(_),
+                                } = &response
+                                {
+                                    connection
+                                        .allow_definition_targets(std::slice::from_ref(location))
+                                        .await;
+                                }
+                                response
+                            }
+                            Err(e) => lsp_error_message(e, "PeekDefinition"),
                         }
                     }
                     Err(e) => ServerMessage::Error {
@@ -544,23 +1721,161 @@ impl Server {
                     },
                 }
             }
-            ClientMessage::CreateTerminal { cols, rows } => {
+            ClientMessage::RenamePreview {
+                path,
+                position,
+                new_name,
+            } => match self.readable_path(&path, connection).await {
+                Ok(full_path) => match self
+                    .lsp_manager
+                    .get_rename_edit(&full_path, position, &new_name)
+                    .await
+                {
+                    Ok(Some(edit)) => {
+                        let locations = self.edit_locations(&edit, connection).await;
+                        ServerMessage::RenamePreview {
+                            edit: Some(edit),
+                            locations,
+                        }
+                    }
+                    Ok(None) => ServerMessage::RenamePreview {
+                        edit: None,
+                        locations: vec![],
+                    },
+                    Err(e) => lsp_error_message(e, "RenamePreview"),
+                },
+                Err(e) => ServerMessage::Error {
+                    message: format!("Invalid path: {}", e),
+                },
+            },
+            ClientMessage::ApplyWorkspaceEdit { edit } => {
+                match self.apply_workspace_edit(edit, tx, connection).await {
+                    Ok(results) => ServerMessage::WorkspaceEditApplied { results },
+                    Err(e) => ServerMessage::Error {
+                        message: format!("Failed to apply workspace edit: {}", e),
+                    },
+                }
+            }
+            ClientMessage::ApplyTextEdits { path, edits, version } => {
+                let uri = match get_full_path(self.file_system.get_workspace_path(), &path)
+                    .and_then(|full_path| path_to_uri(&full_path))
+                {
+                    Ok(uri) => uri,
+                    Err(e) => {
+                        return Ok(tx
+                            .send(ServerMessage::Error {
+                                message: format!("Invalid path: {}", e),
+                            })
+                            .await?)
+                    }
+                };
+                // Same path as a workspace edit, so open documents get a new
+                // version and language servers a didChange
+                let edit = lsp_types::WorkspaceEdit {
+                    document_changes: Some(lsp_types::DocumentChanges::Edits(vec![lsp_types::TextDocumentEdit {
+                        text_document: lsp_types::OptionalVersionedTextDocumentIdentifier { uri, version },
+                        edits: edits.into_iter().map(lsp_types::OneOf::Left).collect(),
+                    }])),
+                    ..Default::default()
+                };
+                match self.apply_workspace_edit(edit, tx, connection).await {
+                    Ok(results) => ServerMessage::WorkspaceEditApplied { results },
+                    Err(e) => ServerMessage::Error {
+                        message: format!("Failed to apply text edits: {}", e),
+                    },
+                }
+            }
+            ClientMessage::ReplaceInFiles {
+                find,
+                replace,
+                is_regex,
+                scope,
+                confirm,
+            } => match TextMatcher::new(&find, is_regex) {
+                Ok(matcher) => {
+                    match self
+                        .replace_in_files(&matcher, &replace, scope.as_deref(), confirm, tx, connection)
+                        .await
+                    {
+                        Ok(response) => response,
+                        Err(e) => ServerMessage::Error {
+                            message: format!("Failed to replace in files: {}", e),
+                        },
+                    }
+                }
+                Err(e) => ServerMessage::Error {
+                    message: format!("Invalid search pattern: {}", e),
+                },
+            },
+            ClientMessage::CreateTerminal {
+                cols,
+                rows,
+                env,
+                log_to_file,
+                keep_log,
+            } => {
                 match self
                     .terminal_manager
-                    .create_terminal(TerminalSize { cols, rows })
+                    .create_terminal(
+                        TerminalSize { cols, rows },
+                        env.unwrap_or_default(),
+                        log_to_file,
+                        keep_log,
+                    )
                     .await
                 {
-                    Ok(id) => ServerMessage::TerminalCreated { terminal_id: id },
+                    Ok((id, log_path)) => ServerMessage::TerminalCreated {
+                        terminal_id: id,
+                        log_path,
+                    },
+                    // No terminal exists yet, so typed errors carry an empty id
+                    Err(e) => terminal_error_message(String::new(), e, "Failed to create terminal"),
+                }
+            }
+            ClientMessage::RunCommand { command, args, cwd } => {
+                match get_full_path(
+                    self.file_system.get_workspace_path(),
+                    cwd.as_deref().unwrap_or(""),
+                ) {
+                    Ok(full_cwd) => match self
+                        .terminal_manager
+                        .run_command(&command, &args, full_cwd)
+                        .await
+                    {
+                        Ok(id) => ServerMessage::CommandStarted { terminal_id: id },
+                        Err(e) => terminal_error_message(String::new(), e, "Failed to run command"),
+                    },
                     Err(e) => ServerMessage::Error {
-                        message: format!("Failed to create terminal: {}", e),
+                        message: format!("Invalid working directory: {}", e),
                     },
                 }
             }
             ClientMessage::WriteTerminal { id, data } => {
                 match self.terminal_manager.write_to_terminal(&id, &data).await {
                     Ok(_) => ServerMessage::Success {},
+                    Err(e) => terminal_error_message(id, e, "Failed to write to terminal"),
+                }
+            }
+            ClientMessage::GetTerminalBuffer { id } => {
+                match self.terminal_manager.get_terminal_screen(&id).await {
+                    Ok(screen) => ServerMessage::TerminalBuffer {
+                        terminal_id: id,
+                        screen,
+                    },
+                    Err(e) => terminal_error_message(id, e, "Failed to get terminal buffer"),
+                }
+            }
+            ClientMessage::GetTerminalLog { id, from_offset } => {
+                match self.terminal_manager.read_terminal_log(&id, from_offset).await {
+                    Ok(page) => ServerMessage::TerminalLog {
+                        next_offset: page.offset + page.data.len() as u64,
+                        offset: page.offset,
+                        total_bytes: page.total_bytes,
+                        data: page.data,
+                        terminal_id: id,
+                    },
                     Err(e) => ServerMessage::Error {
-                        message: format!("Failed to write to terminal: {}", e),
+                        message: format!("Failed to read terminal log: {}", e),
                     },
                 }
             }
@@ -571,9 +1886,7 @@ impl Server {
                     .await
                 {
                     Ok(_) => ServerMessage::Success {},
-                    Err(e) => ServerMessage::Error {
-                        message: format!("Failed to resize terminal: {}", e),
-                    },
+                    Err(e) => terminal_error_message(id, e, "Failed to resize terminal"),
                 }
             }
             ClientMessage::CloseTerminal { id } => {
@@ -585,16 +1898,28 @@ impl Server {
                 }
             }
             ClientMessage::Search {
+                id,
                 query,
                 search_content,
+                mode,
+                min_score,
+                file_types,
             } => {
+                let mode = mode.unwrap_or(if search_content {
+                    SearchMode::Content
+                } else {
+                    SearchMode::Filename
+                });
                 match self
                     .search_manager
                     .clone()
-                    .create_search(&query, search_content)
+                    .create_search(&id, &query, mode, min_score, file_types)
                     .await
                 {
-                    Ok(_) => ServerMessage::Success {},
+                    Ok(_) => {
+                        *self.search_owner.lock().await = Some(connection.id);
+                        ServerMessage::Success {}
+                    }
                     Err(e) => ServerMessage::Error {
                         message: format!("Search failed: {}", e),
                     },
@@ -604,32 +1929,338 @@ impl Server {
                 self.search_manager.close_search().await;
                 ServerMessage::Success {}
             }
-            ClientMessage::CreateFile { path, is_directory } => {
-                println!("Path request {:?}", path);
-                match join_workspace_path(self.file_system.get_workspace_path(), &path) {
-                    Ok(full_path) => {
-                        println!("fullpath good {:?}", full_path);
-                        match self.file_system.create_file(&full_path, is_directory).await {
-                            Ok(_) => ServerMessage::Success {},
-                            Err(e) => ServerMessage::Error {
-                                message: format!("Failed to create file: {}", e),
-                            },
-                        }
-                    }
-                    Err(e) => ServerMessage::Error {
-                        message: format!("Invalid path: {}", e),
-                    },
+            ClientMessage::CancelAll {} => {
+                // Pending LSP queries send $/cancelRequest and reply Success
+                connection.cancel_lsp_requests().await;
+                connection.cancel_streams().await;
+                // Searches are shared, so only one this connection started is closed
+                let mut search_owner = self.search_owner.lock().await;
+                if *search_owner == Some(connection.id) {
+                    self.search_manager.close_search().await;
+                    *search_owner = None;
                 }
+                ServerMessage::Success {}
             }
-
-            ClientMessage::DeleteFile { path } => {
+            // Swaps the connection's state, so run_connection handles it
+            ClientMessage::Resume { .. } => ServerMessage::Error {
+                message: "Resume must be sent over a websocket connection".to_string(),
+            },
+            ClientMessage::GetMetrics {} => ServerMessage::Metrics {
+                active_connections: self
+                    .active_connections
+                    .load(std::sync::atomic::Ordering::Relaxed),
+                messages: self.message_latencies.snapshot(),
+                lsp_requests: self.lsp_manager.request_latencies(),
+                document_cache: self.file_system.cache_stats(),
+                searches: self.search_manager.durations(),
+            },
+            ClientMessage::GetSearchStatus { id } => {
+                let search_id = self.search_manager.search_id().await;
+                if id.is_empty() || id == search_id {
+                    ServerMessage::SearchState {
+                        search_id,
+                        state: self.search_manager.state().await,
+                    }
+                } else {
+                    ServerMessage::Error {
+                        message: format!("No search with id {:?}, the latest search is {:?}", id, search_id),
+                    }
+                }
+            }
+            ClientMessage::UpdateLspConfig { name, settings } => {
+                match self.lsp_manager.update_settings(&name, settings).await {
+                    Ok(_) => ServerMessage::Success {},
+                    Err(e) => ServerMessage::Error {
+                        message: format!("Failed to update LSP config: {}", e),
+                    },
+                }
+            }
+            ClientMessage::Glob { pattern } => match self.file_system.glob(&pattern, MAX_GLOB_MATCHES).await {
+                Ok((paths, truncated)) => ServerMessage::GlobMatches {
+                    pattern,
+                    paths,
+                    truncated,
+                },
+                Err(e) => ServerMessage::Error {
+                    message: format!("Failed to evaluate glob {}: {}", pattern, e),
+                },
+            },
+            ClientMessage::GetSupportedExtensions {} => ServerMessage::SupportedExtensions {
+                extensions: self.lsp_manager.supported_extensions(),
+            },
+            ClientMessage::GetWorkspaceInfo {} => {
+                let info = self.file_system.workspace_info().await;
+                ServerMessage::WorkspaceInfo {
+                    root: info.root,
+                    project_types: info.project_types,
+                    index_state: info.index_state,
+                }
+            }
+            ClientMessage::ListLspServers {} => ServerMessage::LspServers {
+                servers: self.lsp_manager.list_servers().await,
+            },
+            ClientMessage::StopLspServer { name } => match self.lsp_manager.stop_server(&name).await {
+                Ok(()) => ServerMessage::Success {},
+                Err(e) => ServerMessage::Error {
+                    message: format!("Failed to stop LSP server: {}", e),
+                },
+            },
+            ClientMessage::Initialize { capabilities } => {
+                connection
+                    .plain_text_hovers
+                    .store(!capabilities.markdown, std::sync::atomic::Ordering::Relaxed);
+                *connection.frontend_capabilities.lock().await = Some(capabilities.clone());
+                self.lsp_manager
+                    .set_frontend_capabilities(connection.id, capabilities.clone())
+                    .await;
+                ServerMessage::Initialized { capabilities }
+            }
+            ClientMessage::MergeChanges { document, changes } => {
+                let path = match canonicalize_document_path(
+                    self.file_system.get_workspace_path(),
+                    &document,
+                ) {
+                    Ok(p) => p,
+                    Err(e) => {
+                        return Ok(tx
+                            .send(ServerMessage::Error {
+                                message: format!("Invalid document path: {}", e),
+                            })
+                            .await?)
+                    }
+                };
+
+                let document = VersionedDocument {
+                    uri: path.clone(),
+                    version: document.version,
+                };
+                match self.file_system.merge_changes(document, changes).await {
+                    Ok((document, MergeOutcome::Merged(content))) => {
+                        let lsp_change = lsp_types::TextDocumentContentChangeEvent {
+                            range: None,
+                            range_length: None,
+                            text: content.clone(),
+                        };
+                        if let Err(e) = self
+                            .lsp_manager
+                            .notify_document_changed(&path, vec![lsp_change], document.version)
+                            .await
+                        {
+                            eprintln!("LSP change notification failed: {}", e);
+                        }
+
+                        self.notify_peers(connection, &path, document.version, false);
+                        ServerMessage::MergeSuccess { document, content }
+                    }
+                    Ok((document, MergeOutcome::Conflicts(conflicts))) => {
+                        ServerMessage::MergeConflict {
+                            document,
+                            conflicts,
+                        }
+                    }
+                    Err(e) => ServerMessage::Error {
+                        message: format!("Failed to merge changes: {}", e),
+                    },
+                }
+            }
+
+            ClientMessage::DiffWithDisk { path } => {
+                match get_full_path(self.file_system.get_workspace_path(), &path) {
+                    Ok(full_path) => match self.file_system.diff_with_disk(&full_path).await {
+                        Ok(changes) => ServerMessage::DiffWithDiskResponse {
+                            path: full_path,
+                            changes,
+                        },
+                        Err(e) => ServerMessage::Error {
+                            message: format!("Failed to diff with disk: {}", e),
+                        },
+                    },
+                    Err(e) => ServerMessage::Error {
+                        message: format!("Invalid path: {}", e),
+                    },
+                }
+            }
+
+            ClientMessage::GetDocumentAtVersion { path, version } => {
+                match get_full_path(self.file_system.get_workspace_path(), &path) {
+                    Ok(full_path) => match self
+                        .file_system
+                        .get_content_at_version(&full_path, version)
+                        .await
+                    {
+                        Ok(content) => ServerMessage::DocumentAtVersion {
+                            path: full_path,
+                            version,
+                            content,
+                        },
+                        Err(e) => ServerMessage::Error {
+                            message: format!("Failed to get document version: {}", e),
+                        },
+                    },
+                    Err(e) => ServerMessage::Error {
+                        message: format!("Invalid path: {}", e),
+                    },
+                }
+            }
+
+            ClientMessage::SubscribeTreeUpdates {} => {
+                connection
+                    .tree_updates
+                    .store(true, std::sync::atomic::Ordering::Relaxed);
+                ServerMessage::Success {}
+            }
+            ClientMessage::SetFileEventGrouping { grouped } => {
+                connection
+                    .grouped_events
+                    .store(grouped, std::sync::atomic::Ordering::Relaxed);
+                ServerMessage::Success {}
+            }
+            ClientMessage::UnsubscribeTreeUpdates {} => {
+                connection
+                    .tree_updates
+                    .store(false, std::sync::atomic::Ordering::Relaxed);
+                ServerMessage::Success {}
+            }
+            ClientMessage::SubscribeDiagnostics { path } => match path {
+                None => {
+                    connection.diagnostics.lock().await.all = true;
+                    ServerMessage::Success {}
+                }
+                Some(path) => match self.readable_path(&path, connection).await {
+                    Ok(full_path) => {
+                        connection.diagnostics.lock().await.paths.insert(full_path);
+                        ServerMessage::Success {}
+                    }
+                    Err(e) => ServerMessage::Error {
+                        message: format!("Invalid path: {}", e),
+                    },
+                },
+            },
+            ClientMessage::UnsubscribeDiagnostics { path } => {
+                let mut subscription = connection.diagnostics.lock().await;
+                match path {
+                    None => *subscription = DiagnosticsSubscription::default(),
+                    Some(path) => {
+                        // The file may be gone already, so fall back to the plain path
+                        let full_path = self
+                            .readable_path(&path, connection)
+                            .await
+                            .unwrap_or_else(|_| self.file_system.get_workspace_path().join(&path));
+                        subscription.paths.remove(&full_path);
+                    }
+                }
+                ServerMessage::Success {}
+            }
+
+            ClientMessage::GitStatus {} => match self.git_manager.status().await {
+                Ok(files) => ServerMessage::GitStatus { files },
+                Err(e) => ServerMessage::Error {
+                    message: format!("Failed to get git status: {}", e),
+                },
+            },
+
+            ClientMessage::GitBlame { path } => {
+                match get_full_path(self.file_system.get_workspace_path(), &path) {
+                    Ok(full_path) => {
+                        let version = self
+                            .file_system
+                            .get_document_state(&full_path)
+                            .await
+                            .ok()
+                            .filter(|state| state.is_open)
+                            .map(|state| state.version);
+                        let blame = match self.file_system.get_document_content(&full_path).await {
+                            Ok(content) => self.git_manager.blame(full_path.clone(), content).await,
+                            Err(e) => Err(e),
+                        };
+                        match blame {
+                            Ok(lines) => ServerMessage::GitBlame {
+                                path: full_path,
+                                version,
+                                lines,
+                            },
+                            Err(e) => ServerMessage::Error {
+                                message: format!("Failed to get git blame: {}", e),
+                            },
+                        }
+                    }
+                    Err(e) => ServerMessage::Error {
+                        message: format!("Invalid path: {}", e),
+                    },
+                }
+            }
+
+            ClientMessage::GitDiff { path, base } => {
+                match get_full_path(self.file_system.get_workspace_path(), &path) {
+                    Ok(full_path) => match self.git_manager.diff(full_path.clone(), base).await {
+                        Ok(hunks) => ServerMessage::GitDiff {
+                            path: full_path,
+                            base,
+                            hunks,
+                        },
+                        Err(e) => ServerMessage::Error {
+                            message: format!("Failed to get git diff: {}", e),
+                        },
+                    },
+                    Err(e) => ServerMessage::Error {
+                        message: format!("Invalid path: {}", e),
+                    },
+                }
+            }
+
+            ClientMessage::CreateFile { path, is_directory } => {
+                println!("Path request {:?}", path);
+                match join_workspace_path(self.file_system.get_workspace_path(), &path) {
+                    Ok(full_path) => {
+                        println!("fullpath good {:?}", full_path);
+                        match self.file_system.create_file(&full_path, is_directory).await {
+                            Ok(_) => {
+                                if let Err(e) = self
+                                    .lsp_manager
+                                    .notify_file_created(&full_path, is_directory)
+                                    .await
+                                {
+                                    eprintln!("LSP didCreateFiles failed: {}", e);
+                                }
+                                ServerMessage::Success {}
+                            }
+                            Err(e) => ServerMessage::Error {
+                                message: format!("Failed to create file: {}", e),
+                            },
+                        }
+                    }
+                    Err(e) => ServerMessage::Error {
+                        message: format!("Invalid path: {}", e),
+                    },
+                }
+            }
+
+            ClientMessage::DeleteFile { path, dry_run } => {
                 match join_workspace_path(self.file_system.get_workspace_path(), &path) {
-                    Ok(full_path) => match self.file_system.delete_file(&full_path).await {
-                        Ok(_) => ServerMessage::Success {},
+                    Ok(full_path) if dry_run => match self.file_system.preview_delete(&full_path).await {
+                        Ok(affected) => ServerMessage::OperationPreview { affected },
                         Err(e) => ServerMessage::Error {
                             message: format!("Failed to delete file: {}", e),
                         },
                     },
+                    Ok(full_path) => {
+                        let is_directory = full_path.is_dir();
+                        match self.file_system.delete_file(&full_path).await {
+                            Ok(_) => {
+                                if let Err(e) = self
+                                    .lsp_manager
+                                    .notify_file_deleted(&full_path, is_directory)
+                                    .await
+                                {
+                                    eprintln!("LSP didDeleteFiles failed: {}", e);
+                                }
+                                ServerMessage::Success {}
+                            }
+                            Err(e) => ServerMessage::Error {
+                                message: format!("Failed to delete file: {}", e),
+                            },
+                        }
+                    }
                     Err(e) => ServerMessage::Error {
                         message: format!("Invalid path: {}", e),
                     },
@@ -637,17 +2268,15 @@ impl Server {
 
             }
 
-            ClientMessage::RenameFile { old_path, new_path } => {
+            ClientMessage::RenameFile { old_path, new_path, dry_run } => {
                 let full_old_path =
                     match get_full_path(self.file_system.get_workspace_path(), &old_path) {
                         Ok(p) => p,
                         Err(e) => {
-                            return Ok(write
-                                .send(Message::Text(serde_json::to_string(
-                                    &ServerMessage::Error {
-                                        message: format!("Invalid old path: {}", e),
-                                    },
-                                )?))
+                            return Ok(tx
+                                .send(ServerMessage::Error {
+                                    message: format!("Invalid old path: {}", e),
+                                })
                                 .await?)
                         }
                     };
@@ -656,24 +2285,36 @@ impl Server {
                     match join_workspace_path(self.file_system.get_workspace_path(), &new_path) {
                         Ok(p) => p,
                         Err(e) => {
-                            return Ok(write
-                                .send(Message::Text(serde_json::to_string(
-                                    &ServerMessage::Error {
-                                        message: format!("Invalid new path: {}", e),
-                                    },
-                                )?))
+                            return Ok(tx
+                                .send(ServerMessage::Error {
+                                    message: format!("Invalid new path: {}", e),
+                                })
                                 .await?)
                         }
                     };
 
-                match self
-                    .file_system
-                    .rename_file(&full_old_path, &full_new_path)
-                    .await
-                {
-                    Ok(_) => ServerMessage::Success {},
-                    Err(e) => ServerMessage::Error {
-                        message: format!("Failed to rename file: {}", e),
+                if dry_run {
+                    match self.file_system.preview_rename(&full_old_path, &full_new_path).await {
+                        Ok(affected) => ServerMessage::OperationPreview { affected },
+                        Err(e) => ServerMessage::Error {
+                            message: format!("Failed to rename file: {}", e),
+                        },
+                    }
+                } else {
+                    self.rename_with_lsp(&full_old_path, &full_new_path, tx, connection)
+                        .await?
+                }
+            }
+
+            ClientMessage::MoveDirectory { from, to } => {
+                let workspace = self.file_system.get_workspace_path();
+                match (get_full_path(workspace, &from), join_workspace_path(workspace, &to)) {
+                    (Ok(full_from), Ok(full_to)) => {
+                        self.move_directory_with_lsp(&full_from, &full_to, tx, connection)
+                            .await?
+                    }
+                    (Err(e), _) | (_, Err(e)) => ServerMessage::Error {
+                        message: format!("Invalid path: {}", e),
                     },
                 }
             }
@@ -683,24 +2324,586 @@ impl Server {
             return Ok(());
         }
 
-        let message = serde_json::to_string(&response)?;
-        // println!("Sending message: {}", message);
-        write.send(Message::Text(message)).await?;
+        tx.send(response).await?;
         Ok(())
     }
 
+    // Resolves a path for a read-only request, which may also point into an
+    // --allow-path directory or at a file a definition lookup returned
+    async fn readable_path(&self, path: &str, connection: &ConnectionState) -> Result<PathBuf> {
+        let error = match get_readable_path(
+            self.file_system.get_workspace_path(),
+            &self.config.allowed_paths,
+            path,
+        ) {
+            Ok(full_path) => return Ok(full_path),
+            Err(e) => e,
+        };
+
+        let candidate = Path::new(path);
+        if candidate.is_absolute() {
+            if let Ok(canonical) = candidate.canonicalize() {
+                if connection.is_definition_target(&canonical).await {
+                    return Ok(canonical);
+                }
+            }
+        }
+        Err(error)
+    }
+
+    // Lists a directory for a client and remembers that it was loaded, so tree
+    // updates follow it. Without `show_hidden` the client's last choice applies.
+    async fn list_directory(
+        &self,
+        full_path: &PathBuf,
+        show_hidden: Option<bool>,
+        connection: &ConnectionState,
+    ) -> Result<Vec<FileNode>> {
+        let mut content = self.file_system.load_directory(full_path).await?;
+        let mut loaded_tree = connection.loaded_tree.lock().await;
+        if let Some(show_hidden) = show_hidden {
+            loaded_tree.set_show_hidden(show_hidden);
+        }
+        if !loaded_tree.show_hidden() {
+            retain_visible(&mut content);
+        }
+        loaded_tree.mark_loaded(full_path.clone());
+        Ok(content)
+    }
+
+    // Opens a file for a client. The caller is responsible for notifying language servers.
+    async fn open_document(
+        &self,
+        path: &str,
+        connection: &ConnectionState,
+    ) -> Result<(PathBuf, String, DocumentMetadata, i32)> {
+        let full_path = self
+            .readable_path(path, connection)
+            .await
+            .map_err(|e| anyhow::anyhow!("Invalid path: {}", e))?;
+
+        // Validate file exists and is readable before opening
+        if !full_path.exists() {
+            anyhow::bail!("File does not exist: {}", path);
+        }
+
+        let (content, metadata, version) = match self.file_system.open_file(&full_path).await {
+            Ok(opened) => opened,
+            // Kept typed so directories can be answered with their listing
+            Err(e) if e.is::<IsADirectory>() => return Err(e),
+            Err(e) => anyhow::bail!("Failed to open file: {}", e),
+        };
+        Ok((full_path, content, metadata, version))
+    }
+
+    // Renames a file or directory while keeping language servers in sync: edits
+    // returned by willRenameFiles are applied first, then didRenameFiles is sent
+    async fn rename_with_lsp(
+        &self,
+        old_path: &PathBuf,
+        new_path: &PathBuf,
+        tx: &MessageSender,
+        connection: &ConnectionState,
+    ) -> Result<ServerMessage> {
+        // Checked before servers get to edit anything for the rename
+        if let Err(e) = self.file_system.check_rename(old_path, new_path) {
+            return Ok(ServerMessage::Error {
+                message: format!("Failed to rename file: {}", e),
+            });
+        }
+        let is_directory = old_path.is_dir();
+        self.apply_will_rename_edits(old_path, new_path, is_directory, tx, connection)
+            .await;
+
+        let moved_documents = match self.file_system.rename_file(old_path, new_path).await {
+            Ok(moved) => moved,
+            Err(e) => {
+                return Ok(ServerMessage::Error {
+                    message: format!("Failed to rename file: {}", e),
+                })
+            }
+        };
+
+        if let Err(e) = self
+            .lsp_manager
+            .notify_files_renamed(old_path, new_path, is_directory)
+            .await
+        {
+            eprintln!("LSP didRenameFiles failed: {}", e);
+        }
+
+        self.reopen_moved_documents(moved_documents).await;
+
+        Ok(ServerMessage::Success {})
+    }
+
+    async fn move_directory_with_lsp(
+        &self,
+        from: &Path,
+        to: &Path,
+        tx: &MessageSender,
+        connection: &ConnectionState,
+    ) -> Result<ServerMessage> {
+        // Checked before servers get to edit anything for the move
+        let workspace = self.file_system.get_workspace_path();
+        let invalid = if !is_within(from, workspace) || !is_within(to, workspace) {
+            Some("Path is outside of workspace".to_string())
+        } else if !from.is_dir() {
+            Some(format!("Not a directory: {:?}", from))
+        } else if is_within(to, from) {
+            Some("Can't move a directory into itself".to_string())
+        } else if to.exists() {
+            Some(format!("Destination already exists: {:?}", to))
+        } else {
+            None
+        };
+        if let Some(message) = invalid {
+            return Ok(ServerMessage::Error { message });
+        }
+        self.apply_will_rename_edits(from, to, true, tx, connection)
+            .await;
+
+        let (moved_documents, files) = match self.file_system.move_directory(from, to).await {
+            Ok(moved) => moved,
+            Err(e) => {
+                return Ok(ServerMessage::Error {
+                    message: format!("Failed to move directory: {}", e),
+                })
+            }
+        };
+
+        if let Err(e) = self.lsp_manager.notify_directory_moved(from, to, &files).await {
+            eprintln!("LSP didRenameFiles failed: {}", e);
+        }
+
+        self.reopen_moved_documents(moved_documents).await;
+
+        Ok(ServerMessage::Success {})
+    }
+
+    // Applies the edits servers want made before a rename, e.g. module path fixups
+    async fn apply_will_rename_edits(
+        &self,
+        old_path: &Path,
+        new_path: &Path,
+        is_directory: bool,
+        tx: &MessageSender,
+        connection: &ConnectionState,
+    ) {
+        let edits = match self
+            .lsp_manager
+            .will_rename_files(old_path, new_path, is_directory)
+            .await
+        {
+            Ok(edits) => edits,
+            Err(e) => {
+                eprintln!("LSP willRenameFiles failed: {}", e);
+                vec![]
+            }
+        };
+        for edit in edits {
+            match self.apply_workspace_edit(edit, tx, connection).await {
+                Ok(results) => {
+                    for result in results {
+                        if let FileEditResult::Failed { error } = result {
+                            eprintln!("Failed to apply rename edits: {}", error);
+                        }
+                    }
+                }
+                Err(e) => eprintln!("Failed to apply rename edits: {}", e),
+            }
+        }
+    }
+
+    // Applies a workspace edit (text edits and file operations) and notifies language
+    // servers about each step. Edited open documents are pushed to the client.
+    async fn apply_workspace_edit(
+        &self,
+        edit: lsp_types::WorkspaceEdit,
+        tx: &MessageSender,
+        connection: &ConnectionState,
+    ) -> Result<Vec<FileEditResult>> {
+        let outcome = self.file_system.apply_workspace_edit(edit).await;
+
+        let mut results = Vec::with_capacity(outcome.applied.len() + 1);
+        for applied in outcome.applied {
+            let result = match applied {
+                AppliedEdit::Edited {
+                    path,
+                    document: Some((content, metadata, version)),
+                } => {
+                    let lsp_change = lsp_types::TextDocumentContentChangeEvent {
+                        range: None,
+                        range_length: None,
+                        text: content.clone(),
+                    };
+                    if let Err(e) = self
+                        .lsp_manager
+                        .notify_document_changed(&path, vec![lsp_change], version)
+                        .await
+                    {
+                        eprintln!("LSP change notification failed: {}", e);
+                    }
+
+                    self.notify_peers(connection, &path, version, false);
+                    tx.send(ServerMessage::DocumentContent {
+                        path: path.clone(),
+                        content,
+                        metadata,
+                        version,
+                    })
+                    .await?;
+                    FileEditResult::Edited {
+                        path,
+                        version: Some(version),
+                    }
+                }
+                AppliedEdit::Edited {
+                    path,
+                    document: None,
+                } => FileEditResult::Edited {
+                    path,
+                    version: None,
+                },
+                AppliedEdit::Created { path, is_directory } => {
+                    if let Err(e) = self.lsp_manager.notify_file_created(&path, is_directory).await {
+                        eprintln!("LSP didCreateFiles failed: {}", e);
+                    }
+                    FileEditResult::Created { path }
+                }
+                AppliedEdit::Renamed {
+                    old_path,
+                    new_path,
+                    is_directory,
+                    moved_documents,
+                } => {
+                    if let Err(e) = self
+                        .lsp_manager
+                        .notify_files_renamed(&old_path, &new_path, is_directory)
+                        .await
+                    {
+                        eprintln!("LSP didRenameFiles failed: {}", e);
+                    }
+                    self.reopen_moved_documents(moved_documents).await;
+                    FileEditResult::Renamed { old_path, new_path }
+                }
+                AppliedEdit::Deleted { path, is_directory } => {
+                    if let Err(e) = self.lsp_manager.notify_file_deleted(&path, is_directory).await {
+                        eprintln!("LSP didDeleteFiles failed: {}", e);
+                    }
+                    FileEditResult::Deleted { path }
+                }
+            };
+            results.push(result);
+        }
+
+        if let Some(e) = outcome.error {
+            results.push(FileEditResult::Failed {
+                error: format!("{:#}", e),
+            });
+        }
+        Ok(results)
+    }
+
+    // Replaces every match in the workspace files within `scope`, one workspace
+    // edit per file so open documents get a new version and LSP didChange.
+    // Without `confirm` the matches are only counted.
+    async fn replace_in_files(
+        &self,
+        matcher: &TextMatcher,
+        replace: &str,
+        scope: Option<&str>,
+        confirm: bool,
+        tx: &MessageSender,
+        connection: &ConnectionState,
+    ) -> Result<ServerMessage> {
+        let scope = crate::file_system::scope_matcher(scope)?;
+        let candidates =
+            crate::file_system::replace_candidates(self.file_system.get_workspace_path(), scope)
+                .await?;
+
+        let total = candidates.len();
+        let mut files = Vec::new();
+        let mut failed = Vec::new();
+        for (index, path) in candidates.into_iter().enumerate() {
+            if (index + 1) % REPLACE_PROGRESS_INTERVAL == 0 && index + 1 < total {
+                tx.send(ServerMessage::ReplaceProgress {
+                    processed: index + 1,
+                    total,
+                })
+                .await?;
+            }
+
+            // Open documents are replaced in their unsaved content
+            let is_open = self.file_system.get_document_state(&path).await.is_ok_and(|s| s.is_open);
+            let content = if is_open {
+                self.file_system.get_document_content(&path).await
+            } else {
+                self.file_system.read_text(&path).await.map(|(content, _)| content)
+            };
+            // Binary and unreadable files are skipped
+            let Ok(content) = content else {
+                continue;
+            };
+
+            let edits = matcher.replacement_edits(&content, replace);
+            if edits.is_empty() {
+                continue;
+            }
+            let replacements = edits.len();
+            if !confirm {
+                files.push(ReplacedFile {
+                    path,
+                    replacements,
+                    version: None,
+                });
+                continue;
+            }
+
+            let edit = lsp_types::WorkspaceEdit {
+                changes: Some(HashMap::from([(path_to_uri(&path)?, edits)])),
+                ..Default::default()
+            };
+            match self.apply_workspace_edit(edit, tx, connection).await?.pop() {
+                Some(FileEditResult::Edited { version, .. }) => files.push(ReplacedFile {
+                    path,
+                    replacements,
+                    version,
+                }),
+                Some(FileEditResult::Failed { error }) => failed.push(ReplaceFailure { path, error }),
+                _ => {}
+            }
+        }
+
+        tx.send(ServerMessage::ReplaceProgress {
+            processed: total,
+            total,
+        })
+        .await?;
+        Ok(ServerMessage::ReplaceInFilesSummary {
+            applied: confirm,
+            files,
+            failed,
+        })
+    }
+
+    async fn decorate_nodes(
+        &self,
+        nodes: Vec<FileNode>,
+        git: bool,
+        diagnostics: bool,
+    ) -> Vec<RichFileNode> {
+        let mut statuses: HashMap<PathBuf, GitFileStatus> = HashMap::new();
+        if git {
+            match self.git_manager.current_status().await {
+                Ok(files) => statuses.extend(files.into_iter().map(|file| (file.path.clone(), file))),
+                Err(e) => eprintln!("Git status unavailable: {}", e),
+            }
+        }
+
+        let mut decorated = Vec::with_capacity(nodes.len());
+        for node in nodes {
+            let diagnostics = if diagnostics && !node.is_directory {
+                self.lsp_manager.diagnostic_counts(&node.path).await
+            } else {
+                None
+            };
+            decorated.push(RichFileNode {
+                git: statuses.remove(&node.path),
+                diagnostics,
+                node,
+            });
+        }
+        decorated
+    }
+
+    // Lets every other connection know that a document has a new version
+    fn notify_peers(&self, connection: &ConnectionState, path: &Path, version: i32, saved: bool) {
+        let _ = self.document_events.send(DocumentEvent {
+            origin: connection.id,
+            path: path.to_path_buf(),
+            version,
+            saved,
+        });
+    }
+
+    // Open documents are reopened under their new URI after a rename
+    async fn reopen_moved_documents(&self, moved_documents: Vec<(PathBuf, PathBuf)>) {
+        for (old_document, new_document) in moved_documents {
+            if let Err(e) = self.lsp_manager.notify_document_closed(&old_document).await {
+                eprintln!("LSP notification failed: {}", e);
+            }
+            let state = self.file_system.get_document_state(&new_document).await;
+            let content = self.file_system.get_document_content(&new_document).await;
+            if let (Ok(state), Ok(content)) = (state, content) {
+                if let Err(e) = self
+                    .lsp_manager
+                    .notify_document_opened(&new_document, &content, state.version)
+                    .await
+                {
+                    eprintln!("LSP notification failed: {}", e);
+                }
+            }
+        }
+    }
+
+    // Lines a workspace edit touches, read from the current (possibly unsaved)
+    // content. Files the client couldn't read itself are left out.
+    async fn edit_locations(
+        &self,
+        edit: &lsp_types::WorkspaceEdit,
+        connection: &ConnectionState,
+    ) -> Vec<EditLocation> {
+        let mut locations = Vec::new();
+        for operation in document_change_operations(edit.clone()) {
+            let lsp_types::DocumentChangeOperation::Edit(edit) = operation else {
+                continue;
+            };
+            let Ok(path) = uri_to_path(&edit.text_document.uri) else {
+                continue;
+            };
+            let Some(path) = path.to_str() else {
+                continue;
+            };
+            let Ok(path) = self.readable_path(path, connection).await else {
+                continue;
+            };
+            let Ok(content) = self.file_system.get_document_content(&path).await else {
+                continue;
+            };
+            let lines: Vec<&str> = content.lines().collect();
+
+            let mut edited_lines: Vec<u32> = text_edits(edit.edits)
+                .iter()
+                .map(|e| e.range.start.line)
+                .collect();
+            edited_lines.sort_unstable();
+            edited_lines.dedup();
+            for line in edited_lines {
+                locations.push(EditLocation {
+                    path: path.clone(),
+                    line,
+                    text: lines.get(line as usize).unwrap_or(&"").to_string(),
+                });
+            }
+        }
+        locations
+    }
+
+    async fn peek_definition(
+        &self,
+        path: &PathBuf,
+        position: Position,
+        context_lines: u32,
+        cancel: &CancellationToken,
+        connection: &ConnectionState,
+    ) -> Result<ServerMessage> {
+        let location = self
+            .lsp_manager
+            .get_definition(path, position, Some(cancel))
+            .await?
+            .and_then(|locations| locations.into_iter().next());
+
+        let Some(location) = location else {
+            return Ok(ServerMessage::PeekDefinitionResponse {
+                location: None,
+                snippet: None,
+            });
+        };
+
+        let snippet = self
+            .definition_snippet(&location, context_lines, connection)
+            .await?;
+        Ok(ServerMessage::PeekDefinitionResponse {
+            location: Some(location),
+            snippet,
+        })
+    }
+
+    // Source lines around a definition. Goes through the document cache so
+    // unsaved edits show up in the preview. A language server may point
+    // anywhere, so targets that aren't readable through readable_path get no
+    // snippet.
+    async fn definition_snippet(
+        &self,
+        location: &lsp_types::Location,
+        context_lines: u32,
+        connection: &ConnectionState,
+    ) -> Result<Option<CodeSnippet>> {
+        let target = uri_to_path(&location.uri)?;
+        let Some(target) = target.to_str() else {
+            return Ok(None);
+        };
+        let Ok(target) = self.readable_path(target, connection).await else {
+            return Ok(None);
+        };
+        let content = self.file_system.get_document_content(&target).await?;
+
+        let start_line = location.range.start.line.saturating_sub(context_lines);
+        let end_line = location.range.end.line.saturating_add(context_lines);
+        let lines = content
+            .lines()
+            .skip(start_line as usize)
+            .take(end_line.saturating_sub(start_line).saturating_add(1) as usize)
+            .map(String::from)
+            .collect();
+        Ok(Some(CodeSnippet { start_line, lines }))
+    }
+
     async fn handle_connection(&self, stream: TcpStream) -> Result<()> {
         println!("New connection attempt from: {}", stream.peer_addr()?);
 
         let ws_stream = accept_async(stream).await?;
-        let (mut write, mut read) = ws_stream.split();
+        let (write, mut read) = ws_stream.split();
+
+        // All outgoing messages go through this channel so that responses and
+        // the various event streams never write to the socket concurrently
+        let (tx, rx) = mpsc::channel::<ServerMessage>(OUTGOING_QUEUE_SIZE);
+        let writer = tokio::spawn(write_messages(write, rx));
+
+        self.active_connections
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let mut connection = Arc::new(ConnectionState::new());
+        tx.send(ServerMessage::SessionEstablished {
+            session_id: connection.id.to_string(),
+            resumed: false,
+        })
+        .await?;
+        let result = self.run_connection(&mut read, &tx, &mut connection).await;
 
+        // Nobody is left to receive in-flight LSP responses
+        connection.cancel_lsp_requests().await;
+        self.lsp_manager.remove_frontend_capabilities(connection.id).await;
+        self.sessions.detach(connection.id, connection).await;
+        self.active_connections
+            .fetch_sub(1, std::sync::atomic::Ordering::Relaxed);
+
+        // Let the writer flush whatever is still queued before closing
+        drop(tx);
+        let _ = writer.await;
+        result
+    }
+
+    async fn run_connection(
+        &self,
+        read: &mut futures_util::stream::SplitStream<
+            tokio_tungstenite::WebSocketStream<TcpStream>,
+        >,
+        tx: &MessageSender,
+        connection: &mut Arc<ConnectionState>,
+    ) -> Result<()> {
         let mut fs_events = self.file_system.subscribe();
+        let mut watcher_status = self.file_system.subscribe_watcher_status();
         let mut terminal_events = self.terminal_manager.subscribe();
         let mut search_events = self.search_manager.subscribe();
+        let mut git_events = self.git_manager.subscribe();
+        let mut document_events = self.document_events.subscribe();
+        let mut diagnostics_events = self.lsp_manager.subscribe_diagnostics();
+        let mut lsp_status = self.lsp_manager.subscribe_status();
 
         // Buffer for collecting events
-        let mut event_buffer = Vec::with_capacity(100);
+        let batch_size = self.config.fs_batch_size;
+        let mut event_buffer = Vec::with_capacity(batch_size);
         let mut last_send = Instant::now();
 
         loop {
@@ -710,14 +2913,55 @@ impl Server {
                     println!("Server received message: {:?}", msg);
                     match msg? {
                         Message::Text(text) => {
+                            let started = Instant::now();
+                            let message_type = serde_json::from_str::<MessageType>(&text)
+                                .map(|message_type| message_type.name)
+                                .unwrap_or_default();
                             match serde_json::from_str::<ClientMessage>(&text) {
+                                Ok(ClientMessage::Resume { session_id }) => {
+                                    let resumed = match uuid::Uuid::parse_str(&session_id) {
+                                        Ok(id) => self.sessions.resume(&id).await,
+                                        Err(_) => None,
+                                    };
+                                    let reply = match resumed {
+                                        Some(resumed) => {
+                                            // The session's capabilities replace this connection's
+                                            self.lsp_manager.remove_frontend_capabilities(connection.id).await;
+                                            if let Some(capabilities) = resumed.frontend_capabilities.lock().await.clone() {
+                                                self.lsp_manager.set_frontend_capabilities(resumed.id, capabilities).await;
+                                            }
+                                            *connection = resumed;
+                                            ServerMessage::SessionEstablished { session_id, resumed: true }
+                                        }
+                                        None => ServerMessage::Error {
+                                            message: format!("Unknown or expired session: {}", session_id),
+                                        },
+                                    };
+                                    tx.send(reply).await?;
+                                }
+                                Ok(client_message) if client_message.is_lsp_query() => {
+                                    // Queries run concurrently so a newer one can cancel them
+                                    let server = self.clone();
+                                    let tx = tx.clone();
+                                    let connection = Arc::clone(connection);
+                                    tokio::spawn(async move {
+                                        if let Err(e) = server.handle_client_message(client_message, &tx, &connection).await {
+                                            let _ = tx.send(ServerMessage::Error {
+                                                message: format!("Error processing request: {}", e),
+                                            }).await;
+                                        }
+                                        server.message_latencies.record(&message_type, started.elapsed());
+                                    });
+                                },
                                 Ok(client_message) => {
-                                    if let Err(e) = self.handle_client_message(client_message, &mut write).await {
+                                    let result = self.handle_client_message(client_message, tx, connection).await;
+                                    self.message_latencies.record(&message_type, started.elapsed());
+                                    if let Err(e) = result {
                                         println!("Invalid message format: {}", e);
                                         let error_message = ServerMessage::Error {
                                             message: format!("Error processing request: {}", e),
                                         };
-                                        write.send(Message::Text(serde_json::to_string(&error_message)?)).await?;
+                                        tx.send(error_message).await?;
                                     }
                                 },
                                 Err(e) => {
@@ -725,7 +2969,7 @@ impl Server {
                                     let error_message = ServerMessage::Error {
                                         message: format!("Invalid message format: {}", e),
                                     };
-                                    write.send(Message::Text(serde_json::to_string(&error_message)?)).await?;
+                                    tx.send(error_message).await?;
                                 }
                             }
                         }
@@ -737,40 +2981,90 @@ impl Server {
                     println!("Server received file system event");
                     event_buffer.push(event);
 
-                    if event_buffer.len() >= 100 || last_send.elapsed() >= Duration::from_millis(100) {
-                        if !event_buffer.is_empty() {
-                            let message = ServerMessage::FileSystemEvents {
-                                events: std::mem::replace(&mut event_buffer, Vec::with_capacity(100))
-                            };
-                            if let Ok(text) = serde_json::to_string(&message) {
-                                let _ = write.send(Message::Text(text)).await;
+                    if (event_buffer.len() >= batch_size
+                        || last_send.elapsed() >= self.config.fs_batch_interval)
+                        && !event_buffer.is_empty()
+                    {
+                        let events = std::mem::replace(&mut event_buffer, Vec::with_capacity(batch_size));
+                        let tree_updates = connection.tree_updates(&events).await;
+                        let message = if connection.grouped_events.load(std::sync::atomic::Ordering::Relaxed) {
+                            ServerMessage::GroupedFileSystemEvents {
+                                directories: group_by_directory(events),
                             }
-                            last_send = Instant::now();
+                        } else {
+                            ServerMessage::FileSystemEvents { events }
+                        };
+                        let _ = tx.send(message).await;
+                        for update in tree_updates {
+                            let _ = tx.send(update).await;
                         }
+                        last_send = Instant::now();
                     }
                 }
+                Ok(status) = lsp_status.recv() => {
+                    tx.send(ServerMessage::LspStatus {
+                        name: status.name,
+                        state: status.state,
+                    }).await?;
+                }
+                Ok(status) = watcher_status.recv() => {
+                    let message = match status {
+                        WatcherStatus::Error { message } => ServerMessage::WatcherError { message },
+                        WatcherStatus::Restored => ServerMessage::WatcherRestored {},
+                        WatcherStatus::Degraded { reason } => ServerMessage::WatcherDegraded { reason },
+                    };
+                    tx.send(message).await?;
+                }
                 Ok(term_msg) = terminal_events.recv() => {
                     println!("Server received terminal message");
                     match term_msg {
                         TerminalMessage::Output { terminal_id, data } => {
                             println!("Terminal output: {:?}", data);
                             let message = ServerMessage::TerminalOutput { terminal_id, data };
-                            if let Ok(text) = serde_json::to_string(&message) {
-                                let _ = write.send(Message::Text(text)).await;
-                            }
+                            let _ = tx.send(message).await;
                         }
                         TerminalMessage::Error { terminal_id, error } => {
                             println!("Terminal error: {:?}", error);
-                            let message = ServerMessage::TerminalError { terminal_id, error };
-                            if let Ok(text) = serde_json::to_string(&message) {
-                                let _ = write.send(Message::Text(text)).await;
-                            }
+                            let message = ServerMessage::TerminalError { terminal_id, error, code: None };
+                            let _ = tx.send(message).await;
+                        }
+                        TerminalMessage::Exited { terminal_id } => {
+                            let message = ServerMessage::TerminalClosed { id: terminal_id };
+                            let _ = tx.send(message).await;
+                        }
+                        TerminalMessage::CommandFinished { terminal_id, exit_code, duration_ms } => {
+                            let message = ServerMessage::CommandFinished { terminal_id, exit_code, duration_ms };
+                            let _ = tx.send(message).await;
                         }
                         _ => {
                             println!("Unhandled terminal message: {:?}", term_msg);
                         }
                     }
                 }
+                Ok(event) = document_events.recv() => {
+                    if event.origin != connection.id {
+                        tx.send(ServerMessage::DocumentChangedByPeer {
+                            path: event.path,
+                            version: event.version,
+                            saved: event.saved,
+                        }).await?;
+                    }
+                }
+                Ok(params) = diagnostics_events.recv() => {
+                    let Ok(path) = uri_to_path(&params.uri) else {
+                        continue;
+                    };
+                    if connection.wants_diagnostics(&path).await {
+                        tx.send(ServerMessage::Diagnostics {
+                            path,
+                            version: params.version,
+                            diagnostics: params.diagnostics,
+                        }).await?;
+                    }
+                }
+                Ok(GitMessage::StatusChanged { files }) = git_events.recv() => {
+                    tx.send(ServerMessage::GitStatus { files }).await?;
+                }
                 Ok(search_msg) = search_events.recv() => {
                     match search_msg {
                         SearchMessage::Results { search_id, items, is_complete } => {
@@ -780,18 +3074,14 @@ impl Server {
                                 items,
                                 is_complete
                             };
-                            if let Ok(json) = serde_json::to_string(&message) {
-                                write.send(Message::Text(json)).await?;
-                            }
+                            tx.send(message).await?;
                         },
                         SearchMessage::Error { search_id, error } => {
 
                             let message = ServerMessage::Error {
                                 message: format!("Search error ({}): {}", search_id, error)
                             };
-                            if let Ok(json) = serde_json::to_string(&message) {
-                                write.send(Message::Text(json)).await?;
-                            }
+                            tx.send(message).await?;
                         }
                     }
                 }
@@ -800,12 +3090,27 @@ impl Server {
     }
 
     pub async fn start(&self) -> Result<()> {
+        // In the background, so a huge workspace root doesn't hold up accepting
+        // connections. Clients can follow it through GetWorkspaceInfo.
         println!("Initializing file system...");
-        self.file_system.init().await?;
+        let file_system = Arc::clone(&self.file_system);
+        tokio::spawn(async move {
+            // A failure is kept as the index state clients are shown
+            let _ = file_system.init().await;
+            if let IndexState::Failed { message } = file_system.index_state().await {
+                eprintln!("Error: workspace root failed to load, index state is Failed: {}", message);
+            }
+        });
 
         // Start the file watcher
         println!("Starting file watcher...");
-        self.file_system.start_watching().await?;
+        self.file_system
+            .start_watching(self.config.watch_poll_interval, self.config.fs_modify_debounce)
+            .await?;
+        self.git_manager.watch(self.file_system.subscribe());
+        self.lsp_manager.start_idle_shutdown();
+        self.lsp_manager.start_deferred_servers();
+        self.lsp_manager.track_diagnostics();
 
         let addr = format!("127.0.0.1:{}", self.port);
         let listener = TcpListener::bind(&addr).await?;
@@ -828,15 +3133,575 @@ impl Server {
     }
 }
 
+// Streams the file as it is when the stream starts: the size announced in
+// DocumentStreamStart is what is sent, and a file that changes meanwhile ends
+// the stream with an error instead of DocumentStreamEnd
+async fn stream_file(path: &Path, tx: &MessageSender) -> Result<()> {
+    use tokio::io::AsyncReadExt;
+
+    let mut file = tokio::fs::File::open(path).await?;
+    let metadata = file.metadata().await?;
+    if !metadata.is_file() {
+        anyhow::bail!("Not a file");
+    }
+    let total_size = metadata.len();
+    tx.send(ServerMessage::DocumentStreamStart {
+        path: path.to_path_buf(),
+        total_size,
+        chunk_count: total_size.div_ceil(STREAM_CHUNK_BYTES),
+    })
+    .await?;
+
+    let mut offset = 0;
+    while offset < total_size {
+        let mut content = vec![0; (total_size - offset).min(STREAM_CHUNK_BYTES) as usize];
+        file.read_exact(&mut content)
+            .await
+            .map_err(|_| anyhow::anyhow!("File changed while streaming"))?;
+        let length = content.len() as u64;
+        tx.send(ServerMessage::DocumentChunk {
+            path: path.to_path_buf(),
+            content,
+            offset,
+        })
+        .await?;
+        offset += length;
+    }
+
+    let current = tokio::fs::metadata(path).await?;
+    if current.len() != total_size || current.modified().ok() != metadata.modified().ok() {
+        anyhow::bail!("File changed while streaming");
+    }
+    tx.send(ServerMessage::DocumentStreamEnd {
+        path: path.to_path_buf(),
+    })
+    .await?;
+    Ok(())
+}
+
+// Language server errors keep their code so the client can tell them apart.
+// `request` is the client message type that asked.
+fn lsp_error_message(error: anyhow::Error, request: &str) -> ServerMessage {
+    match error.downcast::<LspError>() {
+        // Superseded by a newer request or cancelled with CancelAll
+        Ok(error) if error.is_request_cancelled() => ServerMessage::LspRequestCancelled {
+            request: request.to_string(),
+        },
+        Ok(error) if error.is_not_ready() => ServerMessage::LspNotReady {
+            feature: error.feature().unwrap_or_default().to_string(),
+        },
+        Ok(error) => ServerMessage::LspError {
+            code: error.code,
+            message: error.message,
+            data: error.data,
+        },
+        Err(error) => ServerMessage::Error {
+            message: error.to_string(),
+        },
+    }
+}
+
+// Typed terminal failures become TerminalError so clients can act on the code
+fn terminal_error_message(terminal_id: String, error: anyhow::Error, context: &str) -> ServerMessage {
+    match error.downcast::<TerminalError>() {
+        Ok(error) => ServerMessage::TerminalError {
+            terminal_id,
+            error: error.message,
+            code: Some(error.code),
+        },
+        Err(e) => ServerMessage::Error {
+            message: format!("{}: {}", context, e),
+        },
+    }
+}
+
 // Make Server cloneable
 impl Clone for Server {
     fn clone(&self) -> Self {
         Self {
             port: self.port,
+            config: self.config.clone(),
             file_system: Arc::clone(&self.file_system),
             lsp_manager: Arc::clone(&self.lsp_manager),
             terminal_manager: Arc::clone(&self.terminal_manager),
             search_manager: Arc::clone(&self.search_manager),
+            search_owner: Arc::clone(&self.search_owner),
+            git_manager: Arc::clone(&self.git_manager),
+            document_events: self.document_events.clone(),
+            sessions: Arc::clone(&self.sessions),
+            active_connections: Arc::clone(&self.active_connections),
+            message_latencies: Arc::clone(&self.message_latencies),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_server(workspace: &Path) -> Result<Server> {
+        Server::new(workspace.to_path_buf(), 0, vec![], ServerConfig::default())
+    }
+
+    fn location(path: &Path, start: u32, end: u32) -> lsp_types::Location {
+        lsp_types::Location {
+            uri: path_to_uri(path).unwrap(),
+            range: lsp_types::Range::new(Position::new(start, 0), Position::new(end, 0)),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_messages_are_written_as_whole_frames_in_order() -> Result<()> {
+        let (tx, rx) = mpsc::channel(OUTGOING_QUEUE_SIZE);
+        let writer = tokio::spawn(write_messages(Vec::<Message>::new(), rx));
+
+        let senders: Vec<_> = (0..8)
+            .map(|sender| {
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    for index in 0..50 {
+                        let message = ServerMessage::Error {
+                            message: format!("{} {} {}", sender, index, "x".repeat(4096)),
+                        };
+                        tx.send(message).await.unwrap();
+                    }
+                })
+            })
+            .collect();
+        drop(tx);
+        for sender in senders {
+            sender.await?;
+        }
+
+        let mut next_index = [0; 8];
+        let frames = writer.await?;
+        assert_eq!(frames.len(), 8 * 50);
+        for frame in frames {
+            let Message::Text(text) = frame else {
+                panic!("messages are sent as text frames");
+            };
+            let ServerMessage::Error { message } = serde_json::from_str(&text)? else {
+                panic!("every frame is one whole message");
+            };
+            let mut fields = message.split(' ');
+            let sender: usize = fields.next().unwrap().parse()?;
+            let index: usize = fields.next().unwrap().parse()?;
+            assert_eq!(index, next_index[sender]);
+            next_index[sender] += 1;
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_definition_snippets_stay_readable_and_bounded() -> Result<()> {
+        let workspace = tempfile::tempdir()?;
+        let outside = tempfile::tempdir()?;
+        let server = test_server(workspace.path())?;
+        let connection = ConnectionState::new();
+        let file = workspace.path().canonicalize()?.join("main.rs");
+        std::fs::write(&file, "a\nb\nc\nd\n")?;
+        let secret = outside.path().join("id_rsa");
+        std::fs::write(&secret, "private key")?;
+
+        // A language server pointing outside the workspace gets no content back
+        let snippet = server.definition_snippet(&location(&secret, 0, 0), 1, &connection).await?;
+        assert!(snippet.is_none());
+
+        // An inverted or saturated range doesn't overflow
+        let snippet = server.definition_snippet(&location(&file, 2, 1), 0, &connection).await?;
+        assert_eq!(snippet.map(|snippet| snippet.lines), Some(vec!["c".to_string()]));
+        let snippet = server
+            .definition_snippet(&location(&file, 1, u32::MAX), 0, &connection)
+            .await?
+            .expect("the file is in the workspace");
+        assert_eq!((snippet.start_line, snippet.lines.len()), (1, 3));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_edit_locations_stay_readable() -> Result<()> {
+        let workspace = tempfile::tempdir()?;
+        let outside = tempfile::tempdir()?;
+        let server = test_server(workspace.path())?;
+        let connection = ConnectionState::new();
+        let file = workspace.path().canonicalize()?.join("main.rs");
+        std::fs::write(&file, "fn main() {}\n")?;
+        let secret = outside.path().join("id_rsa");
+        std::fs::write(&secret, "private key")?;
+
+        let rename = || lsp_types::TextEdit {
+            range: lsp_types::Range::new(Position::new(0, 0), Position::new(0, 2)),
+            new_text: "pub fn".to_string(),
+        };
+        let edit = lsp_types::WorkspaceEdit {
+            changes: Some(HashMap::from([
+                (path_to_uri(&file)?, vec![rename()]),
+                (path_to_uri(&secret)?, vec![rename()]),
+            ])),
+            ..Default::default()
+        };
+        let locations = server.edit_locations(&edit, &connection).await;
+        assert_eq!(locations.len(), 1);
+        assert_eq!(locations[0].path, file);
+        assert_eq!(locations[0].text, "fn main() {}");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_replace_reaches_utf16_files_in_a_workspace_under_target() -> Result<()> {
+        let parent = tempfile::tempdir()?;
+        let workspace = parent.path().canonicalize()?.join("target/ws");
+        std::fs::create_dir_all(&workspace)?;
+        let server = test_server(&workspace)?;
+        let connection = ConnectionState::new();
+        let (tx, mut rx) = mpsc::channel(16);
+        let utf16 = |text: &str| text.encode_utf16().flat_map(u16::to_le_bytes).collect::<Vec<u8>>();
+        let path = workspace.join("notes.txt");
+        std::fs::write(&path, [&b"\xFF\xFE"[..], &utf16("café\n")].concat())?;
+
+        let replace = ClientMessage::ReplaceInFiles {
+            find: "café".to_string(),
+            replace: "tea".to_string(),
+            is_regex: false,
+            scope: None,
+            confirm: true,
+        };
+        server.handle_client_message(replace, &tx, &connection).await?;
+        drop(tx);
+        let summary = loop {
+            match rx.recv().await.expect("the replace is answered") {
+                ServerMessage::ReplaceInFilesSummary { files, failed, .. } => break (files.len(), failed.len()),
+                _ => continue,
+            }
+        };
+        assert_eq!(summary, (1, 0));
+        assert_eq!(std::fs::read(&path)?, [&b"\xFF\xFE"[..], &utf16("tea\n")].concat());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_replace_writes_closed_documents_to_disk() -> Result<()> {
+        let workspace = tempfile::tempdir()?;
+        let server = test_server(workspace.path())?;
+        let connection = ConnectionState::new();
+        let (tx, mut rx) = mpsc::channel(16);
+        let path = workspace.path().canonicalize()?.join("notes.txt");
+        std::fs::write(&path, "hello\n")?;
+
+        let open = ClientMessage::OpenFile { path: "notes.txt".to_string() };
+        server.handle_client_message(open, &tx, &connection).await?;
+        let close = ClientMessage::CloseFile { path: "notes.txt".to_string() };
+        server.handle_client_message(close, &tx, &connection).await?;
+        while rx.try_recv().is_ok() {}
+
+        let replace = ClientMessage::ReplaceInFiles {
+            find: "hello".to_string(),
+            replace: "bye".to_string(),
+            is_regex: false,
+            scope: None,
+            confirm: true,
+        };
+        server.handle_client_message(replace, &tx, &connection).await?;
+        drop(tx);
+        let files = loop {
+            match rx.recv().await.expect("the replace is answered") {
+                ServerMessage::ReplaceInFilesSummary { files, .. } => break files,
+                _ => continue,
+            }
+        };
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].version, None);
+        assert_eq!(std::fs::read_to_string(&path)?, "bye\n");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_search_status_is_only_given_for_the_latest_search() -> Result<()> {
+        let workspace = tempfile::tempdir()?;
+        let server = test_server(workspace.path())?;
+        let connection = ConnectionState::new();
+        let (tx, mut rx) = mpsc::channel(16);
+
+        let search = ClientMessage::Search {
+            id: "first".to_string(),
+            query: "main".to_string(),
+            search_content: false,
+            mode: None,
+            min_score: None,
+            file_types: None,
+        };
+        server.handle_client_message(search, &tx, &connection).await?;
+        while rx.try_recv().is_ok() {}
+
+        for id in ["first", "", "other"] {
+            let status = ClientMessage::GetSearchStatus { id: id.to_string() };
+            server.handle_client_message(status, &tx, &connection).await?;
+            match rx.recv().await {
+                Some(ServerMessage::SearchState { search_id, .. }) if id != "other" => assert_eq!(search_id, "first"),
+                Some(ServerMessage::Error { .. }) if id == "other" => {}
+                _ => panic!("unexpected answer for {:?}", id),
+            }
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_open_file_at_clamps_to_the_crlf_line_end() -> Result<()> {
+        let workspace = tempfile::tempdir()?;
+        let server = test_server(workspace.path())?;
+        let connection = ConnectionState::new();
+        let (tx, mut rx) = mpsc::channel(16);
+        std::fs::write(workspace.path().join("main.rs"), "fn main() {\r\n}\r\n")?;
+
+        let open = ClientMessage::OpenFileAt {
+            path: "main.rs".to_string(),
+            line: 0,
+            character: 40,
+        };
+        server.handle_client_message(open, &tx, &connection).await?;
+        let Some(ServerMessage::DocumentContent { content, .. }) = rx.recv().await else {
+            panic!("the content comes first");
+        };
+        assert_eq!(content, "fn main() {\r\n}\r\n");
+        let Some(ServerMessage::OpenFileTarget { position, clamped, .. }) = rx.recv().await else {
+            panic!("followed by the target");
+        };
+        assert_eq!((position, clamped), (Position::new(0, 11), true));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_directories_cant_be_moved_out_of_the_workspace() -> Result<()> {
+        let parent = tempfile::tempdir()?;
+        let workspace = parent.path().canonicalize()?.join("ws");
+        std::fs::create_dir_all(workspace.join("src"))?;
+        std::fs::write(workspace.join("src/main.rs"), "fn main() {}\n")?;
+        let server = test_server(&workspace)?;
+        let connection = ConnectionState::new();
+        let (tx, mut rx) = mpsc::channel(16);
+
+        for to in ["../stolen", "src/../../stolen"] {
+            let move_directory = ClientMessage::MoveDirectory {
+                from: "src".to_string(),
+                to: to.to_string(),
+            };
+            server.handle_client_message(move_directory, &tx, &connection).await?;
+            assert!(matches!(rx.recv().await, Some(ServerMessage::Error { .. })));
         }
+        assert!(!parent.path().join("stolen").exists());
+        assert!(workspace.join("src/main.rs").exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_invalid_renames_are_refused_before_anything_changes() -> Result<()> {
+        let workspace = tempfile::tempdir()?;
+        let root = workspace.path().canonicalize()?;
+        std::fs::write(root.join("a.rs"), "mod b;\n")?;
+        std::fs::write(root.join("b.rs"), "fn b() {}\n")?;
+        let server = test_server(&root)?;
+        let connection = ConnectionState::new();
+        let (tx, mut rx) = mpsc::channel(16);
+
+        for (old_path, new_path) in [("a.rs", "b.rs"), ("missing.rs", "c.rs")] {
+            let rename = ClientMessage::RenameFile {
+                old_path: old_path.to_string(),
+                new_path: new_path.to_string(),
+                dry_run: false,
+            };
+            server.handle_client_message(rename, &tx, &connection).await?;
+            assert!(matches!(rx.recv().await, Some(ServerMessage::Error { .. })));
+        }
+        assert_eq!(std::fs::read_to_string(root.join("a.rs"))?, "mod b;\n");
+        assert_eq!(std::fs::read_to_string(root.join("b.rs"))?, "fn b() {}\n");
+        assert!(!root.join("c.rs").exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_directories_opened_as_files_get_tree_updates() -> Result<()> {
+        let workspace = tempfile::tempdir()?;
+        let server = test_server(workspace.path())?;
+        let connection = ConnectionState::new();
+        connection.tree_updates.store(true, std::sync::atomic::Ordering::Relaxed);
+        let (tx, _rx) = mpsc::channel(16);
+        let root = workspace.path().canonicalize()?;
+        std::fs::create_dir(root.join("src"))?;
+        std::fs::create_dir(root.join("tests"))?;
+
+        let open_file = ClientMessage::OpenFile { path: "src".to_string() };
+        server.handle_client_message(open_file, &tx, &connection).await?;
+        let open_files = ClientMessage::OpenFiles { paths: vec!["tests".to_string()] };
+        server.handle_client_message(open_files, &tx, &connection).await?;
+
+        let mut events = Vec::new();
+        for path in [root.join("src/lib.rs"), root.join("tests/it.rs")] {
+            std::fs::write(&path, "")?;
+            events.push(FileEvent::Created {
+                metadata: FileMetadata::read(&path).await?,
+                path,
+                timestamp_ms: 0,
+            });
+        }
+        let added: Vec<_> = connection
+            .tree_updates(&events)
+            .await
+            .into_iter()
+            .filter_map(|update| match update {
+                ServerMessage::TreeNodeAdded { parent, .. } => Some(parent),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(added, vec![root.join("src"), root.join("tests")]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_hidden_entries_stay_hidden_after_listing_without_them() -> Result<()> {
+        let workspace = tempfile::tempdir()?;
+        let server = test_server(workspace.path())?;
+        let connection = ConnectionState::new();
+        let (tx, mut rx) = mpsc::channel(16);
+        let root = workspace.path().canonicalize()?;
+        std::fs::write(root.join(".env"), "")?;
+        std::fs::write(root.join("main.rs"), "")?;
+
+        let messages = [
+            ClientMessage::GetDirectory { path: ".".to_string(), show_hidden: false },
+            ClientMessage::FilterDirectory { path: ".".to_string(), query: String::new() },
+            ClientMessage::OpenFile { path: ".".to_string() },
+        ];
+        for message in messages {
+            server.handle_client_message(message, &tx, &connection).await?;
+            let content = match rx.recv().await {
+                Some(ServerMessage::DirectoryContent { content, .. })
+                | Some(ServerMessage::FilteredDirectory { content, .. }) => content,
+                other => panic!("unexpected answer: {:?}", other),
+            };
+            let names: Vec<_> = content.into_iter().map(|node| node.name).collect();
+            assert_eq!(names, vec!["main.rs"]);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_cancel_all_leaves_other_connections_searches() -> Result<()> {
+        use crate::lsp::test_support::{fake_tcp_server, tcp_config};
+
+        let workspace = tempfile::tempdir()?;
+        std::fs::write(workspace.path().join("main.fake"), "fn main() {}")?;
+        let (port, mut received) = fake_tcp_server(&["textDocument/hover"]).await?;
+        let server = Server::new(workspace.path().to_path_buf(), 0, vec![tcp_config(port)], ServerConfig::default())?;
+        let connection = Arc::new(ConnectionState::new());
+        let other = ConnectionState::new();
+        let (tx, mut rx) = mpsc::channel(16);
+
+        let search = ClientMessage::Search {
+            id: "other".to_string(),
+            query: "main".to_string(),
+            search_content: false,
+            mode: None,
+            min_score: None,
+            file_types: None,
+        };
+        server.handle_client_message(search, &tx, &other).await?;
+        let open = ClientMessage::OpenFile { path: "main.fake".to_string() };
+        server.handle_client_message(open, &tx, &connection).await?;
+
+        let hover = ClientMessage::Hover {
+            path: "main.fake".to_string(),
+            position: Position::new(0, 3),
+            plain_text: None,
+        };
+        tokio::spawn({
+            let (server, tx, connection) = (server.clone(), tx.clone(), Arc::clone(&connection));
+            async move { server.handle_client_message(hover, &tx, &connection).await }
+        });
+        let method_id = |message: &serde_json::Value, method: &str| {
+            (message["method"] == method).then(|| message["id"].clone())
+        };
+        let hover_id = loop {
+            let message = received.recv().await.expect("the server stays connected");
+            if let Some(id) = method_id(&message, "textDocument/hover") {
+                break id;
+            }
+        };
+
+        server.handle_client_message(ClientMessage::CancelAll {}, &tx, &connection).await?;
+        let cancelled = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let message = received.recv().await.expect("the server stays connected");
+                if message["method"] == "$/cancelRequest" {
+                    break message["params"]["id"].clone();
+                }
+            }
+        })
+        .await?;
+        assert_eq!(cancelled, hover_id);
+        let reply = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                match rx.recv().await.expect("the hover is answered") {
+                    ServerMessage::LspRequestCancelled { request } => break request,
+                    _ => continue,
+                }
+            }
+        })
+        .await?;
+        assert_eq!(reply, "Hover");
+        assert_eq!(*server.search_owner.lock().await, Some(other.id));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_streamed_file_arrives_whole() -> Result<()> {
+        let workspace = tempfile::tempdir()?;
+        let path = workspace.path().join("data.bin");
+        let bytes: Vec<u8> = (0..STREAM_CHUNK_BYTES * 2 + 10).map(|i| i as u8).collect();
+        std::fs::write(&path, &bytes)?;
+        let (tx, mut rx) = mpsc::channel(16);
+
+        stream_file(&path, &tx).await?;
+        drop(tx);
+        let Some(ServerMessage::DocumentStreamStart { total_size, chunk_count, .. }) = rx.recv().await else {
+            panic!("the stream starts with its size");
+        };
+        let mut received = Vec::new();
+        let mut chunks = 0;
+        while let Some(ServerMessage::DocumentChunk { content, offset, .. }) = rx.recv().await {
+            assert_eq!(offset, received.len() as u64);
+            received.extend(content);
+            chunks += 1;
+            if received.len() as u64 == total_size {
+                break;
+            }
+        }
+        assert_eq!((total_size, chunk_count, chunks), (bytes.len() as u64, 3, 3));
+        assert_eq!(received, bytes);
+        assert!(matches!(rx.recv().await, Some(ServerMessage::DocumentStreamEnd { .. })));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_file_truncated_while_streaming_fails() -> Result<()> {
+        let workspace = tempfile::tempdir()?;
+        let path = workspace.path().join("data.bin");
+        std::fs::write(&path, vec![b'x'; STREAM_CHUNK_BYTES as usize * 4])?;
+        // Room for one message, so the stream is at most two chunks ahead
+        let (tx, mut rx) = mpsc::channel(1);
+
+        let stream = tokio::spawn({
+            let path = path.clone();
+            async move { stream_file(&path, &tx).await }
+        });
+        assert!(matches!(rx.recv().await, Some(ServerMessage::DocumentStreamStart { .. })));
+        std::fs::OpenOptions::new().write(true).open(&path)?.set_len(10)?;
+
+        let mut messages = Vec::new();
+        while let Some(message) = rx.recv().await {
+            messages.push(message);
+        }
+        assert!(stream.await?.is_err());
+        assert!(messages.iter().all(|message| matches!(message, ServerMessage::DocumentChunk { .. })));
+        Ok(())
     }
 }
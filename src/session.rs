@@ -0,0 +1,57 @@
+// src/session.rs
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+// State of connections that went away, kept for `timeout` so a client that
+// reconnects can pick it up again
+pub struct DetachedSessions<T> {
+    timeout: Duration,
+    sessions: Mutex<HashMap<uuid::Uuid, (T, Instant)>>,
+}
+
+impl<T> DetachedSessions<T> {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            sessions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub async fn detach(&self, id: uuid::Uuid, state: T) {
+        let mut sessions = self.sessions.lock().await;
+        self.remove_expired(&mut sessions);
+        sessions.insert(id, (state, Instant::now()));
+    }
+
+    // Hands out the session's state once, None if unknown or expired
+    pub async fn resume(&self, id: &uuid::Uuid) -> Option<T> {
+        let mut sessions = self.sessions.lock().await;
+        self.remove_expired(&mut sessions);
+        sessions.remove(id).map(|(state, _)| state)
+    }
+
+    fn remove_expired(&self, sessions: &mut HashMap<uuid::Uuid, (T, Instant)>) {
+        sessions.retain(|_, (_, detached_at)| detached_at.elapsed() < self.timeout);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_sessions_resume_once_and_expire() {
+        let sessions = DetachedSessions::new(Duration::from_millis(50));
+        let first = uuid::Uuid::new_v4();
+        let second = uuid::Uuid::new_v4();
+
+        sessions.detach(first, "first").await;
+        assert_eq!(sessions.resume(&first).await, Some("first"));
+        assert_eq!(sessions.resume(&first).await, None);
+
+        sessions.detach(second, "second").await;
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert_eq!(sessions.resume(&second).await, None);
+    }
+}
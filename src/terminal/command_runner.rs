@@ -0,0 +1,121 @@
+// src/terminal/command_runner.rs
+use anyhow::Result;
+use portable_pty::{native_pty_system, ChildKiller, CommandBuilder, PtySize};
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::{broadcast, RwLock};
+use crate::terminal::types::TerminalMessage;
+
+// Commands get a PTY so tools keep their colored output, but nobody resizes it
+const COMMAND_PTY_SIZE: PtySize = PtySize {
+    rows: 24,
+    cols: 120,
+    pixel_width: 0,
+    pixel_height: 0,
+};
+
+pub type RunningCommands = Arc<RwLock<HashMap<String, Box<dyn ChildKiller + Send + Sync>>>>;
+
+// Runs a single command to completion. Output is sent as TerminalMessage::Output
+// under `id`, followed by one CommandFinished once the process has exited.
+pub async fn run_command(
+    id: String,
+    command: &str,
+    args: &[String],
+    cwd: PathBuf,
+    event_sender: broadcast::Sender<TerminalMessage>,
+    running: RunningCommands,
+) -> Result<()> {
+    let pty_pair = native_pty_system().openpty(COMMAND_PTY_SIZE)?;
+
+    let mut cmd = CommandBuilder::new(command);
+    cmd.args(args);
+    cmd.cwd(cwd);
+    if !cfg!(windows) {
+        cmd.env("TERM", "xterm-256color");
+    }
+
+    let started = Instant::now();
+    let mut child = pty_pair.slave.spawn_command(cmd)?;
+    // Only the child may hold the slave, otherwise the reader never sees EOF
+    drop(pty_pair.slave);
+
+    let mut reader = pty_pair.master.try_clone_reader()?;
+    running.write().await.insert(id.clone(), child.clone_killer());
+
+    std::thread::spawn(move || {
+        // Keep the master open until all output has been read
+        let _master = pty_pair.master;
+        let mut buffer = [0u8; 1024];
+        loop {
+            match reader.read(&mut buffer) {
+                Ok(n) if n > 0 => {
+                    let _ = event_sender.send(TerminalMessage::Output {
+                        terminal_id: id.clone(),
+                        data: buffer[..n].to_vec(),
+                    });
+                }
+                // Linux reports EIO instead of EOF once the child closes the PTY
+                _ => break,
+            }
+        }
+
+        let message = match child.wait() {
+            Ok(status) => TerminalMessage::CommandFinished {
+                terminal_id: id.clone(),
+                exit_code: status.exit_code(),
+                duration_ms: started.elapsed().as_millis() as u64,
+            },
+            Err(e) => TerminalMessage::Error {
+                terminal_id: id.clone(),
+                error: e.to_string(),
+            },
+        };
+        running.blocking_write().remove(&id);
+        let _ = event_sender.send(message);
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_run_command_reports_output_and_exit_code() -> Result<()> {
+        let (event_sender, mut events) = broadcast::channel(100);
+        let running: RunningCommands = Arc::new(RwLock::new(HashMap::new()));
+        let args = vec!["-c".to_string(), "echo hello; exit 3".to_string()];
+
+        run_command(
+            "cmd".to_string(),
+            "sh",
+            &args,
+            std::env::temp_dir(),
+            event_sender,
+            Arc::clone(&running),
+        )
+        .await?;
+
+        let mut output = Vec::new();
+        loop {
+            match tokio::time::timeout(Duration::from_secs(10), events.recv()).await?? {
+                TerminalMessage::Output { data, .. } => output.extend(data),
+                TerminalMessage::CommandFinished { exit_code, .. } => {
+                    assert_eq!(exit_code, 3);
+                    break;
+                }
+                other => panic!("unexpected message: {:?}", other),
+            }
+        }
+
+        assert!(String::from_utf8_lossy(&output).contains("hello"));
+        assert!(running.read().await.is_empty());
+        Ok(())
+    }
+}
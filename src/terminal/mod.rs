@@ -1,3 +1,6 @@
 pub mod types;
 pub mod terminal_server;
-pub mod terminal_manager;
\ No newline at end of file
+pub mod terminal_manager;
+pub mod command_runner;
+pub mod screen;
+pub mod terminal_log;
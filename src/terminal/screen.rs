@@ -0,0 +1,200 @@
+// src/terminal/screen.rs
+use serde::{Deserialize, Serialize};
+use vte::{Params, Parser, Perform};
+
+// Plain text of the visible screen, trailing blanks trimmed
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ScreenSnapshot {
+    pub lines: Vec<String>,
+    pub cursor_row: u16,
+    pub cursor_col: u16,
+}
+
+// Minimal terminal emulator that tracks the characters on screen. Colors and
+// other attributes are ignored, only cursor movement and erasing are applied.
+pub struct ScreenBuffer {
+    parser: Parser,
+    grid: Grid,
+}
+
+struct Grid {
+    rows: usize,
+    cols: usize,
+    cells: Vec<Vec<char>>,
+    cursor_row: usize,
+    cursor_col: usize,
+}
+
+impl ScreenBuffer {
+    pub fn new(rows: u16, cols: u16) -> Self {
+        let (rows, cols) = (rows.max(1) as usize, cols.max(1) as usize);
+        Self {
+            parser: Parser::new(),
+            grid: Grid {
+                rows,
+                cols,
+                cells: vec![vec![' '; cols]; rows],
+                cursor_row: 0,
+                cursor_col: 0,
+            },
+        }
+    }
+
+    pub fn feed(&mut self, data: &[u8]) {
+        for byte in data {
+            self.parser.advance(&mut self.grid, *byte);
+        }
+    }
+
+    pub fn resize(&mut self, rows: u16, cols: u16) {
+        let grid = &mut self.grid;
+        let (rows, cols) = (rows.max(1) as usize, cols.max(1) as usize);
+
+        // Shrinking keeps the bottom of the screen, where the prompt usually is
+        if rows < grid.rows {
+            let excess = grid.rows - rows;
+            grid.cells.drain(..excess);
+            grid.cursor_row = grid.cursor_row.saturating_sub(excess);
+        }
+        grid.cells.resize(rows, vec![' '; cols]);
+        for line in &mut grid.cells {
+            line.resize(cols, ' ');
+        }
+
+        grid.rows = rows;
+        grid.cols = cols;
+        grid.cursor_row = grid.cursor_row.min(rows - 1);
+        grid.cursor_col = grid.cursor_col.min(cols - 1);
+    }
+
+    pub fn snapshot(&self) -> ScreenSnapshot {
+        ScreenSnapshot {
+            lines: self
+                .grid
+                .cells
+                .iter()
+                .map(|line| line.iter().collect::<String>().trim_end().to_string())
+                .collect(),
+            cursor_row: self.grid.cursor_row as u16,
+            cursor_col: self.grid.cursor_col as u16,
+        }
+    }
+}
+
+impl Grid {
+    fn line_feed(&mut self) {
+        if self.cursor_row + 1 >= self.rows {
+            self.cells.remove(0);
+            self.cells.push(vec![' '; self.cols]);
+        } else {
+            self.cursor_row += 1;
+        }
+    }
+
+    fn clear(&mut self, row: usize, columns: std::ops::Range<usize>) {
+        let end = columns.end.min(self.cols);
+        for cell in &mut self.cells[row][columns.start.min(end)..end] {
+            *cell = ' ';
+        }
+    }
+}
+
+// CSI parameter at `index`, with 0 or a missing value meaning `default`
+fn param(params: &Params, index: usize, default: usize) -> usize {
+    match params.iter().nth(index).and_then(|p| p.first()) {
+        Some(&value) if value > 0 => value as usize,
+        _ => default,
+    }
+}
+
+impl Perform for Grid {
+    fn print(&mut self, c: char) {
+        if self.cursor_col >= self.cols {
+            self.cursor_col = 0;
+            self.line_feed();
+        }
+        self.cells[self.cursor_row][self.cursor_col] = c;
+        self.cursor_col += 1;
+    }
+
+    fn execute(&mut self, byte: u8) {
+        match byte {
+            b'\n' | 0x0b | 0x0c => self.line_feed(),
+            b'\r' => self.cursor_col = 0,
+            0x08 => self.cursor_col = self.cursor_col.saturating_sub(1),
+            b'\t' => self.cursor_col = ((self.cursor_col / 8 + 1) * 8).min(self.cols - 1),
+            _ => {}
+        }
+    }
+
+    fn csi_dispatch(&mut self, params: &Params, intermediates: &[u8], _ignore: bool, action: char) {
+        // Private modes (e.g. `?1049h`) don't change what's on screen here
+        if !intermediates.is_empty() {
+            return;
+        }
+
+        let (last_row, last_col) = (self.rows - 1, self.cols - 1);
+        match action {
+            'A' => self.cursor_row = self.cursor_row.saturating_sub(param(params, 0, 1)),
+            'B' => self.cursor_row = (self.cursor_row + param(params, 0, 1)).min(last_row),
+            'C' => self.cursor_col = (self.cursor_col + param(params, 0, 1)).min(last_col),
+            'D' => self.cursor_col = self.cursor_col.saturating_sub(param(params, 0, 1)),
+            'G' => self.cursor_col = (param(params, 0, 1) - 1).min(last_col),
+            'd' => self.cursor_row = (param(params, 0, 1) - 1).min(last_row),
+            'H' | 'f' => {
+                self.cursor_row = (param(params, 0, 1) - 1).min(last_row);
+                self.cursor_col = (param(params, 1, 1) - 1).min(last_col);
+            }
+            'J' => {
+                let row = self.cursor_row;
+                match param(params, 0, 0) {
+                    0 => {
+                        self.clear(row, self.cursor_col..self.cols);
+                        for line in row + 1..self.rows {
+                            self.clear(line, 0..self.cols);
+                        }
+                    }
+                    1 => {
+                        for line in 0..row {
+                            self.clear(line, 0..self.cols);
+                        }
+                        self.clear(row, 0..self.cursor_col + 1);
+                    }
+                    _ => {
+                        for line in 0..self.rows {
+                            self.clear(line, 0..self.cols);
+                        }
+                    }
+                }
+            }
+            'K' => {
+                let row = self.cursor_row;
+                match param(params, 0, 0) {
+                    0 => self.clear(row, self.cursor_col..self.cols),
+                    1 => self.clear(row, 0..self.cursor_col + 1),
+                    _ => self.clear(row, 0..self.cols),
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_screen_applies_cursor_movement_and_erase() {
+        let mut screen = ScreenBuffer::new(3, 10);
+        screen.feed(b"hello\r\nworld\x1b[1;1Hj\x1b[2;3H\x1b[K");
+
+        let snapshot = screen.snapshot();
+        assert_eq!(snapshot.lines, vec!["jello", "wo", ""]);
+        assert_eq!((snapshot.cursor_row, snapshot.cursor_col), (1, 2));
+
+        // Output past the last row scrolls the screen
+        screen.feed(b"\x1b[3;1Hthree\r\nfour");
+        assert_eq!(screen.snapshot().lines, vec!["wo", "three", "four"]);
+    }
+}
@@ -0,0 +1,178 @@
+// src/terminal/terminal_log.rs
+use anyhow::Result;
+use std::fs::{self, File};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+// Most bytes returned by one GetTerminalLog
+pub const TERMINAL_LOG_PAGE_BYTES: usize = 64 * 1024;
+
+// Transcript of a terminal's output on disk. Once the current file reaches
+// `max_bytes` it is rotated to `<id>.log.1`, replacing the previous rotation,
+// so at most two files are kept. Offsets count every byte ever written, so
+// they stay valid across rotations.
+pub struct TerminalLog {
+    path: PathBuf,
+    rotated_path: PathBuf,
+    max_bytes: u64,
+    // Left on disk when the terminal closes
+    keep: bool,
+    state: Mutex<LogState>,
+}
+
+struct LogState {
+    // None once the log has been removed
+    file: Option<File>,
+    // Offsets of the first byte in the current and the rotated file
+    current_start: u64,
+    rotated_start: Option<u64>,
+    total_bytes: u64,
+}
+
+#[derive(Debug)]
+pub struct LogPage {
+    // Where `data` starts, later than requested if that part was rotated away
+    pub offset: u64,
+    pub data: Vec<u8>,
+    pub total_bytes: u64,
+}
+
+impl TerminalLog {
+    pub fn create(dir: &Path, id: &str, max_bytes: u64, keep: bool) -> Result<Self> {
+        fs::create_dir_all(dir)?;
+        let path = dir.join(format!("{}.log", id));
+        let file = File::create(&path)?;
+        Ok(Self {
+            rotated_path: dir.join(format!("{}.log.1", id)),
+            path,
+            max_bytes,
+            keep,
+            state: Mutex::new(LogState {
+                file: Some(file),
+                current_start: 0,
+                rotated_start: None,
+                total_bytes: 0,
+            }),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn keep(&self) -> bool {
+        self.keep
+    }
+
+    pub fn append(&self, data: &[u8]) -> Result<()> {
+        let mut state = self.lock()?;
+        let Some(file) = state.file.as_mut() else {
+            return Ok(());
+        };
+        file.write_all(data)?;
+        state.total_bytes += data.len() as u64;
+
+        if state.total_bytes - state.current_start >= self.max_bytes {
+            // The current file keeps being written if rotating fails, and the
+            // next append tries again
+            fs::rename(&self.path, &self.rotated_path)?;
+            let file = match File::create(&self.path) {
+                Ok(file) => file,
+                Err(e) => {
+                    // The earlier rotated file was replaced by the rename
+                    let _ = fs::rename(&self.rotated_path, &self.path);
+                    state.rotated_start = None;
+                    return Err(e.into());
+                }
+            };
+            state.file = Some(file);
+            state.rotated_start = Some(state.current_start);
+            state.current_start = state.total_bytes;
+        }
+        Ok(())
+    }
+
+    // Reads up to `max_len` bytes from one file, starting at `from_offset` or at
+    // the oldest byte still on disk
+    pub fn read(&self, from_offset: u64, max_len: usize) -> Result<LogPage> {
+        let state = self.lock()?;
+        if state.file.is_none() {
+            anyhow::bail!("Terminal log was removed");
+        }
+
+        let oldest = state.rotated_start.unwrap_or(state.current_start);
+        let offset = from_offset.clamp(oldest, state.total_bytes);
+        let (path, start, end) = match state.rotated_start {
+            Some(rotated_start) if offset < state.current_start => {
+                (&self.rotated_path, rotated_start, state.current_start)
+            }
+            _ => (&self.path, state.current_start, state.total_bytes),
+        };
+
+        let mut data = vec![0; (end - offset).min(max_len as u64) as usize];
+        let mut file = File::open(path)?;
+        file.seek(SeekFrom::Start(offset - start))?;
+        file.read_exact(&mut data)?;
+        Ok(LogPage {
+            offset,
+            data,
+            total_bytes: state.total_bytes,
+        })
+    }
+
+    // Deletes the log files; output that arrives afterwards is dropped
+    pub fn remove(&self) -> Result<()> {
+        let mut state = self.lock()?;
+        state.file = None;
+        for path in [&self.path, &self.rotated_path] {
+            match fs::remove_file(path) {
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn lock(&self) -> Result<std::sync::MutexGuard<'_, LogState>> {
+        self.state
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Terminal log is unavailable"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_log_rotates_and_pages_by_offset() -> Result<()> {
+        let dir = TempDir::new()?;
+        let log = TerminalLog::create(dir.path(), "term", 8, false)?;
+
+        log.append(b"0123456789")?; // rotated right away
+        log.append(b"abc")?;
+
+        let page = log.read(0, 4)?;
+        assert_eq!((page.offset, page.data.as_slice(), page.total_bytes), (0, &b"0123"[..], 13));
+        // A page never spans both files
+        let page = log.read(6, 100)?;
+        assert_eq!((page.offset, page.data.as_slice()), (6, &b"6789"[..]));
+        let page = log.read(10, 100)?;
+        assert_eq!(page.data, b"abc");
+        assert!(log.read(13, 100)?.data.is_empty());
+
+        // A second rotation drops the first file, older offsets start at what's left
+        log.append(b"defgh")?;
+        log.append(b"!")?;
+        let page = log.read(0, 100)?;
+        assert_eq!((page.offset, page.data.as_slice()), (10, &b"abcdefgh"[..]));
+
+        log.remove()?;
+        assert!(!log.path().exists());
+        assert!(fs::read_dir(dir.path())?.next().is_none());
+        assert!(log.read(0, 100).is_err());
+        Ok(())
+    }
+}
@@ -1,24 +1,44 @@
 // src/terminal/terminal_manager.rs
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use tokio::sync::{broadcast, RwLock};
 use std::sync::Arc;
 use anyhow::{Result, anyhow};
-use crate::terminal::types::{TerminalMessage, TerminalSize};
-use crate::terminal::terminal_server::TerminalServer;   
+use crate::terminal::types::{
+    TerminalConfig, TerminalError, TerminalErrorCode, TerminalMessage, TerminalSize,
+};
+use crate::terminal::screen::ScreenSnapshot;
+use crate::terminal::terminal_log::{LogPage, TerminalLog, TERMINAL_LOG_PAGE_BYTES};
+use crate::terminal::terminal_server::TerminalServer;
+use crate::terminal::command_runner::{self, RunningCommands};
+use std::path::PathBuf;
+
+const RECENTLY_CLOSED_LIMIT: usize = 64;
 
 pub struct TerminalManager {
     terminals: RwLock<HashMap<String, Arc<TerminalServer>>>,
     event_sender: broadcast::Sender<TerminalMessage>,
+    config: TerminalConfig,
+    // Ids of recently closed terminals, so late writes/resizes can report Closed
+    // instead of "not found"
+    recently_closed: RwLock<VecDeque<String>>,
+    // One-shot commands started with RunCommand that haven't exited yet
+    commands: RunningCommands,
+    // Output logs by terminal id, kept logs stay readable after the terminal closes
+    logs: RwLock<HashMap<String, Arc<TerminalLog>>>,
 }
 
 impl TerminalManager {
-    pub fn new() -> Self {
+    pub fn new(config: TerminalConfig) -> Self {
         let (event_sender, _) = broadcast::channel(100);
 
 
         Self {
             terminals: RwLock::new(HashMap::new()),
             event_sender,
+            config,
+            recently_closed: RwLock::new(VecDeque::new()),
+            commands: Arc::new(RwLock::new(HashMap::new())),
+            logs: RwLock::new(HashMap::new()),
         }
     }
 
@@ -27,46 +47,168 @@ impl TerminalManager {
         self.event_sender.subscribe()
     }
 
-    pub async fn create_terminal(&self, size: TerminalSize) -> Result<String> {
+    // Drops terminals whose shell has exited, remembering them as closed
+    async fn remove_exited_terminals(&self) {
+        let mut terminals = self.terminals.write().await;
+        let exited: Vec<String> = terminals
+            .iter()
+            .filter(|(_, terminal)| terminal.has_exited())
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in exited {
+            terminals.remove(&id);
+            self.remove_log(&id).await;
+            self.remember_closed(id).await;
+        }
+    }
+
+    async fn remember_closed(&self, id: String) {
+        let mut recently_closed = self.recently_closed.write().await;
+        recently_closed.push_back(id);
+        if recently_closed.len() > RECENTLY_CLOSED_LIMIT {
+            recently_closed.pop_front();
+        }
+    }
+
+    // Deletes the terminal's log unless the client asked to keep it
+    async fn remove_log(&self, id: &str) {
+        let mut logs = self.logs.write().await;
+        if logs.get(id).is_some_and(|log| !log.keep()) {
+            if let Some(log) = logs.remove(id) {
+                if let Err(e) = log.remove() {
+                    eprintln!("Failed to remove log of terminal {}: {}", id, e);
+                }
+            }
+        }
+    }
+
+    async fn ensure_capacity(&self) -> Result<()> {
+        self.remove_exited_terminals().await;
+        let open = self.terminals.read().await.len() + self.commands.read().await.len();
+        if open >= self.config.max_terminals {
+            return Err(TerminalError::new(
+                TerminalErrorCode::LimitReached,
+                format!("Terminal limit of {} reached", self.config.max_terminals),
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    // Returns the terminal's id and, with `log_to_file`, the path of its log
+    pub async fn create_terminal(
+        &self,
+        size: TerminalSize,
+        env: HashMap<String, String>,
+        log_to_file: bool,
+        keep_log: bool,
+    ) -> Result<(String, Option<PathBuf>)> {
+        self.config.validate_env(&env)?;
+        self.ensure_capacity().await?;
+
         let id = uuid::Uuid::new_v4().to_string();
         let event_sender = self.event_sender.clone();
-        
-        let terminal = Arc::new(TerminalServer::new(
-            id.clone(),
-            size,
-            event_sender,
-        )?);
+        let log = if log_to_file {
+            Some(Arc::new(TerminalLog::create(
+                &self.config.log_dir,
+                &id,
+                self.config.max_log_bytes,
+                keep_log,
+            )?))
+        } else {
+            None
+        };
+
+        let started = async {
+            let terminal = Arc::new(TerminalServer::new(id.clone(), size, env, event_sender, log.clone())?);
+            terminal.start().await?;
+            Ok::<_, anyhow::Error>(terminal)
+        }
+        .await;
+        let terminal = match started {
+            Ok(terminal) => terminal,
+            Err(e) => {
+                if let Some(log) = &log {
+                    let _ = log.remove();
+                }
+                return Err(e);
+            }
+        };
 
-        terminal.start().await?;
+        let log_path = log.as_ref().map(|log| log.path().to_path_buf());
+        if let Some(log) = log {
+            self.logs.write().await.insert(id.clone(), log);
+        }
         self.terminals.write().await.insert(id.clone(), terminal);
+        Ok((id, log_path))
+    }
+
+    pub async fn read_terminal_log(&self, id: &str, from_offset: u64) -> Result<LogPage> {
+        let log = match self.logs.read().await.get(id) {
+            Some(log) => Arc::clone(log),
+            None => return Err(anyhow!("No log for terminal: {}", id)),
+        };
+        tokio::task::spawn_blocking(move || log.read(from_offset, TERMINAL_LOG_PAGE_BYTES)).await?
+    }
+
+    pub async fn run_command(&self, command: &str, args: &[String], cwd: PathBuf) -> Result<String> {
+        self.ensure_capacity().await?;
+        let id = uuid::Uuid::new_v4().to_string();
+        command_runner::run_command(
+            id.clone(),
+            command,
+            args,
+            cwd,
+            self.event_sender.clone(),
+            Arc::clone(&self.commands),
+        )
+        .await?;
         Ok(id)
     }
 
-    pub async fn write_to_terminal(&self, id: &str, data: &[u8]) -> Result<()> {
-        let terminals = self.terminals.read().await;
-        if let Some(terminal) = terminals.get(id) {
-            terminal.write(data).await?;
-            Ok(())
+    async fn get_terminal(&self, id: &str) -> Result<Arc<TerminalServer>> {
+        if let Some(terminal) = self.terminals.read().await.get(id) {
+            return Ok(Arc::clone(terminal));
+        }
+
+        if self.recently_closed.read().await.iter().any(|closed| closed == id) {
+            Err(TerminalError::new(TerminalErrorCode::Closed, format!("Terminal was closed: {}", id)).into())
         } else {
             Err(anyhow!("Terminal not found: {}", id))
         }
     }
 
+    pub async fn write_to_terminal(&self, id: &str, data: &[u8]) -> Result<()> {
+        // The map lock isn't held across the write, so a concurrent close can't
+        // stall behind it; a terminal closed meanwhile reports Closed
+        self.get_terminal(id).await?.write(data).await
+    }
+
+    pub async fn get_terminal_screen(&self, id: &str) -> Result<ScreenSnapshot> {
+        self.get_terminal(id).await?.screen()
+    }
+
     pub async fn resize_terminal(&self, id: &str, size: TerminalSize) -> Result<()> {
-        let terminals = self.terminals.read().await;
-        if let Some(terminal) = terminals.get(id) {
-            terminal.resize(size).await?;
-            Ok(())
-        } else {
-            Err(anyhow!("Terminal not found: {}", id))
-        }
+        self.get_terminal(id).await?.resize(size).await
     }
 
     pub async fn close_terminal(&self, id: &str) -> Result<()> {
-        if self.terminals.write().await.remove(id).is_none() {
-            Err(anyhow!("Terminal not found: {}", id))
-        } else {
-            Ok(())
+        // Closing a running command kills it, CommandFinished still follows
+        if let Some(mut killer) = self.commands.write().await.remove(id) {
+            killer.kill()?;
+            return Ok(());
+        }
+
+        // Killed right away rather than on drop, a write in flight may still
+        // hold the terminal
+        match self.terminals.write().await.remove(id) {
+            Some(terminal) => {
+                terminal.kill();
+                self.remove_log(id).await;
+                self.remember_closed(id.to_string()).await;
+                Ok(())
+            }
+            None => Err(anyhow!("Terminal not found: {}", id)),
         }
     }
 }
\ No newline at end of file
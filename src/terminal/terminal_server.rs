@@ -1,23 +1,46 @@
 // src/terminal/terminal_server.rs
 use anyhow::Result;
-use portable_pty::{native_pty_system, PtyPair, PtySize, CommandBuilder};
+use portable_pty::{native_pty_system, ChildKiller, CommandBuilder, PtyPair, PtySize};
+use std::collections::HashMap;
 use std::io::{Read, Write};
-use tokio::sync::{broadcast, Mutex};
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc, Mutex, OwnedSemaphorePermit, Semaphore};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use crate::terminal::types::{TerminalMessage, TerminalSize};
+use crate::terminal::screen::{ScreenBuffer, ScreenSnapshot};
+use crate::terminal::terminal_log::TerminalLog;
+use crate::terminal::types::{TerminalError, TerminalErrorCode, TerminalMessage, TerminalSize};
+
+// Input accepted but not yet written to the PTY, per terminal
+const MAX_PENDING_INPUT_BYTES: usize = 64 * 1024;
+// How long a write waits for queued input to drain before failing with Busy
+const INPUT_DRAIN_TIMEOUT: Duration = Duration::from_secs(2);
 
 pub struct TerminalServer {
     id: String,
     pty_pair: Arc<Mutex<Option<PtyPair>>>,
-    writer: Arc<Mutex<Option<Box<dyn Write + Send>>>>,
+    // Input is written to the PTY on a dedicated thread; each chunk holds permits
+    // for its size until written, which bounds the backlog
+    input_sender: mpsc::UnboundedSender<(Vec<u8>, OwnedSemaphorePermit)>,
+    input_capacity: Arc<Semaphore>,
     event_sender: broadcast::Sender<TerminalMessage>,
+    // Set once the shell process has exited
+    exited: Arc<AtomicBool>,
+    // Kills the shell when the terminal is closed; the waiter thread reaps it
+    killer: std::sync::Mutex<Box<dyn ChildKiller + Send + Sync>>,
+    // Current screen contents, for clients that attach after output was sent
+    screen: Arc<std::sync::Mutex<ScreenBuffer>>,
+    // Transcript on disk, for terminals created with log_to_file
+    log: Option<Arc<TerminalLog>>,
 }
 
 impl TerminalServer {
     pub fn new(
         id: String,
         size: TerminalSize,
+        env: HashMap<String, String>,
         event_sender: broadcast::Sender<TerminalMessage>,
+        log: Option<Arc<TerminalLog>>,
     ) -> Result<Self> {
         let pty_system = native_pty_system();
         
@@ -29,7 +52,7 @@ impl TerminalServer {
         })?;
 
         // Take the writer immediately
-        let writer = pty_pair.master.take_writer()?;
+        let mut writer = pty_pair.master.take_writer()?;
 
         let shell_cmd = if cfg!(windows) {
             std::env::var("COMSPEC").unwrap_or_else(|_| "cmd.exe".to_string())
@@ -37,19 +60,59 @@ impl TerminalServer {
             std::env::var("SHELL").unwrap_or_else(|_| "/bin/bash".to_string())
         };
 
+        // CommandBuilder starts from the server's environment, client variables
+        // are layered on top of it
         let mut cmd = CommandBuilder::new(shell_cmd);
         if !cfg!(windows) {
             cmd.env("TERM", "xterm-256color");
         }
+        for (name, value) in env {
+            cmd.env(name, value);
+        }
+
+        let mut child = pty_pair.slave.spawn_command(cmd)?;
+        let killer = child.clone_killer();
+
+        // The PTY stays open after the shell exits, so watch the process itself
+        let exited = Arc::new(AtomicBool::new(false));
+        {
+            let exited = Arc::clone(&exited);
+            let id = id.clone();
+            let event_sender = event_sender.clone();
+            std::thread::spawn(move || {
+                let _ = child.wait();
+                exited.store(true, Ordering::SeqCst);
+                let _ = event_sender.send(TerminalMessage::Exited { terminal_id: id });
+            });
+        }
 
-        let child = pty_pair.slave.spawn_command(cmd)?;
-        std::mem::drop(child);
+        let (input_sender, mut input_receiver) =
+            mpsc::unbounded_channel::<(Vec<u8>, OwnedSemaphorePermit)>();
+        let writer_id = id.clone();
+        let writer_events = event_sender.clone();
+        std::thread::spawn(move || {
+            // Ends once the terminal is dropped and the sender goes away
+            while let Some((data, _permit)) = input_receiver.blocking_recv() {
+                if let Err(e) = writer.write_all(&data).and_then(|_| writer.flush()) {
+                    let _ = writer_events.send(TerminalMessage::Error {
+                        terminal_id: writer_id.clone(),
+                        error: e.to_string(),
+                    });
+                    break;
+                }
+            }
+        });
 
         Ok(Self {
             id,
             pty_pair: Arc::new(Mutex::new(Some(pty_pair))),
-            writer: Arc::new(Mutex::new(Some(writer))),
+            input_sender,
+            input_capacity: Arc::new(Semaphore::new(MAX_PENDING_INPUT_BYTES)),
             event_sender,
+            exited,
+            killer: std::sync::Mutex::new(killer),
+            screen: Arc::new(std::sync::Mutex::new(ScreenBuffer::new(size.rows, size.cols))),
+            log,
         })
     }
 
@@ -57,6 +120,8 @@ impl TerminalServer {
         let id = self.id.clone();
         let pty_pair = Arc::clone(&self.pty_pair);
         let event_sender = self.event_sender.clone();
+        let screen = Arc::clone(&self.screen);
+        let log = self.log.clone();
 
         let mut reader = {
             let mut pair_guard = pty_pair.lock().await;
@@ -69,11 +134,20 @@ impl TerminalServer {
             loop {
                 match reader.read(&mut buffer) {
                     Ok(n) if n > 0 => {
+                        if let Ok(mut screen) = screen.lock() {
+                            screen.feed(&buffer[..n]);
+                        }
+                        if let Some(log) = &log {
+                            if let Err(e) = log.append(&buffer[..n]) {
+                                eprintln!("Failed to write log of terminal {}: {}", id, e);
+                            }
+                        }
                         let msg = TerminalMessage::Output {
                             terminal_id: id.clone(),
                             data: buffer[..n].to_vec(),
                         };
-                        if event_sender.send(msg).is_err() { break; }
+                        // Keep reading without subscribers so the screen stays current
+                        let _ = event_sender.send(msg);
                     }
                     Ok(_) => break,  // EOF
                     Err(e) => {
@@ -91,18 +165,68 @@ impl TerminalServer {
         Ok(())
     }
 
+    // Queues input for the PTY. Waits up to INPUT_DRAIN_TIMEOUT for room in the
+    // backlog and fails with TerminalErrorCode::Busy if there is none
     pub async fn write(&self, data: &[u8]) -> Result<()> {
-        let mut writer_guard = self.writer.lock().await;
-        if let Some(writer) = writer_guard.as_mut() {
-            writer.write_all(data)?;
-            writer.flush()?;
-            Ok(())
-        } else {
-            Err(anyhow::anyhow!("Terminal writer not available"))
+        self.ensure_running()?;
+        if data.len() > MAX_PENDING_INPUT_BYTES {
+            return Err(TerminalError::new(
+                TerminalErrorCode::Busy,
+                format!(
+                    "Input of {} bytes exceeds the {} byte terminal buffer",
+                    data.len(),
+                    MAX_PENDING_INPUT_BYTES
+                ),
+            )
+            .into());
         }
+
+        let permit = tokio::time::timeout(
+            INPUT_DRAIN_TIMEOUT,
+            Arc::clone(&self.input_capacity).acquire_many_owned(data.len() as u32),
+        )
+        .await
+        .map_err(|_| TerminalError::new(TerminalErrorCode::Busy, "Terminal input buffer is full"))??;
+
+        self.input_sender
+            .send((data.to_vec(), permit))
+            .map_err(|_| TerminalError::new(TerminalErrorCode::Closed, "Terminal writer has stopped"))?;
+        Ok(())
+    }
+
+    pub fn screen(&self) -> Result<ScreenSnapshot> {
+        let screen = self
+            .screen
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Terminal screen is unavailable"))?;
+        Ok(screen.snapshot())
+    }
+
+    pub fn has_exited(&self) -> bool {
+        self.exited.load(Ordering::SeqCst)
+    }
+
+    // Kills the shell if it is still running. Exited follows once it is reaped.
+    pub fn kill(&self) {
+        if self.has_exited() {
+            return;
+        }
+        if let Ok(mut killer) = self.killer.lock() {
+            if let Err(e) = killer.kill() {
+                eprintln!("Failed to kill shell of terminal {}: {}", self.id, e);
+            }
+        }
+    }
+
+    fn ensure_running(&self) -> Result<()> {
+        if self.has_exited() {
+            return Err(TerminalError::new(TerminalErrorCode::Closed, "Terminal has exited").into());
+        }
+        Ok(())
     }
 
     pub async fn resize(&self, size: TerminalSize) -> Result<()> {
+        self.ensure_running()?;
         let mut pair_guard = self.pty_pair.lock().await;
         if let Some(pair) = pair_guard.as_mut() {
             pair.master.resize(PtySize {
@@ -111,22 +235,52 @@ impl TerminalServer {
                 pixel_width: 0,
                 pixel_height: 0,
             })?;
+            if let Ok(mut screen) = self.screen.lock() {
+                screen.resize(size.rows, size.cols);
+            }
             Ok(())
         } else {
-            Err(anyhow::anyhow!("Terminal not available"))
+            Err(TerminalError::new(TerminalErrorCode::Closed, "Terminal is shutting down").into())
         }
     }
 }
 
 impl Drop for TerminalServer {
     fn drop(&mut self) {
-        // Clean up resources when the terminal is dropped
-        // This is best-effort cleanup, so we ignore any errors
-        let _ = std::thread::scope(|_| {
-            // The writer and pty_pair will be dropped automatically
-        });
+        // The PTY and the input channel close with their fields, which ends the
+        // reader and writer threads, but the shell has to be killed explicitly
+        self.kill();
     }
 }
 
-unsafe impl Send for TerminalServer {}
-unsafe impl Sync for TerminalServer {}
\ No newline at end of file
+// Every field is Send + Sync on its own: the PTY handles are only Send, so
+// they stay behind the tokio Mutex, and the writer lives on its own thread.
+// Checked at compile time so a future field can't silently break it.
+const _: fn() = || {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<TerminalServer>();
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_dropping_terminal_kills_shell() -> Result<()> {
+        let (event_sender, mut events) = broadcast::channel(100);
+        let size = TerminalSize { rows: 24, cols: 80 };
+        let terminal = TerminalServer::new("term".to_string(), size, HashMap::new(), event_sender, None)?;
+        terminal.start().await?;
+        assert!(!terminal.has_exited());
+
+        drop(terminal);
+        loop {
+            let message = tokio::time::timeout(Duration::from_secs(10), events.recv()).await??;
+            if let TerminalMessage::Exited { terminal_id } = message {
+                assert_eq!(terminal_id, "term");
+                break;
+            }
+        }
+        Ok(())
+    }
+}
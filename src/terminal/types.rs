@@ -1,4 +1,7 @@
 use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::PathBuf;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TerminalSize {
@@ -25,4 +28,173 @@ pub enum TerminalMessage {
         terminal_id: String,
         error: String,
     },
+    // The shell of an interactive terminal exited
+    Exited {
+        terminal_id: String,
+    },
+    CommandFinished {
+        terminal_id: String,
+        exit_code: u32,
+        duration_ms: u64,
+    },
+}
+
+pub const DEFAULT_MAX_TERMINALS: usize = 32;
+pub const DEFAULT_MAX_TERMINAL_LOG_BYTES: u64 = 16 * 1024 * 1024;
+
+// Variables that make the dynamic loader or the shell run code of the client's
+// choosing before anything is typed. Clients can't set these unless allowed
+// with --terminal-env-allow.
+pub const DEFAULT_DENIED_ENV: &[&str] = &[
+    "LD_PRELOAD",
+    "LD_LIBRARY_PATH",
+    "LD_AUDIT",
+    "DYLD_INSERT_LIBRARIES",
+    "DYLD_LIBRARY_PATH",
+    "DYLD_FRAMEWORK_PATH",
+    "BASH_ENV",
+    "ENV",
+    "ZDOTDIR",
+    "PROMPT_COMMAND",
+    "SHELLOPTS",
+    "BASHOPTS",
+];
+
+// Exported bash functions, which bash defines when it starts
+const DENIED_ENV_PREFIX: &str = "BASH_FUNC_";
+
+#[derive(Debug, Clone)]
+pub struct TerminalConfig {
+    // When set, clients may only set these variables on new terminals
+    pub allowed_env: Option<HashSet<String>>,
+    // Clients may not set these either. Only applies without `allowed_env`,
+    // which already names everything clients may set.
+    pub denied_env: HashSet<String>,
+    // Live terminals plus running commands
+    pub max_terminals: usize,
+    // Where terminals created with log_to_file write their output
+    pub log_dir: PathBuf,
+    // Size at which a terminal log is rotated
+    pub max_log_bytes: u64,
+}
+
+impl Default for TerminalConfig {
+    fn default() -> Self {
+        Self {
+            allowed_env: None,
+            denied_env: default_denied_env(),
+            max_terminals: DEFAULT_MAX_TERMINALS,
+            log_dir: default_log_dir(),
+            max_log_bytes: DEFAULT_MAX_TERMINAL_LOG_BYTES,
+        }
+    }
+}
+
+pub fn default_denied_env() -> HashSet<String> {
+    DEFAULT_DENIED_ENV.iter().map(|name| name.to_string()).collect()
+}
+
+pub fn default_log_dir() -> PathBuf {
+    std::env::temp_dir().join("websocket-ide-terminal-logs")
+}
+
+impl TerminalConfig {
+    pub fn validate_env(&self, env: &HashMap<String, String>) -> anyhow::Result<()> {
+        let is_allowed = |name: &String| match &self.allowed_env {
+            Some(allowed) => allowed.contains(name),
+            None => !self.denied_env.contains(name) && !name.starts_with(DENIED_ENV_PREFIX),
+        };
+        let mut rejected: Vec<&str> = env
+            .keys()
+            .filter(|name| !is_allowed(name))
+            .map(|name| name.as_str())
+            .collect();
+        if !rejected.is_empty() {
+            rejected.sort_unstable();
+            anyhow::bail!("Environment variables not allowed: {}", rejected.join(", "));
+        }
+
+        if let Some(name) = env.keys().find(|name| name.is_empty() || name.contains(['=', '\0'])) {
+            anyhow::bail!("Invalid environment variable name: {:?}", name);
+        }
+        Ok(())
+    }
+}
+
+// Machine-readable reason attached to terminal errors so clients can react
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum TerminalErrorCode {
+    // Input backlog is full, retry later or send smaller chunks
+    Busy,
+    // The terminal existed but its shell exited or it was closed
+    Closed,
+    // Too many terminals are open to create another one
+    LimitReached,
+}
+
+#[derive(Debug)]
+pub struct TerminalError {
+    pub code: TerminalErrorCode,
+    pub message: String,
+}
+
+impl TerminalError {
+    pub fn new(code: TerminalErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for TerminalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for TerminalError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_restricted_env_only_accepts_allowed_names() {
+        let config = TerminalConfig {
+            allowed_env: Some(HashSet::from(["RUST_LOG".to_string()])),
+            ..Default::default()
+        };
+
+        let allowed = HashMap::from([("RUST_LOG".to_string(), "debug".to_string())]);
+        assert!(config.validate_env(&allowed).is_ok());
+
+        let rejected = HashMap::from([("LD_PRELOAD".to_string(), "evil.so".to_string())]);
+        assert!(config.validate_env(&rejected).is_err());
+
+        // Unrestricted servers deny loader and shell startup variables
+        let unrestricted = TerminalConfig::default();
+        assert!(unrestricted.validate_env(&allowed).is_ok());
+        assert!(unrestricted.validate_env(&rejected).is_err());
+        for name in ["BASH_ENV", "BASH_FUNC_ls%%"] {
+            let denied = HashMap::from([(name.to_string(), String::new())]);
+            assert!(unrestricted.validate_env(&denied).is_err());
+        }
+        let config = TerminalConfig {
+            denied_env: HashSet::from(["RUST_LOG".to_string()]),
+            ..Default::default()
+        };
+        assert!(config.validate_env(&allowed).is_err());
+
+        // Listing a denied name in the allowlist lets clients set it
+        let config = TerminalConfig {
+            allowed_env: Some(HashSet::from(["LD_PRELOAD".to_string()])),
+            ..Default::default()
+        };
+        assert!(config.validate_env(&rejected).is_ok());
+
+        // Unrestricted servers still reject names the OS can't represent
+        let invalid = HashMap::from([("A=B".to_string(), String::new())]);
+        assert!(TerminalConfig::default().validate_env(&invalid).is_err());
+    }
 }
@@ -0,0 +1,129 @@
+// src/utils/metrics.rs
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+// Upper bounds of the histogram buckets; slower samples land in a final
+// unbounded bucket
+const BUCKET_BOUNDS_MS: [u64; 10] = [5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000];
+
+#[derive(Debug, Default)]
+pub struct LatencyHistogram {
+    buckets: [AtomicU64; BUCKET_BOUNDS_MS.len() + 1],
+    count: AtomicU64,
+    total_ms: AtomicU64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistogramBucket {
+    // None for the unbounded bucket
+    pub le_ms: Option<u64>,
+    // Samples at or below `le_ms`, cumulative like Prometheus buckets
+    pub count: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistogramSnapshot {
+    pub count: u64,
+    pub total_ms: u64,
+    pub buckets: Vec<HistogramBucket>,
+}
+
+impl LatencyHistogram {
+    pub fn record(&self, elapsed: Duration) {
+        let ms = elapsed.as_millis() as u64;
+        let bucket = BUCKET_BOUNDS_MS
+            .iter()
+            .position(|&bound| ms <= bound)
+            .unwrap_or(BUCKET_BOUNDS_MS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.total_ms.fetch_add(ms, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> HistogramSnapshot {
+        let mut cumulative = 0;
+        let buckets = self
+            .buckets
+            .iter()
+            .enumerate()
+            .map(|(index, bucket)| {
+                cumulative += bucket.load(Ordering::Relaxed);
+                HistogramBucket {
+                    le_ms: BUCKET_BOUNDS_MS.get(index).copied(),
+                    count: cumulative,
+                }
+            })
+            .collect();
+        HistogramSnapshot {
+            count: self.count.load(Ordering::Relaxed),
+            total_ms: self.total_ms.load(Ordering::Relaxed),
+            buckets,
+        }
+    }
+}
+
+// Histograms by name, e.g. one per message type
+#[derive(Debug, Default)]
+pub struct LatencyRegistry {
+    histograms: RwLock<HashMap<String, Arc<LatencyHistogram>>>,
+}
+
+impl LatencyRegistry {
+    pub fn record(&self, name: &str, elapsed: Duration) {
+        let existing = self
+            .histograms
+            .read()
+            .ok()
+            .and_then(|histograms| histograms.get(name).cloned());
+        let histogram = match existing {
+            Some(histogram) => histogram,
+            None => match self.histograms.write() {
+                Ok(mut histograms) => Arc::clone(histograms.entry(name.to_string()).or_default()),
+                Err(_) => return,
+            },
+        };
+        histogram.record(elapsed);
+    }
+
+    pub fn snapshot(&self) -> BTreeMap<String, HistogramSnapshot> {
+        self.histograms
+            .read()
+            .map(|histograms| {
+                histograms
+                    .iter()
+                    .map(|(name, histogram)| (name.clone(), histogram.snapshot()))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram_buckets_are_cumulative() {
+        let registry = LatencyRegistry::default();
+        registry.record("Hover", Duration::from_millis(3));
+        registry.record("Hover", Duration::from_millis(40));
+        registry.record("Hover", Duration::from_secs(10));
+        registry.record("Search", Duration::from_millis(5));
+
+        let snapshot = registry.snapshot();
+        let hover = &snapshot["Hover"];
+        assert_eq!(hover.count, 3);
+        assert_eq!(hover.total_ms, 10_043);
+
+        let count_at = |le_ms| hover.buckets.iter().find(|b| b.le_ms == le_ms).unwrap().count;
+        assert_eq!(count_at(Some(5)), 1);
+        assert_eq!(count_at(Some(25)), 1);
+        assert_eq!(count_at(Some(50)), 2);
+        assert_eq!(count_at(Some(5000)), 2);
+        assert_eq!(count_at(None), 3);
+        assert_eq!(snapshot["Search"].count, 1);
+    }
+}
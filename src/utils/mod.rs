@@ -1 +1,2 @@
+pub mod metrics;
 pub mod path_utils;
\ No newline at end of file
@@ -2,54 +2,83 @@
 use crate::file_system::VersionedDocument;
 use anyhow::bail;
 use anyhow::Result;
-use std::path::PathBuf;
+use std::path::{Component, Path, PathBuf};
 
-pub fn join_workspace_path(workspace_root: &PathBuf, relative_path: &str) -> Result<PathBuf> {
+pub fn join_workspace_path(workspace_root: &Path, relative_path: &str) -> Result<PathBuf> {
     // If empty path, return workspace root
     if relative_path.is_empty() {
-        return Ok(workspace_root.clone());
+        return Ok(workspace_root.to_path_buf());
     }
 
 
-    // If path starts with workspace root, use it directly
-    let path = PathBuf::from(relative_path);
-    if relative_path.starts_with(workspace_root.to_string_lossy().as_ref()) {
-        return Ok(path);
-    }
-
-    // Otherwise join with workspace root
-    let full_path = workspace_root.join(relative_path);
+    // If path starts with workspace root, use it directly, otherwise join with
+    // workspace root. `..` is resolved here because the target may not exist
+    // yet, so there is nothing to canonicalize.
+    let full_path = resolve_parent_dirs(&workspace_root.join(relative_path));
 
     // Basic validation - check it would be within workspace
-    if !full_path.starts_with(workspace_root) {
+    if !is_within(&full_path, workspace_root) {
         bail!("Path would be outside of workspace");
     }
 
     Ok(full_path)
 }
 
-pub fn get_full_path(workspace_root: &PathBuf, relative_path: &str) -> Result<PathBuf> {
+// Drops `..` together with the component before it. `Path::starts_with`
+// compares components literally, so `/ws/../x` would otherwise count as inside `/ws`.
+fn resolve_parent_dirs(path: &Path) -> PathBuf {
+    let mut resolved = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::ParentDir => {
+                resolved.pop();
+            }
+            Component::CurDir => {}
+            other => resolved.push(other),
+        }
+    }
+    resolved
+}
+
+pub fn get_full_path(workspace_root: &Path, relative_path: &str) -> Result<PathBuf> {
     let joined_path = join_workspace_path(workspace_root, relative_path)?;
     let canonical = joined_path.canonicalize()?;
     validate_workspace_path(workspace_root, &canonical)?;
     Ok(canonical)
 }
 
+// Like get_full_path, but also accepts absolute paths under one of `allowed_roots`.
+// Only for read-only operations, anything that writes must stay in the workspace.
+pub fn get_readable_path(
+    workspace_root: &Path,
+    allowed_roots: &[PathBuf],
+    path: &str,
+) -> Result<PathBuf> {
+    let candidate = Path::new(path);
+    if candidate.is_absolute() && !is_within(candidate, workspace_root) {
+        let canonical = candidate.canonicalize()?;
+        if allowed_roots.iter().any(|root| is_within(&canonical, root)) {
+            return Ok(canonical);
+        }
+    }
+    get_full_path(workspace_root, path)
+}
+
 pub fn canonicalize_document_path(
-    workspace_root: &PathBuf,
+    workspace_root: &Path,
     doc: &VersionedDocument,
 ) -> Result<PathBuf> {
     // Handle absolute paths
     if doc.uri.is_absolute() {
         let canonical = doc.uri.canonicalize()?;
-        if canonical.starts_with(workspace_root) {
+        if is_within(&canonical, workspace_root) {
             return Ok(canonical);
         }
     }
 
     // Handle relative or empty paths
     let path = if doc.uri.to_string_lossy().is_empty() {
-        workspace_root.clone()
+        workspace_root.to_path_buf()
     } else {
         workspace_root.join(&doc.uri)
     };
@@ -60,21 +89,118 @@ pub fn canonicalize_document_path(
     Ok(canonical)
 }
 
-fn validate_workspace_path(workspace_root: &PathBuf, path: &PathBuf) -> Result<()> {
-    println!("validating");
-    if !path.starts_with(workspace_root) {
-        anyhow::bail!("Path is outside of workspace: {:?}", path);
+// Whether the platform's usual filesystems (APFS, NTFS) ignore case in names
+const CASE_INSENSITIVE_FS: bool = cfg!(any(target_os = "macos", windows));
+
+// Whether `path` is `root` or inside it, for workspace boundary checks. Where
+// the filesystem ignores case, `/Users/Foo/proj` and `/users/foo/proj` are the
+// same directory even though `Path::starts_with` tells them apart. A path
+// that still has `..` in it never counts, it may climb back out of `root`.
+pub fn is_within(path: &Path, root: &Path) -> bool {
+    !path.components().any(|c| c == Component::ParentDir)
+        && starts_with(path, root, CASE_INSENSITIVE_FS)
+}
+
+fn starts_with(path: &Path, root: &Path, ignore_case: bool) -> bool {
+    if !ignore_case {
+        return path.starts_with(root);
     }
-    println!("done validating");
-    Ok(())
+    let mut components = path.components();
+    root.components().all(|expected| {
+        components.next().is_some_and(|component| {
+            component.as_os_str().to_string_lossy().to_lowercase()
+                == expected.as_os_str().to_string_lossy().to_lowercase()
+        })
+    })
+}
+
+pub fn uri_to_path(uri: &lsp_types::Uri) -> Result<PathBuf> {
+    url::Url::parse(uri.as_str())?
+        .to_file_path()
+        .map_err(|_| anyhow::anyhow!("Not a file URI: {}", uri.as_str()))
+}
+
+// Which symlinks directory listings, search and the file watcher go through
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum SymlinkPolicy {
+    Never,
+    // Only links whose target resolves to a path inside the workspace
+    #[default]
+    WithinWorkspace,
+    Always,
+}
+
+impl SymlinkPolicy {
+    // Whether the symlink at `link` may be followed; `workspace` must be canonical
+    pub fn follows(&self, link: &Path, workspace: &Path) -> bool {
+        match self {
+            SymlinkPolicy::Never => false,
+            SymlinkPolicy::Always => true,
+            SymlinkPolicy::WithinWorkspace => link
+                .canonicalize()
+                .is_ok_and(|target| is_within(&target, workspace)),
+        }
+    }
+
+    // Whether `path` can be reached without going through a symlinked directory
+    // the policy doesn't follow. `path` itself may be a link.
+    pub fn reaches(&self, path: &Path, workspace: &Path) -> bool {
+        if *self == SymlinkPolicy::Always {
+            return true;
+        }
+        let Some(parent) = path.parent() else {
+            return true;
+        };
+        let Ok(relative) = parent.strip_prefix(workspace) else {
+            return true;
+        };
+        let mut current = workspace.to_path_buf();
+        for component in relative.components() {
+            current.push(component);
+            let is_link = current
+                .symlink_metadata()
+                .is_ok_and(|metadata| metadata.file_type().is_symlink());
+            if is_link && !self.follows(&current, workspace) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+// Directories that workspace-wide walks (search, replace) skip. Only the part
+// below the workspace counts, so a workspace inside e.g. `target` is still walked.
+pub fn is_ignored_path(path: &Path, workspace: &Path) -> bool {
+    let relative = path.strip_prefix(workspace).unwrap_or(path);
+    relative.components().any(|c| {
+        let s = c.as_os_str().to_string_lossy();
+        s == ".git" || s == "node_modules" || s == "target"
+    })
+}
+
+pub fn path_to_uri(path: &Path) -> Result<lsp_types::Uri> {
+    let url = url::Url::from_file_path(path)
+        .map_err(|_| anyhow::anyhow!("Invalid file path: {:?}", path))?;
+    url.as_str()
+        .parse()
+        .map_err(|e| anyhow::anyhow!("Invalid URI {}: {}", url, e))
 }
 
-pub fn to_relative_path(workspace_root: &PathBuf, path: &PathBuf) -> Option<PathBuf> {
+pub fn to_relative_path(workspace_root: &Path, path: &Path) -> Option<PathBuf> {
     path.strip_prefix(workspace_root)
         .ok()
         .map(|p| p.to_path_buf())
 }
 
+fn validate_workspace_path(workspace_root: &Path, path: &Path) -> Result<()> {
+    println!("validating");
+    if !is_within(path, workspace_root) {
+        anyhow::bail!("Path is outside of workspace: {:?}", path);
+    }
+    println!("done validating");
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -110,4 +236,94 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_parent_dirs_cannot_leave_workspace() -> Result<()> {
+        let workspace = setup_test_workspace();
+        let workspace_root = workspace.path().to_path_buf();
+
+        // `..` can't leave the workspace, even for paths that don't exist yet
+        assert!(join_workspace_path(&workspace_root, "../x.bin").is_err());
+        assert!(join_workspace_path(&workspace_root, "subdir/../../x.bin").is_err());
+        assert_eq!(
+            join_workspace_path(&workspace_root, "subdir/../new.txt")?,
+            workspace_root.join("new.txt")
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_readable_path_accepts_allowed_roots() -> Result<()> {
+        let workspace = setup_test_workspace();
+        let workspace_root = workspace.path().canonicalize()?;
+        let outside = setup_test_workspace();
+        let outside_root = outside.path().canonicalize()?;
+        let outside_file = outside_root.join("test.txt");
+        let outside_path = outside_file.to_str().unwrap();
+
+        assert!(get_readable_path(&workspace_root, &[], outside_path).is_err());
+        assert_eq!(
+            get_readable_path(&workspace_root, &[outside_root], outside_path)?,
+            outside_file
+        );
+        // Allowed roots don't make writable paths out of them
+        assert!(get_full_path(&workspace_root, outside_path).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_case_insensitive_prefix_matches_whole_components() {
+        let root = Path::new("/Users/Foo/proj");
+        assert!(starts_with(Path::new("/users/foo/PROJ/src/main.rs"), root, true));
+        assert!(!starts_with(Path::new("/users/foo/PROJ/src/main.rs"), root, false));
+        assert!(!starts_with(Path::new("/users/foo/project"), root, true));
+        assert!(!starts_with(Path::new("/users/foo"), root, true));
+        assert!(!is_within(Path::new("/Users/Foo/proj/../outside.bin"), root));
+    }
+
+    #[test]
+    fn test_ignored_paths_only_count_below_the_workspace() {
+        let workspace = Path::new("/home/me/target/ws");
+        assert!(!is_ignored_path(&workspace.join("src/main.rs"), workspace));
+        assert!(is_ignored_path(&workspace.join("node_modules/x.js"), workspace));
+        assert!(is_ignored_path(&workspace.join("sub/.git/HEAD"), workspace));
+    }
+
+    #[cfg(any(target_os = "macos", windows))]
+    #[test]
+    fn test_mixed_case_paths_stay_in_workspace() -> Result<()> {
+        let workspace = setup_test_workspace();
+        let workspace_root = workspace.path().canonicalize()?;
+        let file = workspace_root.join("test.txt");
+        let mixed_case = file.to_string_lossy().to_uppercase();
+
+        assert!(validate_workspace_path(&workspace_root, &PathBuf::from(&mixed_case)).is_ok());
+        assert!(get_full_path(&workspace_root, &mixed_case).is_ok());
+        assert!(get_full_path(&workspace_root, "TEST.TXT").is_ok());
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlink_policy() -> Result<()> {
+        let workspace = setup_test_workspace();
+        let workspace_root = workspace.path().canonicalize()?;
+        let outside = setup_test_workspace();
+        std::os::unix::fs::symlink(workspace_root.join("subdir"), workspace_root.join("inside"))?;
+        std::os::unix::fs::symlink(outside.path(), workspace_root.join("outside"))?;
+
+        let policy = SymlinkPolicy::WithinWorkspace;
+        assert!(policy.follows(&workspace_root.join("inside"), &workspace_root));
+        assert!(!policy.follows(&workspace_root.join("outside"), &workspace_root));
+        assert!(policy.reaches(&workspace_root.join("inside/a.txt"), &workspace_root));
+        assert!(!policy.reaches(&workspace_root.join("outside/test.txt"), &workspace_root));
+        // The link itself is part of the workspace
+        assert!(policy.reaches(&workspace_root.join("outside"), &workspace_root));
+
+        assert!(!SymlinkPolicy::Never.reaches(&workspace_root.join("inside/a.txt"), &workspace_root));
+        assert!(SymlinkPolicy::Always.reaches(&workspace_root.join("outside/test.txt"), &workspace_root));
+        Ok(())
+    }
 }